@@ -0,0 +1,181 @@
+use anyhow::{Result, anyhow};
+use log::warn;
+use reqwest::redirect;
+use serde::Deserialize;
+use std::io::Read;
+use std::net::{IpAddr, ToSocketAddrs};
+use url::Url;
+
+use crate::http::{HttpConfig, MAX_REDIRECTS};
+
+/// Guardrails for urls found inside feed content (content src links,
+/// linked articles), since an untrusted feed can point these anywhere
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FetchPolicy {
+    /// Follow urls found in feed content at all
+    pub enabled: bool,
+    /// When non-empty only these hosts (and their subdomains) are fetched
+    pub allow_hosts: Vec<String>,
+    /// Hosts (and their subdomains) that are never fetched
+    pub deny_hosts: Vec<String>,
+    /// Allow hosts resolving to loopback, private, or link-local addresses
+    pub allow_private: bool,
+    /// Largest response body read per fetch
+    pub max_bytes: u64,
+}
+
+impl Default for FetchPolicy {
+    fn default() -> Self {
+        FetchPolicy {
+            enabled: true,
+            allow_hosts: vec![],
+            deny_hosts: vec![],
+            allow_private: false,
+            max_bytes: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// Fetch an html page found in feed content, subject to the policy.
+/// Every redirect hop is checked against the policy as well.
+pub fn fetch_html(policy: &FetchPolicy, http: &HttpConfig, url: &str) -> Result<String> {
+    let (content_type, body) = fetch(policy, http, url)?;
+    if !content_type.contains("html") {
+        return Err(anyhow!("expected html but got {:?}", content_type));
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Fetch an image referenced by feed content, returning its mime type
+/// and bytes
+pub fn fetch_image(
+    policy: &FetchPolicy,
+    http: &HttpConfig,
+    url: &str,
+) -> Result<(String, Vec<u8>)> {
+    let (content_type, body) = fetch(policy, http, url)?;
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    if !mime_type.starts_with("image/") {
+        return Err(anyhow!("expected an image but got {:?}", content_type));
+    }
+    Ok((mime_type, body))
+}
+
+fn fetch(policy: &FetchPolicy, http: &HttpConfig, url: &str) -> Result<(String, Vec<u8>)> {
+    if let Err(e) = check_url(policy, url) {
+        warn!("Blocked fetch of {}: {}", url, e);
+        return Err(e);
+    }
+
+    let redirect_policy = policy.clone();
+    let client = http
+        .client()?
+        .redirect(redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                return attempt.error(anyhow!("too many redirects"));
+            }
+            match check_url(&redirect_policy, attempt.url().as_str()) {
+                Ok(_) => attempt.follow(),
+                Err(e) => {
+                    warn!("Blocked redirect to {}: {}", attempt.url(), e);
+                    attempt.error(e)
+                }
+            }
+        }))
+        .build()?;
+
+    let resp = http.send(client.get(url))?.error_for_status()?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut body = vec![];
+    resp.take(policy.max_bytes + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > policy.max_bytes {
+        warn!(
+            "Blocked fetch of {}: response exceeds {} bytes",
+            url, policy.max_bytes
+        );
+        return Err(anyhow!("response exceeds {} bytes", policy.max_bytes));
+    }
+    Ok((content_type, body))
+}
+
+fn check_url(policy: &FetchPolicy, url: &str) -> Result<()> {
+    if !policy.enabled {
+        return Err(anyhow!("following content urls is disabled"));
+    }
+
+    let parsed = Url::parse(url)?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(anyhow!("unsupported scheme {}", parsed.scheme()));
+    }
+    let host = parsed
+        .host_str()
+        .ok_or(anyhow!("url has no host"))?
+        .to_lowercase();
+
+    if !policy.allow_hosts.is_empty() && !matches_any(&host, &policy.allow_hosts) {
+        return Err(anyhow!("host {} is not in allow_hosts", host));
+    }
+    if matches_any(&host, &policy.deny_hosts) {
+        return Err(anyhow!("host {} is in deny_hosts", host));
+    }
+
+    if !policy.allow_private {
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        for addr in (host, port).to_socket_addrs()? {
+            if is_private(addr.ip()) {
+                return Err(anyhow!(
+                    "host {} resolves to private address {}",
+                    host,
+                    addr.ip()
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the host is one of the domains or a subdomain of one
+pub fn matches_any(host: &str, domains: &[String]) -> bool {
+    domains.iter().any(|domain| {
+        let domain = domain.trim().trim_start_matches('.').to_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_private(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // link local fe80::/10
+                || (first & 0xffc0) == 0xfe80
+        }
+    }
+}
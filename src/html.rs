@@ -0,0 +1,901 @@
+use anyhow::{Result, anyhow};
+use html5ever::tree_builder::TreeBuilderOpts;
+use html5ever::{Attribute, LocalName, ParseOpts, QualName, ns, parse_document};
+use markup5ever_rcdom::{Handle, Node, NodeData, RcDom, SerializableHandle};
+use std::cell::RefCell;
+use std::rc::Rc;
+use tendril::{StrTendril, TendrilSink};
+use url::Url;
+use xml5ever::serialize::{SerializeOpts, serialize as serialize_xml};
+
+use crate::fetch;
+
+// Small helpers for rewriting the rcdom tree produced by parse_xhtml
+// before it gets serialized into a chapter
+
+pub fn parse(content: &str) -> Result<RcDom> {
+    let parse_opts = ParseOpts {
+        tree_builder: TreeBuilderOpts {
+            drop_doctype: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let dom = parse_document(RcDom::default(), parse_opts)
+        .from_utf8()
+        .read_from(&mut content.as_bytes())?;
+
+    // reparsing our own xhtml keeps xmlns as a plain attribute, which the
+    // serializer would then write out next to the namespace it adds itself
+    for html in elements(&dom.document, "html") {
+        if let NodeData::Element { attrs, .. } = &html.data {
+            attrs.borrow_mut().retain(|x| &*x.name.local != "xmlns");
+        }
+    }
+    Ok(dom)
+}
+
+pub fn serialize(document: &Handle) -> Result<String> {
+    // Prepare for serialization
+    let mut buffer = Vec::new();
+
+    // Serialize with XML compliant options
+    let ser_opts = SerializeOpts {
+        // traversal_scope: TraversalScope::IncludeNode,
+        ..Default::default()
+    };
+
+    // Convert DOM to XHTML
+    let document: SerializableHandle = document.clone().into();
+    serialize_xml(&mut buffer, &document, ser_opts)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Some feeds escape their html twice, e.g. inside CDATA, so once parsed
+/// it is text reading "<p>..." instead of markup. When the content has
+/// no elements of its own but its text starts with a tag and parses to
+/// some, that text is the html the feed meant.
+pub fn unescape_escaped_html(content: &str) -> Result<Option<String>> {
+    let dom = parse(content)?;
+    if has_body_elements(&dom.document) {
+        return Ok(None);
+    }
+    let text = text_content(&dom.document);
+    let text = text.trim();
+    if !text.starts_with('<') || !has_body_elements(&parse(text)?.document) {
+        return Ok(None);
+    }
+    Ok(Some(text.to_string()))
+}
+
+fn has_body_elements(document: &Handle) -> bool {
+    let mut found = vec![];
+    for body in elements(document, "body") {
+        for child in body.children.borrow().iter() {
+            collect_all_elements(child, &mut found);
+        }
+    }
+    !found.is_empty()
+}
+
+/// Collect every element with the given tag name in document order
+pub fn elements(handle: &Handle, tag: &str) -> Vec<Handle> {
+    let mut found = vec![];
+    collect_elements(handle, tag, &mut found);
+    found
+}
+
+fn collect_elements(handle: &Handle, tag: &str, found: &mut Vec<Handle>) {
+    if is_element(handle, tag) {
+        found.push(handle.clone());
+    }
+    for child in handle.children.borrow().iter() {
+        collect_elements(child, tag, found);
+    }
+}
+
+pub fn is_element(handle: &Handle, tag: &str) -> bool {
+    match &handle.data {
+        NodeData::Element { name, .. } => name.local.as_ref() == tag,
+        _ => false,
+    }
+}
+
+pub fn get_attr(handle: &Handle, attr: &str) -> Option<String> {
+    match &handle.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|x| x.name.local.as_ref() == attr)
+            .map(|x| x.value.to_string()),
+        _ => None,
+    }
+}
+
+pub fn set_attr(handle: &Handle, attr: &str, value: &str) {
+    if let NodeData::Element { attrs, .. } = &handle.data {
+        let mut attrs = attrs.borrow_mut();
+        match attrs.iter_mut().find(|x| x.name.local.as_ref() == attr) {
+            Some(existing) => existing.value = StrTendril::from(value),
+            None => attrs.push(Attribute {
+                name: QualName::new(None, ns!(), LocalName::from(attr)),
+                value: StrTendril::from(value),
+            }),
+        }
+    }
+}
+
+/// Concatenate all text beneath a node
+pub fn text_content(handle: &Handle) -> String {
+    let mut text = String::new();
+    collect_text(handle, &mut text);
+    text
+}
+
+fn collect_text(handle: &Handle, text: &mut String) {
+    if let NodeData::Text { contents } = &handle.data {
+        text.push_str(&contents.borrow());
+    }
+    for child in handle.children.borrow().iter() {
+        collect_text(child, text);
+    }
+}
+
+/// Replace all children of a node
+pub fn set_children(node: &Handle, children: Vec<Handle>) {
+    for child in &children {
+        child.parent.set(Some(Rc::downgrade(node)));
+    }
+    let old = std::mem::replace(&mut *node.children.borrow_mut(), children);
+    for child in old {
+        child.parent.set(None);
+    }
+}
+
+pub fn prepend_child(node: &Handle, child: Handle) {
+    child.parent.set(Some(Rc::downgrade(node)));
+    node.children.borrow_mut().insert(0, child);
+}
+
+pub fn text_node(text: &str) -> Handle {
+    Node::new(NodeData::Text {
+        contents: RefCell::new(StrTendril::from(text)),
+    })
+}
+
+pub fn new_element(tag: &str, attrs: &[(&str, &str)], children: Vec<Handle>) -> Handle {
+    let attrs = attrs
+        .iter()
+        .map(|(name, value)| Attribute {
+            name: QualName::new(None, ns!(), LocalName::from(*name)),
+            value: StrTendril::from(*value),
+        })
+        .collect();
+    let element = Node::new(NodeData::Element {
+        name: QualName::new(None, ns!(html), LocalName::from(tag)),
+        attrs: RefCell::new(attrs),
+        template_contents: RefCell::new(None),
+        mathml_annotation_xml_integration_point: false,
+    });
+    set_children(&element, children);
+    element
+}
+
+/// Join the bodies of several documents into the first one, each under
+/// an h2 heading with the given anchor and title
+pub fn merge_documents(parts: Vec<(&str, &str, &str)>) -> Result<String> {
+    let mut merged = None;
+    let mut children = vec![];
+    for (anchor, title, content) in parts {
+        let dom = parse(content)?;
+        children.push(new_element("h2", &[("id", anchor)], vec![text_node(title)]));
+        if let Some(body) = elements(&dom.document, "body").first() {
+            children.extend(std::mem::take(&mut *body.children.borrow_mut()));
+        }
+        merged.get_or_insert(dom);
+    }
+    let merged = merged.ok_or(anyhow!("no documents to merge"))?;
+    let body = elements(&merged.document, "body")
+        .into_iter()
+        .next()
+        .ok_or(anyhow!("merged document has no body"))?;
+    append_children(&body, children);
+    serialize(&merged.document)
+}
+
+/// Swap a node out of its parent for the given replacement nodes
+pub fn replace_node(node: &Handle, replacements: Vec<Handle>) {
+    let Some(weak) = node.parent.take() else {
+        return;
+    };
+    let Some(parent) = weak.upgrade() else {
+        return;
+    };
+    let mut children = parent.children.borrow_mut();
+    if let Some(i) = children.iter().position(|x| Rc::ptr_eq(x, node)) {
+        for replacement in &replacements {
+            replacement.parent.set(Some(Rc::downgrade(&parent)));
+        }
+        children.splice(i..=i, replacements);
+    }
+}
+
+/// Collect every element matching one of the given tag names in document order
+pub fn elements_any(handle: &Handle, tags: &[&str]) -> Vec<Handle> {
+    let mut found = vec![];
+    collect_elements_any(handle, tags, &mut found);
+    found
+}
+
+fn collect_elements_any(handle: &Handle, tags: &[&str], found: &mut Vec<Handle>) {
+    if tags.iter().any(|tag| is_element(handle, tag)) {
+        found.push(handle.clone());
+    }
+    for child in handle.children.borrow().iter() {
+        collect_elements_any(child, tags, found);
+    }
+}
+
+/// Swap a node out of its parent for its own children
+pub fn unwrap_node(node: &Handle) {
+    let children = std::mem::take(&mut *node.children.borrow_mut());
+    replace_node(node, children);
+}
+
+pub fn append_children(node: &Handle, children: Vec<Handle>) {
+    for child in &children {
+        child.parent.set(Some(Rc::downgrade(node)));
+    }
+    node.children.borrow_mut().extend(children);
+}
+
+/// Keep the first `max` images and replace the rest with their alt text,
+/// or a link to the image when there is no alt text.
+/// Returns the number of images dropped.
+pub fn limit_images(document: &Handle, max: usize) -> usize {
+    let images = elements(document, "img");
+    let mut dropped = 0;
+    for img in images.iter().skip(max) {
+        let alt = get_attr(img, "alt").filter(|x| !x.trim().is_empty());
+        let src = get_attr(img, "src");
+        let replacement = match (alt, src) {
+            (Some(alt), _) => vec![text_node(&alt)],
+            (None, Some(src)) => vec![new_element("a", &[("href", &src)], vec![text_node(&src)])],
+            (None, None) => vec![],
+        };
+        replace_node(img, replacement);
+        dropped += 1;
+    }
+    dropped
+}
+
+/// Unwrap links and drop images that point at a denied domain or one of its
+/// subdomains. Returns the number of links and images removed.
+pub fn apply_link_denylist(document: &Handle, denylist: &[String]) -> (usize, usize) {
+    let mut links = 0;
+    for a in elements(document, "a") {
+        if get_attr(&a, "href").is_some_and(|x| is_denied(&x, denylist)) {
+            unwrap_node(&a);
+            links += 1;
+        }
+    }
+    let mut images = 0;
+    for img in elements(document, "img") {
+        if get_attr(&img, "src").is_some_and(|x| is_denied(&x, denylist)) {
+            replace_node(&img, vec![]);
+            images += 1;
+        }
+    }
+    (links, images)
+}
+
+fn is_denied(url: &str, denylist: &[String]) -> bool {
+    let Some(host) = Url::parse(url)
+        .ok()
+        .and_then(|x| x.host_str().map(|h| h.to_lowercase()))
+    else {
+        return false;
+    };
+    fetch::matches_any(&host, denylist)
+}
+
+/// Give every h2/h3 heading an anchor and return (anchor, heading text) pairs
+/// in document order, skipping headings with no text
+pub fn anchor_headings(document: &Handle) -> Vec<(String, String)> {
+    let mut sections = vec![];
+    for (i, heading) in elements_any(document, &["h2", "h3"]).iter().enumerate() {
+        let text = text_content(heading)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if text.is_empty() {
+            continue;
+        }
+        let anchor = match get_attr(heading, "id").filter(|x| !x.is_empty()) {
+            Some(id) => id,
+            None => {
+                let id = format!("section_{}", i + 1);
+                set_attr(heading, "id", &id);
+                id
+            }
+        };
+        sections.push((anchor, text));
+    }
+    sections
+}
+
+/// Find the first absolute link pointing away from `own_host`
+pub fn first_outbound_link(document: &Handle, own_host: Option<&str>) -> Option<String> {
+    elements(document, "a")
+        .iter()
+        .filter_map(|a| get_attr(a, "href"))
+        .find(|href| {
+            Url::parse(href)
+                .is_ok_and(|x| matches!(x.scheme(), "http" | "https") && x.host_str() != own_host)
+        })
+}
+
+/// Pick the element most likely to hold a page's article and strip page
+/// furniture from it. Falls back to the body when there is no article or main.
+pub fn main_content(document: &Handle) -> Option<Handle> {
+    let content = elements(document, "article")
+        .into_iter()
+        .chain(elements(document, "main"))
+        .chain(elements(document, "body"))
+        .next()?;
+    let furniture = [
+        "script", "style", "noscript", "nav", "header", "footer", "aside", "form", "iframe",
+    ];
+    for node in elements_any(&content, &furniture) {
+        replace_node(&node, vec![]);
+    }
+    Some(content)
+}
+
+/// Strip markup that is useless or harmful in an e-reader. Scripts, styles,
+/// iframes, and 1x1 tracking pixels are dropped along with their contents,
+/// event handler and javascript: attributes are removed, and any other tag
+/// outside the allowlist is unwrapped so its text is kept.
+/// Returns the number of elements dropped or unwrapped.
+pub fn sanitize(document: &Handle, allowed_tags: &[String]) -> usize {
+    let is_allowed = |handle: &Handle| match tag_name(handle) {
+        Some(tag) => {
+            matches!(tag.as_str(), "html" | "head" | "body")
+                || allowed_tags.iter().any(|x| x.eq_ignore_ascii_case(&tag))
+        }
+        None => true,
+    };
+
+    let mut removed = 0;
+    for node in elements_any(document, &["script", "style", "iframe", "img"]) {
+        let is_pixel = is_element(&node, "img")
+            && get_attr(&node, "width").as_deref() == Some("1")
+            && get_attr(&node, "height").as_deref() == Some("1");
+        if (!is_element(&node, "img") && !is_allowed(&node)) || is_pixel {
+            replace_node(&node, vec![]);
+            removed += 1;
+        }
+    }
+
+    let mut all = vec![];
+    collect_all_elements(document, &mut all);
+    for node in all {
+        if let NodeData::Element { attrs, .. } = &node.data {
+            attrs.borrow_mut().retain(|x| {
+                !x.name.local.to_ascii_lowercase().starts_with("on")
+                    && !x
+                        .value
+                        .trim()
+                        .to_ascii_lowercase()
+                        .starts_with("javascript:")
+            });
+        }
+        if !is_allowed(&node) {
+            unwrap_node(&node);
+            removed += 1;
+        }
+    }
+    removed
+}
+
+fn tag_name(handle: &Handle) -> Option<String> {
+    match &handle.data {
+        NodeData::Element { name, .. } => Some(name.local.to_string()),
+        _ => None,
+    }
+}
+
+fn collect_all_elements(handle: &Handle, found: &mut Vec<Handle>) {
+    if let NodeData::Element { .. } = &handle.data {
+        found.push(handle.clone());
+    }
+    for child in handle.children.borrow().iter() {
+        collect_all_elements(child, found);
+    }
+}
+
+/// Resolve relative link and image urls against the base, including
+/// protocol-relative ones. In-page fragment links are left alone.
+/// Returns the number of urls rewritten.
+pub fn absolutize_urls(document: &Handle, base: &Url) -> usize {
+    let mut rewritten = 0;
+    for (tag, attr) in [("a", "href"), ("img", "src"), ("source", "src")] {
+        for node in elements(document, tag) {
+            let Some(value) = get_attr(&node, attr) else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() || value.starts_with('#') || Url::parse(value).is_ok() {
+                continue;
+            }
+            if let Ok(absolute) = base.join(value) {
+                set_attr(&node, attr, absolute.as_str());
+                rewritten += 1;
+            }
+        }
+    }
+    rewritten
+}
+
+/// Remove query parameters named in `params` from link urls, a trailing *
+/// matches any name with that prefix, e.g. utm_*. Other parameters keep
+/// their original encoding and fragments are left alone.
+/// Returns the number of links rewritten.
+pub fn strip_tracking_params(document: &Handle, params: &[String]) -> usize {
+    let mut rewritten = 0;
+    for a in elements(document, "a") {
+        let Some(mut url) = get_attr(&a, "href").and_then(|x| Url::parse(x.trim()).ok()) else {
+            continue;
+        };
+        if strip_url_tracking_params(&mut url, params) {
+            set_attr(&a, "href", url.as_str());
+            rewritten += 1;
+        }
+    }
+    rewritten
+}
+
+/// Remove the query parameters named in `params` from the url, returning
+/// whether any were removed
+pub fn strip_url_tracking_params(url: &mut Url, params: &[String]) -> bool {
+    let is_tracking = |name: &str| {
+        params.iter().any(|x| match x.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == x,
+        })
+    };
+    let Some(query) = url.query() else {
+        return false;
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|x| !is_tracking(x.split('=').next().unwrap_or_default()))
+        .collect();
+    if kept.len() == query.split('&').count() {
+        return false;
+    }
+    let kept = kept.join("&");
+    url.set_query(Some(kept.as_str()).filter(|x| !x.is_empty()));
+    true
+}
+
+/// Find the first element matching a simple css selector: compounds of a
+/// tag name, #id, and .classes, e.g. "article.post", joined by spaces for
+/// descendants, e.g. "#main div.content"
+pub fn select_first(handle: &Handle, selector: &str) -> Option<Handle> {
+    let compounds: Vec<&str> = selector.split_whitespace().collect();
+    if compounds.is_empty() {
+        return None;
+    }
+    select_descendant(handle, &compounds)
+}
+
+/// Find every element matching a simple css selector, see select_first
+pub fn select_all(handle: &Handle, selector: &str) -> Vec<Handle> {
+    let compounds: Vec<&str> = selector.split_whitespace().collect();
+    let mut found = vec![];
+    if !compounds.is_empty() {
+        select_descendants(handle, &compounds, &mut found);
+    }
+    found
+}
+
+fn select_descendants(handle: &Handle, compounds: &[&str], found: &mut Vec<Handle>) {
+    let mut all = vec![];
+    for child in handle.children.borrow().iter() {
+        collect_all_elements(child, &mut all);
+    }
+    for x in all
+        .into_iter()
+        .filter(|x| matches_compound(x, compounds[0]))
+    {
+        match compounds.len() {
+            1 if !found.iter().any(|y| Rc::ptr_eq(y, &x)) => found.push(x),
+            1 => {}
+            _ => select_descendants(&x, &compounds[1..], found),
+        }
+    }
+}
+
+/// Remove every element matching one of the selectors, see select_first.
+/// Returns the number of elements removed.
+pub fn remove_selected(document: &Handle, selectors: &[String]) -> usize {
+    let mut removed = 0;
+    for selector in selectors {
+        for element in select_all(document, selector) {
+            replace_node(&element, vec![]);
+            removed += 1;
+        }
+    }
+    removed
+}
+
+fn select_descendant(handle: &Handle, compounds: &[&str]) -> Option<Handle> {
+    let mut all = vec![];
+    for child in handle.children.borrow().iter() {
+        collect_all_elements(child, &mut all);
+    }
+    all.into_iter()
+        .filter(|x| matches_compound(x, compounds[0]))
+        .find_map(|x| match compounds.len() {
+            1 => Some(x),
+            _ => select_descendant(&x, &compounds[1..]),
+        })
+}
+
+fn matches_compound(handle: &Handle, compound: &str) -> bool {
+    let Some(tag) = tag_name(handle) else {
+        return false;
+    };
+    let classes = get_attr(handle, "class").unwrap_or_default();
+    let id = get_attr(handle, "id");
+    // split before each # and . keeping the marker with its name
+    let mut parts = vec![];
+    let mut start = 0;
+    for (i, c) in compound.char_indices() {
+        if (c == '#' || c == '.') && i > start {
+            parts.push(&compound[start..i]);
+            start = i;
+        }
+    }
+    parts.push(&compound[start..]);
+    parts.iter().all(|part| {
+        if let Some(name) = part.strip_prefix('#') {
+            id.as_deref() == Some(name)
+        } else if let Some(name) = part.strip_prefix('.') {
+            classes.split_whitespace().any(|x| x == name)
+        } else {
+            *part == "*" || tag.eq_ignore_ascii_case(part)
+        }
+    })
+}
+
+// elements whose text is laid out exactly as written
+const PREFORMATTED_TAGS: &[&str] = &["pre", "code", "textarea"];
+
+// elements whose leading and trailing whitespace never renders
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "body",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "li",
+    "ol",
+    "p",
+    "section",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+/// Collapse runs of whitespace in text outside preformatted elements, trim
+/// it from the edges of block elements, and drop paragraphs left empty.
+/// Returns the number of empty paragraphs removed.
+pub fn normalize_whitespace(document: &Handle) -> usize {
+    let mut removed = 0;
+    for p in elements(document, "p") {
+        let empty = text_content(&p).trim().is_empty()
+            && p.children
+                .borrow()
+                .iter()
+                .all(|x| matches!(x.data, NodeData::Text { .. }) || is_element(x, "br"));
+        if empty {
+            replace_node(&p, vec![]);
+            removed += 1;
+        }
+    }
+    collapse_whitespace(document);
+    removed
+}
+
+fn collapse_whitespace(handle: &Handle) {
+    if PREFORMATTED_TAGS.iter().any(|tag| is_element(handle, tag)) {
+        return;
+    }
+    // text left side by side, e.g. around a removed paragraph, is merged
+    // so its whitespace collapses together
+    handle
+        .children
+        .borrow_mut()
+        .dedup_by(|next, previous| match (&next.data, &previous.data) {
+            (NodeData::Text { contents: next }, NodeData::Text { contents: previous }) => {
+                previous.borrow_mut().push_tendril(&next.borrow());
+                true
+            }
+            _ => false,
+        });
+    for child in handle.children.borrow().iter() {
+        if let NodeData::Text { contents } = &child.data {
+            let mut contents = contents.borrow_mut();
+            // non-breaking spaces are kept, they are usually deliberate
+            let mut collapsed = String::with_capacity(contents.len());
+            for c in contents.chars() {
+                match c.is_ascii_whitespace() {
+                    true if collapsed.ends_with(' ') => {}
+                    true => collapsed.push(' '),
+                    false => collapsed.push(c),
+                }
+            }
+            *contents = StrTendril::from(collapsed);
+        } else {
+            collapse_whitespace(child);
+        }
+    }
+
+    if BLOCK_TAGS.iter().any(|tag| is_element(handle, tag)) {
+        let mut children = handle.children.borrow_mut();
+        if let Some(NodeData::Text { contents }) = children.first().map(|x| &x.data) {
+            let trimmed = StrTendril::from(contents.borrow().trim_start_matches(' '));
+            *contents.borrow_mut() = trimmed;
+        }
+        if let Some(NodeData::Text { contents }) = children.last().map(|x| &x.data) {
+            let trimmed = StrTendril::from(contents.borrow().trim_end_matches(' '));
+            *contents.borrow_mut() = trimmed;
+        }
+        children.retain(|x| match &x.data {
+            NodeData::Text { contents } => !contents.borrow().is_empty(),
+            _ => true,
+        });
+    }
+}
+
+/// Clean untrusted entry html the way feed-rs would, but keep the class of
+/// code blocks, which carries their language, e.g. "language-rust"
+pub fn clean(content: &str) -> String {
+    ammonia::Builder::default()
+        .add_tag_attributes("code", &["class"])
+        .add_tag_attributes("pre", &["class"])
+        .clean(content)
+        .to_string()
+}
+
+/// The language a code block is marked as, from a language-x or lang-x
+/// class on the pre or the code inside it
+pub fn code_language(pre: &Handle) -> Option<String> {
+    let code = elements(pre, "code").into_iter().next();
+    [Some(pre.clone()), code]
+        .iter()
+        .flatten()
+        .filter_map(|x| get_attr(x, "class"))
+        .flat_map(|class| {
+            class
+                .split_whitespace()
+                .filter_map(|x| {
+                    x.strip_prefix("language-")
+                        .or_else(|| x.strip_prefix("lang-"))
+                })
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .next()
+}
+
+/// Escape text for use in xml built by hand
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the body of the content after the rewrite, serialized
+    fn rewrite(content: &str, rewrite: impl FnOnce(&Handle)) -> String {
+        let dom = parse(content).unwrap();
+        rewrite(&dom.document);
+        let html = serialize(&dom.document).unwrap();
+        let start = html.find("<body>").unwrap() + "<body>".len();
+        let end = html.rfind("</body>").unwrap();
+        html[start..end].to_string()
+    }
+
+    fn tags(tags: &str) -> Vec<String> {
+        tags.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn sanitize_drops_scripts_and_handlers() {
+        let html = rewrite(
+            r#"<p onclick="steal()">Hi<script>alert(1)</script></p><style>p {}</style><iframe src="https://ads.example.com"></iframe><a href="javascript:go()">x</a>"#,
+            |document| {
+                sanitize(document, &tags("p a"));
+            },
+        );
+        assert_eq!(html, "<p>Hi</p><a>x</a>");
+    }
+
+    #[test]
+    fn sanitize_unwraps_tags_outside_the_allowlist() {
+        let html = rewrite("<p><blink>kept</blink> text</p>", |document| {
+            sanitize(document, &tags("p"));
+        });
+        assert_eq!(html, "<p>kept text</p>");
+    }
+
+    #[test]
+    fn absolutize_urls_resolves_against_the_base() {
+        let base = Url::parse("https://blog.example.com/posts/entry").unwrap();
+        let html = rewrite(
+            concat!(
+                r#"<a href="/about">a</a><img src="pic.png"/>"#,
+                r#"<a href="https://other.example.com/x">b</a>"#,
+                r##"<img src="//cdn.example.com/y.png"/><a href="#notes">c</a>"##,
+            ),
+            |document| {
+                absolutize_urls(document, &base);
+            },
+        );
+        assert_eq!(
+            html,
+            concat!(
+                r#"<a href="https://blog.example.com/about">a</a>"#,
+                r#"<img src="https://blog.example.com/posts/pic.png"></img>"#,
+                r#"<a href="https://other.example.com/x">b</a>"#,
+                r##"<img src="https://cdn.example.com/y.png"></img><a href="#notes">c</a>"##,
+            )
+        );
+    }
+
+    fn stripped(url: &str) -> String {
+        let mut url = Url::parse(url).unwrap();
+        strip_url_tracking_params(&mut url, &tags("utm_* fbclid"));
+        url.to_string()
+    }
+
+    #[test]
+    fn strip_tracking_params_keeps_legitimate_params() {
+        assert_eq!(
+            stripped(
+                "https://example.com/a?id=7&utm_source=rss&utm_medium=feed&q=a%20b&fbclid=x#part"
+            ),
+            "https://example.com/a?id=7&q=a%20b#part"
+        );
+        assert_eq!(
+            stripped("https://example.com/a?utm_source=rss#part"),
+            "https://example.com/a#part"
+        );
+        assert_eq!(
+            stripped("https://example.com/a#part"),
+            "https://example.com/a#part"
+        );
+        // only whole names match unless the entry ends in *
+        assert_eq!(
+            stripped("https://example.com/a?fbclid_extra=1"),
+            "https://example.com/a?fbclid_extra=1"
+        );
+    }
+
+    #[test]
+    fn strip_tracking_params_rewrites_only_changed_links() {
+        let mut rewritten = 0;
+        let html = rewrite(
+            r#"<a href="https://example.com/?utm_campaign=x&amp;page=2">a</a><a href="https://example.com/b">b</a>"#,
+            |document| rewritten = strip_tracking_params(document, &tags("utm_*")),
+        );
+        assert_eq!(
+            html,
+            r#"<a href="https://example.com/?page=2">a</a><a href="https://example.com/b">b</a>"#
+        );
+        assert_eq!(rewritten, 1);
+    }
+
+    #[test]
+    fn unescape_escaped_html_decodes_doubly_escaped_markup() {
+        let unescaped =
+            unescape_escaped_html("&lt;p&gt;One&lt;/p&gt;&lt;p&gt;Two &amp;amp; more&lt;/p&gt;")
+                .unwrap();
+        assert_eq!(
+            unescaped.as_deref(),
+            Some("<p>One</p><p>Two &amp; more</p>")
+        );
+    }
+
+    #[test]
+    fn unescape_escaped_html_leaves_markup_and_plain_text() {
+        assert_eq!(
+            unescape_escaped_html("<p>&lt;p&gt; is a tag</p>").unwrap(),
+            None
+        );
+        assert_eq!(
+            unescape_escaped_html("1 &lt; 2 and 3 &gt; 2").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn normalize_whitespace_collapses_and_trims_text() {
+        let mut removed = 0;
+        let html = rewrite(
+            "<p>\n  Some   text\twith <em> erratic </em>\n gaps  </p>\n\n<p>&nbsp;</p><p> <br> </p>\n<p>Next</p>",
+            |document| removed = normalize_whitespace(document),
+        );
+        assert_eq!(
+            html,
+            "<p>Some text with <em> erratic </em> gaps</p> <p>Next</p>"
+        );
+        assert_eq!(removed, 2);
+    }
+
+    #[test]
+    fn normalize_whitespace_keeps_preformatted_text() {
+        let html = rewrite(
+            "<pre>fn main() {\n    println!();\n}</pre><p><code>a  =  1</code></p>",
+            |document| {
+                normalize_whitespace(document);
+            },
+        );
+        assert_eq!(
+            html,
+            "<pre>fn main() {\n    println!();\n}</pre><p><code>a  =  1</code></p>"
+        );
+    }
+
+    #[test]
+    fn clean_keeps_the_language_class_of_code_blocks() {
+        let cleaned = clean(
+            r#"<pre class="lang-go"><code class="language-go" onclick="x()">x  :=  1</code></pre><p class="note">Hi</p>"#,
+        );
+        assert_eq!(
+            cleaned,
+            r#"<pre class="lang-go"><code class="language-go">x  :=  1</code></pre><p>Hi</p>"#
+        );
+    }
+
+    #[test]
+    fn code_language_reads_the_pre_or_its_code() {
+        let language = |content: &str| {
+            let dom = parse(content).unwrap();
+            code_language(&elements(&dom.document, "pre")[0])
+        };
+        assert_eq!(
+            language(r#"<pre class="lang-go"><code>x</code></pre>"#).as_deref(),
+            Some("go")
+        );
+        assert_eq!(
+            language(r#"<pre><code class="hljs language-rust">x</code></pre>"#).as_deref(),
+            Some("rust")
+        );
+        assert_eq!(language("<pre><code>x</code></pre>"), None);
+    }
+}
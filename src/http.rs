@@ -0,0 +1,82 @@
+use anyhow::{Result, anyhow};
+use reqwest::Proxy;
+use reqwest::blocking::{ClientBuilder, RequestBuilder, Response};
+use reqwest::redirect::Policy;
+
+use crate::tls::TlsConfig;
+
+/// Most redirects followed for a single request before it fails
+pub const MAX_REDIRECTS: usize = 10;
+
+/// Settings every outbound http client is built with, so feed, article,
+/// image, and read-later requests all look and connect the same way
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub tls: TlsConfig,
+    pub user_agent: String,
+    /// Proxy for every request, reqwest falls back to the HTTP_PROXY and
+    /// HTTPS_PROXY environment variables when unset
+    pub proxy: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            tls: TlsConfig::default(),
+            user_agent: default_user_agent(),
+            proxy: None,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn client(&self) -> Result<ClientBuilder> {
+        // advertised in Accept-Encoding and decoded transparently
+        let mut builder = self
+            .tls
+            .http_client()?
+            .user_agent(&self.user_agent)
+            .gzip(true)
+            .deflate(true)
+            .brotli(true)
+            .redirect(self.redirect_policy());
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(parse_proxy(proxy)?);
+        }
+        Ok(builder)
+    }
+
+    pub fn send(&self, request: RequestBuilder) -> Result<Response> {
+        Ok(request.send()?)
+    }
+
+    // a pinned host is only trusted for itself, a redirect away from it
+    // would carry its credentials and headers to an unpinned peer
+    fn redirect_policy(&self) -> Policy {
+        let tls = self.tls.clone();
+        let limited = Policy::limited(MAX_REDIRECTS);
+        Policy::custom(move |attempt| {
+            let from = attempt.previous().last().and_then(|x| x.host_str());
+            if let Some(from) = from.filter(|x| tls.pins_host(x))
+                && attempt.url().host_str() != Some(from)
+            {
+                let error = format!(
+                    "refusing to follow a redirect from pinned host {} to {}",
+                    from,
+                    attempt.url()
+                );
+                return attempt.error(error);
+            }
+            limited.redirect(attempt)
+        })
+    }
+}
+
+pub fn default_user_agent() -> String {
+    format!("saga/{}", env!("CARGO_PKG_VERSION"))
+}
+
+// the url is left out of the error since it may carry credentials
+pub fn parse_proxy(proxy: &str) -> Result<Proxy> {
+    Proxy::all(proxy).map_err(|e| anyhow!("proxy is not a valid url: {}", e))
+}
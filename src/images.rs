@@ -0,0 +1,134 @@
+use anyhow::Result;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use std::io::Cursor;
+
+// quality of re-encoded jpegs, photos on e-ink don't show the difference
+const JPEG_QUALITY: u8 = 85;
+
+/// Downscale an image to max_width, keeping its aspect ratio, and convert
+/// it to grayscale when asked. Returns the new mime type and bytes, or
+/// None when the image is left as it is: svgs, formats that can't be
+/// decoded, and images that need neither change.
+pub fn optimize(
+    mime_type: &str,
+    data: &[u8],
+    max_width: Option<u32>,
+    grayscale: bool,
+) -> Result<Option<(String, Vec<u8>)>> {
+    if mime_type.starts_with("image/svg") {
+        return Ok(None);
+    }
+    let Some(format) = ImageFormat::from_mime_type(mime_type).filter(|x| x.reading_enabled())
+    else {
+        return Ok(None);
+    };
+    let mut img = image::load_from_memory_with_format(data, format)?;
+    let resize = max_width.filter(|&x| img.width() > x);
+    if resize.is_none() && !grayscale {
+        return Ok(None);
+    }
+
+    if let Some(width) = resize {
+        let height = (img.height() as u64 * width as u64 / img.width() as u64).max(1) as u32;
+        img = img.resize_exact(width, height, FilterType::Lanczos3);
+    }
+    if grayscale {
+        img = DynamicImage::ImageLumaA8(img.to_luma_alpha8());
+    }
+
+    // png keeps transparency and sharp edges for diagrams, anything else
+    // is taken to be a photo
+    let mut output = Cursor::new(vec![]);
+    match format {
+        ImageFormat::Png | ImageFormat::Gif => {
+            img.write_to(&mut output, ImageFormat::Png)?;
+            Ok(Some((String::from("image/png"), output.into_inner())))
+        }
+        _ => {
+            let img = match grayscale {
+                true => DynamicImage::ImageLuma8(img.to_luma8()),
+                false => DynamicImage::ImageRgb8(img.to_rgb8()),
+            };
+            img.write_with_encoder(JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY))?;
+            Ok(Some((String::from("image/jpeg"), output.into_inner())))
+        }
+    }
+}
+
+// edge of the square favicons are scaled down to
+const ICON_SIZE: u32 = 64;
+
+/// Decode a favicon, trusting its bytes over the mime type servers often
+/// get wrong for .ico files, and re-encode it as a png no larger than
+/// ICON_SIZE. Svg icons are kept as they are.
+pub fn icon(mime_type: &str, data: &[u8]) -> Result<(String, Vec<u8>)> {
+    if mime_type.starts_with("image/svg") {
+        return Ok((mime_type.to_string(), data.to_vec()));
+    }
+    let mut img = image::load_from_memory(data)?;
+    if img.width() > ICON_SIZE || img.height() > ICON_SIZE {
+        img = img.resize(ICON_SIZE, ICON_SIZE, FilterType::Lanczos3);
+    }
+    let mut output = Cursor::new(vec![]);
+    img.write_to(&mut output, ImageFormat::Png)?;
+    Ok((String::from("image/png"), output.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(img: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut output = Cursor::new(vec![]);
+        img.write_to(&mut output, format).unwrap();
+        output.into_inner()
+    }
+
+    fn photo(width: u32, height: u32) -> Vec<u8> {
+        let img = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        });
+        encode(DynamicImage::ImageRgb8(img), ImageFormat::Jpeg)
+    }
+
+    #[test]
+    fn oversized_image_is_downscaled_keeping_its_aspect_ratio() {
+        let (mime_type, data) = optimize("image/jpeg", &photo(1200, 800), Some(600), false)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(mime_type, "image/jpeg");
+        let img = image::load_from_memory(&data).unwrap();
+        assert_eq!((img.width(), img.height()), (600, 400));
+    }
+
+    #[test]
+    fn small_image_is_left_alone() {
+        assert!(
+            optimize("image/jpeg", &photo(300, 200), Some(600), false)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            optimize("image/svg+xml", b"<svg/>", Some(1), true)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn grayscale_keeps_pngs_as_pngs() {
+        let png = encode(
+            DynamicImage::ImageRgba8(image::RgbaImage::new(10, 10)),
+            ImageFormat::Png,
+        );
+
+        let (mime_type, data) = optimize("image/png", &png, None, true).unwrap().unwrap();
+
+        assert_eq!(mime_type, "image/png");
+        let img = image::load_from_memory(&data).unwrap();
+        assert_eq!(img.color(), image::ColorType::La8);
+    }
+}
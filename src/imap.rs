@@ -0,0 +1,214 @@
+use anyhow::{Result, anyhow};
+use log::info;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::tls::TlsConfig;
+
+pub const IMAPS_PORT: u16 = 993;
+pub const IMAP_PORT: u16 = 143;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the imap connection is secured, the same choices as for smtp
+pub enum Security {
+    Tls,
+    Starttls,
+    None,
+}
+
+/// Where and as whom a message is appended
+pub struct Mailbox<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub security: Security,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub folder: &'a str,
+}
+
+trait Stream: Read + Write {}
+impl<T: Read + Write> Stream for T {}
+
+/// Append a formatted message to the mailbox's folder. This is a minimal
+/// client speaking only LOGIN, APPEND, and LOGOUT, enough for the servers
+/// people deliver digests to but not a general imap implementation.
+pub fn append(tls: &TlsConfig, mailbox: &Mailbox, message: &[u8]) -> Result<()> {
+    let tcp = TcpStream::connect((mailbox.host, mailbox.port))?;
+    tcp.set_read_timeout(Some(TIMEOUT))?;
+    tcp.set_write_timeout(Some(TIMEOUT))?;
+
+    let stream: Box<dyn Stream> = match mailbox.security {
+        Security::Tls => Box::new(tls.connect(mailbox.host, tcp)?),
+        Security::Starttls => {
+            // the greeting and STARTTLS go over the plain connection, which
+            // must not be buffered past the server's reply
+            let mut session = Session::new(&tcp);
+            if session.greeting()? {
+                return Err(anyhow!(
+                    "imap server {} logged in before starttls, refusing to go on unencrypted",
+                    mailbox.host
+                ));
+            }
+            session.command("STARTTLS", &[])?;
+            Box::new(tls.connect(mailbox.host, tcp)?)
+        }
+        Security::None => {
+            if tls.pins_host(mailbox.host) {
+                return Err(anyhow!(
+                    "certificate pins for {} need encryption: tls or starttls",
+                    mailbox.host
+                ));
+            }
+            Box::new(tcp)
+        }
+    };
+
+    let mut session = Session::new(stream);
+    let preauthenticated = match mailbox.security {
+        Security::Starttls => false,
+        _ => session.greeting()?,
+    };
+    if preauthenticated {
+        info!("imap server {} logged the connection in", mailbox.host);
+    } else {
+        session
+            .command(
+                "LOGIN",
+                &[Arg::Text(mailbox.username), Arg::Text(mailbox.password)],
+            )
+            .map_err(|e| anyhow!("imap login as {} failed: {}", mailbox.username, e))?;
+    }
+    session
+        .command(
+            "APPEND",
+            &[Arg::Text(mailbox.folder), Arg::Literal(message)],
+        )
+        .map_err(|e| anyhow!("could not append to {}: {}", mailbox.folder, e))?;
+    session.command("LOGOUT", &[])?;
+    info!("Email appended to {} on {}", mailbox.folder, mailbox.host);
+    Ok(())
+}
+
+/// A command argument, text goes out as a quoted string when it can
+enum Arg<'a> {
+    Text(&'a str),
+    Literal(&'a [u8]),
+}
+
+struct Session<S: Read + Write> {
+    stream: BufReader<S>,
+    tag: u32,
+}
+
+impl<S: Read + Write> Session<S> {
+    fn new(stream: S) -> Self {
+        Session {
+            stream: BufReader::new(stream),
+            tag: 0,
+        }
+    }
+
+    /// Read the server's greeting, true when it has already logged the
+    /// connection in
+    fn greeting(&mut self) -> Result<bool> {
+        let line = self.read_line()?;
+        if line.starts_with("* PREAUTH") {
+            return Ok(true);
+        }
+        match line.starts_with("* OK") {
+            true => Ok(false),
+            false => Err(anyhow!("unexpected imap greeting {:?}", line)),
+        }
+    }
+
+    /// Send a command and wait for its tagged completion
+    fn command(&mut self, command: &str, args: &[Arg]) -> Result<()> {
+        // checked up front so nothing is sent for a command that can't be
+        let args = args.iter().map(encode).collect::<Result<Vec<_>>>()?;
+        self.tag += 1;
+        let tag = format!("a{}", self.tag);
+        let mut line = format!("{} {}", tag, command).into_bytes();
+        for arg in args {
+            line.push(b' ');
+            match arg {
+                Encoded::Quoted(quoted) => line.extend(quoted.into_bytes()),
+                Encoded::Literal(bytes) => {
+                    line.extend(format!("{{{}}}\r\n", bytes.len()).into_bytes());
+                    self.write(&line)?;
+                    self.continuation(&tag)?;
+                    self.write(bytes)?;
+                    line.clear();
+                }
+            }
+        }
+        line.extend(b"\r\n");
+        self.write(&line)?;
+        self.complete(&tag)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(bytes)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    // literals are synchronizing, the server asks for each one with a
+    // continuation once it has accepted the size
+    fn continuation(&mut self, tag: &str) -> Result<()> {
+        loop {
+            let line = self.read_line()?;
+            if line.starts_with('+') {
+                return Ok(());
+            }
+            if let Some(status) = line.strip_prefix(&format!("{} ", tag)) {
+                return Err(anyhow!("imap server refused the literal: {}", status));
+            }
+        }
+    }
+
+    // untagged responses before the completion are of no interest here
+    fn complete(&mut self, tag: &str) -> Result<()> {
+        loop {
+            let line = self.read_line()?;
+            if let Some(status) = line.strip_prefix(&format!("{} ", tag)) {
+                return match status.starts_with("OK") {
+                    true => Ok(()),
+                    false => Err(anyhow!("{}", status)),
+                };
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            return Err(anyhow!("imap server closed the connection"));
+        }
+        Ok(line.trim_end().to_string())
+    }
+}
+
+enum Encoded<'a> {
+    Quoted(String),
+    Literal(&'a [u8]),
+}
+
+// Credentials and folder names go out as quoted strings, which only carry
+// ascii, so anything else is sent as a literal. Neither can carry line
+// breaks or NULs, a value with one would be read as more commands.
+fn encode<'a>(arg: &Arg<'a>) -> Result<Encoded<'a>> {
+    match arg {
+        Arg::Literal(bytes) => Ok(Encoded::Literal(bytes)),
+        Arg::Text(value) if value.contains(['\r', '\n', '\0']) => Err(anyhow!(
+            "imap values can't contain line breaks or NUL characters"
+        )),
+        Arg::Text(value) if value.is_ascii() => Ok(Encoded::Quoted(format!(
+            "\"{}\"",
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        ))),
+        Arg::Text(value) => Ok(Encoded::Literal(value.as_bytes())),
+    }
+}
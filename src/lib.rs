@@ -0,0 +1,7193 @@
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ReferenceType, TocElement, ZipLibrary};
+use feed_rs::{
+    model::{Entry, FeedType},
+    parser,
+};
+use lettre::message::{MultiPart, SinglePart, header};
+use lettre::transport::smtp::authentication::{Credentials, DEFAULT_MECHANISMS};
+use lettre::transport::smtp::client::{SmtpConnection, Tls};
+use lettre::transport::smtp::extension::ClientId;
+use lettre::transport::smtp::{SMTP_PORT, SUBMISSION_PORT, SUBMISSIONS_PORT};
+use lettre::{FileTransport, Message, SmtpTransport, Transport};
+use log::{error, info, warn};
+use markup5ever_rcdom::Handle;
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use regex::Regex;
+use reqwest::blocking;
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::collections::{HashMap, HashSet};
+use std::default::Default;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, str::FromStr, thread};
+use url::Url;
+
+mod fetch;
+mod html;
+mod http;
+mod images;
+mod imap;
+pub mod logging;
+mod markdown;
+mod metrics;
+mod notify;
+mod pages;
+mod pdfs;
+mod read_later;
+mod readability;
+pub mod subscriptions;
+mod tls;
+
+use fetch::FetchPolicy;
+use http::HttpConfig;
+use metrics::Metrics;
+use notify::RunReport;
+use pages::PageConfig;
+use pdfs::PdfConfig;
+use read_later::ReadLaterConfig;
+use tls::TlsConfig;
+
+/// A config and the database of what it has processed, the entry point
+/// for running saga from another program
+pub struct Saga {
+    config: Config,
+    db: Connection,
+}
+
+impl Saga {
+    /// Migrates the database if needed, an in-memory connection works for
+    /// runs that don't need to remember anything
+    pub fn new(config: Config, db: Connection) -> Result<Saga> {
+        migrate(&db)?;
+        Ok(Saga { config, db })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Fetch, deliver, and mark processed once, as a run from the command
+    /// line does
+    pub fn run_once(&self, options: &RunOptions) -> Result<()> {
+        process(&self.db, &self.config, options)
+    }
+
+    /// The entries a run would deliver, without delivering them or
+    /// marking anything processed
+    pub fn pick_entries(&self, options: &RunOptions) -> Result<Vec<DisplayEntry>> {
+        let picked = pick_run(
+            &self.db,
+            &self.config,
+            options,
+            Utc::now(),
+            &mut RunReport::default(),
+        )?;
+        Ok(picked.entries)
+    }
+
+    pub fn build_epub(&self, entries: &[DisplayEntry]) -> Result<Vec<u8>> {
+        generate_epub(&self.config, entries, None)
+    }
+
+    /// Run on the schedule until SIGINT or SIGTERM, config_path is what
+    /// the config was loaded from so SIGHUP can load it again
+    pub fn run_daemon(self, config_path: Option<&Path>, options: &RunOptions) -> Result<()> {
+        start_daemon(&self.db, config_path, self.config, options)
+    }
+
+    pub fn list_feeds(&self, format: ReportFormat) -> Result<()> {
+        list_feeds(&self.db, &self.config, format)
+    }
+
+    pub fn show_status(&self, format: ReportFormat) -> Result<()> {
+        show_status(&self.db, &self.config, format)
+    }
+
+    pub fn prune(&self, older_than_days: u32) -> Result<usize> {
+        prune_entries(&self.db, older_than_days)
+    }
+
+    pub fn rebuild(&self, since: NaiveDate) -> Result<()> {
+        rebuild(&self.db, &self.config, since)
+    }
+
+    pub fn search(&self, query: &str, limit: usize) -> Result<()> {
+        search(&self.db, query, limit)
+    }
+
+    /// Deliver up to limit of a feed's unprocessed entries, oldest first,
+    /// in one go to catch up on its backlog
+    pub fn backfill(&self, url: &str, limit: usize, options: &RunOptions) -> Result<usize> {
+        backfill(&self.db, &self.config, url, limit, options)
+    }
+
+    /// Print how a run would pick a feed's entries and what it would
+    /// pick, without delivering or marking anything
+    pub fn explain(&self, url: &str, format: ReportFormat, options: &RunOptions) -> Result<()> {
+        explain_feed(&self.db, &self.config, url, format, options)
+    }
+}
+
+// What makes two feed urls the same feed: the parsed url, which lowercases
+// the scheme and host and drops a default port, without a trailing slash
+// or fragment
+fn feed_url_key(url: &str) -> String {
+    let url = url.trim();
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.trim_end_matches('/').to_string();
+    };
+    parsed.set_fragment(None);
+    let path = parsed.path().trim_end_matches('/').to_string();
+    parsed.set_path(&path);
+    parsed.to_string().trim_end_matches('/').to_string()
+}
+
+// A feed whose url in the config changed to another spelling of a stored
+// feed's, by feed_url_key, keeps its history rather than starting over as
+// a new feed. Only runs that write do this, so status and explain don't.
+fn adopt_renamed_feeds(db: &Connection, config: &Config) -> Result<()> {
+    let stored = db
+        .prepare("SELECT url FROM feeds")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    for feed_conf in &config.rss {
+        let url = &feed_conf.url;
+        if stored.contains(url) {
+            continue;
+        }
+        let key = feed_url_key(url);
+        let Some(other) = stored
+            .iter()
+            .find(|x| feed_url_key(x) == key && !config.rss.iter().any(|f| &f.url == *x))
+        else {
+            continue;
+        };
+        db.execute(
+            "UPDATE feeds SET url = ?1 WHERE url = ?2",
+            params![url, other],
+        )?;
+        db.execute(
+            "UPDATE backlog SET feed_url = ?1 WHERE feed_url = ?2",
+            params![url, other],
+        )?;
+        info!("Feed {} was stored as {}, carrying it over", url, other);
+    }
+    Ok(())
+}
+
+/// Forget a feed removed from the config, its entries stay processed
+pub fn forget_feed(db: &Connection, url: &str) -> Result<()> {
+    db.execute("DELETE FROM feeds WHERE url = ?1", params![url])?;
+    Ok(())
+}
+
+// Picks from the feed as on its first run, or a random entry, using the
+// config's settings for everything but the feed itself
+pub fn preview_feed(config: &Config, url: &str, random: bool) -> Result<()> {
+    let feed_conf = FeedConfig::from_url(url, random)?;
+    let timeout = Duration::from_secs(config.request_timeout_secs);
+    let client = config
+        .http
+        .client()?
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?;
+    let (mut entries, _, _) = get_entries(
+        config,
+        &client,
+        &feed_conf,
+        &FeedValidators::default(),
+        &RunOptions::default(),
+    )?;
+
+    if random {
+        entries.shuffle(&mut config.rng(url));
+    } else {
+        entries.sort_by(|a, b| feed_conf.sort_by.compare(b, a));
+    }
+    entries.truncate(feed_conf.max_entries_per_run);
+    if entries.is_empty() {
+        return Err(anyhow!("feed {} has no entries to preview", url));
+    }
+
+    let output = Output {
+        name: format!("saga_preview_{}.epub", Utc::now().format("%Y%m%d_%H%M%S")),
+        format: OutputFormat::Epub,
+        content: generate_epub(config, &entries, None)?,
+    };
+    let output_dir = Path::new(config.output_dir.as_deref().unwrap_or("."));
+    save_output(output_dir, &output)?;
+    Ok(())
+}
+
+/// Parse a --since date, an rfc 3339 or rfc 2822 time, or a local
+/// "YYYY-MM-DD HH:MM[:SS]" time or "YYYY-MM-DD" date, taken as midnight
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc2822(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let local = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_time(NaiveTime::MIN))
+        })
+        .ok_or(anyhow!(
+            "{:?} is not a date, try 2025-01-31 or 2025-01-31 08:00",
+            value
+        ))?;
+    local
+        .and_local_timezone(chrono::Local)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or(anyhow!("{} doesn't exist in the local timezone", local))
+}
+
+// the date is taken as local midnight, entries are only there if they
+// were processed with store_content set
+fn rebuild(db: &Connection, config: &Config, since: NaiveDate) -> Result<()> {
+    let from = since
+        .and_time(NaiveTime::MIN)
+        .and_local_timezone(chrono::Local)
+        .earliest()
+        .ok_or(anyhow!("{} has no local midnight", since))?
+        .with_timezone(&Utc);
+    let entries = get_stored_entries(db, from)?;
+    if entries.is_empty() {
+        return Err(anyhow!("no stored entries processed since {}", since));
+    }
+    info!(
+        "Rebuilding {} entries processed since {}",
+        entries.len(),
+        since
+    );
+
+    let output = Output {
+        name: format!("saga_rebuild_{}.epub", since.format("%Y%m%d")),
+        format: OutputFormat::Epub,
+        content: generate_epub(config, &entries, None)?,
+    };
+    let output_dir = Path::new(config.output_dir.as_deref().unwrap_or("."));
+    save_output(output_dir, &output)?;
+    Ok(())
+}
+
+// Everything seen of the feed that isn't processed yet is a candidate,
+// the feed's last processed time is left alone so its regular runs carry
+// on as before
+fn backfill(
+    db: &Connection,
+    config: &Config,
+    url: &str,
+    limit: usize,
+    options: &RunOptions,
+) -> Result<usize> {
+    let Some(feed_conf) = config.rss.iter().find(|x| x.url == url) else {
+        return Err(anyhow!(
+            "Unknown feed {}, the configured feeds are:\n  {}",
+            url,
+            config.feed_urls().join("\n  ")
+        ));
+    };
+    if !options.dry_run {
+        adopt_renamed_feeds(db, config)?;
+    }
+    let timeout = Duration::from_secs(config.request_timeout_secs);
+    let client = config
+        .http
+        .client()?
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?;
+    // a feed that is down can still be caught up on from what is stored
+    let fetched = match get_entries(
+        config,
+        &client,
+        feed_conf,
+        &FeedValidators::default(),
+        options,
+    ) {
+        Ok((entries, _, _)) => {
+            if !options.dry_run {
+                record_backlog(db, url, &entries)?;
+            }
+            entries
+        }
+        Err(e) => {
+            warn!("Could not fetch {}, using the stored backlog: {:#}", url, e);
+            vec![]
+        }
+    };
+
+    let eligible_before = feed_conf.eligible_before(Utc::now());
+    let mut entries = vec![];
+    for id in get_backlog_ids(db, url, eligible_before)? {
+        if entries.len() >= limit {
+            break;
+        }
+        let entry = match fetched.iter().find(|x| x.id == id) {
+            Some(entry) => entry.clone(),
+            None => get_backlog_entry(db, &id)?,
+        };
+        if !feed_conf.is_too_old(&entry) && feed_conf.matches_filters(&entry) {
+            entries.push(entry);
+        }
+    }
+    if entries.is_empty() {
+        info!("Feed {} has no backlog to deliver", url);
+        return Ok(0);
+    }
+    info!("Backfilling {} entries from {}", entries.len(), url);
+
+    let now = Utc::now();
+    for (index, (recipients, group)) in group_by_recipients(config, entries.clone())
+        .iter()
+        .enumerate()
+    {
+        let stem = output_stem(config.epub_name_template.as_deref(), now, group.len());
+        let stem = match index {
+            0 => stem,
+            _ => format!("{}_{}", stem, index + 1),
+        };
+        deliver(
+            config,
+            options,
+            recipients,
+            group,
+            &stem,
+            &mut RunReport::default(),
+        )?;
+        if !options.dry_run {
+            mark_processed(db, config.store_content, &[], group)?;
+        }
+    }
+    Ok(entries.len())
+}
+
+/// Which way pick_entries chooses a feed's entries for a run
+#[derive(Clone, Copy)]
+enum PickBranch {
+    /// Every entry is processed, filtered out, or too recent
+    NoCandidates,
+    /// First run, the newest entries are taken
+    Latest,
+    /// The oldest entries published since the last run are taken
+    OldestNew,
+    /// Nothing new so a random backlog entry is taken
+    RandomBacklog,
+    /// Nothing new and random is off
+    NothingNew,
+}
+
+impl PickBranch {
+    fn name(self) -> &'static str {
+        match self {
+            PickBranch::NoCandidates => "no_candidates",
+            PickBranch::Latest => "latest",
+            PickBranch::OldestNew => "oldest_new",
+            PickBranch::RandomBacklog => "random_backlog",
+            PickBranch::NothingNew => "nothing_new",
+        }
+    }
+
+    fn describe(self, max_entries: usize) -> String {
+        match self {
+            PickBranch::NoCandidates => String::from("nothing to pick, every entry is left out"),
+            PickBranch::Latest => format!("first run, up to {} of the latest entries", max_entries),
+            PickBranch::OldestNew => format!(
+                "up to {} of the oldest entries published since the last run",
+                max_entries
+            ),
+            PickBranch::RandomBacklog => {
+                String::from("nothing new, a random unprocessed entry from the backlog")
+            }
+            PickBranch::NothingNew => {
+                String::from("nothing published since the last run and random is off")
+            }
+        }
+    }
+}
+
+// Goes through the same steps as a run for one feed and reports how it
+// got to its pick. The backlog is recorded inside a transaction that is
+// rolled back so random picks see what a run would, and nothing is kept.
+fn explain_feed(
+    db: &Connection,
+    config: &Config,
+    url: &str,
+    format: ReportFormat,
+    options: &RunOptions,
+) -> Result<()> {
+    let Some(feed_conf) = config.rss.iter().find(|x| x.url == url) else {
+        return Err(anyhow!(
+            "Unknown feed {}, the configured feeds are:\n  {}",
+            url,
+            config.feed_urls().join("\n  ")
+        ));
+    };
+    if options.use_cache && config.cache_dir.is_none() {
+        return Err(anyhow!("--use-cache needs cache_dir to be set"));
+    }
+    let timeout = Duration::from_secs(config.request_timeout_secs);
+    let client = config
+        .http
+        .client()?
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?;
+    let (entries, _, _) = get_entries(
+        config,
+        &client,
+        feed_conf,
+        &FeedValidators::default(),
+        options,
+    )?;
+    let fetched = entries.len();
+
+    let tx = db.unchecked_transaction()?;
+    record_backlog(&tx, &feed_conf.url, &entries)?;
+    let eligible_before = feed_conf.eligible_before(Utc::now());
+    let seen = config.dedup_by_content.then(HashSet::new);
+    let candidates = find_candidates(&tx, feed_conf, entries, eligible_before, seen.as_ref())?;
+    let last_processed = match options.since {
+        Some(since) => Some(since),
+        None => get_feed_last_processed(&tx, &feed_conf.url)?,
+    };
+    let newer = last_processed.map(|last_processed| {
+        candidates
+            .entries
+            .iter()
+            .filter(|x| {
+                x.published
+                    .is_none_or(|published| published > last_processed)
+            })
+            .count()
+    });
+    let branch = match (candidates.entries.is_empty(), newer) {
+        (true, _) => PickBranch::NoCandidates,
+        (false, None) => PickBranch::Latest,
+        (false, Some(0)) if feed_conf.random => PickBranch::RandomBacklog,
+        (false, Some(0)) => PickBranch::NothingNew,
+        (false, Some(_)) => PickBranch::OldestNew,
+    };
+    let counts = [
+        ("fetched", fetched),
+        ("held_back", candidates.held_back),
+        ("already_processed", candidates.already_processed),
+        ("too_old", candidates.too_old),
+        ("filtered", candidates.filtered),
+        ("duplicates", candidates.duplicates),
+        ("candidates", candidates.entries.len()),
+    ];
+    let picked = match branch {
+        PickBranch::NoCandidates => vec![],
+        _ => {
+            let mut picked = pick_entries(
+                &tx,
+                feed_conf,
+                candidates.entries,
+                eligible_before,
+                options.since,
+                &mut config.rng(&feed_conf.url),
+            )?
+            .entries;
+            if feed_conf.merge_by_title_prefix {
+                picked = merge_title_parts(picked)?;
+            }
+            picked
+        }
+    };
+    drop(tx);
+    // a random pick is only repeatable with a seed
+    let repeatable = !matches!(branch, PickBranch::RandomBacklog) || config.random_seed.is_some();
+
+    if let ReportFormat::Json = format {
+        let mut report = serde_json::Map::new();
+        report.insert(String::from("url"), serde_json::json!(feed_conf.url));
+        for (name, count) in counts {
+            report.insert(String::from(name), serde_json::json!(count));
+        }
+        report.insert(
+            String::from("last_processed"),
+            serde_json::json!(last_processed.map(|x| x.to_rfc3339())),
+        );
+        report.insert(
+            String::from("newer_than_last_processed"),
+            serde_json::json!(newer),
+        );
+        report.insert(String::from("branch"), serde_json::json!(branch.name()));
+        report.insert(String::from("repeatable"), serde_json::json!(repeatable));
+        let picked: Vec<_> = picked
+            .iter()
+            .map(|x| {
+                serde_json::json!({
+                    "id": x.id,
+                    "title": x.title,
+                    "published": x.published.map(|x| x.to_rfc3339()),
+                })
+            })
+            .collect();
+        report.insert(String::from("picked"), serde_json::json!(picked));
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!("{}", feed_conf.url);
+    for (name, count) in counts {
+        println!("  {}: {}", name.replace('_', " "), count);
+    }
+    match (last_processed, options.since) {
+        (Some(since), Some(_)) => println!("  last processed: {} (--since)", since),
+        (Some(last_processed), None) => println!("  last processed: {}", last_processed),
+        (None, _) => println!("  last processed: never"),
+    }
+    if let Some(newer) = newer {
+        println!("  newer than last processed: {}", newer);
+    }
+    println!(
+        "  branch: {}",
+        branch.describe(feed_conf.max_entries_per_run.max(1))
+    );
+    if !repeatable {
+        println!("  a run picks at random again, set random_seed for the same pick");
+    }
+    if matches!(branch, PickBranch::RandomBacklog) && config.skip_email_when_empty {
+        println!("  a run only delivers this when another feed has something new");
+    }
+    match picked.is_empty() {
+        true => println!("  would pick: nothing"),
+        false => println!("  would pick:"),
+    }
+    for entry in &picked {
+        let published = entry
+            .published
+            .map_or(String::from("undated"), |x| x.to_rfc3339());
+        println!("    {} ({})\n      {}", entry.title, published, entry.id);
+    }
+    Ok(())
+}
+
+fn search(db: &Connection, query: &str, limit: usize) -> Result<()> {
+    let hits = search_entries(db, query, limit)?;
+    if hits.is_empty() {
+        println!("No stored entries match {:?}", query);
+    }
+    for hit in hits {
+        let published = hit.published.map_or(String::from("undated"), |x| {
+            x.format("%B %-d, %Y").to_string()
+        });
+        println!("{}\n  {} · {}", hit.title, hit.feed_title, published);
+        println!(
+            "  {}",
+            hit.snippet.split_whitespace().collect::<Vec<_>>().join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// How list and status print, text for people or json for scripts
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum ReportFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+fn list_feeds(db: &Connection, config: &Config, format: ReportFormat) -> Result<()> {
+    if let ReportFormat::Json = format {
+        let mut feeds = vec![];
+        for feed_conf in &config.rss {
+            let last_processed = get_feed_last_processed(db, &feed_conf.url)?;
+            feeds.push(serde_json::json!({
+                "url": feed_conf.url,
+                "last_processed": last_processed.map(|x| x.to_rfc3339()),
+            }));
+        }
+        println!("{}", serde_json::to_string_pretty(&feeds)?);
+        return Ok(());
+    }
+
+    for feed_conf in &config.rss {
+        match get_feed_last_processed(db, &feed_conf.url)? {
+            Some(last_processed) => {
+                println!("{} (last processed {})", feed_conf.url, last_processed)
+            }
+            None => println!("{} (never processed)", feed_conf.url),
+        }
+    }
+    Ok(())
+}
+
+fn show_status(db: &Connection, config: &Config, format: ReportFormat) -> Result<()> {
+    let stored = get_feeds(db)?;
+    if let ReportFormat::Json = format {
+        return print_status_json(db, config, &stored);
+    }
+    let format_time = |time: DateTime<Utc>| {
+        time.with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S %Z")
+            .to_string()
+    };
+    let print_feed = |url: &str, state: Option<&str>| -> Result<()> {
+        println!("{}", url);
+        if let Some(state) = state {
+            println!("  {}", state);
+        }
+        let last_processed = stored.iter().find(|(x, _)| x == url).and_then(|(_, x)| *x);
+        match last_processed {
+            Some(last_processed) => println!("  last processed: {}", format_time(last_processed)),
+            None if state.is_none() => println!("  pending first run"),
+            None => println!("  last processed: never"),
+        }
+        match get_feed_status(db, url)? {
+            Some(FeedStatus {
+                error: Some(error),
+                checked_at,
+                consecutive_failures,
+            }) => {
+                println!("  last run: failed {}: {}", format_time(checked_at), error);
+                match config.is_feed_disabled(consecutive_failures) {
+                    true => println!(
+                        "  disabled after {} consecutive failures, run with --feed to retry",
+                        consecutive_failures
+                    ),
+                    false => println!("  consecutive failures: {}", consecutive_failures),
+                }
+            }
+            Some(FeedStatus { checked_at, .. }) => {
+                println!("  last run: ok {}", format_time(checked_at))
+            }
+            None => {}
+        }
+        println!("  entries delivered: {}", count_delivered_entries(db, url)?);
+        Ok(())
+    };
+
+    for feed_conf in &config.rss {
+        print_feed(&feed_conf.url, None)?;
+    }
+    for (url, _) in &stored {
+        if config.rss.iter().all(|x| &x.url != url) {
+            print_feed(url, Some("orphaned, not in the config"))?;
+        }
+    }
+    Ok(())
+}
+
+// state is pending for feeds that haven't been processed yet and orphaned
+// for ones only left in the database
+fn print_status_json(
+    db: &Connection,
+    config: &Config,
+    stored: &[(String, Option<DateTime<Utc>>)],
+) -> Result<()> {
+    let feed_json = |url: &str, state: &str, last_processed: Option<DateTime<Utc>>| {
+        let status = get_feed_status(db, url)?;
+        Ok::<_, anyhow::Error>(serde_json::json!({
+            "url": url,
+            "state": state,
+            "last_processed": last_processed.map(|x| x.to_rfc3339()),
+            "last_status": status.as_ref().map(|x| match x.error {
+                Some(_) => "error",
+                None => "ok",
+            }),
+            "last_error": status.as_ref().and_then(|x| x.error.as_deref()),
+            "last_checked": status.as_ref().map(|x| x.checked_at.to_rfc3339()),
+            "consecutive_failures": status.as_ref().map_or(0, |x| x.consecutive_failures),
+            "disabled": status
+                .as_ref()
+                .is_some_and(|x| config.is_feed_disabled(x.consecutive_failures)),
+            "entries_delivered": count_delivered_entries(db, url)?,
+        }))
+    };
+    let mut feeds = vec![];
+    for feed_conf in &config.rss {
+        let last_processed = stored
+            .iter()
+            .find(|(url, _)| url == &feed_conf.url)
+            .and_then(|(_, x)| *x);
+        let state = match last_processed {
+            Some(_) => "processed",
+            None => "pending",
+        };
+        feeds.push(feed_json(&feed_conf.url, state, last_processed)?);
+    }
+    for (url, last_processed) in stored {
+        if config.rss.iter().all(|x| &x.url != url) {
+            feeds.push(feed_json(url, "orphaned", *last_processed)?);
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&feeds)?);
+    Ok(())
+}
+
+// config_path is what the config was loaded from so SIGHUP can load it again
+fn start_daemon(
+    db: &Connection,
+    config_path: Option<&Path>,
+    mut config: Config,
+    options: &RunOptions,
+) -> Result<()> {
+    info!("Using schedule: {}", config.schedule);
+    check_feed_filter(&config, options)?;
+
+    let mut schedules = feed_schedules(&config)?;
+    let mut next_runs = upcoming_runs(&schedules, config.schedule_timezone());
+    let mut poll_intervals: HashMap<String, chrono::Duration> = HashMap::new();
+    // the handlers only set the flags so an in-flight process is left to
+    // finish, the loop checks them between runs and while sleeping
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+    }
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    metrics
+        .lock()
+        .unwrap()
+        .set_feeds(config.source_urls().len());
+    if let Some(port) = config.metrics_port {
+        metrics::serve(config.metrics_addr, port, Arc::clone(&metrics))?;
+    }
+
+    info!("Daemon started, waiting for next scheduled run...");
+    while !shutdown.load(Ordering::Relaxed) {
+        if reload.swap(false, Ordering::Relaxed) {
+            reload_config(&mut config, &mut schedules, || get_config(config_path));
+            next_runs = upcoming_runs(&schedules, config.schedule_timezone());
+            metrics
+                .lock()
+                .unwrap()
+                .set_feeds(config.source_urls().len());
+        }
+        if let Some((next, due)) = next_wake(&next_runs) {
+            let now = Utc::now();
+            let duration_until_next = next.signed_duration_since(now);
+
+            if duration_until_next > chrono::Duration::zero() {
+                info!("Next run scheduled at: {}", next);
+                if let Ok(std_duration) = duration_until_next.to_std() {
+                    sleep_unless(std_duration, &[&shutdown, &reload]);
+                } else {
+                    warn!("Calculated duration is negative, running immediately.");
+                }
+            } else {
+                info!("Scheduled time is now or in the past, running immediately.");
+            }
+            if shutdown.load(Ordering::Relaxed) {
+                break;
+            }
+            // woken early to reload, the next run is worked out again
+            // from the new schedules
+            if reload.load(Ordering::Relaxed) && Utc::now() < next {
+                continue;
+            }
+
+            info!("Running scheduled process for {} feeds...", due.len());
+            let mut due_urls = vec![];
+            for &i in &due {
+                let url = config.source_urls()[i];
+                if let Some(urls) = &options.feeds {
+                    if urls.iter().any(|x| x == url) {
+                        due_urls.push(url.to_string());
+                    }
+                } else if !config.is_feed_disabled(get_consecutive_failures(db, url)?) {
+                    due_urls.push(url.to_string());
+                }
+            }
+            let run_options = RunOptions {
+                toc_only: options.toc_only,
+                dry_run: options.dry_run,
+                feeds: Some(due_urls),
+                since: None,
+                use_cache: false,
+            };
+            let mut report = RunReport::default();
+            let result = process_reporting(db, &config, &run_options, &mut report);
+            if let Err(e) = &result {
+                error!("Error during scheduled process: {}", e);
+            }
+            metrics.lock().unwrap().record_run(&report, &result);
+            info!("Scheduled process finished.");
+            // a feed that isn't refetched in full keeps the last interval
+            // it asked for
+            poll_intervals.extend(report.poll_intervals);
+
+            let now = Utc::now();
+            let urls = config.source_urls();
+            for i in due {
+                let poll_interval = poll_intervals.get(urls[i]).copied();
+                if let Some(interval) = poll_interval {
+                    info!(
+                        "Feed {} asks to be polled every {} minutes",
+                        urls[i],
+                        interval.num_minutes()
+                    );
+                }
+                let skipped = failure_backoff(get_consecutive_failures(db, urls[i])?);
+                if skipped > 0 {
+                    info!(
+                        "Feed {} keeps failing, skipping its next {} scheduled runs",
+                        urls[i], skipped
+                    );
+                }
+                next_runs[i] = next_feed_run(
+                    &schedules[i],
+                    config.schedule_timezone(),
+                    next,
+                    now,
+                    poll_interval,
+                    skipped,
+                );
+            }
+
+            sleep_unless(Duration::from_secs(1), &[&shutdown]);
+        } else {
+            error!("Could not determine next schedule time.");
+            sleep_unless(Duration::from_secs(60), &[&shutdown, &reload]);
+            next_runs = upcoming_runs(&schedules, config.schedule_timezone());
+        }
+    }
+    info!("Shutdown requested, daemon stopped.");
+    Ok(())
+}
+
+// Runs missed while processing are skipped rather than caught up, a feed
+// isn't run again sooner than it asked to be polled, and a failing feed
+// skips the given number of runs
+fn next_feed_run(
+    schedule: &Schedule,
+    timezone: Tz,
+    ran_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+    poll_interval: Option<chrono::Duration>,
+    skipped: u32,
+) -> Option<DateTime<Utc>> {
+    let mut after = match poll_interval {
+        Some(interval) => now.max(ran_at + interval - chrono::Duration::seconds(1)),
+        None => now,
+    };
+    for _ in 0..skipped {
+        match next_run(schedule, timezone, after) {
+            Some(run) => after = run,
+            None => break,
+        }
+    }
+    next_run(schedule, timezone, after)
+}
+
+// Scheduled runs a failing feed sits out, doubling with each failure in a
+// row up to MAX_BACKOFF_DOUBLINGS, so a feed down for weeks is polled
+// about every 64th run
+fn failure_backoff(consecutive_failures: u32) -> u32 {
+    match consecutive_failures {
+        0 => 0,
+        n => (1 << (n - 1).min(MAX_BACKOFF_DOUBLINGS)) - 1,
+    }
+}
+
+const MAX_BACKOFF_DOUBLINGS: u32 = 6;
+
+// A feed asked for on the command line that isn't configured is more
+// likely a typo than something to silently skip
+fn check_feed_filter(config: &Config, options: &RunOptions) -> Result<()> {
+    let Some(urls) = &options.feeds else {
+        return Ok(());
+    };
+    let known = config.source_urls();
+    let unknown: Vec<&str> = urls
+        .iter()
+        .map(String::as_str)
+        .filter(|url| !known.contains(url))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Unknown feed {}, the configured feeds are:\n  {}",
+        unknown.join(", "),
+        known.join("\n  ")
+    ))
+}
+
+// each feed's own schedule or the global one, which pages and pdfs use,
+// in the order of config.source_urls
+fn feed_schedules(config: &Config) -> Result<Vec<Schedule>> {
+    config
+        .rss
+        .iter()
+        .map(|feed_conf| feed_conf.schedule.as_ref().unwrap_or(&config.schedule))
+        .chain(config.pages.iter().map(|_| &config.schedule))
+        .chain(config.pdfs.iter().map(|_| &config.schedule))
+        .map(|schedule| {
+            Schedule::from_str(schedule)
+                .map_err(|e| anyhow!("invalid schedule {:?}: {}", schedule, e))
+        })
+        .collect()
+}
+
+fn upcoming_runs(schedules: &[Schedule], timezone: Tz) -> Vec<Option<DateTime<Utc>>> {
+    let now = Utc::now();
+    schedules
+        .iter()
+        .map(|x| next_run(x, timezone, now))
+        .collect()
+}
+
+// the schedule is evaluated in the timezone so "0 7 * * *" means 7am there,
+// the run time comes back in utc for sleeping
+fn next_run(schedule: &Schedule, timezone: Tz, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    schedule
+        .after(&after.with_timezone(&timezone))
+        .next()
+        .map(|x| x.with_timezone(&Utc))
+}
+
+// The earliest upcoming run and the indexes of the feeds due at it,
+// None when no feed has another run
+fn next_wake(next_runs: &[Option<DateTime<Utc>>]) -> Option<(DateTime<Utc>, Vec<usize>)> {
+    let next = next_runs.iter().flatten().min().copied()?;
+    let due = next_runs
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.is_some_and(|x| x <= next))
+        .map(|(i, _)| i)
+        .collect();
+    Some((next, due))
+}
+
+// A config that fails to load or validate is logged and the daemon keeps
+// running with the one it has
+fn reload_config(
+    config: &mut Config,
+    schedules: &mut Vec<Schedule>,
+    load: impl FnOnce() -> Result<Config>,
+) {
+    info!("Reloading config...");
+    match load().and_then(|new| Ok((feed_schedules(&new)?, new))) {
+        Ok((new_schedules, new_config)) => {
+            info!(
+                "Config reloaded with {} feeds, using schedule: {}",
+                new_config.rss.len(),
+                new_config.schedule
+            );
+            if let Err(e) = new_config.configure_logging() {
+                error!("Could not apply the reloaded log settings: {:#}", e);
+            }
+            *schedules = new_schedules;
+            *config = new_config;
+        }
+        Err(e) => error!("Could not reload config, keeping the current one: {:#}", e),
+    }
+}
+
+// sleeps in short steps so a signal doesn't wait out the whole duration
+fn sleep_unless(duration: Duration, flags: &[&AtomicBool]) {
+    let until = Instant::now() + duration;
+    while !flags.iter().any(|x| x.load(Ordering::Relaxed)) {
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(Duration::from_millis(500)));
+    }
+}
+
+/// How a single run behaves
+#[derive(Default)]
+pub struct RunOptions {
+    /// Skip processing entry content and deliver a single chapter of
+    /// headlines linking to the original articles
+    pub toc_only: bool,
+    /// Generate the output into the current directory without
+    /// delivering it or marking anything processed
+    pub dry_run: bool,
+    /// Urls of the feeds to process, every configured feed when unset
+    pub feeds: Option<Vec<String>>,
+    /// Consider entries published after this instead of after when each
+    /// feed was last processed
+    pub since: Option<DateTime<Utc>>,
+    /// Read feeds from cache_dir instead of fetching them
+    pub use_cache: bool,
+}
+
+fn process(db: &Connection, config: &Config, options: &RunOptions) -> Result<()> {
+    process_reporting(db, config, options, &mut RunReport::default())
+}
+
+fn process_reporting(
+    db: &Connection,
+    config: &Config,
+    options: &RunOptions,
+    report: &mut RunReport,
+) -> Result<()> {
+    let started_at = Utc::now();
+    let result = process_feeds(db, config, options, report);
+    if let Some(url) = config.notify_url.as_ref().filter(|_| !options.dry_run) {
+        let timeout = Duration::from_secs(config.request_timeout_secs);
+        notify::notify_run(url, &config.http, timeout, started_at, report, &result);
+    }
+    result
+}
+
+/// Entries picked for a run, with how far each feed is processed once
+/// they are delivered
+struct PickedRun<'a> {
+    entries: Vec<DisplayEntry>,
+    processed_feeds: Vec<(&'a str, DateTime<Utc>, FeedValidators)>,
+    /// Each feed tried and the error it failed with, if it did
+    feed_results: Vec<(&'a str, Option<String>)>,
+}
+
+// Fetches every source and picks the entries a run delivers, nothing is
+// marked processed here
+fn pick_run<'a>(
+    db: &Connection,
+    config: &'a Config,
+    options: &RunOptions,
+    cutoff: DateTime<Utc>,
+    report: &mut RunReport,
+) -> Result<PickedRun<'a>> {
+    check_feed_filter(config, options)?;
+    if options.use_cache && config.cache_dir.is_none() {
+        return Err(anyhow!("--use-cache needs cache_dir to be set"));
+    }
+    let toc_only = options.toc_only;
+    let mut entries = vec![];
+    let mut processed_feeds = vec![];
+    let mut feed_results = vec![];
+    let timeout = Duration::from_secs(config.request_timeout_secs);
+    let client = config
+        .http
+        .client()?
+        .connect_timeout(timeout)
+        .timeout(timeout)
+        .build()?;
+    let mut feeds: Vec<&FeedConfig> = config
+        .rss
+        .iter()
+        .filter(|x| {
+            options
+                .feeds
+                .as_ref()
+                .is_none_or(|urls| urls.contains(&x.url))
+        })
+        .collect();
+    // a feed asked for by url is tried even when disabled, succeeding
+    // enables it again
+    if options.feeds.is_none() {
+        feeds = feeds
+            .into_iter()
+            .map(|x| Ok((x, get_consecutive_failures(db, &x.url)?)))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .filter_map(|(x, failures)| {
+                if !config.is_feed_disabled(failures) {
+                    return Some(x);
+                }
+                warn!(
+                    "Skipping feed {} disabled after {} consecutive failures, run with --feed {} to retry it",
+                    x.url, failures, x.url
+                );
+                None
+            })
+            .collect();
+    }
+    // toc runs don't mark feeds processed so they always fetch in full,
+    // and a 304 would hide the entries since asks for
+    let validators = feeds
+        .iter()
+        .map(|feed_conf| match toc_only || options.since.is_some() {
+            true => Ok(FeedValidators::default()),
+            false => get_feed_validators(db, &feed_conf.url),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let fetched = fetch_feeds(config, &client, &feeds, &validators, options);
+    let mut seen_hashes = HashSet::new();
+    let mut new_count = 0;
+    for (feed_conf, fetched) in feeds.into_iter().zip(fetched) {
+        let seen = config.dedup_by_content.then_some(&seen_hashes);
+        // one broken feed shouldn't hold back the healthy ones, it is
+        // left unmarked so the next run tries it again
+        match fetched.and_then(|(entries, validators, poll_interval)| {
+            if let Some(interval) = poll_interval {
+                report
+                    .poll_intervals
+                    .push((feed_conf.url.clone(), interval));
+            }
+            let fetched = (entries, validators);
+            let mut rng = config.rng(&feed_conf.url);
+            let mut picked = find_entries(db, feed_conf, cutoff, options, seen, &mut rng, fetched)?;
+            if feed_conf.merge_by_title_prefix {
+                picked.entries = merge_title_parts(picked.entries)?;
+            }
+            Ok(picked)
+        }) {
+            Ok(picked) => {
+                seen_hashes.extend(picked.entries.iter().filter_map(content_hash));
+                for entry in &picked.entries {
+                    info!("Found entry {}", entry.title);
+                }
+                if !picked.backfill {
+                    new_count += picked.entries.len();
+                }
+                if picked.entries.is_empty() {
+                    report.quiet_feeds.push(feed_conf.url.clone());
+                }
+                entries.extend(picked.entries);
+                processed_feeds.push((
+                    feed_conf.url.as_str(),
+                    picked.processed_until,
+                    picked.validators,
+                ));
+                feed_results.push((feed_conf.url.as_str(), None));
+            }
+            Err(e) => {
+                error!("Could not process feed {}: {:#}", feed_conf.url, e);
+                let error = format!("{:#}", e);
+                report
+                    .failed_feeds
+                    .push((feed_conf.url.clone(), error.clone()));
+                feed_results.push((feed_conf.url.as_str(), Some(error)));
+            }
+        }
+    }
+
+    let pages = config.pages.iter().filter(|x| {
+        options
+            .feeds
+            .as_ref()
+            .is_none_or(|urls| urls.contains(&x.url))
+    });
+    for page in pages {
+        match pages::fetch_page_entry(config, &client, page)
+            .and_then(|entry| filter_unprocessed(db, entry.into_iter().collect()))
+        {
+            Ok(picked) => {
+                for entry in &picked {
+                    info!("Found page {} from {}", entry.title, entry.feed_title);
+                }
+                new_count += picked.len();
+                entries.extend(picked);
+            }
+            Err(e) => error!("Could not process page {}: {:#}", page.url, e),
+        }
+    }
+
+    // a pdf is only delivered once so it isn't read again after that
+    let pdfs = config.pdfs.iter().filter(|x| {
+        options
+            .feeds
+            .as_ref()
+            .is_none_or(|urls| urls.contains(&x.path))
+    });
+    for pdf in pdfs {
+        let entry = match is_processed(db, &pdf.path) {
+            Ok(true) => continue,
+            Ok(false) => pdfs::read_pdf_entry(config, &client, pdf),
+            Err(e) => Err(e),
+        };
+        match entry {
+            Ok(Some(entry)) => {
+                info!("Found pdf {}", entry.title);
+                new_count += 1;
+                entries.push(entry);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Could not process pdf {}: {:#}", pdf.path, e),
+        }
+    }
+
+    report.feeds_processed = processed_feeds.len();
+
+    // random backfill alone isn't worth an email
+    if config.skip_email_when_empty && new_count == 0 && !entries.is_empty() {
+        info!("Run found nothing new, leaving out the random backfill");
+        entries.clear();
+    }
+
+    if let Some(max) = config.max_total_entries {
+        cap_entries(
+            &mut entries,
+            &mut processed_feeds,
+            max,
+            config.max_total_keep,
+            cutoff,
+        );
+    }
+
+    if config.show_favicons {
+        add_feed_icons(db, config, &mut entries);
+    }
+
+    Ok(PickedRun {
+        entries,
+        processed_feeds,
+        feed_results,
+    })
+}
+
+// favicons are fetched again after this long in case a site changes its own
+const FAVICON_MAX_AGE_DAYS: i64 = 30;
+
+// Favicons are looked up once per host, a host without one just has no
+// icon next to its feed title
+fn add_feed_icons(db: &Connection, config: &Config, entries: &mut [DisplayEntry]) {
+    let mut icons: HashMap<String, Option<EmbeddedImage>> = HashMap::new();
+    for entry in entries.iter_mut() {
+        let Ok(mut origin) = Url::parse(&entry.feed_url) else {
+            continue;
+        };
+        origin.set_path("/");
+        origin.set_query(None);
+        origin.set_fragment(None);
+        let icon = icons.entry(origin.to_string()).or_insert_with(|| {
+            match feed_icon(db, config, &origin) {
+                Ok(icon) => icon,
+                Err(e) => {
+                    warn!("Could not get the favicon of {}: {:#}", origin, e);
+                    None
+                }
+            }
+        });
+        entry.feed_icon = icon.clone();
+    }
+}
+
+// Cached favicon of the site, misses are cached as well so a site without
+// one isn't asked every run
+fn feed_icon(db: &Connection, config: &Config, origin: &Url) -> Result<Option<EmbeddedImage>> {
+    let host = origin.as_str();
+    let cached = db
+        .query_row(
+            "SELECT url, mime_type, data, fetched_at FROM favicons WHERE host = ?1",
+            params![host],
+            |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<Vec<u8>>>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            },
+        )
+        .optional()?;
+    let stale_before = Utc::now() - chrono::Duration::days(FAVICON_MAX_AGE_DAYS);
+    if let Some((url, mime_type, data, fetched_at)) = cached
+        && fetched_at > stale_before.timestamp_millis()
+    {
+        return Ok(match (url, mime_type, data) {
+            (Some(url), Some(mime_type), Some(data)) => Some(EmbeddedImage {
+                url,
+                mime_type,
+                data,
+            }),
+            _ => None,
+        });
+    }
+
+    let icon = fetch_favicon(config, origin);
+    db.execute(
+        "INSERT OR REPLACE INTO favicons (host, url, mime_type, data, fetched_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            host,
+            icon.as_ref().map(|x| &x.url),
+            icon.as_ref().map(|x| &x.mime_type),
+            icon.as_ref().map(|x| &x.data),
+            Utc::now().timestamp_millis()
+        ],
+    )?;
+    Ok(icon)
+}
+
+// The icons the home page links to, then /favicon.ico, the first that
+// loads as an image wins
+fn fetch_favicon(config: &Config, origin: &Url) -> Option<EmbeddedImage> {
+    info!("Fetching the favicon of {}", origin);
+    let mut urls = vec![];
+    if let Ok(page) = fetch::fetch_html(&config.fetch, &config.http, origin.as_str())
+        && let Ok(dom) = html::parse(&page)
+    {
+        for link in html::elements(&dom.document, "link") {
+            let is_icon = html::get_attr(&link, "rel").is_some_and(|rel| {
+                rel.split_whitespace()
+                    .any(|x| x.eq_ignore_ascii_case("icon"))
+            });
+            if let Some(href) = html::get_attr(&link, "href").filter(|_| is_icon)
+                && let Ok(url) = origin.join(&href)
+            {
+                urls.push(url.to_string());
+            }
+        }
+    }
+    if let Ok(url) = origin.join("/favicon.ico") {
+        urls.push(url.to_string());
+    }
+
+    for url in urls {
+        let icon = fetch::fetch_image(&config.fetch, &config.http, &url)
+            .and_then(|(mime_type, data)| images::icon(&mime_type, &data));
+        match icon {
+            Ok((mime_type, data)) => {
+                return Some(EmbeddedImage {
+                    url,
+                    mime_type,
+                    data,
+                });
+            }
+            Err(e) => info!("Favicon {} is unusable: {:#}", url, e),
+        }
+    }
+    None
+}
+
+fn process_feeds(
+    db: &Connection,
+    config: &Config,
+    options: &RunOptions,
+    report: &mut RunReport,
+) -> Result<()> {
+    // toc and dry runs leave entries to be picked again by a full run,
+    // though a toc run can be set to mark what it delivered
+    let commit = !options.toc_only && !options.dry_run;
+    let mark = commit || (options.toc_only && config.toc_marks_processed && !options.dry_run);
+    // toc entries have no content to keep
+    let store_content = config.store_content && !options.toc_only;
+    if !options.dry_run {
+        adopt_renamed_feeds(db, config)?;
+    }
+    if let Some(days) = config.prune_after_days.filter(|_| commit) {
+        prune_entries(db, days)?;
+    }
+    let cutoff = Utc::now();
+    let PickedRun {
+        mut entries,
+        processed_feeds,
+        feed_results,
+    } = pick_run(db, config, options, cutoff, report)?;
+    if !options.dry_run {
+        record_feed_results(db, &feed_results, cutoff)?;
+    }
+
+    // entries are only pushed once delivered or staged, so a failed
+    // delivery doesn't push them twice, and never from a toc or dry run
+    let push_read_later = |entries: &[DisplayEntry]| {
+        if let Some(read_later) = config.read_later.as_ref().filter(|_| commit) {
+            read_later::push_entries(read_later, &config.http, entries);
+        }
+    };
+
+    let mut bundled = None;
+    if let Some(interval) = config.bundle_interval_mins.filter(|_| commit) {
+        stage_entries(db, &entries, cutoff)?;
+        let window_start = get_bundle_window_start(db)?.unwrap_or(cutoff);
+        let due = window_start + chrono::Duration::minutes(interval as i64);
+        if cutoff < due {
+            // staged entries are safe in the db so they count as processed
+            mark_processed(db, config.store_content, &processed_feeds, &entries)?;
+            push_read_later(&entries);
+            info!("Bundle staged, next delivery due at {}", due);
+            return Ok(());
+        }
+        // the earlier runs' staged entries were pushed when they were staged
+        bundled = Some(std::mem::replace(&mut entries, get_staged_entries(db)?));
+        if entries.is_empty() {
+            info!("Bundle is empty, nothing to deliver");
+            return Ok(());
+        }
+        info!("Delivering bundle of {} entries", entries.len());
+    }
+
+    if entries.is_empty() {
+        info!("No entries to deliver");
+        if mark {
+            mark_processed(db, store_content, &processed_feeds, &entries)?;
+        }
+        return Ok(());
+    }
+
+    // each group of recipients gets its own files, marked processed as
+    // soon as they are delivered so a later failing group doesn't cause
+    // the earlier ones to be delivered twice
+    let groups = group_by_recipients(config, entries.clone());
+    let now = Utc::now();
+    for (index, (recipients, group)) in groups.iter().enumerate() {
+        let stem = output_stem(config.epub_name_template.as_deref(), now, group.len());
+        let stem = match groups.len() {
+            1 => stem,
+            _ => format!("{}_{}", stem, index + 1),
+        };
+        deliver(config, options, recipients, group, &stem, report)?;
+        report.entries_delivered += group.len();
+        if mark && groups.len() > 1 {
+            mark_processed(db, store_content, &[], group)?;
+            if bundled.is_none() {
+                push_read_later(group);
+            }
+        }
+    }
+
+    if options.dry_run {
+        info!("Dry run, nothing was delivered or marked processed");
+        return Ok(());
+    }
+
+    // only reached once delivery succeeded so a failed send leaves
+    // the entries to be picked again on the next run
+    if mark {
+        mark_processed(db, store_content, &processed_feeds, &entries)?;
+    }
+    match &bundled {
+        Some(picked) => push_read_later(picked),
+        None if groups.len() == 1 => push_read_later(&entries),
+        None => {}
+    }
+
+    if config.bundle_interval_mins.is_some() && commit {
+        clear_staged_entries(db, cutoff)?;
+    }
+
+    Ok(())
+}
+
+// Groups entries by the feed's own recipients, falling back to those of the
+// email config, keeping the order entries were picked in. Everything is one
+// group for folder delivery.
+fn group_by_recipients(
+    config: &Config,
+    entries: Vec<DisplayEntry>,
+) -> Vec<(Vec<String>, Vec<DisplayEntry>)> {
+    let default_to = config.email.as_ref().map(|x| x.to.addresses().to_vec());
+    let mut groups: Vec<(Vec<String>, Vec<DisplayEntry>)> = vec![];
+    for entry in entries {
+        let to = match config.delivery {
+            Delivery::Email => config
+                .rss
+                .iter()
+                .find(|x| x.url == entry.feed_url)
+                .and_then(|x| x.to.as_ref())
+                .map(|x| x.addresses().to_vec())
+                .or(default_to.clone())
+                .unwrap_or_default(),
+            Delivery::Folder => vec![],
+        };
+        match groups.iter_mut().find(|(group_to, _)| *group_to == to) {
+            Some((_, group)) => group.push(entry),
+            None => groups.push((to, vec![entry])),
+        }
+    }
+    groups
+}
+
+// Characters that can't be in a file name on at least one of the systems
+// an output might be synced to are dropped from a templated name
+fn output_stem(template: Option<&str>, now: DateTime<Utc>, count: usize) -> String {
+    let default = format!("saga_output_{}", now.format("%Y%m%d_%H%M%S"));
+    let Some(template) = template else {
+        return default;
+    };
+    let name: String = template
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{time}", &now.format("%H%M%S").to_string())
+        .replace("{count}", &count.to_string())
+        .chars()
+        .filter(|c| !c.is_control() && !r#"/\:*?"<>|"#.contains(*c))
+        .collect();
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        return default;
+    }
+    name.to_string()
+}
+
+fn email_subject(template: Option<&str>, now: DateTime<Utc>, entries: &[DisplayEntry]) -> String {
+    let mut feeds: Vec<&str> = vec![];
+    for entry in entries {
+        if !feeds.contains(&entry.feed_title.as_str()) {
+            feeds.push(&entry.feed_title);
+        }
+    }
+    template
+        .unwrap_or("Saga Digest — {date} ({count} articles)")
+        .replace("{date}", &now.format("%Y-%m-%d").to_string())
+        .replace("{count}", &entries.len().to_string())
+        .replace("{feeds}", &feeds.join(", "))
+        // a header can't span lines
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Generates the outputs for one group of entries and delivers them, or
+// only saves them for a dry run
+fn deliver(
+    config: &Config,
+    options: &RunOptions,
+    to: &[String],
+    entries: &[DisplayEntry],
+    stem: &str,
+    report: &mut RunReport,
+) -> Result<()> {
+    let subject = email_subject(
+        config.email_subject_template.as_deref(),
+        Utc::now(),
+        entries,
+    );
+    let toc;
+    let entries = match options.toc_only {
+        true => {
+            toc = [toc_entry(entries)?];
+            &toc[..]
+        }
+        false => entries,
+    };
+
+    // a backfill has no feeds to report on
+    let run_report = Some(&*report).filter(|x| {
+        config.include_run_report && !(x.failed_feeds.is_empty() && x.quiet_feeds.is_empty())
+    });
+    let parts = match config.max_attachment_mb {
+        Some(mb) if matches!(config.delivery, Delivery::Email) => {
+            split_outputs(config, entries, stem, mb * 1024 * 1024, run_report)?
+        }
+        _ => vec![generate_outputs(config, entries, stem, run_report)?],
+    };
+    if parts.len() > 1 {
+        info!(
+            "Split {} entries across {} emails to keep attachments under {} MB",
+            entries.len(),
+            parts.len(),
+            config.max_attachment_mb.unwrap_or_default()
+        );
+    }
+
+    for (index, outputs) in parts.iter().enumerate() {
+        let subject = match parts.len() {
+            1 => subject.clone(),
+            count => format!("{} (part {} of {})", subject, index + 1, count),
+        };
+        deliver_outputs(config, options, to, &subject, outputs, report)?;
+    }
+    Ok(())
+}
+
+fn generate_outputs(
+    config: &Config,
+    entries: &[DisplayEntry],
+    stem: &str,
+    run_report: Option<&RunReport>,
+) -> Result<Vec<Output>> {
+    let mut outputs = vec![];
+    for format in &config.output_formats {
+        outputs.push(Output {
+            name: format!("{}.{}", stem, format.extension()),
+            format: *format,
+            content: generate_output(config, *format, entries, run_report)?,
+        });
+    }
+    Ok(outputs)
+}
+
+// Packs entries in order into parts estimated to fit under max_bytes,
+// then halves any part whose generated attachments still don't fit. An
+// entry too large on its own is sent alone anyway.
+fn split_outputs(
+    config: &Config,
+    entries: &[DisplayEntry],
+    stem: &str,
+    max_bytes: u64,
+    run_report: Option<&RunReport>,
+) -> Result<Vec<Vec<Output>>> {
+    let estimate = |entry: &DisplayEntry| {
+        (entry.content.len() + entry.images.iter().map(|x| x.data.len()).sum::<usize>()) as u64
+    };
+    let mut packed: Vec<&[DisplayEntry]> = vec![];
+    let (mut start, mut size) = (0, 0);
+    for (i, entry) in entries.iter().enumerate() {
+        if i > start && size + estimate(entry) > max_bytes {
+            packed.push(&entries[start..i]);
+            (start, size) = (i, 0);
+        }
+        size += estimate(entry);
+    }
+    packed.push(&entries[start..]);
+
+    let mut fitted: Vec<Vec<Output>> = vec![];
+    // worked through back to front so parts come out in entry order
+    packed.reverse();
+    while let Some(part) = packed.pop() {
+        let outputs = generate_outputs(config, part, stem, run_report)?;
+        let size = match config.email_per_format {
+            true => outputs.iter().map(|x| x.content.len()).max(),
+            false => Some(outputs.iter().map(|x| x.content.len()).sum()),
+        }
+        .unwrap_or_default() as u64;
+        // providers count the attachments base64 encoded, a third larger
+        let size = size.div_ceil(3) * 4;
+        if size <= max_bytes {
+            fitted.push(outputs);
+        } else if part.len() > 1 {
+            let (first, second) = part.split_at(part.len() / 2);
+            packed.push(second);
+            packed.push(first);
+        } else {
+            warn!(
+                "Entry {} alone makes {} MB of attachments, over max_attachment_mb",
+                part[0].title,
+                size.div_ceil(1024 * 1024)
+            );
+            fitted.push(outputs);
+        }
+    }
+
+    let count = fitted.len();
+    Ok(fitted
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut outputs)| {
+            if count > 1 {
+                for output in &mut outputs {
+                    output.name =
+                        format!("{}_part{}.{}", stem, index + 1, output.format.extension());
+                }
+            }
+            outputs
+        })
+        .collect())
+}
+
+// Saves a dry run's outputs or delivers them, as one email or to the folder
+fn deliver_outputs(
+    config: &Config,
+    options: &RunOptions,
+    to: &[String],
+    subject: &str,
+    outputs: &[Output],
+    report: &mut RunReport,
+) -> Result<()> {
+    let output_dir = Path::new(config.output_dir.as_deref().unwrap_or("."));
+    if options.dry_run {
+        for output in outputs {
+            save_output(output_dir, output)?;
+        }
+        return Ok(());
+    }
+
+    match config.delivery {
+        Delivery::Email => {
+            let mut output_paths = vec![];
+            for output in outputs {
+                let output_path = save_output(output_dir, output)?;
+                if let Some(hook) = &config.post_generate {
+                    run_post_generate(hook, &output_path)?;
+                }
+                output_paths.push(output_path);
+            }
+
+            let email_config = config
+                .email
+                .as_ref()
+                .ok_or(anyhow!("email config is required for email delivery"))?;
+            let mut send = |outputs: &[Output]| {
+                let sent = send_email(
+                    email_config,
+                    &config.tls,
+                    config.email_max_retries,
+                    config.archive_mbox.as_deref(),
+                    to,
+                    subject,
+                    outputs,
+                );
+                match sent {
+                    Ok(_) => report.emails_sent += 1,
+                    Err(_) => report.emails_failed += 1,
+                }
+                sent
+            };
+            if config.email_per_format {
+                for output in outputs {
+                    send(std::slice::from_ref(output))?;
+                }
+            } else {
+                send(outputs)?;
+            }
+
+            if !config.keep_epubs {
+                for output_path in &output_paths {
+                    fs::remove_file(output_path)?;
+                    info!("Removed sent file {:?}", output_path);
+                }
+            }
+        }
+        Delivery::Folder => {
+            let folder = config
+                .folder
+                .as_ref()
+                .ok_or(anyhow!("folder is required for folder delivery"))?;
+            fs::create_dir_all(folder)?;
+            for output in outputs {
+                let output_path = Path::new(folder).join(&output.name);
+                write_atomically(&output_path, &output.content)?;
+                info!(
+                    "{:?} file saved to synced folder as: {:?}",
+                    output.format, output_path
+                );
+                if let Some(hook) = &config.post_generate {
+                    run_post_generate(hook, &output_path)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// hash of the entry text with whitespace and case normalised, None for
+// entries with no text so link-only entries aren't all treated as one
+fn content_hash(entry: &DisplayEntry) -> Option<String> {
+    let text = html::parse(&entry.content)
+        .map(|dom| html::text_content(&dom.document))
+        .unwrap_or_default();
+    let text = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    if text.is_empty() {
+        return None;
+    }
+    let hash = openssl::sha::sha256(text.as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+    Some(hash)
+}
+
+/// Entries picked from a feed for this run
+struct PickedEntries {
+    entries: Vec<DisplayEntry>,
+    /// The feed is marked processed up to here, held back when new
+    /// entries are left over so the next run can pick them up
+    processed_until: DateTime<Utc>,
+    /// Stored for the next fetch once the feed is marked processed
+    validators: FeedValidators,
+    /// The entries are a random pick from the backlog rather than newly
+    /// published ones
+    backfill: bool,
+}
+
+// A feed's entries, its cache validators, and how often it asks to be polled
+type FetchedFeed = (Vec<DisplayEntry>, FeedValidators, Option<chrono::Duration>);
+
+// Fetches and parses every feed on up to max_concurrency threads,
+// returning the results in the same order as the feeds given.
+// The database isn't touched here so a single connection is enough.
+fn fetch_feeds(
+    config: &Config,
+    client: &blocking::Client,
+    feeds: &[&FeedConfig],
+    validators: &[FeedValidators],
+    options: &RunOptions,
+) -> Vec<Result<FetchedFeed>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+    let workers = config.max_concurrency.clamp(1, feeds.len().max(1));
+    // earliest time the next fetch may start, per host when fetching
+    // concurrently and for every feed otherwise
+    let next_fetch: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+    let delay = Duration::from_millis(config.fetch_delay_ms);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(feed_conf) = feeds.get(index) else {
+                        break;
+                    };
+                    let key = match workers {
+                        1 => String::new(),
+                        _ => Url::parse(&feed_conf.url)
+                            .ok()
+                            .and_then(|x| x.host_str().map(String::from))
+                            .unwrap_or_default(),
+                    };
+                    let start = {
+                        let mut next_fetch = next_fetch.lock().unwrap();
+                        let now = Instant::now();
+                        let start = next_fetch.get(&key).copied().unwrap_or(now).max(now);
+                        next_fetch.insert(key, start + delay);
+                        start
+                    };
+                    thread::sleep(start.saturating_duration_since(Instant::now()));
+                    info!("Fetching entries from rss feed: {}", feed_conf.url);
+                    let result =
+                        get_entries(config, client, feed_conf, &validators[index], options);
+                    results.lock().unwrap().push((index, result));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// A feed's entries that could be picked this run, with counts of the
+/// ones left out and why
+struct Candidates {
+    entries: Vec<DisplayEntry>,
+    /// Published within the feed's grace period
+    held_back: usize,
+    already_processed: usize,
+    too_old: usize,
+    /// Left out by the include/exclude patterns
+    filtered: usize,
+    /// Delivered from another feed with the same text
+    duplicates: usize,
+}
+
+fn find_candidates(
+    db: &Connection,
+    feed_conf: &FeedConfig,
+    entries: Vec<DisplayEntry>,
+    eligible_before: DateTime<Utc>,
+    seen_hashes: Option<&HashSet<String>>,
+) -> Result<Candidates> {
+    // find new entries that have not been processed yet, a database
+    // error fails the feed rather than the whole run
+    let count = entries.len();
+    let eligible: Vec<DisplayEntry> = entries
+        .into_iter()
+        // undated entries can't be held back by the grace period
+        .filter(|x| {
+            x.published
+                .is_none_or(|published| published < eligible_before)
+        })
+        .collect();
+    let held_back = count - eligible.len();
+
+    let count = eligible.len();
+    let new_entries = filter_unprocessed(db, eligible)?;
+    let already_processed = count - new_entries.len();
+
+    let count = new_entries.len();
+    let new_entries: Vec<DisplayEntry> = new_entries
+        .into_iter()
+        .filter(|x| !feed_conf.is_too_old(x))
+        .collect();
+    let too_old = count - new_entries.len();
+
+    let count = new_entries.len();
+    let new_entries: Vec<DisplayEntry> = new_entries
+        .into_iter()
+        .filter(|x| feed_conf.matches_filters(x))
+        .collect();
+    let filtered = count - new_entries.len();
+
+    // the same article syndicated through another feed has its own id,
+    // so it is recognised by its text instead
+    let count = new_entries.len();
+    let new_entries = match seen_hashes {
+        Some(seen_hashes) => {
+            let mut unique = vec![];
+            for entry in new_entries {
+                let duplicate = match content_hash(&entry) {
+                    Some(hash) => seen_hashes.contains(&hash) || is_content_delivered(db, &hash)?,
+                    None => false,
+                };
+                if !duplicate {
+                    unique.push(entry);
+                }
+            }
+            unique
+        }
+        None => new_entries,
+    };
+    let duplicates = count - new_entries.len();
+
+    Ok(Candidates {
+        entries: new_entries,
+        held_back,
+        already_processed,
+        too_old,
+        filtered,
+        duplicates,
+    })
+}
+
+// cut off time is used to guard against race condition of an entry
+// being published during processing and being considered missed
+fn find_entries(
+    db: &Connection,
+    feed_conf: &FeedConfig,
+    cutoff: DateTime<Utc>,
+    options: &RunOptions,
+    seen_hashes: Option<&HashSet<String>>,
+    rng: &mut StdRng,
+    (entries, validators): (Vec<DisplayEntry>, FeedValidators),
+) -> Result<PickedEntries> {
+    info!("Processing rss feed: {}", feed_conf.url);
+
+    // toc runs don't carry content so they would clobber what is stored,
+    // and dry runs write nothing, picking at random from what already is
+    if !options.toc_only && !options.dry_run {
+        record_backlog(db, &feed_conf.url, &entries)?;
+    }
+
+    info!("Finding entries");
+
+    let eligible_before = feed_conf.eligible_before(cutoff);
+    let candidates = find_candidates(db, feed_conf, entries, eligible_before, seen_hashes)?;
+    if candidates.too_old > 0 {
+        info!(
+            "Skipped {} entries older than {} days",
+            candidates.too_old,
+            feed_conf.max_age_days.unwrap_or_default()
+        );
+    }
+    if candidates.filtered > 0 {
+        info!(
+            "Filtered out {} entries by include/exclude patterns",
+            candidates.filtered
+        );
+    }
+    if candidates.duplicates > 0 {
+        info!(
+            "Skipped {} entries already delivered from another feed",
+            candidates.duplicates
+        );
+    }
+    let new_entries = candidates.entries;
+
+    if new_entries.is_empty() {
+        warn!("Feed is empty");
+        return Ok(PickedEntries {
+            entries: vec![],
+            processed_until: eligible_before,
+            validators,
+            backfill: false,
+        });
+    }
+
+    let mut picked = pick_entries(
+        db,
+        feed_conf,
+        new_entries,
+        eligible_before,
+        options.since,
+        rng,
+    )?;
+    // a 304 next run would hide the entries left over from this one
+    if picked.processed_until == eligible_before {
+        picked.validators = validators;
+    }
+    Ok(picked)
+}
+
+/// Delimiters a part title follows, the last one in a title splits it
+const TITLE_PART_DELIMITERS: &[&str] = &[":", " - ", " – ", " — ", " | "];
+
+// "Story: Part 2" is part of "Story"
+fn title_prefix(title: &str) -> Option<&str> {
+    TITLE_PART_DELIMITERS
+        .iter()
+        .filter_map(|delimiter| title.rfind(delimiter))
+        .max()
+        .map(|i| title[..i].trim())
+        .filter(|x| !x.is_empty())
+}
+
+// Entries sharing a title prefix become one entry titled by the prefix,
+// each part under its own heading in published order. The merged entry
+// takes the id of the earliest part and carries the others' ids.
+fn merge_title_parts(entries: Vec<DisplayEntry>) -> Result<Vec<DisplayEntry>> {
+    let mut groups: Vec<(Option<String>, Vec<DisplayEntry>)> = vec![];
+    for entry in entries {
+        let prefix = title_prefix(&entry.title).map(String::from);
+        match groups
+            .iter_mut()
+            .find(|(x, _)| prefix.is_some() && x == &prefix)
+        {
+            Some((_, parts)) => parts.push(entry),
+            None => groups.push((prefix, vec![entry])),
+        }
+    }
+
+    let mut merged = vec![];
+    for (prefix, mut parts) in groups {
+        let Some(prefix) = prefix.filter(|_| parts.len() > 1) else {
+            merged.extend(parts);
+            continue;
+        };
+        parts.sort_by_key(|x| (x.published, x.position));
+        info!("Merging {} parts of {}", parts.len(), prefix);
+        let sections: Vec<Section> = parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| Section {
+                anchor: format!("part_{}", i + 1),
+                title: part.title.clone(),
+            })
+            .collect();
+        let content = html::merge_documents(
+            sections
+                .iter()
+                .zip(&parts)
+                .map(|(section, part)| {
+                    (
+                        section.anchor.as_str(),
+                        section.title.as_str(),
+                        part.content.as_str(),
+                    )
+                })
+                .collect(),
+        )?;
+        let mut rest = parts.split_off(1);
+        let mut entry = parts.remove(0);
+        entry.title = prefix;
+        entry.content = content;
+        entry.sections = sections;
+        entry.updated = rest.iter().map(|x| x.updated).fold(entry.updated, Ord::max);
+        for part in &mut rest {
+            entry.merged_ids.push(part.id.clone());
+            entry.merged_ids.append(&mut part.aliases);
+            for image in part.images.drain(..) {
+                if entry.images.iter().all(|x| x.url != image.url) {
+                    entry.images.push(image);
+                }
+            }
+            for enclosure in part.enclosures.drain(..) {
+                if !entry.enclosures.contains(&enclosure) {
+                    entry.enclosures.push(enclosure);
+                }
+            }
+        }
+        merged.push(entry);
+    }
+    Ok(merged)
+}
+
+// Trim a run down to max entries, holding each feed back to just before
+// its earliest dropped entry so the next run picks the dropped ones up.
+// Pages and pdfs aren't marked at all until they are delivered.
+fn cap_entries(
+    entries: &mut Vec<DisplayEntry>,
+    processed_feeds: &mut [(&str, DateTime<Utc>, FeedValidators)],
+    max: usize,
+    keep: KeepEntries,
+    cutoff: DateTime<Utc>,
+) {
+    if entries.len() <= max {
+        return;
+    }
+    // undated entries count as the newest
+    entries.sort_by_key(|x| x.published.unwrap_or(cutoff));
+    let dropped = match keep {
+        KeepEntries::Oldest => entries.split_off(max),
+        KeepEntries::Newest => {
+            let kept = entries.split_off(entries.len() - max);
+            std::mem::replace(entries, kept)
+        }
+    };
+    info!(
+        "Keeping {} of {} entries, leaving the rest for the next run",
+        max,
+        max + dropped.len()
+    );
+    for (url, processed_until, validators) in processed_feeds.iter_mut() {
+        let held_back = dropped
+            .iter()
+            .filter(|x| x.feed_url == *url)
+            .filter_map(|x| x.published)
+            .map(|published| published - chrono::Duration::milliseconds(1))
+            .min();
+        if let Some(held_back) = held_back.filter(|x| x < processed_until) {
+            *processed_until = held_back;
+            // a 304 next run would hide the dropped entries
+            *validators = FeedValidators::default();
+        }
+    }
+}
+
+fn pick_entries(
+    db: &Connection,
+    feed_conf: &FeedConfig,
+    mut new_entries: Vec<DisplayEntry>,
+    eligible_before: DateTime<Utc>,
+    since: Option<DateTime<Utc>>,
+    rng: &mut StdRng,
+) -> Result<PickedEntries> {
+    let max_entries = feed_conf.max_entries_per_run.max(1);
+    let picked = |entries| PickedEntries {
+        entries,
+        processed_until: eligible_before,
+        validators: FeedValidators::default(),
+        backfill: false,
+    };
+    // since only moves where this run looks from, the stored value is
+    // only updated once the run is delivered
+    let last_processed = match since {
+        Some(since) => Some(since),
+        None => get_feed_last_processed(db, &feed_conf.url)?,
+    };
+    match last_processed {
+        Some(last_processed) => {
+            // find unprocessed new entries published after the last processed time,
+            // undated ones are only ever delivered once so they always count as new
+            let mut unprocessed_entries: Vec<DisplayEntry> = new_entries
+                .iter()
+                .filter(|x| {
+                    x.published
+                        .is_none_or(|published| published > last_processed)
+                })
+                .cloned()
+                .collect();
+
+            // if there is nothing new and random is set
+            // take a random old one that has not been processed,
+            // drawn from every entry we have seen for the feed
+            // rather than just the current page of the feed
+            if unprocessed_entries.is_empty() && feed_conf.random {
+                info!("Picking a random entry from the backlog");
+                let mut ids = get_backlog_ids(db, &feed_conf.url, eligible_before)?;
+                feed_conf.backfill_order.shuffle(&mut ids, rng);
+                for id in &ids {
+                    let entry = match new_entries.iter().find(|x| &x.id == id) {
+                        Some(entry) => entry.clone(),
+                        None => get_backlog_entry(db, id)?,
+                    };
+                    if !feed_conf.is_too_old(&entry) && feed_conf.matches_filters(&entry) {
+                        return Ok(PickedEntries {
+                            backfill: true,
+                            ..picked(vec![entry])
+                        });
+                    }
+                }
+                match ids.is_empty() {
+                    true => warn!("Backlog is empty"),
+                    false => warn!("No backlog entry matches the include/exclude patterns"),
+                }
+                return Ok(picked(vec![]));
+            }
+
+            if unprocessed_entries.is_empty() {
+                warn!("No entries published since the last run");
+                return Ok(picked(vec![]));
+            }
+
+            info!("Picking up to {} of the oldest new entries", max_entries);
+            // take the oldest after the cutoff
+            unprocessed_entries.sort_by(|a, b| feed_conf.sort_by.compare(a, b));
+            let left_over =
+                unprocessed_entries.split_off(max_entries.min(unprocessed_entries.len()));
+            // already delivered entries are skipped by id so holding the
+            // feed back to just before the earliest left over is enough
+            let processed_until = left_over
+                .iter()
+                .filter_map(|x| x.published)
+                .map(|published| published - chrono::Duration::milliseconds(1))
+                .min()
+                .unwrap_or(eligible_before);
+            Ok(PickedEntries {
+                entries: unprocessed_entries,
+                processed_until,
+                validators: FeedValidators::default(),
+                backfill: false,
+            })
+        }
+        None => {
+            // If there's no last processed time, take the newest entries
+            info!("Picking up to {} of the latest entries", max_entries);
+            new_entries.sort_by(|a, b| feed_conf.sort_by.compare(b, a));
+            new_entries.truncate(max_entries);
+            Ok(picked(new_entries))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    #[serde(default)]
+    delivery: Delivery,
+    email: Option<EmailConfig>,
+    folder: Option<String>,
+    schedule: String,
+    /// IANA timezone schedules are evaluated in, e.g. Europe/Berlin, UTC
+    /// when unset
+    timezone: Option<String>,
+    rss: Vec<FeedConfig>,
+    /// Pages of blogs without a feed, delivered whenever their text changes
+    #[serde(default)]
+    pages: Vec<PageConfig>,
+    /// Pdfs, e.g. papers, each delivered once
+    #[serde(default)]
+    pdfs: Vec<PdfConfig>,
+    /// Embedded images wider than this many pixels are scaled down to it
+    image_max_width: Option<u32>,
+    /// Convert embedded images to grayscale for e-ink screens
+    #[serde(default)]
+    image_grayscale: bool,
+    /// Default cap on images kept per entry, unlimited when unset
+    max_images_per_entry: Option<usize>,
+    /// Domains (and their subdomains) whose links are unwrapped and images dropped
+    #[serde(default)]
+    link_denylist: Vec<String>,
+    /// Query parameters removed from links in entry content, a trailing *
+    /// matches by prefix
+    #[serde(default = "default_tracking_params")]
+    tracking_params: Vec<String>,
+    #[serde(default)]
+    on_empty_content: OnEmptyContent,
+    #[serde(default)]
+    on_duplicate_feed: OnDuplicateFeed,
+    /// Entries with less text than this many characters are taken to be
+    /// teasers of a paywalled or truncated article
+    min_content_chars: Option<usize>,
+    /// Skip teasers instead of fetching the full article from their link
+    #[serde(default)]
+    skip_truncated: bool,
+    /// Entries with more text than this many characters are split into
+    /// sub-chapters at their h2/h3 headings
+    split_chapters_over: Option<usize>,
+    /// Show an estimated reading level at the top of each chapter
+    #[serde(default)]
+    reading_level: bool,
+    /// Formats generated from each run, all of them are delivered. A
+    /// single format can be given as output_format.
+    #[serde(
+        default = "default_output_formats",
+        alias = "output_format",
+        deserialize_with = "deserialize_output_formats"
+    )]
+    output_formats: Vec<OutputFormat>,
+    /// Send one email per output format instead of attaching them all to one
+    #[serde(default)]
+    email_per_format: bool,
+    /// Digests whose attachments would be larger than this many megabytes
+    /// are split across several emails
+    max_attachment_mb: Option<u64>,
+    /// Mbox file every sent digest email is appended to for the record
+    archive_mbox: Option<String>,
+    /// Limits on following urls found inside feed content
+    #[serde(default)]
+    fetch: FetchPolicy,
+    /// Command run after each generated file is written
+    post_generate: Option<HookConfig>,
+    /// off, error, warn, info, debug, or trace, RUST_LOG overrides it.
+    /// info by default.
+    log_level: Option<String>,
+    /// File logs are written to as well as stderr, moved aside to
+    /// <file>.1 once it grows past 10MB
+    log_file: Option<String>,
+    /// Url posted a json summary at the end of every run, e.g. a chat
+    /// webhook
+    notify_url: Option<String>,
+    /// Port the daemon serves Prometheus metrics on at /metrics
+    metrics_port: Option<u16>,
+    /// Address the metrics are served on, only this machine by default
+    #[serde(default = "default_metrics_addr")]
+    metrics_addr: IpAddr,
+    /// Directory every fetched feed body is cached in, for re-running
+    /// with --use-cache without fetching
+    cache_dir: Option<String>,
+    /// Also push picked entries to a read-later service
+    read_later: Option<ReadLaterConfig>,
+    /// Stage picked entries and only deliver them once this many minutes
+    /// have passed since the last delivery, delivers every run when unset
+    bundle_interval_mins: Option<u32>,
+    /// Custom CA bundle and certificate pins for feed and smtp connections
+    #[serde(default)]
+    tls: TlsConfig,
+    /// Seconds to wait on a feed request before giving up on it
+    #[serde(default = "default_request_timeout_secs")]
+    request_timeout_secs: u64,
+    /// Times a feed request is retried after a timeout or server error
+    #[serde(default = "default_max_retries")]
+    max_retries: u32,
+    /// Times sending an email is retried after anything but a rejection
+    #[serde(default = "default_max_retries")]
+    email_max_retries: u32,
+    /// Feeds that failed this many runs in a row are disabled until
+    /// retried with --feed
+    max_consecutive_failures: Option<u32>,
+    /// Tags kept in entry html, any others are unwrapped down to their text
+    #[serde(default = "default_allowed_tags")]
+    allowed_tags: Vec<String>,
+    /// CSS file used for the epub instead of the built in stylesheet
+    stylesheet_path: Option<String>,
+    /// Image used as the epub cover instead of the generated one
+    cover_image: Option<String>,
+    /// Language of the epub, chapters from feeds that declare their own
+    /// language are marked with that instead
+    #[serde(default = "default_epub_language")]
+    epub_language: String,
+    /// "2" for older readers that can't open EPUB 3, "3" by default
+    epub_version: Option<String>,
+    /// Name of the output files without the extension, {date}, {time},
+    /// and {count} are replaced, e.g. "Daily Digest {date}"
+    epub_name_template: Option<String>,
+    /// Subject of the digest email, {date}, {count}, and {feeds} are
+    /// replaced, "Saga Digest — {date} ({count} articles)" by default
+    email_subject_template: Option<String>,
+    /// Sent with every request, saga/<version> by default
+    user_agent: Option<String>,
+    /// Proxy url for every request, e.g. http://proxy.internal:3128
+    proxy: Option<String>,
+    /// Built from the tls and user_agent settings once the config is loaded
+    #[serde(skip)]
+    http: HttpConfig,
+    /// Most feeds fetched at the same time
+    #[serde(default = "default_max_concurrency")]
+    max_concurrency: usize,
+    /// Milliseconds between feed fetches, between fetches from the same
+    /// host when fetching concurrently
+    #[serde(default = "default_fetch_delay_ms")]
+    fetch_delay_ms: u64,
+    /// Skip entries whose text matches one already delivered, from this
+    /// run or an earlier one, e.g. an article syndicated to several feeds
+    #[serde(default)]
+    dedup_by_content: bool,
+    /// Also skip entries whose canonical link was already delivered, so a
+    /// feed changing its id scheme doesn't send everything again
+    #[serde(default)]
+    dedup_by_link: bool,
+    /// Show each feed's favicon next to its title in the digest
+    #[serde(default)]
+    show_favicons: bool,
+    /// End the epub with a chapter listing the feeds that failed and the
+    /// ones that had nothing new
+    #[serde(default)]
+    include_run_report: bool,
+    /// Forget processed entries after this many days at the start of each
+    /// run. They are only picked again if still in a feed and undated or
+    /// newer than its last processed time.
+    prune_after_days: Option<u32>,
+    /// Directory generated files are written to before being emailed,
+    /// the current directory when unset
+    output_dir: Option<String>,
+    /// Keep generated files once they have been emailed
+    #[serde(default = "default_true")]
+    keep_epubs: bool,
+    /// Keep the content of delivered entries in the database so old
+    /// digests can be rebuilt
+    #[serde(default = "default_true")]
+    store_content: bool,
+    /// Deliver nothing when a run only found random backfill entries and
+    /// nothing newly published
+    #[serde(default = "default_true")]
+    skip_email_when_empty: bool,
+    /// Seed for picking random entries so a run can be reproduced, fresh
+    /// entropy every run when unset
+    random_seed: Option<u64>,
+    /// Most entries delivered in one run across every feed, the rest are
+    /// left for the following runs
+    max_total_entries: Option<usize>,
+    /// Which entries are kept when a run finds more than max_total_entries
+    #[serde(default)]
+    max_total_keep: KeepEntries,
+    /// Mark the entries of a toc run processed instead of leaving them
+    /// for the next full run
+    #[serde(default)]
+    toc_marks_processed: bool,
+}
+
+/// Which end of a run's entries, by published date, is kept when there
+/// are too many
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum KeepEntries {
+    #[default]
+    Oldest,
+    Newest,
+}
+
+impl Config {
+    /// Apply the log level and file, see logging::configure
+    pub fn configure_logging(&self) -> Result<()> {
+        logging::configure(self.log_level.as_deref(), self.log_file.as_deref())
+    }
+
+    pub fn feed_urls(&self) -> Vec<&str> {
+        self.rss.iter().map(|x| x.url.as_str()).collect()
+    }
+
+    // feeds, pages, and then pdfs, the sources a run can be limited to
+    fn source_urls(&self) -> Vec<&str> {
+        self.rss
+            .iter()
+            .map(|x| x.url.as_str())
+            .chain(self.pages.iter().map(|x| x.url.as_str()))
+            .chain(self.pdfs.iter().map(|x| x.path.as_str()))
+            .collect()
+    }
+
+    // each feed draws from its own rng so its picks don't depend on the
+    // feeds before it, which lets explain reproduce a run's pick
+    fn rng(&self, url: &str) -> StdRng {
+        match self.random_seed {
+            Some(seed) => {
+                let mut key = seed.to_le_bytes().to_vec();
+                key.extend_from_slice(url.as_bytes());
+                StdRng::from_seed(openssl::sha::sha256(&key))
+            }
+            None => StdRng::from_os_rng(),
+        }
+    }
+
+    fn is_feed_disabled(&self, consecutive_failures: u32) -> bool {
+        self.max_consecutive_failures
+            .is_some_and(|max| consecutive_failures >= max)
+    }
+
+    // only ever invalid before validate has run
+    fn schedule_timezone(&self) -> Tz {
+        self.timezone
+            .as_deref()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
+
+    // duplicates are left in for validate to refuse when on_duplicate_feed
+    // is error
+    fn dedupe_feeds(&mut self) {
+        for feed_conf in &mut self.rss {
+            feed_conf.url = feed_conf.url.trim().to_string();
+        }
+        if self.on_duplicate_feed == OnDuplicateFeed::Error {
+            return;
+        }
+        let mut seen = HashSet::new();
+        self.rss.retain(|feed_conf| {
+            let first = seen.insert(feed_url_key(&feed_conf.url));
+            if !first {
+                warn!(
+                    "Feed {} is listed more than once, only the first is used",
+                    feed_conf.url
+                );
+            }
+            first
+        });
+    }
+
+    // checks the values serde can't, reporting every problem at once
+    // so a broken config can be fixed in one go
+    fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+
+        if let Err(e) = Schedule::from_str(&self.schedule) {
+            problems.push(format!(
+                "schedule {:?} is not a valid cron expression: {}",
+                self.schedule, e
+            ));
+        }
+
+        match self.delivery {
+            Delivery::Email if self.email.is_none() => {
+                problems.push(String::from(
+                    "email config is required when delivery is email",
+                ));
+            }
+            Delivery::Folder if self.folder.is_none() => {
+                problems.push(String::from("folder is required when delivery is folder"));
+            }
+            _ => {}
+        }
+
+        if let Some(email) = &self.email {
+            if let Err(e) = email.from.parse::<lettre::message::Mailbox>() {
+                problems.push(format!(
+                    "email from {:?} is not a valid address: {}",
+                    email.from, e
+                ));
+            }
+            if email.to.addresses().is_empty() {
+                problems.push(String::from("email to has no addresses"));
+            }
+            for address in email.to.addresses() {
+                if let Err(e) = address.parse::<lettre::message::Mailbox>() {
+                    problems.push(format!(
+                        "email to {:?} is not a valid address: {}",
+                        address, e
+                    ));
+                }
+            }
+            match email.transport {
+                EmailTransport::Smtp | EmailTransport::Imap if email.relay.trim().is_empty() => {
+                    problems.push(String::from("email relay is empty"));
+                }
+                EmailTransport::Imap if email.username.is_empty() => {
+                    problems.push(String::from(
+                        "email username is required for the imap transport",
+                    ));
+                }
+                EmailTransport::File if email.file_dir.is_none() => {
+                    problems.push(String::from(
+                        "email file_dir is required for the file transport",
+                    ));
+                }
+                _ => {}
+            }
+            if email.kindle_mode && !self.output_formats.contains(&OutputFormat::Epub) {
+                problems.push(String::from(
+                    "email kindle_mode only sends epubs but output_formats has no epub",
+                ));
+            }
+        }
+
+        if self.output_formats.is_empty() {
+            problems.push(String::from("output_formats has no formats"));
+        }
+
+        if let Some(Err(e)) = self.proxy.as_deref().map(http::parse_proxy) {
+            problems.push(e.to_string());
+        }
+
+        if let Some(timezone) = &self.timezone
+            && let Err(e) = timezone.parse::<Tz>()
+        {
+            problems.push(format!("timezone {:?} is not valid: {}", timezone, e));
+        }
+
+        if let Some(Err(e)) = self.log_level.as_deref().map(logging::parse_level) {
+            problems.push(e.to_string());
+        }
+
+        if let Err(e) = parse_epub_version(self.epub_version.as_deref()) {
+            problems.push(e.to_string());
+        }
+
+        let mut seen = HashSet::new();
+        for feed_conf in &self.rss {
+            if !seen.insert(feed_url_key(&feed_conf.url)) {
+                problems.push(format!("feed {} is listed more than once", feed_conf.url));
+            }
+            if let Some(schedule) = &feed_conf.schedule
+                && let Err(e) = Schedule::from_str(schedule)
+            {
+                problems.push(format!(
+                    "feed {} schedule {:?} is not a valid cron expression: {}",
+                    feed_conf.url, schedule, e
+                ));
+            }
+            if feed_conf
+                .strip_selectors
+                .iter()
+                .any(|x| x.trim().is_empty())
+            {
+                problems.push(format!(
+                    "feed {} has an empty strip selector",
+                    feed_conf.url
+                ));
+            }
+            for address in feed_conf.to.iter().flat_map(|x| x.addresses()) {
+                if let Err(e) = address.parse::<lettre::message::Mailbox>() {
+                    problems.push(format!(
+                        "feed {} to {:?} is not a valid address: {}",
+                        feed_conf.url, address, e
+                    ));
+                }
+            }
+            for (name, value) in &feed_conf.headers {
+                if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    problems.push(format!(
+                        "feed {} header name {:?} is not valid",
+                        feed_conf.url, name
+                    ));
+                }
+                // the value is left out in case it is a secret
+                if reqwest::header::HeaderValue::from_str(value).is_err() {
+                    problems.push(format!(
+                        "feed {} header {} has an invalid value",
+                        feed_conf.url, name
+                    ));
+                }
+            }
+            if feed_conf.password.is_some() && feed_conf.username.is_none() {
+                problems.push(format!(
+                    "feed {} has a password but no username",
+                    feed_conf.url
+                ));
+            }
+            match Url::parse(&feed_conf.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                Ok(url) => problems.push(format!(
+                    "feed url {} has unsupported scheme {}",
+                    feed_conf.url,
+                    url.scheme()
+                )),
+                Err(e) => {
+                    problems.push(format!("feed url {:?} is not valid: {}", feed_conf.url, e))
+                }
+            }
+        }
+
+        for page in &self.pages {
+            match Url::parse(&page.url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                Ok(url) => problems.push(format!(
+                    "page url {} has unsupported scheme {}",
+                    page.url,
+                    url.scheme()
+                )),
+                Err(e) => problems.push(format!("page url {:?} is not valid: {}", page.url, e)),
+            }
+            if page.selector.as_ref().is_some_and(|x| x.trim().is_empty()) {
+                problems.push(format!("page {} has an empty selector", page.url));
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!("invalid config:\n  - {}", problems.join("\n  - ")))
+    }
+}
+
+/// What to do with an entry that has no readable text once parsed:
+/// - skip: leave it out of the run
+/// - link_only: deliver a chapter that only links to the original
+/// - deliver: deliver it as-is
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum OnEmptyContent {
+    Skip,
+    #[default]
+    LinkOnly,
+    Deliver,
+}
+
+/// What to do with a feed listed more than once, a trailing slash aside:
+/// - warn: keep the first and drop the others with a warning
+/// - error: refuse the config
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum OnDuplicateFeed {
+    #[default]
+    Warn,
+    Error,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    Epub,
+    Markdown,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Epub => "epub",
+            OutputFormat::Markdown => "md",
+        }
+    }
+
+    fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Epub => "application/epub+zip",
+            OutputFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn parse_epub_version(version: Option<&str>) -> Result<EpubVersion> {
+    match version {
+        None | Some("3") => Ok(EpubVersion::V30),
+        Some("2") => Ok(EpubVersion::V20),
+        Some(version) => Err(anyhow!(
+            "epub_version {:?} is not supported, use \"2\" or \"3\"",
+            version
+        )),
+    }
+}
+
+fn default_epub_language() -> String {
+    String::from("en")
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_max_concurrency() -> usize {
+    4
+}
+
+fn default_fetch_delay_ms() -> u64 {
+    250
+}
+
+fn default_allowed_tags() -> Vec<String> {
+    let tags = "\
+        a abbr article aside b blockquote br caption cite code col colgroup dd del \
+        details dfn div dl dt em figcaption figure footer h1 h2 h3 h4 h5 h6 header hr \
+        i img ins kbd li main mark ol p picture pre q s samp section small source \
+        span strong sub summary sup table tbody td tfoot th thead time tr u ul";
+    tags.split_whitespace().map(String::from).collect()
+}
+
+fn default_tracking_params() -> Vec<String> {
+    let params = "utm_* fbclid gclid dclid msclkid yclid igshid mc_cid mc_eid _hsenc _hsmi mkt_tok";
+    params.split_whitespace().map(String::from).collect()
+}
+
+fn default_metrics_addr() -> IpAddr {
+    IpAddr::from([127, 0, 0, 1])
+}
+
+fn default_output_formats() -> Vec<OutputFormat> {
+    vec![OutputFormat::Epub]
+}
+
+// accepts a single format as well as a list
+fn deserialize_output_formats<'de, D>(deserializer: D) -> Result<Vec<OutputFormat>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Formats {
+        One(OutputFormat),
+        Many(Vec<OutputFormat>),
+    }
+    Ok(match Formats::deserialize(deserializer)? {
+        Formats::One(format) => vec![format],
+        Formats::Many(formats) => formats,
+    })
+}
+
+fn save_output(dir: &Path, output: &Output) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let output_path = dir.join(&output.name);
+    write_atomically(&output_path, &output.content)?;
+    info!("{:?} file saved as: {:?}", output.format, output_path);
+    Ok(output_path)
+}
+
+// Written to a hidden temp file next to the path and renamed into place,
+// so a crash or full disk never leaves a partial file to be delivered or
+// picked up by a sync client
+fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or(anyhow!("{:?} has no file name", path))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let written = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(anyhow!("Could not write {:?}: {}", path, e));
+    }
+    Ok(())
+}
+
+/// A generated file ready to be saved and delivered
+struct Output {
+    name: String,
+    format: OutputFormat,
+    content: Vec<u8>,
+}
+
+/// How the generated EPUB reaches the reader:
+/// - email: sent as an attachment over SMTP
+/// - folder: written to a synced folder (Syncthing, Dropbox, ...) with no email
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum Delivery {
+    #[default]
+    Email,
+    Folder,
+}
+
+#[derive(Deserialize, Debug)]
+struct FeedConfig {
+    url: String,
+    random: bool,
+    /// Overrides the global max_images_per_entry for this feed
+    max_images_per_entry: Option<usize>,
+    /// Minutes an entry must have been published before it can be delivered
+    publish_grace_mins: Option<u32>,
+    /// Fetch and inline the article behind short link-style posts
+    #[serde(default)]
+    expand_link_posts: bool,
+    #[serde(default)]
+    sort_by: SortBy,
+    /// Which backlog entries random picks favour
+    #[serde(default)]
+    backfill_order: BackfillOrder,
+    /// Download images so the epub reads offline
+    #[serde(default = "default_true")]
+    embed_images: bool,
+    /// Send this feed's entries to these addresses instead of the email
+    /// config's, in their own email
+    to: Option<Recipients>,
+    /// Most new entries delivered from this feed in one run, any others
+    /// are left for the following runs
+    #[serde(default = "default_max_entries_per_run")]
+    max_entries_per_run: usize,
+    /// Only entries whose title or content match one of these are delivered
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    include: Vec<Regex>,
+    /// Entries whose title or content match any of these are never delivered
+    #[serde(default, deserialize_with = "deserialize_patterns")]
+    exclude: Vec<Regex>,
+    /// Skip entries published more than this many days ago
+    max_age_days: Option<u32>,
+    /// Basic auth credentials for feeds behind a login
+    username: Option<String>,
+    password: Option<String>,
+    /// Extra headers sent when fetching the feed, e.g. an api token
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Cron schedule the daemon processes this feed on instead of the
+    /// global schedule
+    schedule: Option<String>,
+    /// Css selectors of boilerplate removed from every entry, e.g.
+    /// "div.footer"
+    #[serde(default)]
+    strip_selectors: Vec<String>,
+    /// Parse the feed as this format instead of detecting it, for servers
+    /// that send a wrong content type or junk ahead of the feed
+    format: Option<FeedFormat>,
+    /// Merge entries of a run whose titles share a prefix up to a
+    /// delimiter, e.g. "Story: Part 1" and "Story: Part 2", into one
+    /// chapter
+    #[serde(default)]
+    merge_by_title_prefix: bool,
+    /// Regex find and replace rules applied in order to each entry's html
+    /// before it is parsed, for fixing a feed's quirks
+    #[serde(default)]
+    transforms: Vec<Transform>,
+}
+
+/// Replaces every match of find with replace, which can refer to capture
+/// groups as $1 or ${name}
+#[derive(Deserialize, Debug)]
+struct Transform {
+    #[serde(deserialize_with = "deserialize_pattern")]
+    find: Regex,
+    replace: String,
+}
+
+/// Which parser a feed is forced through:
+/// - rss: rss 0.9x, 1.0 (rdf), or 2.0
+/// - atom: an atom feed
+/// - json: a json feed
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum FeedFormat {
+    Rss,
+    Atom,
+    Json,
+}
+
+impl FeedFormat {
+    // feed-rs picks its parser from the first '<' or '{' in the body, so
+    // anything ahead of the feed root (an html error banner, a stray
+    // php warning) is cut to force the right one
+    fn prepare<'a>(&self, body: &'a [u8]) -> Result<&'a [u8]> {
+        let roots: &[&[u8]] = match self {
+            FeedFormat::Rss => &[b"<rss", b"<rdf:RDF", b"<RDF"],
+            FeedFormat::Atom => &[b"<feed"],
+            FeedFormat::Json => &[b"{"],
+        };
+        let find = |needle: &[u8]| body.windows(needle.len()).position(|x| x == needle);
+        let root = roots
+            .iter()
+            .filter_map(|root| find(root))
+            .min()
+            .ok_or(anyhow!("Feed body has no {:?} root", self))?;
+        // keep the xml declaration so its encoding still applies
+        let start = match find(b"<?xml") {
+            Some(declaration) if declaration < root => declaration,
+            _ => root,
+        };
+        Ok(&body[start..])
+    }
+
+    fn matches(&self, feed_type: &FeedType) -> bool {
+        match self {
+            FeedFormat::Rss => {
+                matches!(feed_type, FeedType::RSS0 | FeedType::RSS1 | FeedType::RSS2)
+            }
+            FeedFormat::Atom => *feed_type == FeedType::Atom,
+            FeedFormat::Json => *feed_type == FeedType::JSON,
+        }
+    }
+}
+
+/// Which order decides the oldest and newest entries of a feed:
+/// - published: the published date
+/// - updated: the updated date, falling back to published
+/// - feed_order: the order entries appear in the feed, newest first
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum SortBy {
+    #[default]
+    Published,
+    Updated,
+    FeedOrder,
+}
+
+/// Which backlog entries a random pick favours:
+/// - random: every entry is as likely
+/// - oldest: older entries are more likely, to work through a backlog
+/// - newest: newer entries are more likely
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum BackfillOrder {
+    #[default]
+    Random,
+    Oldest,
+    Newest,
+}
+
+impl BackfillOrder {
+    // Shuffle ids sorted oldest first into the order they are tried in.
+    // Oldest and newest weigh each entry by its rank from that end and
+    // sample without replacement, each id keyed by u^(1/weight).
+    fn shuffle(&self, ids: &mut Vec<String>, rng: &mut StdRng) {
+        let oldest_first = match self {
+            BackfillOrder::Random => return ids.shuffle(rng),
+            BackfillOrder::Oldest => true,
+            BackfillOrder::Newest => false,
+        };
+        let len = ids.len();
+        let mut keyed: Vec<(f64, String)> = ids
+            .drain(..)
+            .enumerate()
+            .map(|(i, id)| {
+                let weight = match oldest_first {
+                    true => len - i,
+                    false => i + 1,
+                };
+                (rng.random::<f64>().powf(1.0 / weight as f64), id)
+            })
+            .collect();
+        keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+        ids.extend(keyed.into_iter().map(|(_, id)| id));
+    }
+}
+
+impl FeedConfig {
+    // a feed with only its url set and every other setting at its default
+    fn from_url(url: &str, random: bool) -> Result<FeedConfig> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "url": url,
+            "random": random,
+        }))?)
+    }
+
+    // entries without a published date can't be shown to be recent so
+    // they are always too old once a max age is set
+    fn is_too_old(&self, entry: &DisplayEntry) -> bool {
+        self.max_age_days.is_some_and(|days| {
+            entry.published.is_none_or(|published| {
+                published < Utc::now() - chrono::Duration::days(days as i64)
+            })
+        })
+    }
+
+    fn matches_filters(&self, entry: &DisplayEntry) -> bool {
+        let matches =
+            |pattern: &Regex| pattern.is_match(&entry.title) || pattern.is_match(&entry.content);
+        if self.exclude.iter().any(matches) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(matches)
+    }
+
+    // the grace period narrows the cutoff further: entries published less than
+    // publish_grace_mins before the cutoff are left unprocessed for a later run
+    // so we deliver the settled version rather than a freshly published draft
+    fn eligible_before(&self, cutoff: DateTime<Utc>) -> DateTime<Utc> {
+        cutoff - chrono::Duration::minutes(self.publish_grace_mins.unwrap_or(0) as i64)
+    }
+}
+
+impl SortBy {
+    // orders from oldest to newest, with undated entries last
+    fn compare(&self, a: &DisplayEntry, b: &DisplayEntry) -> std::cmp::Ordering {
+        let dated_first = |a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>| match (a, b) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+        match self {
+            SortBy::Published => dated_first(a.published, b.published),
+            SortBy::Updated => dated_first(a.updated.or(a.published), b.updated.or(b.published)),
+            SortBy::FeedOrder => b.position.cmp(&a.position),
+        }
+    }
+}
+
+/// A command run with the path of a generated file as its last argument,
+/// e.g. to convert it with ebook-convert or copy it to a mounted device
+#[derive(Deserialize, Debug)]
+struct HookConfig {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    /// Fail the run when the command exits non-zero
+    #[serde(default = "default_true")]
+    fail_on_error: bool,
+}
+
+// patterns are compiled as the config is read so a bad one fails at startup
+fn deserialize_patterns<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pattern| compile_pattern(pattern))
+        .collect()
+}
+
+fn deserialize_pattern<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    compile_pattern(&String::deserialize(deserializer)?)
+}
+
+fn compile_pattern<E: serde::de::Error>(pattern: &str) -> Result<Regex, E> {
+    Regex::new(pattern)
+        .map_err(|e| serde::de::Error::custom(format!("invalid pattern {:?}: {}", pattern, e)))
+}
+
+fn default_max_entries_per_run() -> usize {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug)]
+struct EmailConfig {
+    to: Recipients,
+    from: String,
+    /// Not needed for the file transport, the imap server for the imap
+    /// transport
+    #[serde(default)]
+    relay: String,
+    /// Leave the username unset for relays that don't authenticate
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+    /// Environment variable the password is read from instead
+    password_env: Option<String>,
+    /// Defaults to the usual port for the encryption
+    port: Option<u16>,
+    #[serde(default)]
+    encryption: Encryption,
+    #[serde(default)]
+    transport: EmailTransport,
+    /// Directory messages are written to by the file transport
+    file_dir: Option<String>,
+    /// Mailbox the imap transport appends messages to, INBOX by default
+    imap_folder: Option<String>,
+    /// Follow Amazon's send to Kindle rules: only epubs are attached, under
+    /// plain ascii file names, with an empty subject
+    #[serde(default)]
+    kindle_mode: bool,
+}
+
+// Amazon bounces documents from senders that aren't on the account's
+// approved list, often without saying why
+const KINDLE_APPROVED_SENDER: &str = "Kindle only accepts documents from addresses on the \
+    Approved Personal Document E-mail List, add the from address under Manage Your Content \
+    and Devices > Preferences > Personal Document Settings";
+
+// Kindle rejects some file names with spaces or non-ascii characters
+fn kindle_file_name(name: &str) -> String {
+    name.chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect()
+}
+
+/// How emails leave saga:
+/// - smtp: sent through the relay
+/// - file: the full message is written to file_dir instead, e.g. to
+///   check what would be sent without delivering it
+/// - imap: experimental, the message is appended to imap_folder on the
+///   relay, an imap server, e.g. for reading digests in a mail client
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum EmailTransport {
+    #[default]
+    Smtp,
+    File,
+    Imap,
+}
+
+/// How the smtp connection is secured:
+/// - tls: implicit tls, port 465 by default
+/// - starttls: upgraded plain connection, port 587 by default
+/// - none: plain smtp, port 25 by default
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+enum Encryption {
+    #[default]
+    Tls,
+    Starttls,
+    None,
+}
+
+/// A single address or a list of them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Recipients {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Recipients {
+    fn addresses(&self) -> &[String] {
+        match self {
+            Recipients::One(address) => std::slice::from_ref(address),
+            Recipients::Many(addresses) => addresses,
+        }
+    }
+
+    fn addresses_mut(&mut self) -> &mut [String] {
+        match self {
+            Recipients::One(address) => std::slice::from_mut(address),
+            Recipients::Many(addresses) => addresses,
+        }
+    }
+}
+
+impl EmailConfig {
+    // secrets can live in the environment rather than the config file,
+    // either as ${VAR} placeholders in any field or through password_env
+    fn expand_env(&mut self) -> Result<()> {
+        let mut missing = vec![];
+        let fields = self.to.addresses_mut().iter_mut().chain([
+            &mut self.from,
+            &mut self.relay,
+            &mut self.username,
+            &mut self.password,
+        ]);
+        for field in fields {
+            *field = expand_env_vars(field, &mut missing);
+        }
+        if let Some(name) = &self.password_env {
+            match std::env::var(name) {
+                Ok(password) => self.password = password,
+                Err(_) => missing.push(name.clone()),
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "email config references unset environment variables: {}",
+            missing.join(", ")
+        ))
+    }
+}
+
+// unset variables are added to missing and left as the placeholder
+fn expand_env_vars(value: &str, missing: &mut Vec<String>) -> String {
+    let placeholder = Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    placeholder
+        .replace_all(value, |captures: &regex::Captures| {
+            let name = &captures[1];
+            std::env::var(name).unwrap_or_else(|_| {
+                missing.push(name.to_string());
+                captures[0].to_string()
+            })
+        })
+        .into_owned()
+}
+
+const EXAMPLE_CONFIG: &str = include_str!("config.example.yml");
+
+/// Write a commented example config covering every setting to the path,
+/// refusing to replace an existing file unless forced
+pub fn init_config(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        return Err(anyhow!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        ));
+    }
+    fs::write(path, EXAMPLE_CONFIG)?;
+    info!("Wrote an example config to {}", path.display());
+    Ok(())
+}
+
+/// Config is layered from lowest to highest precedence:
+/// 1. /etc/saga/config.yml (system defaults, e.g. set by an admin)
+/// 2. $XDG_CONFIG_HOME/saga/config.yml or ~/.config/saga/config.yml (user)
+/// 3. ./config.yml (current directory)
+///
+/// Missing layers are skipped. When merging, the `rss` feed lists of every
+/// layer are appended together, nested maps such as `email` are merged key by
+/// key, and any other value from a later layer overrides the earlier one.
+///
+/// An explicit path skips the layering and is the only file read.
+pub fn get_config(path: Option<&Path>) -> Result<Config> {
+    let paths = match path {
+        Some(path) => vec![std::env::current_dir()?.join(path)],
+        None => config_paths()?,
+    };
+
+    let mut merged: Option<serde_yml::Value> = None;
+    let mut used_paths = vec![];
+    for config_path in paths {
+        if !config_path.exists() {
+            if path.is_some() {
+                return Err(anyhow!("no config found at {:?}", config_path));
+            }
+            continue;
+        }
+        let config_str = std::fs::read_to_string(&config_path)?;
+        let layer: serde_yml::Value = serde_yml::from_str(&config_str)
+            .map_err(|e| anyhow!("invalid config at {:?}: {}", config_path, e))?;
+        merged = Some(match merged {
+            Some(base) => merge_config(base, layer),
+            None => layer,
+        });
+        used_paths.push(config_path);
+    }
+
+    let merged = merged.ok_or(anyhow!("no config.yml found"))?;
+    let mut config: Config = serde_yml::from_value(merged)?;
+    if let Some(email) = &mut config.email {
+        email.expand_env()?;
+    }
+    config.dedupe_feeds();
+    config.validate()?;
+    config.http = HttpConfig {
+        tls: config.tls.clone(),
+        user_agent: config
+            .user_agent
+            .clone()
+            .unwrap_or_else(http::default_user_agent),
+        proxy: config.proxy.clone(),
+    };
+
+    info!("Using config at paths {:?}", used_paths);
+
+    Ok(config)
+}
+
+fn config_paths() -> Result<Vec<PathBuf>> {
+    let mut paths = vec![PathBuf::from("/etc/saga/config.yml")];
+
+    let user_config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")));
+    if let Some(dir) = user_config_dir {
+        paths.push(dir.join("saga").join("config.yml"));
+    }
+
+    let mut config_path = std::env::current_dir()?;
+    config_path.push("config.yml");
+    paths.push(config_path);
+
+    Ok(paths)
+}
+
+fn merge_config(base: serde_yml::Value, layer: serde_yml::Value) -> serde_yml::Value {
+    use serde_yml::Value;
+
+    match (base, layer) {
+        (Value::Mapping(mut base), Value::Mapping(layer)) => {
+            for (key, value) in layer {
+                let merged = match (base.remove(&key), value) {
+                    // feeds from every layer are kept
+                    (Some(Value::Sequence(mut feeds)), Value::Sequence(more))
+                        if key.as_str() == Some("rss") =>
+                    {
+                        feeds.extend(more);
+                        Value::Sequence(feeds)
+                    }
+                    (Some(existing), value) => merge_config(existing, value),
+                    (None, value) => value,
+                };
+                base.insert(key, merged);
+            }
+            Value::Mapping(base)
+        }
+        (_, layer) => layer,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DisplayEntry {
+    pub id: String,
+    /// Url of the configured feed the entry came from
+    #[serde(default)]
+    pub feed_url: String,
+    pub feed_title: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    /// Falls back to the updated date for feeds that only set that
+    pub published: Option<DateTime<Utc>>,
+    pub updated: Option<DateTime<Utc>>,
+    /// Index of the entry in the feed document
+    position: usize,
+    pub link: Option<String>,
+    pub content: String,
+    sections: Vec<Section>,
+    /// Downloaded copies of images in the content, keyed by their src
+    #[serde(default)]
+    images: Vec<EmbeddedImage>,
+    /// Language declared by the entry or its feed
+    #[serde(default)]
+    language: Option<String>,
+    /// Url and mime type of the media the entry carries, e.g. a podcast
+    /// episode, linked from the chapter rather than downloaded
+    #[serde(default)]
+    enclosures: Vec<(String, String)>,
+    /// Favicon of the feed's site when show_favicons is set
+    #[serde(default)]
+    feed_icon: Option<EmbeddedImage>,
+    /// Ids of the other parts merged into this entry, marked processed
+    /// along with it
+    #[serde(default)]
+    merged_ids: Vec<String>,
+    /// Other keys the entry may have been processed under, the id as the
+    /// feed gave it and its canonical link with dedup_by_link
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EmbeddedImage {
+    url: String,
+    mime_type: String,
+    data: Vec<u8>,
+}
+
+impl EmbeddedImage {
+    fn extension(&self) -> &str {
+        match self.mime_type.as_str() {
+            "image/jpeg" => "jpg",
+            "image/svg+xml" => "svg",
+            mime_type => mime_type.trim_start_matches("image/"),
+        }
+    }
+}
+
+/// Entry content after parse_xhtml has rewritten it
+#[derive(Default)]
+struct ParsedContent {
+    content: String,
+    sections: Vec<Section>,
+    images: Vec<EmbeddedImage>,
+}
+
+/// A heading inside a long entry that gets its own TOC element
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Section {
+    anchor: String,
+    title: String,
+}
+
+fn get_entries(
+    config: &Config,
+    client: &blocking::Client,
+    feed_conf: &FeedConfig,
+    validators: &FeedValidators,
+    options: &RunOptions,
+) -> Result<FetchedFeed> {
+    let toc_only = options.toc_only;
+    let fetched = match options.use_cache {
+        true => Some((
+            read_cached_feed(config, &feed_conf.url)?,
+            validators.clone(),
+        )),
+        false => fetch_feed(config, client, feed_conf, validators)?,
+    };
+    let Some((resp, validators)) = fetched else {
+        info!("Feed {} has not changed since the last run", feed_conf.url);
+        return Ok((vec![], validators.clone(), None));
+    };
+    let body = gunzip_if_compressed(resp);
+    if let Some(dir) = config.cache_dir.as_deref().filter(|_| !options.use_cache)
+        && let Err(e) = write_cached_feed(dir, &feed_conf.url, &body)
+    {
+        warn!("Could not cache feed {}: {:#}", feed_conf.url, e);
+    }
+    let source = match feed_conf.format {
+        Some(format) => format.prepare(&body)?,
+        None => &body[..],
+    };
+    // feed-rs sanitizing drops the classes strip_selectors match on and
+    // code blocks carry their language in, parse_xhtml cleans the content
+    // itself
+    let feed = parser::Builder::new()
+        .sanitize_content(false)
+        .build()
+        .parse(source)?;
+    if let Some(format) = feed_conf.format
+        && !format.matches(&feed.feed_type)
+    {
+        return Err(anyhow!(
+            "Feed {} is configured as {:?} but parsed as {:?}",
+            feed_conf.url,
+            format,
+            feed.feed_type
+        ));
+    }
+    let poll_interval = poll_interval(feed.ttl, &body);
+    let mut display_enrties: Vec<DisplayEntry> = vec![];
+    let mut seen_ids = HashSet::new();
+    for (position, entry) in feed.entries.into_iter().enumerate() {
+        // some feeds repeat a guid across items, only the first one counts
+        if !seen_ids.insert(entry.id.clone()) {
+            warn!(
+                "Feed {} repeats entry id {}, keeping the first occurrence",
+                feed_conf.url, entry.id
+            );
+            continue;
+        }
+        let feed_title = feed
+            .title
+            .as_ref()
+            .map_or(String::from("Unknown Feed"), |x| x.content.clone());
+        let id = normalize_entry_id(&entry.id, &config.tracking_params);
+        let title = entry
+            .title
+            .as_ref()
+            .map_or(String::from("Unknown Title"), |x| x.content.clone());
+        let authors = entry.authors.iter().map(|a| a.name.clone()).collect();
+        let published = entry.published.or(entry.updated);
+        let updated = entry.updated;
+        let link = get_entry_link(&entry);
+        let mut aliases = vec![];
+        if id != entry.id {
+            aliases.push(entry.id.clone());
+        }
+        if config.dedup_by_link
+            && let Some(link) = link
+                .as_deref()
+                .and_then(|x| canonical_url(x, &config.tracking_params))
+        {
+            aliases.push(format!("link:{}", link));
+        }
+        let language = entry.language.clone().or(feed.language.clone());
+        let enclosures = get_enclosures(&entry);
+        let parsed = if toc_only {
+            ParsedContent::default()
+        } else {
+            match parse_xhtml(config, feed_conf, entry)? {
+                Some(parsed) => parsed,
+                None => continue,
+            }
+        };
+        info!("Contet: {}", parsed.content);
+        display_enrties.push(DisplayEntry {
+            id,
+            feed_url: feed_conf.url.clone(),
+            feed_title,
+            title,
+            authors,
+            published,
+            updated,
+            position,
+            link,
+            content: parsed.content,
+            sections: parsed.sections,
+            images: parsed.images,
+            language,
+            enclosures,
+            feed_icon: None,
+            merged_ids: vec![],
+            aliases,
+        });
+    }
+
+    Ok((display_enrties, validators, poll_interval))
+}
+
+// Ids that are urls are canonicalized so a feed tidying up its
+// permalinks doesn't look like it has all new entries, any other id is
+// kept as given
+fn normalize_entry_id(id: &str, tracking_params: &[String]) -> String {
+    canonical_url(id, tracking_params).unwrap_or_else(|| id.to_string())
+}
+
+/// The url with a lowercase host and without its fragment, tracking
+/// parameters, or trailing slash, None when it isn't an http(s) url
+fn canonical_url(url: &str, tracking_params: &[String]) -> Option<String> {
+    let mut url = Url::parse(url.trim())
+        .ok()
+        .filter(|x| matches!(x.scheme(), "http" | "https"))?;
+    url.set_fragment(None);
+    html::strip_url_tracking_params(&mut url, tracking_params);
+    let path = url.path().trim_end_matches('/').to_string();
+    url.set_path(&path);
+    Some(url.to_string().trim_end_matches('/').to_string())
+}
+
+// Rss enclosures and media rss content. Images are left out since they
+// are usually a thumbnail of what the content already shows.
+fn get_enclosures(entry: &Entry) -> Vec<(String, String)> {
+    let mut enclosures: Vec<(String, String)> = vec![];
+    for content in entry.media.iter().flat_map(|x| &x.content) {
+        let Some(url) = &content.url else {
+            continue;
+        };
+        let mime_type = content
+            .content_type
+            .as_ref()
+            .map_or(String::from("application/octet-stream"), |x| x.to_string());
+        if mime_type.starts_with("image/") || enclosures.iter().any(|(x, _)| x == url.as_str()) {
+            continue;
+        }
+        enclosures.push((url.to_string(), mime_type));
+    }
+    enclosures
+}
+
+// How often the feed asks to be polled, from the rss ttl in minutes or the
+// syndication module's update period and frequency, the longer of the two
+// when both are given. feed-rs doesn't parse the syndication module so it
+// is picked out of the raw xml.
+fn poll_interval(ttl: Option<u32>, body: &[u8]) -> Option<chrono::Duration> {
+    let ttl = ttl
+        .filter(|&x| x > 0)
+        .map(|x| chrono::Duration::minutes(x as i64));
+
+    let xml = String::from_utf8_lossy(body);
+    let element = |name: &str| {
+        Regex::new(&format!(
+            r"<(?:\w+:)?{0}>\s*([^<\s]+)\s*</(?:\w+:)?{0}>",
+            name
+        ))
+        .unwrap()
+        .captures(&xml)
+        .map(|x| x[1].to_lowercase())
+    };
+    let period = element("updatePeriod").and_then(|x| match x.as_str() {
+        "hourly" => Some(chrono::Duration::hours(1)),
+        "daily" => Some(chrono::Duration::days(1)),
+        "weekly" => Some(chrono::Duration::weeks(1)),
+        "monthly" => Some(chrono::Duration::days(30)),
+        "yearly" => Some(chrono::Duration::days(365)),
+        _ => None,
+    });
+    let frequency = element("updateFrequency")
+        .and_then(|x| x.parse::<i32>().ok())
+        .filter(|&x| x > 0)
+        .unwrap_or(1);
+    let syndication = period.map(|x| x / frequency);
+
+    ttl.max(syndication)
+}
+
+/// When a cached feed body was fetched, kept next to it
+#[derive(Serialize, Deserialize)]
+struct CachedFeed {
+    url: String,
+    fetched_at: DateTime<Utc>,
+}
+
+// a cached copy older than this is used anyway, with a warning
+const CACHE_STALE_HOURS: i64 = 24;
+
+// files are named by a hash of the feed url, the body in .xml and when
+// it was fetched in .json
+fn cache_path(dir: &str, url: &str) -> PathBuf {
+    let hash: String = openssl::sha::sha256(url.as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+    Path::new(dir).join(hash)
+}
+
+fn write_cached_feed(dir: &str, url: &str, body: &[u8]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = cache_path(dir, url);
+    fs::write(path.with_extension("xml"), body)?;
+    let meta = CachedFeed {
+        url: url.to_string(),
+        fetched_at: Utc::now(),
+    };
+    fs::write(path.with_extension("json"), serde_json::to_vec(&meta)?)?;
+    Ok(())
+}
+
+fn read_cached_feed(config: &Config, url: &str) -> Result<Vec<u8>> {
+    let dir = config
+        .cache_dir
+        .as_deref()
+        .ok_or(anyhow!("--use-cache needs cache_dir to be set"))?;
+    let path = cache_path(dir, url);
+    let meta: CachedFeed = fs::read(path.with_extension("json"))
+        .map_err(anyhow::Error::from)
+        .and_then(|x| Ok(serde_json::from_slice(&x)?))
+        .map_err(|e| {
+            anyhow!(
+                "no cached copy of feed {}, run once without --use-cache: {}",
+                url,
+                e
+            )
+        })?;
+    let age = Utc::now() - meta.fetched_at;
+    if age > chrono::Duration::hours(CACHE_STALE_HOURS) {
+        warn!(
+            "Cached copy of feed {} is stale, it was fetched {} hours ago",
+            url,
+            age.num_hours()
+        );
+    } else {
+        info!(
+            "Using the copy of feed {} cached at {}",
+            url, meta.fetched_at
+        );
+    }
+    Ok(fs::read(path.with_extension("xml"))?)
+}
+
+// Some servers gzip the body without saying so in Content-Encoding so it
+// reaches us still compressed, the gzip magic number gives it away
+fn gunzip_if_compressed(body: Vec<u8>) -> Vec<u8> {
+    if !body.starts_with(&[0x1f, 0x8b]) {
+        return body;
+    }
+    let mut decoded = vec![];
+    match flate2::read::GzDecoder::new(&body[..]).read_to_end(&mut decoded) {
+        Ok(_) => decoded,
+        Err(e) => {
+            warn!(
+                "Feed body looks gzipped but could not be decompressed: {}",
+                e
+            );
+            body
+        }
+    }
+}
+
+fn run_post_generate(hook: &HookConfig, path: &Path) -> Result<()> {
+    info!("Running post_generate hook {} for {:?}", hook.command, path);
+    let output = std::process::Command::new(&hook.command)
+        .args(&hook.args)
+        .arg(path)
+        .output()?;
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("[{}] {}", hook.command, line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("[{}] {}", hook.command, line);
+    }
+
+    if !output.status.success() {
+        if hook.fail_on_error {
+            return Err(anyhow!(
+                "post_generate hook {} failed with {}",
+                hook.command,
+                output.status
+            ));
+        }
+        warn!(
+            "post_generate hook {} failed with {}",
+            hook.command, output.status
+        );
+    }
+    Ok(())
+}
+
+// Builds the single headlines chapter delivered in toc mode
+fn toc_entry(entries: &[DisplayEntry]) -> Result<DisplayEntry> {
+    let dom = html::parse("")?;
+    let items = entries
+        .iter()
+        .map(|entry| {
+            let title = match &entry.link {
+                Some(link) => {
+                    html::new_element("a", &[("href", link)], vec![html::text_node(&entry.title)])
+                }
+                None => html::text_node(&entry.title),
+            };
+            let details = match entry.published {
+                Some(published) => format!(
+                    " ({}, {})",
+                    entry.feed_title,
+                    published.format("%B %-d, %Y")
+                ),
+                None => format!(" ({})", entry.feed_title),
+            };
+            let details = html::text_node(&details);
+            html::new_element("li", &[], vec![title, details])
+        })
+        .collect();
+    if let Some(body) = html::elements(&dom.document, "body").first() {
+        html::set_children(body, vec![html::new_element("ul", &[], items)]);
+    }
+
+    Ok(DisplayEntry {
+        id: String::from("saga-toc"),
+        feed_url: String::new(),
+        feed_title: String::from("Saga"),
+        title: String::from("Headlines"),
+        authors: vec![],
+        published: Some(Utc::now()),
+        updated: None,
+        position: 0,
+        link: None,
+        content: html::serialize(&dom.document)?,
+        sections: vec![],
+        images: vec![],
+        language: None,
+        enclosures: vec![],
+        feed_icon: None,
+        merged_ids: vec![],
+        aliases: vec![],
+    })
+}
+
+/// Cache validators from the last full fetch of a feed, sent back so an
+/// unchanged feed can answer 304 Not Modified instead of the whole body
+#[derive(Debug, Clone, Default)]
+struct FeedValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+// Timeouts, connection failures, and server errors are usually transient
+// so they are retried with exponential backoff before giving up on the feed
+// None when the feed has not been modified since the validators were issued
+fn fetch_feed(
+    config: &Config,
+    client: &blocking::Client,
+    feed_conf: &FeedConfig,
+    validators: &FeedValidators,
+) -> Result<Option<(Vec<u8>, FeedValidators)>> {
+    let url = feed_conf.url.as_str();
+    let mut attempt = 0;
+    loop {
+        let mut request = client.get(url);
+        for (name, value) in &feed_conf.headers {
+            request = request.header(name, value);
+        }
+        if let Some(username) = &feed_conf.username {
+            request = request.basic_auth(username, feed_conf.password.as_ref());
+        }
+        if let Some(etag) = &validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let result = config.http.send(request).and_then(|resp| {
+            if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+                return Ok(None);
+            }
+            let resp = resp.error_for_status()?;
+            if resp.url().as_str() != url {
+                info!(
+                    "Feed {} redirected to {}, consider updating it in the config",
+                    url,
+                    resp.url()
+                );
+            }
+            let header = |name| {
+                resp.headers()
+                    .get(name)
+                    .and_then(|x: &reqwest::header::HeaderValue| x.to_str().ok())
+                    .map(String::from)
+            };
+            let validators = FeedValidators {
+                etag: header(reqwest::header::ETAG),
+                last_modified: header(reqwest::header::LAST_MODIFIED),
+            };
+            Ok(Some((resp.bytes()?.to_vec(), validators)))
+        });
+        match result {
+            Ok(fetched) => return Ok(fetched),
+            Err(e) if attempt < config.max_retries && is_transient(&e) => {
+                let backoff = Duration::from_secs(1 << attempt.min(6));
+                attempt += 1;
+                warn!("Fetching {} failed, retrying in {:?}: {}", url, backoff, e);
+                thread::sleep(backoff);
+            }
+            Err(e)
+                if e.downcast_ref::<reqwest::Error>()
+                    .is_some_and(|x| x.is_redirect()) =>
+            {
+                return Err(anyhow!(
+                    "Could not fetch feed {}: redirects looped or went past the limit of {}",
+                    url,
+                    http::MAX_REDIRECTS
+                ));
+            }
+            Err(e) => return Err(anyhow!("Could not fetch feed {}: {}", url, e)),
+        }
+    }
+}
+
+fn is_transient(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(e) => {
+            (e.is_timeout() || e.is_connect() || e.status().is_some_and(|x| x.is_server_error()))
+                && !is_certificate_refused(e)
+        }
+        None => false,
+    }
+}
+
+// a certificate refused in the handshake, e.g. for not matching its pin,
+// is refused again on a retry
+fn is_certificate_refused(e: &reqwest::Error) -> bool {
+    fn refused(error: &(dyn std::error::Error + 'static)) -> bool {
+        // io errors hide what they wrap from source, so it is looked at too
+        let wrapped = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|x| x.get_ref());
+        error.is::<rustls::Error>()
+            || wrapped.is_some_and(|x| refused(x))
+            || error.source().is_some_and(refused)
+    }
+    std::error::Error::source(e).is_some_and(refused)
+}
+
+// prefer the alternate link as it points at the article itself
+fn get_entry_link(entry: &Entry) -> Option<String> {
+    entry
+        .links
+        .iter()
+        .find(|x| x.rel.as_deref() == Some("alternate"))
+        .or(entry.links.first())
+        .map(|x| x.href.clone())
+}
+
+// posts with less text than this and an outbound link are treated as link posts
+const LINK_POST_MAX_CHARS: usize = 500;
+
+// Inline the linked article below the blogger's commentary,
+// leaving the original content untouched on any failure. True when the
+// article was added.
+fn expand_link_post(
+    policy: &FetchPolicy,
+    http: &HttpConfig,
+    document: &Handle,
+    entry_link: Option<&str>,
+    title: &str,
+) -> bool {
+    if html::text_content(document).trim().chars().count() > LINK_POST_MAX_CHARS {
+        return false;
+    }
+    let own_host = entry_link
+        .and_then(|x| Url::parse(x).ok())
+        .and_then(|x| x.host_str().map(String::from));
+    let Some(target) = html::first_outbound_link(document, own_host.as_deref()) else {
+        return false;
+    };
+
+    info!("Expanding link post {} with {}", title, target);
+    let article = match fetch::fetch_html(policy, http, &target) {
+        Ok(article) => article,
+        Err(e) => {
+            warn!("Could not expand link post {}: {}", title, e);
+            return false;
+        }
+    };
+    let article_dom = match html::parse(&article) {
+        Ok(dom) => dom,
+        Err(e) => {
+            warn!("Could not parse linked article for {}: {}", title, e);
+            return false;
+        }
+    };
+    let (Some(main), Some(body)) = (
+        html::main_content(&article_dom.document),
+        html::elements(document, "body").first().cloned(),
+    ) else {
+        return false;
+    };
+
+    if let Ok(base) = Url::parse(&target) {
+        html::absolutize_urls(&main, &base);
+    }
+    let children = std::mem::take(&mut *main.children.borrow_mut());
+    html::append_children(&body, vec![html::new_element("hr", &[], vec![])]);
+    html::append_children(&body, children);
+    true
+}
+
+// Swap a teaser for the article on its own page, keeping the teaser when
+// the page can't be fetched or has no more text than it. True when the
+// teaser was swapped.
+fn fetch_full_article(
+    policy: &FetchPolicy,
+    http: &HttpConfig,
+    document: &Handle,
+    link: &str,
+    title: &str,
+) -> bool {
+    info!("Entry {} looks truncated, fetching {}", title, link);
+    let article = match fetch::fetch_html(policy, http, link) {
+        Ok(article) => article,
+        Err(e) => {
+            warn!("Could not fetch the full article for {}: {}", title, e);
+            return false;
+        }
+    };
+    let article_dom = match html::parse(&article) {
+        Ok(dom) => dom,
+        Err(e) => {
+            warn!("Could not parse the full article for {}: {}", title, e);
+            return false;
+        }
+    };
+    let (Some(main), Some(body)) = (
+        html::main_content(&article_dom.document),
+        html::elements(document, "body").first().cloned(),
+    ) else {
+        return false;
+    };
+    let teaser_chars = html::text_content(&body).trim().chars().count();
+    if html::text_content(&main).trim().chars().count() <= teaser_chars {
+        warn!(
+            "Full article for {} is no longer than the teaser, keeping the teaser",
+            title
+        );
+        return false;
+    }
+
+    if let Ok(base) = Url::parse(link) {
+        html::absolutize_urls(&main, &base);
+    }
+    let children = std::mem::take(&mut *main.children.borrow_mut());
+    html::set_children(&body, children);
+    true
+}
+
+pub fn inspect_feed(url: &str, count: usize) -> Result<()> {
+    // inspect runs without a config so it uses the default settings
+    let http = HttpConfig::default();
+    let resp = http.send(http.client()?.build()?.get(url))?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .map(String::from);
+    let body = resp.bytes()?;
+    let feed = parser::parse(&body[..])?;
+
+    let charset = content_type
+        .as_deref()
+        .and_then(|x| {
+            x.split(';')
+                .find_map(|param| param.trim().strip_prefix("charset="))
+        })
+        .unwrap_or("unspecified");
+
+    println!("Feed: {}", url);
+    println!(
+        "  content type: {}",
+        content_type.as_deref().unwrap_or("unspecified")
+    );
+    println!("  charset: {}", charset);
+    println!("  format: {:?}", feed.feed_type);
+    println!(
+        "  title: {}",
+        feed.title.as_ref().map_or("absent", |x| x.content.as_str())
+    );
+    println!("  entries: {}", feed.entries.len());
+
+    let present = |x: bool| if x { "present" } else { "absent" };
+    for (i, entry) in feed.entries.iter().take(count).enumerate() {
+        println!("Entry {}:", i + 1);
+        println!("  id: {}", present(!entry.id.is_empty()));
+        println!("  title: {}", present(entry.title.is_some()));
+        println!(
+            "  authors: {} ({})",
+            present(!entry.authors.is_empty()),
+            entry.authors.len()
+        );
+        println!("  published: {}", present(entry.published.is_some()));
+        println!("  updated: {}", present(entry.updated.is_some()));
+        match &entry.content {
+            Some(content) => println!(
+                "  content: present (type {}, body {}, src {})",
+                content.content_type,
+                present(content.body.is_some()),
+                present(content.src.is_some())
+            ),
+            None => println!("  content: absent"),
+        }
+        println!("  summary: {}", present(entry.summary.is_some()));
+        println!(
+            "  links: {} ({})",
+            present(!entry.links.is_empty()),
+            entry.links.len()
+        );
+        println!(
+            "  categories: {} ({})",
+            present(!entry.categories.is_empty()),
+            entry.categories.len()
+        );
+        let enclosures = entry
+            .media
+            .iter()
+            .flat_map(|x| &x.content)
+            .filter(|x| x.url.is_some())
+            .count();
+        println!("  enclosures: {} ({})", present(enclosures > 0), enclosures);
+    }
+
+    Ok(())
+}
+
+// The passes every entry's html goes through after the feed's own
+// boilerplate is stripped. Fetched articles are merged in later, so they
+// go through them again, which leaves already cleaned content as it is.
+fn clean_content(
+    config: &Config,
+    feed_conf: &FeedConfig,
+    document: &Handle,
+    link: Option<&str>,
+    title: &str,
+) {
+    let sanitized = html::sanitize(document, &config.allowed_tags);
+    if sanitized > 0 {
+        info!("Sanitized {} elements from entry {}", sanitized, title);
+    }
+
+    let emptied = html::normalize_whitespace(document);
+    if emptied > 0 {
+        info!("Removed {} empty paragraphs from entry {}", emptied, title);
+    }
+
+    // relative urls have nothing to resolve against once inside the epub
+    if let Ok(base) = Url::parse(link.unwrap_or(&feed_conf.url)) {
+        html::absolutize_urls(document, &base);
+    }
+
+    let stripped = html::strip_tracking_params(document, &config.tracking_params);
+    if stripped > 0 {
+        info!(
+            "Stripped tracking parameters from {} links in entry {}",
+            stripped, title
+        );
+    }
+
+    if let Some(max_images) = feed_conf
+        .max_images_per_entry
+        .or(config.max_images_per_entry)
+    {
+        let dropped = html::limit_images(document, max_images);
+        if dropped > 0 {
+            info!("Dropped {} images from entry {}", dropped, title);
+        }
+    }
+
+    if !config.link_denylist.is_empty() {
+        let (links, images) = html::apply_link_denylist(document, &config.link_denylist);
+        if links > 0 || images > 0 {
+            info!(
+                "Removed {} denied links and {} denied images from entry {}",
+                links, images, title
+            );
+        }
+    }
+}
+
+// Full content is preferred, then the page the content src points at,
+// falling back to the summary since that is all some feeds provide
+fn get_entry_body(config: &Config, entry: &Entry) -> Result<String> {
+    if let Some(content) = entry.content.as_ref()
+        && let Some(body) = content.body.as_ref().filter(|x| !x.trim().is_empty())
+    {
+        // json feed content_text and atom type="text" content
+        if content.content_type.to_string().starts_with("text/plain") {
+            return Ok(plain_text_to_html(body));
+        }
+        return Ok(body.clone());
+    }
+
+    if let Some(src) = entry.content.as_ref().and_then(|x| x.src.as_ref()) {
+        info!("Fetching content from {}", src.href);
+        match fetch_content_from_src(config, &src.href) {
+            Ok(content) => return Ok(content),
+            Err(e) if entry.summary.is_some() => warn!(
+                "Could not fetch content from {}, using the summary: {:#}",
+                src.href, e
+            ),
+            Err(e) => return Err(e),
+        }
+    }
+
+    match &entry.summary {
+        Some(summary) if !summary.content.trim().is_empty() => {
+            if summary.content_type.to_string().starts_with("text/plain") {
+                Ok(plain_text_to_html(&summary.content))
+            } else {
+                Ok(summary.content.clone())
+            }
+        }
+        // podcast episodes often only describe themselves in media rss,
+        // the chapter is then just the enclosure links
+        _ if !get_enclosures(entry).is_empty() => Ok(entry
+            .media
+            .iter()
+            .find_map(|x| x.description.as_ref())
+            .map_or(String::new(), |x| plain_text_to_html(&x.content))),
+        _ => Err(anyhow!("No content or summary found")),
+    }
+}
+
+// the page is trimmed down to its main content so the chapter doesn't
+// carry the site's navigation and scripts along
+fn fetch_content_from_src(config: &Config, url: &str) -> Result<String> {
+    let page = fetch::fetch_html(&config.fetch, &config.http, url)?;
+    let page_dom = html::parse(&page)?;
+    let main =
+        html::main_content(&page_dom.document).ok_or(anyhow!("No content found at {}", url))?;
+    html::absolutize_urls(&main, &Url::parse(url)?);
+
+    let dom = html::parse("")?;
+    if let Some(body) = html::elements(&dom.document, "body").first() {
+        let children = std::mem::take(&mut *main.children.borrow_mut());
+        html::append_children(body, children);
+    }
+    html::serialize(&dom.document)
+}
+
+// escape plain text so it parses as text, keeping blank lines as paragraphs
+fn plain_text_to_html(text: &str) -> String {
+    text.split("\n\n")
+        .filter(|x| !x.trim().is_empty())
+        .map(|x| {
+            let escaped = x
+                .trim()
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;");
+            format!("<p>{}</p>", escaped)
+        })
+        .collect()
+}
+
+// Returns the content along with any heading sections it was split into,
+// or None when the entry should be left out of the run
+fn parse_xhtml(
+    config: &Config,
+    feed_conf: &FeedConfig,
+    entry: Entry,
+) -> Result<Option<ParsedContent>> {
+    let title = entry
+        .title
+        .as_ref()
+        .map_or(String::from("Unknown Title"), |x| x.content.clone());
+    let link = get_entry_link(&entry);
+    let mut content = get_entry_body(config, &entry)?;
+    if let Some(unescaped) = html::unescape_escaped_html(&content)? {
+        info!("Unescaped the doubly escaped html of entry {}", title);
+        content = unescaped;
+    }
+    for transform in &feed_conf.transforms {
+        let transformed = transform
+            .find
+            .replace_all(&content, transform.replace.as_str());
+        if transformed != content {
+            info!(
+                "Applied transform {} to entry {}",
+                transform.find.as_str(),
+                title
+            );
+            content = transformed.into_owned();
+        }
+    }
+    // the classes strip_selectors match on have to survive until
+    // parse_content strips them, which sanitizes the content as well
+    if feed_conf.strip_selectors.is_empty() {
+        content = html::clean(&content);
+    }
+    parse_content(config, feed_conf, &title, link, &content)
+}
+
+// Cleans up entry html and downloads its images, shared by feed entries
+// and pages. link is where the content came from, relative urls are
+// resolved against it.
+fn parse_content(
+    config: &Config,
+    feed_conf: &FeedConfig,
+    title: &str,
+    link: Option<String>,
+    content: &str,
+) -> Result<Option<ParsedContent>> {
+    let dom = html::parse(content)?;
+
+    // before sanitizing, which drops the classes selectors match on
+    let removed = html::remove_selected(&dom.document, &feed_conf.strip_selectors);
+    if removed > 0 {
+        info!(
+            "Stripped {} boilerplate elements from entry {}",
+            removed, title
+        );
+    }
+
+    clean_content(config, feed_conf, &dom.document, link.as_deref(), title);
+
+    if html::text_content(&dom.document).trim().is_empty() {
+        match (&config.on_empty_content, link.clone()) {
+            (OnEmptyContent::Skip, _) => {
+                info!("Skipping entry {} with no readable text", title);
+                return Ok(None);
+            }
+            (OnEmptyContent::LinkOnly, Some(link)) => {
+                info!(
+                    "Delivering link only for entry {} with no readable text",
+                    title
+                );
+                if let Some(body) = html::elements(&dom.document, "body").first() {
+                    let anchor =
+                        html::new_element("a", &[("href", &link)], vec![html::text_node(title)]);
+                    html::set_children(body, vec![html::new_element("p", &[], vec![anchor])]);
+                }
+            }
+            (OnEmptyContent::LinkOnly, None) => {
+                warn!(
+                    "Entry {} has no readable text and no link, delivering as-is",
+                    title
+                );
+            }
+            (OnEmptyContent::Deliver, _) => {
+                info!("Delivering entry {} with no readable text as-is", title);
+            }
+        }
+    }
+
+    if feed_conf.expand_link_posts {
+        // the linked article brings its own images and links along
+        if expand_link_post(
+            &config.fetch,
+            &config.http,
+            &dom.document,
+            link.as_deref(),
+            title,
+        ) {
+            clean_content(config, feed_conf, &dom.document, link.as_deref(), title);
+        }
+    }
+
+    if let Some(min_chars) = config.min_content_chars {
+        let chars = html::text_content(&dom.document).trim().chars().count();
+        if chars > 0 && chars < min_chars {
+            if config.skip_truncated {
+                info!(
+                    "Skipping entry {} with only {} characters of text, it looks truncated",
+                    title, chars
+                );
+                return Ok(None);
+            }
+            if let Some(link) = link.as_deref()
+                && fetch_full_article(&config.fetch, &config.http, &dom.document, link, title)
+            {
+                clean_content(config, feed_conf, &dom.document, Some(link), title);
+            }
+        }
+    }
+
+    let images = if feed_conf.embed_images {
+        download_images(config, &dom.document)
+    } else {
+        vec![]
+    };
+
+    let sections = match config.split_chapters_over {
+        Some(threshold) if html::text_content(&dom.document).chars().count() > threshold => {
+            html::anchor_headings(&dom.document)
+                .into_iter()
+                .map(|(anchor, title)| Section { anchor, title })
+                .collect()
+        }
+        _ => vec![],
+    };
+
+    if config.reading_level {
+        let text = html::text_content(&dom.document);
+        if let (Some(grade), Some(body)) = (
+            readability::flesch_kincaid_grade(&text),
+            html::elements(&dom.document, "body").first(),
+        ) {
+            info!("Entry {} has reading level grade {:.1}", title, grade);
+            html::prepend_child(
+                body,
+                html::new_element(
+                    "p",
+                    &[("class", "saga-reading-level")],
+                    vec![html::text_node(&format!(
+                        "Reading level: grade {:.1}",
+                        grade.max(0.0)
+                    ))],
+                ),
+            );
+        }
+    }
+
+    Ok(Some(ParsedContent {
+        content: html::serialize(&dom.document)?,
+        sections,
+        images,
+    }))
+}
+
+// The content keeps the original srcs so other output formats can still
+// link to them, the epub swaps in the downloaded copies when it's built.
+// Images are downloaded on up to max_concurrency threads, ones that fail
+// are left out so their original src stays in place.
+fn download_images(config: &Config, document: &Handle) -> Vec<EmbeddedImage> {
+    let mut urls: Vec<String> = vec![];
+    for img in html::elements(document, "img") {
+        let Some(src) = html::get_attr(&img, "src") else {
+            continue;
+        };
+        if (src.starts_with("http://") || src.starts_with("https://")) && !urls.contains(&src) {
+            urls.push(src);
+        }
+    }
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![]);
+    let workers = config.max_concurrency.clamp(1, urls.len().max(1));
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(url) = urls.get(index) else {
+                        break;
+                    };
+                    if let Some(image) = download_image(config, url) {
+                        results.lock().unwrap().push((index, image));
+                    }
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, image)| image).collect()
+}
+
+fn download_image(config: &Config, url: &str) -> Option<EmbeddedImage> {
+    let (mime_type, data) = match fetch::fetch_image(&config.fetch, &config.http, url) {
+        Ok(image) => image,
+        Err(e) => {
+            warn!("Could not download image {}: {}", url, e);
+            return None;
+        }
+    };
+    // an image that can't be optimized is still better than none
+    let (mime_type, data) = match images::optimize(
+        &mime_type,
+        &data,
+        config.image_max_width,
+        config.image_grayscale,
+    ) {
+        Ok(Some(optimized)) => optimized,
+        Ok(None) => (mime_type, data),
+        Err(e) => {
+            warn!("Could not optimize image {}: {}", url, e);
+            (mime_type, data)
+        }
+    };
+    Some(EmbeddedImage {
+        url: url.to_string(),
+        mime_type,
+        data,
+    })
+}
+
+fn generate_output(
+    config: &Config,
+    format: OutputFormat,
+    entries: &[DisplayEntry],
+    run_report: Option<&RunReport>,
+) -> Result<Vec<u8>> {
+    match format {
+        OutputFormat::Epub => generate_epub(config, entries, run_report),
+        OutputFormat::Markdown => markdown::generate_markdown(entries),
+    }
+}
+
+const DEFAULT_STYLESHEET: &str = include_str!("epub.css");
+
+fn generate_epub(
+    config: &Config,
+    entries: &[DisplayEntry],
+    run_report: Option<&RunReport>,
+) -> Result<Vec<u8>> {
+    let mut output = Vec::<u8>::new();
+    let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
+    let now = Utc::now();
+    let title = format!("Saga - {}", now.format("%B %-d, %Y"));
+    let version = parse_epub_version(config.epub_version.as_deref())?;
+    builder
+        .epub_version(version)
+        .metadata("title", title)?
+        .metadata("lang", &config.epub_language)?
+        .metadata("generator", format!("saga {}", env!("CARGO_PKG_VERSION")))?;
+    builder.set_publication_date(now);
+
+    if entries.is_empty() {
+        return Err(anyhow!("no entries to put in the epub"));
+    }
+
+    let stylesheet = match &config.stylesheet_path {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| anyhow!("could not read stylesheet {}: {}", path, e))?,
+        None => DEFAULT_STYLESHEET.to_string(),
+    };
+    builder.stylesheet(stylesheet.as_bytes())?;
+
+    let mut authors: Vec<&String> = vec![];
+    for author in entries.iter().flat_map(|x| &x.authors) {
+        if !authors.contains(&author) {
+            authors.push(author);
+        }
+    }
+    if authors.is_empty() {
+        builder.metadata("author", "Saga")?;
+    }
+    for author in authors {
+        builder.metadata("author", author)?;
+    }
+
+    add_cover(&mut builder, config, entries)?;
+
+    // images shared between entries are only stored once
+    let mut image_paths: HashMap<&str, String> = HashMap::new();
+    let mut icon_paths: HashMap<&str, String> = HashMap::new();
+    for icon in entries.iter().filter_map(|x| x.feed_icon.as_ref()) {
+        if !icon_paths.contains_key(icon.url.as_str()) {
+            let path = format!("images/icon_{}.{}", icon_paths.len() + 1, icon.extension());
+            builder.add_resource(&path, icon.data.as_slice(), &icon.mime_type)?;
+            icon_paths.insert(&icon.url, path);
+        }
+    }
+    let icon_path = |entry: &DisplayEntry| {
+        entry
+            .feed_icon
+            .as_ref()
+            .map(|x| icon_paths[x.url.as_str()].as_str())
+    };
+    // entries from several feeds get a page per feed with its entries
+    // nested under it in the toc
+    let groups = feed_groups(config, entries);
+    let nested = groups.len() > 1;
+    let level = if nested { 2 } else { 1 };
+    if entries.len() > 1 {
+        let ordered: Vec<&DisplayEntry> = groups.iter().flat_map(|(_, x)| x).copied().collect();
+        let page = summary_page(&ordered, icon_path)?;
+        builder.add_content(
+            EpubContent::new("summary.xhtml", page.as_bytes()).title("In This Digest"),
+        )?;
+    }
+    let mut chapters = 0;
+    for (i, (feed_title, group)) in groups.iter().enumerate() {
+        if nested {
+            let file_name = format!("feed_{}.xhtml", i + 1);
+            let page = feed_page(feed_title, group.first().and_then(|x| icon_path(x)))?;
+            builder
+                .add_content(EpubContent::new(&file_name, page.as_bytes()).title(*feed_title))?;
+        }
+        for entry in group {
+            chapters += 1;
+            let file_name = format!("chapter_{}.xhtml", chapters);
+            let dom = html::parse(&entry.content)?;
+            add_entry_header(&dom.document, entry);
+            add_entry_enclosures(&dom.document, entry, version);
+            add_entry_footer(&dom.document, entry);
+            link_stylesheet(&dom.document);
+            if version == EpubVersion::V20 {
+                for node in html::elements_any(&dom.document, HTML5_ONLY_TAGS) {
+                    html::unwrap_node(&node);
+                }
+            }
+            if let Some(language) = &entry.language
+                && let Some(root) = html::elements(&dom.document, "html").first()
+            {
+                // xhtml 1.1 in epub 2 only has xml:lang
+                if version != EpubVersion::V20 {
+                    html::set_attr(root, "lang", language);
+                }
+                html::set_attr(root, "xml:lang", language);
+            }
+            for img in html::elements(&dom.document, "img") {
+                let Some(image) = html::get_attr(&img, "src")
+                    .and_then(|src| entry.images.iter().find(|x| x.url == src))
+                else {
+                    continue;
+                };
+                if !image_paths.contains_key(image.url.as_str()) {
+                    let path = format!(
+                        "images/image_{}.{}",
+                        image_paths.len() + 1,
+                        image.extension()
+                    );
+                    builder.add_resource(&path, image.data.as_slice(), &image.mime_type)?;
+                    image_paths.insert(&image.url, path);
+                }
+                html::set_attr(&img, "src", &image_paths[image.url.as_str()]);
+            }
+            let chapter = html::serialize(&dom.document)?;
+            let mut content = EpubContent::new(&file_name, chapter.as_bytes())
+                .title(&entry.title)
+                .level(level);
+            for section in &entry.sections {
+                content = content.child(TocElement::new(
+                    format!("{}#{}", file_name, section.anchor),
+                    &section.title,
+                ));
+            }
+            builder.add_content(content)?;
+        }
+    }
+    if let Some(report) = run_report {
+        let page = run_report_page(report)?;
+        builder.add_content(
+            EpubContent::new("run_report.xhtml", page.as_bytes()).title("Saga Run Report"),
+        )?;
+    }
+    builder.inline_toc();
+    builder.generate(&mut output)?;
+    let output = repack_epub(&output)?;
+    info!("Generated an epub of {} KB", output.len().div_ceil(1024));
+    Ok(output)
+}
+
+// Media that is already compressed is stored as is, deflating it only
+// costs time
+const COMPRESSED_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "mp3", "mp4"];
+
+// epub-builder deflates every file at the default level. The archive is
+// rewritten with text deflated as small as it goes and the mimetype
+// first and uncompressed, as the epub spec requires.
+fn repack_epub(epub: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(epub))?;
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    let stored =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let deflated = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .compression_level(Some(9));
+
+    writer.start_file("mimetype", stored)?;
+    writer.write_all(b"application/epub+zip")?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let name = file.name().to_string();
+        if name == "mimetype" || file.is_dir() {
+            continue;
+        }
+        let extension = Path::new(&name)
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let options = match COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+            true => stored,
+            false => deflated,
+        };
+        writer.start_file(name, options)?;
+        std::io::copy(&mut file, &mut writer)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+// Entries grouped by feed title, feeds in config order with any not in the
+// config after them and entries by published date. A single feed keeps
+// the order it was given in.
+fn feed_groups<'a>(
+    config: &Config,
+    entries: &'a [DisplayEntry],
+) -> Vec<(&'a str, Vec<&'a DisplayEntry>)> {
+    let mut groups: Vec<(&str, Vec<&DisplayEntry>)> = vec![];
+    for entry in entries {
+        match groups
+            .iter_mut()
+            .find(|(title, _)| *title == entry.feed_title)
+        {
+            Some((_, group)) => group.push(entry),
+            None => groups.push((&entry.feed_title, vec![entry])),
+        }
+    }
+    if groups.len() < 2 {
+        return groups;
+    }
+
+    let config_order = |group: &[&DisplayEntry]| {
+        config
+            .rss
+            .iter()
+            .position(|x| x.url == group[0].feed_url)
+            .unwrap_or(usize::MAX)
+    };
+    groups.sort_by_key(|(_, group)| config_order(group));
+    for (_, group) in &mut groups {
+        group.sort_by(|a, b| SortBy::Published.compare(a, b));
+    }
+    groups
+}
+
+// Opens the digest with every entry's feed, title, authors, and reading
+// time, linking to its chapter. Entries are given in chapter order.
+fn summary_page<'a>(
+    entries: &[&DisplayEntry],
+    icon_path: impl Fn(&DisplayEntry) -> Option<&'a str>,
+) -> Result<String> {
+    let items = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let words = html::parse(&entry.content)
+                .map(|dom| readability::word_count(&html::text_content(&dom.document)))
+                .unwrap_or_default();
+            let mut details = vec![entry.feed_title.clone()];
+            if !entry.authors.is_empty() {
+                details.push(format!("by {}", entry.authors.join(", ")));
+            }
+            details.push(readability::reading_time(words));
+            let href = format!("chapter_{}.xhtml", i + 1);
+            let mut details: Vec<Handle> = vec![html::text_node(&details.join(" · "))];
+            if let Some(path) = icon_path(entry) {
+                details.insert(0, feed_icon_element(path));
+            }
+            html::new_element(
+                "li",
+                &[],
+                vec![
+                    html::new_element("a", &[("href", &href)], vec![html::text_node(&entry.title)]),
+                    html::new_element("p", &[("class", "saga-summary-details")], details),
+                ],
+            )
+        })
+        .collect();
+
+    let dom = html::parse("")?;
+    link_stylesheet(&dom.document);
+    if let Some(body) = html::elements(&dom.document, "body").first() {
+        html::set_children(
+            body,
+            vec![
+                html::new_element("h1", &[], vec![html::text_node("In This Digest")]),
+                html::new_element("ul", &[("class", "saga-summary")], items),
+            ],
+        );
+    }
+    html::serialize(&dom.document)
+}
+
+// the page a feed's entries are nested under in the toc
+fn feed_page(feed_title: &str, icon_path: Option<&str>) -> Result<String> {
+    let dom = html::parse("")?;
+    link_stylesheet(&dom.document);
+    let mut heading = vec![html::text_node(feed_title)];
+    if let Some(path) = icon_path {
+        heading.insert(0, feed_icon_element(path));
+    }
+    if let Some(body) = html::elements(&dom.document, "body").first() {
+        html::set_children(
+            body,
+            vec![html::new_element(
+                "h1",
+                &[("class", "saga-feed-page")],
+                heading,
+            )],
+        );
+    }
+    html::serialize(&dom.document)
+}
+
+// Feeds that failed this run and those with nothing new, so problems
+// show up in what gets read rather than only in the logs
+fn run_report_page(report: &RunReport) -> Result<String> {
+    let dom = html::parse("")?;
+    link_stylesheet(&dom.document);
+    let list = |items: Vec<Vec<Handle>>| {
+        html::new_element(
+            "ul",
+            &[],
+            items
+                .into_iter()
+                .map(|x| html::new_element("li", &[], x))
+                .collect(),
+        )
+    };
+    let mut children = vec![html::new_element(
+        "h1",
+        &[],
+        vec![html::text_node("Saga Run Report")],
+    )];
+    if report.failed_feeds.is_empty() {
+        children.push(html::new_element(
+            "p",
+            &[],
+            vec![html::text_node("Every feed was fetched.")],
+        ));
+    } else {
+        children.push(html::new_element(
+            "h2",
+            &[],
+            vec![html::text_node("Failed feeds")],
+        ));
+        children.push(list(
+            report
+                .failed_feeds
+                .iter()
+                .map(|(url, error)| {
+                    vec![
+                        html::new_element("strong", &[], vec![html::text_node(url)]),
+                        html::text_node(&format!(": {}", error)),
+                    ]
+                })
+                .collect(),
+        ));
+    }
+    if !report.quiet_feeds.is_empty() {
+        children.push(html::new_element(
+            "h2",
+            &[],
+            vec![html::text_node("Nothing new")],
+        ));
+        children.push(list(
+            report
+                .quiet_feeds
+                .iter()
+                .map(|url| vec![html::text_node(url)])
+                .collect(),
+        ));
+    }
+    if let Some(body) = html::elements(&dom.document, "body").first() {
+        html::set_children(
+            body,
+            vec![html::new_element(
+                "div",
+                &[("class", "saga-run-report")],
+                children,
+            )],
+        );
+    }
+    html::serialize(&dom.document)
+}
+
+fn feed_icon_element(path: &str) -> Handle {
+    html::new_element(
+        "img",
+        &[("class", "saga-feed-icon"), ("src", path), ("alt", "")],
+        vec![],
+    )
+}
+
+// The cover image is the user's cover_image or a generated one with the
+// date and feeds so digests can be told apart in library views. It is
+// also shown on a cover page at the start of the reading order.
+fn add_cover(
+    builder: &mut EpubBuilder<ZipLibrary>,
+    config: &Config,
+    entries: &[DisplayEntry],
+) -> Result<()> {
+    let (image_name, image, mime_type) = match &config.cover_image {
+        Some(path) => {
+            let extension = Path::new(path)
+                .extension()
+                .and_then(|x| x.to_str())
+                .unwrap_or_default()
+                .to_lowercase();
+            let mime_type = match extension.as_str() {
+                "png" => "image/png",
+                "jpg" | "jpeg" => "image/jpeg",
+                "gif" => "image/gif",
+                "svg" => "image/svg+xml",
+                _ => {
+                    return Err(anyhow!(
+                        "cover_image {} is not a png, jpeg, gif, or svg",
+                        path
+                    ));
+                }
+            };
+            let image = fs::read(path)
+                .map_err(|e| anyhow!("could not read cover_image {}: {}", path, e))?;
+            (format!("cover.{}", extension), image, mime_type)
+        }
+        None => (
+            String::from("cover.svg"),
+            cover_svg(entries).into_bytes(),
+            "image/svg+xml",
+        ),
+    };
+    builder.add_cover_image(&image_name, image.as_slice(), mime_type)?;
+
+    let dom = html::parse("")?;
+    link_stylesheet(&dom.document);
+    if let Some(body) = html::elements(&dom.document, "body").first() {
+        html::set_children(
+            body,
+            vec![html::new_element(
+                "div",
+                &[("class", "saga-cover")],
+                vec![html::new_element(
+                    "img",
+                    &[("src", &image_name), ("alt", "Cover")],
+                    vec![],
+                )],
+            )],
+        );
+    }
+    let page = html::serialize(&dom.document)?;
+    builder.add_content(
+        EpubContent::new("cover.xhtml", page.as_bytes())
+            .title("Cover")
+            .reftype(ReferenceType::Cover),
+    )?;
+    Ok(())
+}
+
+// elements epub 2 readers, which expect xhtml 1.1, don't know. They are
+// unwrapped so their content is kept.
+const HTML5_ONLY_TAGS: &[&str] = &[
+    "article",
+    "aside",
+    "figcaption",
+    "figure",
+    "footer",
+    "header",
+    "main",
+    "mark",
+    "nav",
+    "section",
+    "time",
+];
+
+// most feed titles listed on a generated cover before the rest are counted
+const COVER_MAX_FEEDS: usize = 8;
+
+fn cover_svg(entries: &[DisplayEntry]) -> String {
+    let mut feeds: Vec<&str> = vec![];
+    for entry in entries {
+        if !feeds.contains(&entry.feed_title.as_str()) {
+            feeds.push(&entry.feed_title);
+        }
+    }
+    let mut lines: Vec<String> = feeds
+        .iter()
+        .take(COVER_MAX_FEEDS)
+        .map(|x| html::escape_xml(x))
+        .collect();
+    if feeds.len() > COVER_MAX_FEEDS {
+        lines.push(format!("and {} more", feeds.len() - COVER_MAX_FEEDS));
+    }
+    let feed_lines: String = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| {
+            format!(
+                "<text x=\"300\" y=\"{}\" font-size=\"26\" text-anchor=\"middle\">{}</text>",
+                440 + i * 40,
+                line
+            )
+        })
+        .collect();
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"600\" height=\"800\" viewBox=\"0 0 600 800\">\
+        <rect width=\"600\" height=\"800\" fill=\"#f4f1ea\"/>\
+        <g font-family=\"Georgia, serif\" fill=\"#222\">\
+        <text x=\"300\" y=\"240\" font-size=\"96\" text-anchor=\"middle\">Saga</text>\
+        <text x=\"300\" y=\"310\" font-size=\"32\" text-anchor=\"middle\">{}</text>\
+        <line x1=\"150\" y1=\"370\" x2=\"450\" y2=\"370\" stroke=\"#222\" stroke-width=\"2\"/>\
+        {}</g></svg>",
+        Utc::now().format("%B %-d, %Y"),
+        feed_lines
+    )
+}
+
+fn link_stylesheet(document: &Handle) {
+    if let Some(head) = html::elements(document, "head").first() {
+        html::append_children(
+            head,
+            vec![html::new_element(
+                "link",
+                &[
+                    ("rel", "stylesheet"),
+                    ("type", "text/css"),
+                    ("href", "stylesheet.css"),
+                ],
+                vec![],
+            )],
+        );
+    }
+}
+
+// Puts the feed, title, authors, and date at the top of the chapter
+// so the reader can tell where a piece came from
+fn add_entry_header(document: &Handle, entry: &DisplayEntry) {
+    let Some(body) = html::elements(document, "body").first().cloned() else {
+        return;
+    };
+    let words = readability::word_count(&html::text_content(&body));
+
+    let mut byline = vec![];
+    if !entry.authors.is_empty() {
+        byline.push(format!("by {}", entry.authors.join(", ")));
+    }
+    if let Some(published) = entry.published {
+        byline.push(published.format("%B %-d, %Y").to_string());
+    }
+
+    // a div rather than a header element so epub 2 chapters can keep it
+    let header = html::new_element(
+        "div",
+        &[("class", "saga-entry-header")],
+        vec![
+            html::new_element(
+                "p",
+                &[("class", "saga-feed")],
+                vec![html::text_node(&entry.feed_title)],
+            ),
+            html::new_element("h1", &[], vec![html::text_node(&entry.title)]),
+            html::new_element(
+                "p",
+                &[("class", "saga-byline")],
+                vec![html::text_node(&byline.join(" · "))],
+            ),
+            html::new_element(
+                "p",
+                &[("class", "saga-reading-time")],
+                vec![html::text_node(&format!(
+                    "{} · {} words",
+                    readability::reading_time(words),
+                    words
+                ))],
+            ),
+        ],
+    );
+    html::prepend_child(&body, header);
+}
+
+// Media is linked rather than downloaded since episodes run to hundreds of
+// megabytes, epub 3 also gets a player for readers that can stream it
+fn add_entry_enclosures(document: &Handle, entry: &DisplayEntry, version: EpubVersion) {
+    let Some(body) = html::elements(document, "body").first().cloned() else {
+        return;
+    };
+    let paragraphs = entry
+        .enclosures
+        .iter()
+        .map(|(url, mime_type)| {
+            let kind = mime_type.split('/').next().unwrap_or_default();
+            let label = match kind {
+                "audio" => "Listen to the audio",
+                "video" => "Watch the video",
+                _ => "Download the attachment",
+            };
+            let link = html::new_element("a", &[("href", url)], vec![html::text_node(label)]);
+            let mut children = vec![];
+            if version != EpubVersion::V20 && matches!(kind, "audio" | "video") {
+                children.push(html::new_element(
+                    kind,
+                    &[("controls", "controls"), ("src", url)],
+                    vec![],
+                ));
+            }
+            children.push(link);
+            html::new_element("p", &[("class", "saga-enclosure")], children)
+        })
+        .collect();
+    html::append_children(&body, paragraphs);
+}
+
+// Links back to the original article for comments or media that didn't
+// survive extraction
+fn add_entry_footer(document: &Handle, entry: &DisplayEntry) {
+    let Some(link) = &entry.link else {
+        return;
+    };
+    let Some(body) = html::elements(document, "body").first().cloned() else {
+        return;
+    };
+    html::append_children(
+        &body,
+        vec![html::new_element(
+            "p",
+            &[("class", "saga-original-link")],
+            vec![html::new_element(
+                "a",
+                &[("href", link)],
+                vec![html::text_node("Read the original")],
+            )],
+        )],
+    );
+}
+
+fn send_email(
+    config: &EmailConfig,
+    tls: &TlsConfig,
+    max_retries: u32,
+    archive_mbox: Option<&str>,
+    to: &[String],
+    subject: &str,
+    outputs: &[Output],
+) -> Result<()> {
+    if config.kindle_mode {
+        warn_unapproved_kindle_sender(config, to);
+    }
+    // Kindle doesn't take the other formats
+    let outputs: Vec<&Output> = outputs
+        .iter()
+        .filter(|x| !config.kindle_mode || x.format == OutputFormat::Epub)
+        .collect();
+    if config.kindle_mode && outputs.is_empty() {
+        info!("Nothing for Kindle in this email, skipping it");
+        return Ok(());
+    }
+    info!("Sending to email: {:?}", to);
+
+    let mut attachments = outputs.iter().map(|output| {
+        let name = match config.kindle_mode {
+            true => kindle_file_name(&output.name),
+            false => output.name.clone(),
+        };
+        SinglePart::builder()
+            .header(header::ContentType::parse(output.format.mime_type()).unwrap())
+            .header(header::ContentDisposition::attachment(&name))
+            .body(output.content.clone())
+    });
+    let first = attachments
+        .next()
+        .ok_or(anyhow!("no attachments to send"))?;
+    let body = attachments.fold(MultiPart::mixed().singlepart(first), |body, attachment| {
+        body.singlepart(attachment)
+    });
+
+    let subject = match config.kindle_mode {
+        true => "",
+        false => subject,
+    };
+    let email = message_builder(config, to, subject)?.multipart(body)?;
+    send_message(config, tls, max_retries, &email)?;
+
+    // the digest is already out, failing now would only send it again
+    if let Some(path) = archive_mbox
+        && let Err(e) = archive_message(path, &email)
+    {
+        warn!("Could not archive the email to {}: {:?}", path, e);
+    }
+    Ok(())
+}
+
+/// Append the message to an mbox file, quoting its From lines the mboxrd
+/// way. The file is locked while writing so concurrent runs don't
+/// interleave their messages.
+fn archive_message(path: &str, email: &Message) -> Result<()> {
+    let sender = email
+        .envelope()
+        .from()
+        .map(|x| x.to_string())
+        .unwrap_or(String::from("MAILER-DAEMON"));
+    let mut entry = format!(
+        "From {} {}\n",
+        sender,
+        Utc::now().format("%a %b %e %H:%M:%S %Y")
+    );
+    let formatted = String::from_utf8_lossy(&email.formatted()).replace("\r\n", "\n");
+    for line in formatted.lines() {
+        if line.trim_start_matches('>').starts_with("From ") {
+            entry.push('>');
+        }
+        entry.push_str(line);
+        entry.push('\n');
+    }
+    entry.push('\n');
+
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.lock()?;
+    file.write_all(entry.as_bytes())?;
+    file.unlock()?;
+    info!("Archived the email to {}", path);
+    Ok(())
+}
+
+/// Send a short plain text email to the configured recipients through the
+/// same transport digests go through, without retrying, to check the
+/// email settings
+pub fn send_test_email(config: &Config) -> Result<()> {
+    let email_config = config
+        .email
+        .as_ref()
+        .ok_or(anyhow!("there is no email config to test"))?;
+    let to = email_config.to.addresses();
+    if email_config.kindle_mode {
+        warn!("Kindle ignores emails without a document, this one may only bounce");
+    }
+    info!("Sending a test email to {:?}", to);
+    let email = message_builder(email_config, to, "Saga test email")?.body(String::from(
+        "This is a test email from saga. Your email settings work.",
+    ))?;
+    send_message(email_config, &config.tls, 0, &email)
+}
+
+// a typo in one address shouldn't stop the others from getting the digest
+fn message_builder(
+    config: &EmailConfig,
+    to: &[String],
+    subject: &str,
+) -> Result<lettre::message::MessageBuilder> {
+    let mut builder = Message::builder()
+        .from(config.from.parse()?)
+        .subject(subject);
+    let mut recipients = 0;
+    for address in to {
+        match address.parse() {
+            Ok(mailbox) => {
+                builder = builder.to(mailbox);
+                recipients += 1;
+            }
+            Err(e) => warn!("Skipping invalid recipient {}: {}", address, e),
+        }
+    }
+    if recipients == 0 {
+        return Err(anyhow!("no valid recipients to send to"));
+    }
+    Ok(builder)
+}
+
+fn send_message(
+    config: &EmailConfig,
+    tls: &TlsConfig,
+    max_retries: u32,
+    email: &Message,
+) -> Result<()> {
+    if let EmailTransport::File = config.transport {
+        let dir = config
+            .file_dir
+            .as_ref()
+            .ok_or(anyhow!("file_dir is required for the file transport"))?;
+        fs::create_dir_all(dir)?;
+        FileTransport::new(dir)
+            .send(email)
+            .map_err(|e| anyhow!("Could not write email to {}: {:?}", dir, e))?;
+        info!("Email written to {} instead of being sent", dir);
+        return Ok(());
+    }
+
+    if let EmailTransport::Imap = config.transport {
+        let security = match config.encryption {
+            Encryption::Tls => imap::Security::Tls,
+            Encryption::Starttls => imap::Security::Starttls,
+            Encryption::None => imap::Security::None,
+        };
+        let port = match security {
+            imap::Security::Tls => imap::IMAPS_PORT,
+            _ => imap::IMAP_PORT,
+        };
+        let mailbox = imap::Mailbox {
+            host: &config.relay,
+            port: config.port.unwrap_or(port),
+            security,
+            username: &config.username,
+            password: &config.password,
+            folder: config.imap_folder.as_deref().unwrap_or("INBOX"),
+        };
+        return imap::append(tls, &mailbox, &email.formatted())
+            .map_err(|e| anyhow!("Could not append email: {}", e));
+    }
+
+    let (transport_tls, default_port) = match config.encryption {
+        Encryption::Tls => (
+            Tls::Wrapper(tls.smtp_parameters(&config.relay)?),
+            SUBMISSIONS_PORT,
+        ),
+        Encryption::Starttls => {
+            if tls.pins_host(&config.relay) {
+                return Err(anyhow!(
+                    "certificate pins for {} need encryption: tls",
+                    config.relay
+                ));
+            }
+            (
+                Tls::Required(tls.smtp_parameters(&config.relay)?),
+                SUBMISSION_PORT,
+            )
+        }
+        Encryption::None => (Tls::None, SMTP_PORT),
+    };
+    let port = config.port.unwrap_or(default_port);
+    let pinned = tls.pins_host(&config.relay);
+    let mut mailer = SmtpTransport::builder_dangerous(&config.relay)
+        .port(port)
+        .tls(transport_tls);
+    if !config.username.is_empty() {
+        mailer = mailer.credentials(Credentials::new(
+            config.username.clone(),
+            config.password.clone(),
+        ));
+    }
+    let mailer = mailer.build();
+
+    // rejections by the relay won't change on a retry, anything else
+    // (connection drops, busy or greylisting relays) might
+    let mut attempt = 0;
+    loop {
+        let sent = match pinned {
+            true => send_over_pinned_tls(config, tls, port, email)?,
+            false => mailer.send(email).map(drop),
+        };
+        match sent {
+            Ok(_) => break,
+            Err(e) if attempt < max_retries && !e.is_permanent() => {
+                let backoff = Duration::from_secs(1 << attempt.min(6));
+                attempt += 1;
+                warn!("Sending email failed, retrying in {:?}: {}", backoff, e);
+                thread::sleep(backoff);
+            }
+            Err(e) if config.kindle_mode => {
+                return Err(anyhow!(
+                    "Could not send email: {:?}. {}",
+                    e,
+                    KINDLE_APPROVED_SENDER
+                ));
+            }
+            Err(e) => return Err(anyhow!("Could not send email: {:?}", e)),
+        }
+    }
+    info!("Email sent successfully!");
+
+    Ok(())
+}
+
+// lettre's transport doesn't expose the certificate it was given, so a
+// pinned relay is sent to over a connection whose certificate is checked
+// before logging in. Only a pin mismatch is an error of its own, anything
+// the relay does is left to the caller's retries.
+fn send_over_pinned_tls(
+    config: &EmailConfig,
+    tls: &TlsConfig,
+    port: u16,
+    email: &Message,
+) -> Result<Result<(), lettre::transport::smtp::Error>> {
+    let parameters = tls.smtp_parameters(&config.relay)?;
+    let mut connection = match SmtpConnection::connect(
+        (config.relay.as_str(), port),
+        Some(Duration::from_secs(60)),
+        &ClientId::default(),
+        Some(&parameters),
+        None,
+    ) {
+        Ok(connection) => connection,
+        Err(e) => return Ok(Err(e)),
+    };
+    let checked = connection
+        .peer_certificate()
+        .map_err(|e| anyhow!("{:?}", e))
+        .and_then(|der| tls.check_peer(&config.relay, &der));
+    if let Err(e) = checked {
+        connection.abort();
+        return Err(e);
+    }
+
+    let mut send = || {
+        if !config.username.is_empty() {
+            let credentials = Credentials::new(config.username.clone(), config.password.clone());
+            connection.auth(DEFAULT_MECHANISMS, &credentials)?;
+        }
+        connection.send(email.envelope(), &email.formatted())?;
+        connection.quit()?;
+        Ok(())
+    };
+    let sent = send();
+    if sent.is_err() {
+        connection.abort();
+    }
+    Ok(sent)
+}
+
+// The approved list can't be checked from here, only senders Amazon is
+// sure to bounce and recipients that aren't Kindles are caught
+fn warn_unapproved_kindle_sender(config: &EmailConfig, to: &[String]) {
+    let from_domain = config
+        .from
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.trim_end_matches('>').to_lowercase());
+    if from_domain.is_none_or(|x| !x.contains('.') || x == "localhost") {
+        warn!(
+            "Kindle will bounce documents from {}. {}",
+            config.from, KINDLE_APPROVED_SENDER
+        );
+    }
+    for address in to {
+        let address = address.to_lowercase();
+        if !(address.ends_with("@kindle.com") || address.ends_with("@free.kindle.com")) {
+            warn!("kindle_mode is set but {} is not a Kindle address", address);
+        }
+    }
+}
+
+pub fn get_db_conn(path: Option<&Path>) -> Result<Connection> {
+    let db_path = std::env::current_dir()?.join(path.unwrap_or(Path::new("database.db3")));
+    let conn = Connection::open(&db_path)?;
+    // a daemon and a one off run can share the database
+    conn.busy_timeout(Duration::from_secs(30))?;
+    migrate(&conn)?;
+    info!("Openned connection at path: {:?}", db_path);
+    Ok(conn)
+}
+
+// Schema changes in the order they were made, each applied once and
+// tracked with the user_version pragma. Databases from before versioning
+// start at 0 with some of these already applied, so every step has to
+// tolerate its tables and columns already existing.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS feeds (
+                url TEXT PRIMARY KEY,
+                last_processed INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS entries (
+                id TEXT PRIMARY KEY
+            );",
+        )?;
+        Ok(())
+    },
+    // entries picked but held back until the bundle interval elapses,
+    // kept here so a restart doesn't lose them
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS staged (
+                id TEXT PRIMARY KEY,
+                staged_at INTEGER NOT NULL,
+                entry TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS bundles (
+                delivered_at INTEGER NOT NULL
+            );",
+        )?;
+        Ok(())
+    },
+    // every entry seen in a feed, delivered or not, so random picks
+    // can reach entries that have since dropped off the feed.
+    // undated entries are stored with a published of MIN_UTC
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS backlog (
+                id TEXT PRIMARY KEY,
+                feed_url TEXT NOT NULL,
+                feed_title TEXT NOT NULL,
+                title TEXT NOT NULL,
+                authors TEXT NOT NULL,
+                published INTEGER NOT NULL,
+                updated INTEGER,
+                link TEXT,
+                content TEXT NOT NULL
+            );",
+        )?;
+        Ok(())
+    },
+    |conn| {
+        add_missing_column(conn, "feeds", "etag", "TEXT")?;
+        add_missing_column(conn, "feeds", "last_modified", "TEXT")?;
+        Ok(())
+    },
+    // hashes of delivered entry text for dedup_by_content
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS delivered_content (
+                hash TEXT PRIMARY KEY
+            );",
+        )?;
+        Ok(())
+    },
+    // entries processed before processed_at existed start aging from now
+    |conn| {
+        if add_missing_column(conn, "entries", "processed_at", "INTEGER")? {
+            conn.execute(
+                "UPDATE entries SET processed_at = ?1",
+                params![Utc::now().timestamp_millis()],
+            )?;
+        }
+        Ok(())
+    },
+    // what was delivered, kept when store_content is set so old digests
+    // can be rebuilt
+    |conn| {
+        add_missing_column(conn, "entries", "feed_title", "TEXT")?;
+        add_missing_column(conn, "entries", "title", "TEXT")?;
+        add_missing_column(conn, "entries", "authors", "TEXT")?;
+        add_missing_column(conn, "entries", "published", "INTEGER")?;
+        add_missing_column(conn, "entries", "link", "TEXT")?;
+        add_missing_column(conn, "entries", "content", "TEXT")?;
+        Ok(())
+    },
+    // search index over the text of stored entries, filled from the
+    // entries already stored
+    |conn| {
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS entries_fts
+                USING fts5(id UNINDEXED, title, content);",
+        )?;
+        let mut stmt =
+            conn.prepare("SELECT id, title, content FROM entries WHERE content IS NOT NULL")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()?;
+        for (id, title, content) in rows {
+            index_entry(conn, &id, &title, &content)?;
+        }
+        Ok(())
+    },
+    // favicons by site origin for show_favicons, a row without data is a
+    // site that has none
+    |conn| {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS favicons (
+                host TEXT PRIMARY KEY,
+                url TEXT,
+                mime_type TEXT,
+                data BLOB,
+                fetched_at INTEGER NOT NULL
+            );",
+        )?;
+        Ok(())
+    },
+    // how the last attempt at each feed went, for status
+    |conn| {
+        add_missing_column(conn, "feeds", "last_status", "TEXT")?;
+        add_missing_column(conn, "feeds", "last_error", "TEXT")?;
+        add_missing_column(conn, "feeds", "last_checked", "INTEGER")?;
+        Ok(())
+    },
+    // failing feeds back off and are eventually disabled
+    |conn| {
+        add_missing_column(
+            conn,
+            "feeds",
+            "consecutive_failures",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        Ok(())
+    },
+    // the other keys a backlog entry may have been processed under, as a
+    // json array
+    |conn| {
+        add_missing_column(conn, "backlog", "aliases", "TEXT")?;
+        Ok(())
+    },
+];
+
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(anyhow!(
+            "database schema version {} is newer than this saga supports ({})",
+            version,
+            MIGRATIONS.len()
+        ));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        info!("Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+// returns whether the column was added
+fn add_missing_column(conn: &Connection, table: &str, column: &str, kind: &str) -> Result<bool> {
+    let exists: bool = conn.query_row(
+        "SELECT count(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, kind),
+            [],
+        )?;
+        info!("Added column {} to table {}", column, table);
+    }
+    Ok(!exists)
+}
+
+/// Forget processed entries older than the given number of days.
+/// Returns the number of entries removed.
+fn prune_entries(conn: &Connection, older_than_days: u32) -> Result<usize> {
+    let before = Utc::now() - chrono::Duration::days(older_than_days as i64);
+    let pruned = conn.execute(
+        "DELETE FROM entries WHERE processed_at < ?1",
+        params![before.timestamp_millis()],
+    )?;
+    conn.execute(
+        "DELETE FROM entries_fts WHERE id NOT IN (SELECT id FROM entries)",
+        [],
+    )?;
+    info!(
+        "Pruned {} processed entries older than {} days",
+        pruned, older_than_days
+    );
+    Ok(pruned)
+}
+
+fn get_feed_last_processed(conn: &Connection, url: &String) -> Result<Option<DateTime<Utc>>> {
+    let last_processed = match conn
+        .query_row(
+            "SELECT last_processed FROM feeds WHERE url = ?1",
+            params![url],
+            |row| row.get::<_, Option<i64>>(0),
+        )
+        .optional()?
+        .flatten()
+    {
+        Some(last_processed) => Some(
+            DateTime::from_timestamp_millis(last_processed)
+                .ok_or(anyhow!("couldn't parse last_processed"))?,
+        ),
+        None => None,
+    };
+    Ok(last_processed)
+}
+
+fn get_feed_validators(conn: &Connection, url: &str) -> Result<FeedValidators> {
+    let validators = conn
+        .query_row(
+            "SELECT etag, last_modified FROM feeds WHERE url = ?1",
+            params![url],
+            |row| {
+                Ok(FeedValidators {
+                    etag: row.get(0)?,
+                    last_modified: row.get(1)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(validators.unwrap_or_default())
+}
+
+fn get_feeds(conn: &Connection) -> Result<Vec<(String, Option<DateTime<Utc>>)>> {
+    let mut stmt = conn.prepare("SELECT url, last_processed FROM feeds ORDER BY url")?;
+    let feeds = stmt
+        .query_map([], |row| {
+            let last_processed: Option<i64> = row.get(1)?;
+            Ok((
+                row.get(0)?,
+                last_processed.and_then(DateTime::from_timestamp_millis),
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(feeds)
+}
+
+// entries only know their feed through the backlog
+fn count_delivered_entries(conn: &Connection, feed_url: &str) -> Result<i64> {
+    Ok(conn.query_row(
+        "SELECT count(*) FROM backlog
+            WHERE feed_url = ?1 AND id IN (SELECT id FROM entries)",
+        params![feed_url],
+        |row| row.get(0),
+    )?)
+}
+
+fn is_content_delivered(conn: &Connection, hash: &str) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT count(*) > 0 FROM delivered_content WHERE hash = ?1",
+        params![hash],
+        |row| row.get(0),
+    )?)
+}
+
+fn is_processed(conn: &Connection, id: &str) -> Result<bool> {
+    Ok(conn.query_row(
+        "SELECT count(*) > 0 FROM entries WHERE id = ?1",
+        params![id],
+        |row| row.get(0),
+    )?)
+}
+
+// ids are looked up a chunk at a time to stay well under sqlite's limit
+// on bound parameters
+const PROCESSED_LOOKUP_CHUNK: usize = 500;
+
+// every key an entry is marked processed under
+fn entry_keys(entry: &DisplayEntry) -> impl Iterator<Item = &String> {
+    std::iter::once(&entry.id)
+        .chain(&entry.merged_ids)
+        .chain(&entry.aliases)
+}
+
+// the entries that haven't been processed under any of their keys, in
+// the order given
+fn filter_unprocessed(conn: &Connection, entries: Vec<DisplayEntry>) -> Result<Vec<DisplayEntry>> {
+    let keys: Vec<&String> = entries.iter().flat_map(entry_keys).collect();
+    let mut processed: HashSet<String> = HashSet::new();
+    for chunk in keys.chunks(PROCESSED_LOOKUP_CHUNK) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT id FROM entries WHERE id IN ({})",
+            placeholders
+        ))?;
+        let ids = stmt
+            .query_map(params_from_iter(chunk), |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        processed.extend(ids);
+    }
+    Ok(entries
+        .into_iter()
+        .filter(|x| entry_keys(x).all(|key| !processed.contains(key)))
+        .collect())
+}
+
+// feeds are stamped with the time up to which they were considered so
+// the next run only looks at entries published after it
+fn mark_processed(
+    conn: &Connection,
+    store_content: bool,
+    feeds: &[(&str, DateTime<Utc>, FeedValidators)],
+    entries: &[DisplayEntry],
+) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for entry in entries {
+        for id in entry_keys(entry) {
+            tx.execute(
+                "INSERT OR IGNORE INTO entries (id, processed_at) VALUES (?1, ?2)",
+                params![id, Utc::now().timestamp_millis()],
+            )?;
+        }
+        if store_content {
+            tx.execute(
+                "UPDATE entries SET feed_title = ?2, title = ?3, authors = ?4,
+                    published = ?5, link = ?6, content = ?7
+                    WHERE id = ?1",
+                params![
+                    entry.id,
+                    entry.feed_title,
+                    entry.title,
+                    serde_json::to_string(&entry.authors)?,
+                    entry.published.map(|x| x.timestamp_millis()),
+                    entry.link,
+                    entry.content,
+                ],
+            )?;
+            index_entry(&tx, &entry.id, &entry.title, &entry.content)?;
+        }
+        if let Some(hash) = content_hash(entry) {
+            tx.execute(
+                "INSERT OR IGNORE INTO delivered_content (hash) VALUES (?1)",
+                params![hash],
+            )?;
+        }
+    }
+    for (url, processed_until, validators) in feeds {
+        tx.execute(
+            "INSERT INTO feeds (url, last_processed, etag, last_modified)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT (url) DO UPDATE SET last_processed = excluded.last_processed,
+                    etag = excluded.etag, last_modified = excluded.last_modified",
+            params![
+                url,
+                processed_until.timestamp_millis(),
+                validators.etag,
+                validators.last_modified
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// a feed that fails before its first delivery gets a row without a last
+// processed time so its error still shows in status
+fn record_feed_results(
+    conn: &Connection,
+    results: &[(&str, Option<String>)],
+    checked_at: DateTime<Utc>,
+) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for (url, error) in results {
+        tx.execute(
+            "INSERT INTO feeds
+                (url, last_status, last_error, last_checked, consecutive_failures)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (url) DO UPDATE SET last_status = excluded.last_status,
+                    last_error = excluded.last_error, last_checked = excluded.last_checked,
+                    consecutive_failures = CASE WHEN excluded.consecutive_failures > 0
+                        THEN feeds.consecutive_failures + 1 ELSE 0 END",
+            params![
+                url,
+                match error {
+                    Some(_) => "error",
+                    None => "ok",
+                },
+                error,
+                checked_at.timestamp_millis(),
+                error.is_some() as u32
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// How the last attempt at a feed went
+struct FeedStatus {
+    error: Option<String>,
+    checked_at: DateTime<Utc>,
+    consecutive_failures: u32,
+}
+
+fn get_feed_status(conn: &Connection, url: &str) -> Result<Option<FeedStatus>> {
+    let row: Option<(Option<String>, Option<i64>, u32)> = conn
+        .query_row(
+            "SELECT last_error, last_checked, consecutive_failures FROM feeds WHERE url = ?1",
+            params![url],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    Ok(row.and_then(|(error, checked_at, consecutive_failures)| {
+        Some(FeedStatus {
+            error,
+            checked_at: DateTime::from_timestamp_millis(checked_at?)?,
+            consecutive_failures,
+        })
+    }))
+}
+
+fn get_consecutive_failures(conn: &Connection, url: &str) -> Result<u32> {
+    let failures = conn
+        .query_row(
+            "SELECT consecutive_failures FROM feeds WHERE url = ?1",
+            params![url],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(failures.unwrap_or(0))
+}
+
+fn record_backlog(conn: &Connection, feed_url: &str, entries: &[DisplayEntry]) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT OR REPLACE INTO backlog
+            (id, feed_url, feed_title, title, authors, published, updated, link, content, aliases)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+    )?;
+    for entry in entries {
+        stmt.execute(params![
+            entry.id,
+            feed_url,
+            entry.feed_title,
+            entry.title,
+            serde_json::to_string(&entry.authors)?,
+            entry
+                .published
+                .unwrap_or(DateTime::<Utc>::MIN_UTC)
+                .timestamp_millis(),
+            entry.updated.map(|x| x.timestamp_millis()),
+            entry.link,
+            entry.content,
+            serde_json::to_string(&entry.aliases)?,
+        ])?;
+    }
+    Ok(())
+}
+
+// backlog entries for a feed that have never been processed, under their
+// id or any of their aliases
+fn get_backlog_ids(
+    conn: &Connection,
+    feed_url: &str,
+    published_before: DateTime<Utc>,
+) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT id FROM backlog
+            WHERE feed_url = ?1 AND published < ?2
+            AND id NOT IN (SELECT id FROM entries)
+            AND NOT EXISTS (
+                SELECT 1 FROM json_each(COALESCE(backlog.aliases, '[]'))
+                WHERE value IN (SELECT id FROM entries)
+            )
+            ORDER BY published, id",
+    )?;
+    let ids = stmt
+        .query_map(
+            params![feed_url, published_before.timestamp_millis()],
+            |row| row.get(0),
+        )?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(ids)
+}
+
+// a backlog row as stored: id, feed url and title, title, authors,
+// published, updated, link, content, and aliases
+type BacklogRow = (
+    String,
+    String,
+    String,
+    String,
+    String,
+    i64,
+    Option<i64>,
+    Option<String>,
+    String,
+    Option<String>,
+);
+
+fn get_backlog_entry(conn: &Connection, id: &str) -> Result<DisplayEntry> {
+    let row: BacklogRow = conn.query_row(
+        "SELECT id, feed_url, feed_title, title, authors, published, updated, link, content,
+            aliases
+            FROM backlog WHERE id = ?1",
+        params![id],
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+                row.get(7)?,
+                row.get(8)?,
+                row.get(9)?,
+            ))
+        },
+    )?;
+    let (id, feed_url, feed_title, title, authors, published, updated, link, content, aliases) =
+        row;
+    Ok(DisplayEntry {
+        id,
+        feed_url,
+        feed_title,
+        title,
+        authors: serde_json::from_str(&authors)?,
+        published: Some(
+            DateTime::from_timestamp_millis(published)
+                .ok_or(anyhow!("couldn't parse published"))?,
+        )
+        .filter(|x| *x != DateTime::<Utc>::MIN_UTC),
+        updated: updated.and_then(DateTime::from_timestamp_millis),
+        position: 0,
+        link,
+        content,
+        sections: vec![],
+        images: vec![],
+        language: None,
+        enclosures: vec![],
+        feed_icon: None,
+        merged_ids: vec![],
+        aliases: aliases
+            .map(|x| serde_json::from_str(&x))
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+// the index holds the text of the content so markup doesn't match or
+// show up in snippets
+fn index_entry(conn: &Connection, id: &str, title: &str, content: &str) -> Result<()> {
+    let text = html::text_content(&html::parse(content)?.document);
+    conn.execute("DELETE FROM entries_fts WHERE id = ?1", params![id])?;
+    conn.execute(
+        "INSERT INTO entries_fts (id, title, content) VALUES (?1, ?2, ?3)",
+        params![id, title, text],
+    )?;
+    Ok(())
+}
+
+/// A stored entry matching a search, with a snippet of the matching text
+struct SearchHit {
+    feed_title: String,
+    title: String,
+    published: Option<DateTime<Utc>>,
+    snippet: String,
+}
+
+// best matches first, the query is passed to fts5 as-is so AND, OR,
+// NOT, "phrases", and prefix* all work
+fn search_entries(conn: &Connection, query: &str, limit: usize) -> Result<Vec<SearchHit>> {
+    let mut stmt = conn.prepare(
+        "SELECT entries.feed_title, entries.title, entries.published,
+                snippet(entries_fts, 2, '[', ']', '...', 16)
+            FROM entries_fts JOIN entries ON entries.id = entries_fts.id
+            WHERE entries_fts MATCH ?1
+            ORDER BY rank LIMIT ?2",
+    )?;
+    let hits = stmt
+        .query_map(params![query, limit], |row| {
+            let published: Option<i64> = row.get(2)?;
+            Ok(SearchHit {
+                feed_title: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                title: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                published: published.and_then(DateTime::from_timestamp_millis),
+                snippet: row.get(3)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(|e| anyhow!("search {:?} failed: {}", query, e))?;
+    Ok(hits)
+}
+
+// entries processed since the given time that had their content stored,
+// in the order they were processed
+fn get_stored_entries(conn: &Connection, since: DateTime<Utc>) -> Result<Vec<DisplayEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, feed_title, title, authors, published, link, content FROM entries
+            WHERE processed_at >= ?1 AND content IS NOT NULL
+            ORDER BY processed_at",
+    )?;
+    let rows = stmt
+        .query_map(params![since.timestamp_millis()], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    rows.into_iter()
+        .map(
+            |(id, feed_title, title, authors, published, link, content)| {
+                Ok(DisplayEntry {
+                    id,
+                    feed_url: String::new(),
+                    feed_title,
+                    title,
+                    authors: serde_json::from_str(&authors)?,
+                    published: published.and_then(DateTime::from_timestamp_millis),
+                    updated: None,
+                    position: 0,
+                    link,
+                    content,
+                    sections: vec![],
+                    images: vec![],
+                    language: None,
+                    enclosures: vec![],
+                    feed_icon: None,
+                    merged_ids: vec![],
+                    aliases: vec![],
+                })
+            },
+        )
+        .collect()
+}
+
+// restaging an entry refreshes its content but keeps its place in the bundle
+fn stage_entries(
+    conn: &Connection,
+    entries: &[DisplayEntry],
+    staged_at: DateTime<Utc>,
+) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO staged (id, staged_at, entry) VALUES (?1, ?2, ?3)
+            ON CONFLICT(id) DO UPDATE SET entry = excluded.entry",
+    )?;
+    for entry in entries {
+        stmt.execute(params![
+            entry.id,
+            staged_at.timestamp_millis(),
+            serde_json::to_string(entry)?,
+        ])?;
+    }
+    Ok(())
+}
+
+fn get_staged_entries(conn: &Connection) -> Result<Vec<DisplayEntry>> {
+    let mut stmt = conn.prepare("SELECT entry FROM staged ORDER BY staged_at")?;
+    let entries = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .map(|x| Ok(serde_json::from_str(&x?)?))
+        .collect::<Result<Vec<DisplayEntry>>>()?;
+    Ok(entries)
+}
+
+// the window runs from the last delivered bundle, or from the first staged
+// entry when no bundle has been delivered yet
+fn get_bundle_window_start(conn: &Connection) -> Result<Option<DateTime<Utc>>> {
+    let millis: Option<i64> = conn.query_row(
+        "SELECT COALESCE(
+            (SELECT MAX(delivered_at) FROM bundles),
+            (SELECT MIN(staged_at) FROM staged)
+        )",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(millis.and_then(DateTime::from_timestamp_millis))
+}
+
+fn clear_staged_entries(conn: &Connection, delivered_at: DateTime<Utc>) -> Result<()> {
+    conn.execute("DELETE FROM staged", [])?;
+    conn.execute(
+        "INSERT INTO bundles (delivered_at) VALUES (?1)",
+        params![delivered_at.timestamp_millis()],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Timelike};
+
+    fn config(yaml: &str) -> Config {
+        serde_yml::from_str(&format!(
+            "delivery: folder\nfolder: out\nschedule: \"0 0 7 * * *\"\nrss: []\n{}",
+            yaml
+        ))
+        .unwrap()
+    }
+
+    const EMAIL_CONFIG: &str = "\
+delivery: email
+email:
+  to: you@example.com
+  from: saga@example.com
+  relay: smtp.example.com
+  username: saga@example.com
+  password: secret
+schedule: \"0 0 7 * * *\"
+rss:
+  - url: https://example.com/feed.xml
+    random: false
+";
+
+    fn email_config(yaml: &str) -> EmailConfig {
+        serde_yml::from_str::<Config>(yaml).unwrap().email.unwrap()
+    }
+
+    fn validate(yaml: &str) -> Result<()> {
+        serde_yml::from_str::<Config>(yaml).unwrap().validate()
+    }
+
+    fn feed_config(yaml: &str) -> FeedConfig {
+        serde_yml::from_str(&format!(
+            "url: https://example.com/feed.xml\nrandom: false\n{}",
+            yaml
+        ))
+        .unwrap()
+    }
+
+    fn entry(id: &str, published: Option<DateTime<Utc>>) -> DisplayEntry {
+        DisplayEntry {
+            id: id.to_string(),
+            feed_url: String::from("https://example.com/feed.xml"),
+            feed_title: String::from("Feed"),
+            title: id.to_string(),
+            authors: vec![],
+            published,
+            updated: None,
+            position: 0,
+            link: None,
+            content: format!("<p>{}</p>", id),
+            sections: vec![],
+            images: vec![],
+            language: None,
+            enclosures: vec![],
+            feed_icon: None,
+            merged_ids: vec![],
+            aliases: vec![],
+        }
+    }
+
+    fn days_ago(days: i64) -> Option<DateTime<Utc>> {
+        Some(Utc::now() - chrono::Duration::days(days))
+    }
+
+    fn db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        conn
+    }
+
+    fn processed_ids(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn.prepare("SELECT id FROM entries ORDER BY id").unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn atom_entry(entry: &str) -> Entry {
+        let xml = format!(
+            r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>Feed</title><id>feed</id>
+            <entry><id>entry</id><title>Entry</title><link href="https://example.com/entry"/>{}</entry></feed>"#,
+            entry
+        );
+        // unsanitized like get_entries parses feeds
+        parser::Builder::new()
+            .sanitize_content(false)
+            .build()
+            .parse(xml.as_bytes())
+            .unwrap()
+            .entries
+            .remove(0)
+    }
+
+    // the content parsed for a feed with the given settings
+    fn parse(config: &Config, feed_yaml: &str, entry: Entry) -> String {
+        parse_xhtml(config, &feed_config(feed_yaml), entry)
+            .unwrap()
+            .unwrap()
+            .content
+    }
+
+    #[test]
+    fn entry_without_content_uses_its_summary() {
+        let entry = atom_entry(
+            r#"<summary type="html">&lt;p&gt;Only a &lt;em&gt;summary&lt;/em&gt;&lt;/p&gt;</summary>"#,
+        );
+
+        let content = parse(&config(""), "", entry);
+
+        assert!(content.contains("<em>summary</em>"), "{}", content);
+    }
+
+    #[test]
+    fn max_age_skips_old_and_undated_entries() {
+        let feed_conf = feed_config("max_age_days: 30");
+        let too_old = |entry: DisplayEntry| feed_conf.is_too_old(&entry);
+
+        assert!(!too_old(entry("yesterday", days_ago(1))));
+        assert!(!too_old(entry("last month", days_ago(29))));
+        assert!(too_old(entry("over a month", days_ago(31))));
+        assert!(too_old(entry("last year", days_ago(400))));
+        assert!(too_old(entry("undated", None)));
+    }
+
+    #[test]
+    fn without_max_age_nothing_is_too_old() {
+        let feed_conf = feed_config("");
+
+        assert!(!feed_conf.is_too_old(&entry("last year", days_ago(400))));
+        assert!(!feed_conf.is_too_old(&entry("undated", None)));
+    }
+
+    #[test]
+    fn undated_entries_sort_last() {
+        let mut entries = [
+            entry("undated", None),
+            entry("newer", days_ago(1)),
+            entry("older", days_ago(2)),
+        ];
+
+        entries.sort_by(|a, b| SortBy::Published.compare(a, b));
+
+        let ids: Vec<&str> = entries.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, vec!["older", "newer", "undated"]);
+    }
+
+    #[test]
+    fn prune_removes_only_entries_older_than_the_threshold() {
+        let conn = db();
+        let entries = [entry("old", None), entry("recent", None)];
+        mark_processed(&conn, false, &[], &entries).unwrap();
+        conn.execute(
+            "UPDATE entries SET processed_at = ?1 WHERE id = 'old'",
+            params![days_ago(31).unwrap().timestamp_millis()],
+        )
+        .unwrap();
+
+        assert_eq!(prune_entries(&conn, 30).unwrap(), 1);
+        assert_eq!(processed_ids(&conn), vec!["recent"]);
+    }
+
+    #[test]
+    fn valid_config_passes_validation() {
+        validate(EMAIL_CONFIG).unwrap();
+    }
+
+    #[test]
+    fn invalid_schedule_fails_validation() {
+        let e = validate(&EMAIL_CONFIG.replace("0 0 7 * * *", "every morning")).unwrap_err();
+        assert!(
+            e.to_string().contains("not a valid cron expression"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn invalid_from_address_fails_validation() {
+        let e =
+            validate(&EMAIL_CONFIG.replace("from: saga@example.com", "from: saga")).unwrap_err();
+        assert!(e.to_string().contains("email from \"saga\""), "{}", e);
+    }
+
+    #[test]
+    fn invalid_to_address_fails_validation() {
+        let e = validate(&EMAIL_CONFIG.replace("to: you@example.com", "to: you")).unwrap_err();
+        assert!(e.to_string().contains("email to \"you\""), "{}", e);
+    }
+
+    #[test]
+    fn empty_relay_fails_validation() {
+        let e = validate(&EMAIL_CONFIG.replace("smtp.example.com", "\"\"")).unwrap_err();
+        assert!(e.to_string().contains("email relay is empty"), "{}", e);
+    }
+
+    #[test]
+    fn metrics_are_only_served_locally_by_default() {
+        let config = |yaml: &str| serde_yml::from_str::<Config>(yaml).unwrap();
+
+        assert_eq!(
+            config(EMAIL_CONFIG).metrics_addr,
+            IpAddr::from([127, 0, 0, 1])
+        );
+        assert_eq!(
+            config(&format!("{}metrics_addr: 0.0.0.0\n", EMAIL_CONFIG)).metrics_addr,
+            IpAddr::from([0, 0, 0, 0])
+        );
+    }
+
+    #[test]
+    fn empty_output_formats_fail_validation() {
+        let e = validate(&format!("{}output_formats: []\n", EMAIL_CONFIG)).unwrap_err();
+        assert!(
+            e.to_string().contains("output_formats has no formats"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn invalid_feed_url_fails_validation() {
+        let e =
+            validate(&EMAIL_CONFIG.replace("https://example.com/feed.xml", "example")).unwrap_err();
+        assert!(e.to_string().contains("feed url \"example\""), "{}", e);
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let yaml = EMAIL_CONFIG
+            .replace("0 0 7 * * *", "every morning")
+            .replace("from: saga@example.com", "from: saga")
+            .replace("smtp.example.com", "\"\"")
+            .replace("https://example.com/feed.xml", "example");
+
+        let e = validate(&yaml).unwrap_err().to_string();
+
+        for problem in [
+            "schedule \"every morning\"",
+            "email from \"saga\"",
+            "email relay is empty",
+            "feed url \"example\"",
+        ] {
+            assert!(e.contains(problem), "{}", e);
+        }
+    }
+
+    // each test sets variables only it reads, since tests run in parallel
+    #[test]
+    fn email_placeholders_expand_from_the_environment() {
+        unsafe {
+            std::env::set_var("SAGA_TEST_RELAY_HOST", "mail.example.com");
+            std::env::set_var("SAGA_TEST_RELAY_PASSWORD", "hunter2");
+        }
+        let mut email = email_config(
+            &EMAIL_CONFIG
+                .replace("smtp.example.com", "smtp.${SAGA_TEST_RELAY_HOST}")
+                .replace("password: secret", "password: ${SAGA_TEST_RELAY_PASSWORD}"),
+        );
+
+        email.expand_env().unwrap();
+
+        assert_eq!(email.relay, "smtp.mail.example.com");
+        assert_eq!(email.password, "hunter2");
+    }
+
+    #[test]
+    fn password_env_reads_the_password() {
+        unsafe { std::env::set_var("SAGA_TEST_PASSWORD_ENV", "from-env") };
+        let mut email = email_config(
+            &EMAIL_CONFIG.replace("password: secret", "password_env: SAGA_TEST_PASSWORD_ENV"),
+        );
+
+        email.expand_env().unwrap();
+
+        assert_eq!(email.password, "from-env");
+    }
+
+    #[test]
+    fn missing_environment_variables_are_reported() {
+        let mut email = email_config(
+            &EMAIL_CONFIG
+                .replace("password: secret", "password_env: SAGA_TEST_UNSET_PASSWORD")
+                .replace("smtp.example.com", "${SAGA_TEST_UNSET_RELAY}"),
+        );
+
+        let e = email.expand_env().unwrap_err().to_string();
+
+        assert!(e.contains("SAGA_TEST_UNSET_RELAY"), "{}", e);
+        assert!(e.contains("SAGA_TEST_UNSET_PASSWORD"), "{}", e);
+    }
+
+    #[test]
+    fn output_stem_fills_in_the_placeholders() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 7, 5, 9).unwrap();
+
+        assert_eq!(
+            output_stem(Some("Daily Digest {date} {time} ({count})"), now, 3),
+            "Daily Digest 2024-06-01 070509 (3)"
+        );
+    }
+
+    #[test]
+    fn output_stem_strips_illegal_characters() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 7, 5, 9).unwrap();
+
+        assert_eq!(output_stem(Some("a/b:c*{date}?."), now, 1), "abc2024-06-01");
+        assert_eq!(
+            output_stem(Some("/:?"), now, 1),
+            "saga_output_20240601_070509"
+        );
+    }
+
+    #[test]
+    fn output_stem_defaults_to_the_timestamp() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 7, 5, 9).unwrap();
+
+        assert_eq!(output_stem(None, now, 1), "saga_output_20240601_070509");
+    }
+
+    #[test]
+    fn entry_link_prefers_the_alternate_link() {
+        let link = |rel: Option<&str>, href: &str| feed_rs::model::Link {
+            href: href.to_string(),
+            rel: rel.map(String::from),
+            media_type: None,
+            href_lang: None,
+            title: None,
+            length: None,
+        };
+        let mut entry = atom_entry("");
+        entry.links = vec![
+            link(Some("replies"), "https://example.com/comments"),
+            link(Some("alternate"), "https://example.com/canonical"),
+        ];
+        assert_eq!(
+            get_entry_link(&entry).as_deref(),
+            Some("https://example.com/canonical")
+        );
+
+        entry.links.remove(1);
+        assert_eq!(
+            get_entry_link(&entry).as_deref(),
+            Some("https://example.com/comments")
+        );
+
+        entry.links.clear();
+        assert_eq!(get_entry_link(&entry), None);
+    }
+
+    #[test]
+    fn reload_swaps_in_the_new_config_and_schedules() {
+        let mut config = config("");
+        let mut schedules = feed_schedules(&config).unwrap();
+        let reloaded: Config =
+            serde_yml::from_str(&EMAIL_CONFIG.replace("0 0 7", "0 30 6")).unwrap();
+
+        reload_config(&mut config, &mut schedules, || Ok(reloaded));
+
+        assert_eq!(config.schedule, "0 30 6 * * *");
+        assert_eq!(config.feed_urls(), vec!["https://example.com/feed.xml"]);
+        assert_eq!(schedules.len(), 1);
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_current_config() {
+        let mut config = config("");
+        let mut schedules = feed_schedules(&config).unwrap();
+
+        reload_config(&mut config, &mut schedules, || {
+            Err(anyhow!("invalid config"))
+        });
+        // a feed schedule that doesn't parse is refused as a whole
+        let reloaded: Config =
+            serde_yml::from_str(&format!("{}    schedule: every morning\n", EMAIL_CONFIG)).unwrap();
+        reload_config(&mut config, &mut schedules, || Ok(reloaded));
+
+        assert_eq!(config.schedule, "0 0 7 * * *");
+        assert!(config.rss.is_empty());
+        assert!(schedules.is_empty());
+    }
+
+    #[test]
+    fn next_wake_is_the_earliest_run_with_every_feed_due_then() {
+        let at = |hour| Some(Utc.with_ymd_and_hms(2024, 6, 1, hour, 0, 0).unwrap());
+
+        assert_eq!(
+            next_wake(&[at(9), at(7), None, at(7)]),
+            Some((at(7).unwrap(), vec![1, 3]))
+        );
+        assert_eq!(next_wake(&[None, None]), None);
+        assert_eq!(next_wake(&[]), None);
+    }
+
+    #[test]
+    fn feeds_use_their_own_schedule_or_the_global_one() {
+        let config: Config = serde_yml::from_str(&format!(
+            "{}    schedule: \"0 0 * * * *\"\n  - url: https://example.com/weekly.xml\n    random: false\n",
+            EMAIL_CONFIG
+        ))
+        .unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 6, 1, 7, 30, 0).unwrap();
+
+        let runs: Vec<_> = feed_schedules(&config)
+            .unwrap()
+            .iter()
+            .map(|x| next_run(x, Tz::UTC, after))
+            .collect();
+
+        assert_eq!(
+            runs,
+            vec![
+                Some(Utc.with_ymd_and_hms(2024, 6, 1, 8, 0, 0).unwrap()),
+                Some(Utc.with_ymd_and_hms(2024, 6, 2, 7, 0, 0).unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn daily_schedule_fires_at_the_local_hour() {
+        let schedule = Schedule::from_str("0 0 7 * * *").unwrap();
+        let timezone: Tz = "America/New_York".parse().unwrap();
+        // 7am in new york is 11am utc in summer and noon in winter
+        let summer = Utc.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap();
+        let winter = Utc.with_ymd_and_hms(2024, 12, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(
+            next_run(&schedule, timezone, summer),
+            Some(Utc.with_ymd_and_hms(2024, 6, 1, 11, 0, 0).unwrap())
+        );
+        assert_eq!(
+            next_run(&schedule, timezone, winter),
+            Some(Utc.with_ymd_and_hms(2024, 12, 1, 12, 0, 0).unwrap())
+        );
+        assert_eq!(
+            next_run(&schedule, Tz::UTC, summer),
+            Some(Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap())
+        );
+    }
+
+    fn search_titles(conn: &Connection, query: &str) -> Vec<String> {
+        let mut titles: Vec<String> = search_entries(conn, query, 10)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.title)
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn search_finds_stored_entries() {
+        let conn = db();
+        let mut entries = vec![];
+        for (title, content) in [
+            ("Borrowing", "<p>Rust lifetimes and the borrow checker.</p>"),
+            ("Goroutines", "<p>Go channels make concurrency simple.</p>"),
+            ("Fearless", "<p>Rust makes concurrency fearless.</p>"),
+        ] {
+            let mut entry = entry(title, days_ago(1));
+            entry.content = content.to_string();
+            entries.push(entry);
+        }
+        mark_processed(&conn, true, &[], &entries).unwrap();
+
+        assert_eq!(search_titles(&conn, "rust"), vec!["Borrowing", "Fearless"]);
+        assert_eq!(
+            search_titles(&conn, "rust AND concurrency"),
+            vec!["Fearless"]
+        );
+        assert_eq!(
+            search_titles(&conn, "lifetimes OR channels"),
+            vec!["Borrowing", "Goroutines"]
+        );
+        assert_eq!(
+            search_titles(&conn, "\"borrow checker\""),
+            vec!["Borrowing"]
+        );
+        assert_eq!(search_titles(&conn, "goroutines"), vec!["Goroutines"]);
+        assert!(search_titles(&conn, "python").is_empty());
+
+        let hit = search_entries(&conn, "checker", 10).unwrap().remove(0);
+        assert_eq!(hit.feed_title, "Feed");
+        assert!(hit.snippet.contains("[checker]"), "{}", hit.snippet);
+    }
+
+    #[test]
+    fn invalid_search_query_is_an_error() {
+        assert!(search_entries(&db(), "\"unclosed", 10).is_err());
+    }
+
+    #[test]
+    fn database_errors_fail_the_feed_instead_of_panicking() {
+        // without migrations there is no entries table to query
+        let conn = Connection::open_in_memory().unwrap();
+        let entries = vec![entry("new", days_ago(1))];
+
+        let candidates = find_candidates(&conn, &feed_config(""), entries, Utc::now(), None);
+
+        assert!(candidates.is_err());
+    }
+
+    #[test]
+    fn filter_unprocessed_matches_a_lookup_per_entry() {
+        let conn = db();
+        // enough entries to span several lookup chunks
+        let entries: Vec<DisplayEntry> = (0..PROCESSED_LOOKUP_CHUNK * 2 + 7)
+            .map(|i| entry(&format!("entry-{}", i), None))
+            .collect();
+        let processed: Vec<DisplayEntry> = entries.iter().step_by(3).cloned().collect();
+        mark_processed(&conn, false, &[], &processed).unwrap();
+        let mut aliased = entry("renamed", None);
+        aliased.aliases = vec![String::from("entry-0")];
+
+        let mut candidates = entries.clone();
+        candidates.push(aliased);
+        let unprocessed = filter_unprocessed(&conn, candidates).unwrap();
+
+        let expected: Vec<&str> = entries
+            .iter()
+            .filter(|x| {
+                conn.query_row("SELECT 1 FROM entries WHERE id = ?1", [&x.id], |_| Ok(()))
+                    .is_err()
+            })
+            .map(|x| x.id.as_str())
+            .collect();
+        let ids: Vec<&str> = unprocessed.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(ids.len(), entries.len() - processed.len());
+    }
+
+    #[test]
+    fn strip_selectors_remove_boilerplate() {
+        let entry = atom_entry(
+            r#"<content type="html">&lt;p&gt;The article.&lt;/p&gt;&lt;div class="footer"&gt;&lt;p&gt;Subscribe now&lt;/p&gt;&lt;/div&gt;</content>"#,
+        );
+
+        let content = parse(
+            &config(""),
+            "strip_selectors: [div.footer, aside.nothing]",
+            entry,
+        );
+
+        assert!(content.contains("The article."), "{}", content);
+        assert!(!content.contains("Subscribe"), "{}", content);
+    }
+
+    #[test]
+    fn ttl_and_syndication_hints_give_the_poll_interval() {
+        let syndication = br#"<rss xmlns:sy="http://purl.org/rss/1.0/modules/syndication/">
+            <sy:updatePeriod>daily</sy:updatePeriod>
+            <sy:updateFrequency>4</sy:updateFrequency></rss>"#;
+
+        assert_eq!(
+            poll_interval(Some(180), b"<rss/>"),
+            Some(chrono::Duration::hours(3))
+        );
+        assert_eq!(
+            poll_interval(None, syndication),
+            Some(chrono::Duration::hours(6))
+        );
+        // the longer of the two wins
+        assert_eq!(
+            poll_interval(Some(600), syndication),
+            Some(chrono::Duration::hours(10))
+        );
+        assert_eq!(poll_interval(Some(0), b"<rss/>"), None);
+    }
+
+    #[test]
+    fn large_ttl_defers_the_next_poll() {
+        let hourly = Schedule::from_str("0 0 * * * *").unwrap();
+        let ran_at = Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap();
+        let now = ran_at + chrono::Duration::seconds(30);
+        let at = |hour| Some(Utc.with_ymd_and_hms(2024, 6, 1, hour, 0, 0).unwrap());
+
+        assert_eq!(next_feed_run(&hourly, Tz::UTC, ran_at, now, None, 0), at(8));
+        assert_eq!(
+            next_feed_run(
+                &hourly,
+                Tz::UTC,
+                ran_at,
+                now,
+                Some(chrono::Duration::hours(3)),
+                0
+            ),
+            at(10)
+        );
+        // a hint shorter than the schedule changes nothing
+        assert_eq!(
+            next_feed_run(
+                &hourly,
+                Tz::UTC,
+                ran_at,
+                now,
+                Some(chrono::Duration::minutes(10)),
+                0
+            ),
+            at(8)
+        );
+        assert_eq!(
+            next_feed_run(&hourly, Tz::UTC, ran_at, now, None, 2),
+            at(10)
+        );
+    }
+
+    #[test]
+    fn email_subject_fills_in_the_placeholders_on_one_line() {
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap();
+        let mut entries = vec![entry("a", None), entry("b", None), entry("c", None)];
+        entries[1].feed_title = String::from("Other");
+
+        assert_eq!(
+            email_subject(Some("{feeds}:\n  {count} on {date}"), now, &entries),
+            "Feed, Other: 3 on 2024-06-01"
+        );
+        assert_eq!(
+            email_subject(None, now, &entries),
+            "Saga Digest — 2024-06-01 (3 articles)"
+        );
+    }
+
+    #[test]
+    fn short_teasers_are_skipped_and_full_articles_kept() {
+        let config = config("min_content_chars: 200\nskip_truncated: true");
+        let parsed = |html: &str| {
+            let entry = atom_entry(&format!(
+                r#"<content type="html">{}</content>"#,
+                html.replace('<', "&lt;").replace('>', "&gt;")
+            ));
+            parse_xhtml(&config, &feed_config(""), entry).unwrap()
+        };
+
+        assert!(parsed("<p>The first lines of a story... Read more</p>").is_none());
+        let article = format!("<p>{}</p>", "A whole paragraph of the story. ".repeat(10));
+        assert!(parsed(&article).is_some());
+    }
+
+    // the entry a random feed backfills from a backlog of twenty, one
+    // published each day, when the config has the seed
+    fn backfill_pick(feed_yaml: &str, seed: u64) -> String {
+        let conn = db();
+        let mut feed_conf = feed_config(feed_yaml);
+        feed_conf.random = true;
+        let entries: Vec<DisplayEntry> = (0..20)
+            .map(|i| entry(&format!("entry-{:02}", i), days_ago(40 - i)))
+            .collect();
+        record_backlog(&conn, &feed_conf.url, &entries).unwrap();
+        let config = config(&format!("random_seed: {}", seed));
+        let now = Utc::now();
+
+        let picked = pick_entries(
+            &conn,
+            &feed_conf,
+            entries,
+            now,
+            Some(now),
+            &mut config.rng(&feed_conf.url),
+        )
+        .unwrap();
+
+        assert!(picked.backfill);
+        assert_eq!(picked.entries.len(), 1);
+        picked.entries[0].id.clone()
+    }
+
+    #[test]
+    fn same_seed_picks_the_same_backfill_entry() {
+        for seed in [1, 7, 42] {
+            assert_eq!(backfill_pick("", seed), backfill_pick("", seed));
+        }
+        let picks: HashSet<String> = (0..10).map(|seed| backfill_pick("", seed)).collect();
+        assert!(picks.len() > 1, "{:?}", picks);
+    }
+
+    // how old the picks are on average, 0 the oldest entry and 19 the newest
+    fn mean_backfill_rank(order: &str) -> f64 {
+        let total: usize = (0..100)
+            .map(|seed| {
+                let id = backfill_pick(&format!("backfill_order: {}", order), seed);
+                id["entry-".len()..].parse::<usize>().unwrap()
+            })
+            .sum();
+        total as f64 / 100.0
+    }
+
+    #[test]
+    fn oldest_backfill_order_favours_older_entries() {
+        let rank = mean_backfill_rank("oldest");
+        assert!(rank < 8.0, "{}", rank);
+    }
+
+    #[test]
+    fn newest_backfill_order_favours_newer_entries() {
+        let rank = mean_backfill_rank("newest");
+        assert!(rank > 11.0, "{}", rank);
+    }
+
+    #[test]
+    fn random_backfill_order_favours_neither() {
+        let rank = mean_backfill_rank("random");
+        assert!((8.0..=11.0).contains(&rank), "{}", rank);
+        assert_eq!(
+            backfill_pick("backfill_order: random", 3),
+            backfill_pick("", 3)
+        );
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn write_atomically_leaves_only_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("digest.epub");
+
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(file_names(dir.path()), vec!["digest.epub"]);
+    }
+
+    #[test]
+    fn failed_write_leaves_no_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // renaming over a directory that isn't empty fails after the
+        // temp file is written
+        let path = dir.path().join("digest.epub");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("kept"), b"").unwrap();
+
+        let error = write_atomically(&path, b"content").unwrap_err();
+
+        assert!(error.to_string().contains("Could not write"), "{}", error);
+        assert_eq!(file_names(dir.path()), vec!["digest.epub"]);
+        assert!(path.is_dir());
+    }
+
+    fn config_with_feeds(urls: &[&str], yaml: &str) -> Config {
+        let rss: String = urls
+            .iter()
+            .map(|url| format!("  - url: {}\n    random: false\n", url))
+            .collect();
+        serde_yml::from_str(&format!(
+            "delivery: folder\nfolder: out\nschedule: \"0 0 7 * * *\"\nrss:\n{}{}",
+            rss, yaml
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn feed_url_key_ignores_trailing_slashes_and_case() {
+        let key = feed_url_key("https://example.com/feed");
+        assert_eq!(feed_url_key("https://example.com/feed/"), key);
+        assert_eq!(feed_url_key(" HTTPS://Example.COM:443/feed#top "), key);
+        assert_ne!(feed_url_key("https://example.com/Feed"), key);
+        assert_ne!(feed_url_key("https://example.com/feed?page=2"), key);
+    }
+
+    #[test]
+    fn duplicate_feeds_keep_the_first_by_default() {
+        let mut config = config_with_feeds(
+            &[
+                "https://example.com/feed",
+                "https://other.example.com/feed",
+                "https://example.com/feed/",
+            ],
+            "",
+        );
+
+        config.dedupe_feeds();
+
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.feed_urls(),
+            vec!["https://example.com/feed", "https://other.example.com/feed"]
+        );
+    }
+
+    #[test]
+    fn duplicate_feeds_are_refused_when_set_to_error() {
+        let mut config = config_with_feeds(
+            &["https://example.com/feed", "https://example.com/feed/"],
+            "on_duplicate_feed: error",
+        );
+
+        config.dedupe_feeds();
+
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("feed https://example.com/feed/ is listed more than once"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn feed_keeps_its_history_when_its_url_gains_a_slash() {
+        let conn = db();
+        let old = "https://example.com/feed";
+        record_feed_results(&conn, &[(old, None)], Utc::now()).unwrap();
+        record_backlog(&conn, old, &[entry("a", days_ago(3))]).unwrap();
+        let config = config_with_feeds(&["https://example.com/feed/"], "");
+
+        adopt_renamed_feeds(&conn, &config).unwrap();
+
+        assert!(get_feed_status(&conn, old).unwrap().is_none());
+        assert!(
+            get_feed_status(&conn, "https://example.com/feed/")
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(
+            get_backlog_ids(&conn, "https://example.com/feed/", Utc::now()).unwrap(),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn feed_keeps_its_history_when_its_url_is_spelled_differently() {
+        let conn = db();
+        let old = "https://Example.com:443/feed";
+        let new = "https://example.com/feed/";
+        record_feed_results(&conn, &[(old, None)], Utc::now()).unwrap();
+        record_backlog(&conn, old, &[entry("a", days_ago(3))]).unwrap();
+
+        adopt_renamed_feeds(&conn, &config_with_feeds(&[new], "")).unwrap();
+
+        assert!(get_feed_status(&conn, old).unwrap().is_none());
+        assert!(get_feed_status(&conn, new).unwrap().is_some());
+        assert_eq!(get_backlog_ids(&conn, new, Utc::now()).unwrap(), vec!["a"]);
+    }
+
+    #[test]
+    fn feed_is_not_adopted_from_another_configured_feed() {
+        let conn = db();
+        let old = "https://example.com/feed";
+        record_feed_results(&conn, &[(old, None)], Utc::now()).unwrap();
+        let config = config_with_feeds(&[old, "https://example.com/feed/"], "");
+
+        adopt_renamed_feeds(&conn, &config).unwrap();
+
+        assert!(get_feed_status(&conn, old).unwrap().is_some());
+    }
+
+    #[test]
+    fn status_leaves_a_renamed_feed_to_the_next_run() {
+        let conn = db();
+        let old = "https://example.com/feed";
+        record_feed_results(&conn, &[(old, None)], Utc::now()).unwrap();
+        let config = config_with_feeds(&["https://example.com/feed/"], "");
+
+        let saga = Saga::new(config, conn).unwrap();
+        saga.show_status(ReportFormat::Json).unwrap();
+
+        assert!(get_feed_status(&saga.db, old).unwrap().is_some());
+    }
+
+    #[test]
+    fn archived_messages_quote_their_from_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sent.mbox");
+        let email = Message::builder()
+            .from("saga@example.com".parse().unwrap())
+            .to("you@example.com".parse().unwrap())
+            .subject("Digest")
+            .body(String::from("From the start\n>From a quote\nFromage"))
+            .unwrap();
+
+        archive_message(path.to_str().unwrap(), &email).unwrap();
+
+        let archive = fs::read_to_string(&path).unwrap();
+        assert!(archive.starts_with("From saga@example.com "), "{}", archive);
+        assert!(archive.contains("\n>From the start\n"), "{}", archive);
+        assert!(archive.contains("\n>>From a quote\n"), "{}", archive);
+        assert!(archive.contains("\nFromage\n"), "{}", archive);
+    }
+
+    #[test]
+    fn failing_feed_backs_off_more_with_each_failure_until_it_succeeds() {
+        let conn = db();
+        let url = "https://example.com/feed.xml";
+        let hourly = Schedule::from_str("0 0 * * * *").unwrap();
+        let ran_at = Utc.with_ymd_and_hms(2024, 6, 1, 7, 0, 0).unwrap();
+        let next = |conn: &Connection| {
+            let skipped = failure_backoff(get_consecutive_failures(conn, url).unwrap());
+            next_feed_run(&hourly, Tz::UTC, ran_at, ran_at, None, skipped)
+                .unwrap()
+                .hour()
+        };
+        let fail = |conn: &Connection| {
+            record_feed_results(conn, &[(url, Some(String::from("404")))], Utc::now()).unwrap()
+        };
+
+        assert_eq!(next(&conn), 8);
+        let mut hours = vec![];
+        for _ in 0..4 {
+            fail(&conn);
+            hours.push(next(&conn));
+        }
+        assert_eq!(hours, vec![8, 9, 11, 15]);
+
+        record_feed_results(&conn, &[(url, None)], Utc::now()).unwrap();
+        assert_eq!(get_consecutive_failures(&conn, url).unwrap(), 0);
+        assert_eq!(next(&conn), 8);
+    }
+
+    #[test]
+    fn failure_backoff_is_capped() {
+        assert_eq!(failure_backoff(0), 0);
+        assert_eq!(failure_backoff(7), 63);
+        assert_eq!(failure_backoff(100), 63);
+    }
+
+    #[test]
+    fn feed_is_disabled_after_max_consecutive_failures() {
+        assert!(!config("").is_feed_disabled(1000));
+        let config = config("max_consecutive_failures: 3");
+        assert!(!config.is_feed_disabled(2));
+        assert!(config.is_feed_disabled(3));
+    }
+
+    #[test]
+    fn parse_since_takes_several_formats() {
+        let utc = |h| Utc.with_ymd_and_hms(2025, 1, 31, h, 0, 0).unwrap();
+        assert_eq!(parse_since("2025-01-31T08:00:00Z").unwrap(), utc(8));
+        assert_eq!(parse_since("2025-01-31T10:00:00+02:00").unwrap(), utc(8));
+        assert_eq!(
+            parse_since("Fri, 31 Jan 2025 08:00:00 +0000").unwrap(),
+            utc(8)
+        );
+        let local = |value| {
+            parse_since(value)
+                .unwrap()
+                .with_timezone(&chrono::Local)
+                .naive_local()
+        };
+        let date = NaiveDate::from_ymd_opt(2025, 1, 31).unwrap();
+        assert_eq!(local("2025-01-31"), date.and_hms_opt(0, 0, 0).unwrap());
+        assert_eq!(
+            local(" 2025-01-31 08:30 "),
+            date.and_hms_opt(8, 30, 0).unwrap()
+        );
+        assert_eq!(
+            local("2025-01-31 08:30:15"),
+            date.and_hms_opt(8, 30, 15).unwrap()
+        );
+        let error = parse_since("last tuesday").unwrap_err().to_string();
+        assert!(error.contains("is not a date"), "{}", error);
+    }
+
+    #[test]
+    fn parts_sharing_a_title_prefix_merge_into_one_chapter() {
+        let entries = vec![
+            entry("Story: Part 2", days_ago(1)),
+            entry("Unrelated", days_ago(3)),
+            entry("Story: Part 1", days_ago(2)),
+        ];
+
+        let merged = merge_title_parts(entries).unwrap();
+
+        let titles: Vec<&str> = merged.iter().map(|x| x.title.as_str()).collect();
+        assert_eq!(titles, vec!["Story", "Unrelated"]);
+        let story = &merged[0];
+        assert_eq!(story.id, "Story: Part 1");
+        assert_eq!(story.merged_ids, vec!["Story: Part 2"]);
+        let sections: Vec<(&str, &str)> = story
+            .sections
+            .iter()
+            .map(|x| (x.anchor.as_str(), x.title.as_str()))
+            .collect();
+        assert_eq!(
+            sections,
+            vec![("part_1", "Story: Part 1"), ("part_2", "Story: Part 2")]
+        );
+        assert!(
+            story.content.contains(
+                r#"<h2 id="part_1">Story: Part 1</h2><p>Story: Part 1</p><h2 id="part_2">Story: Part 2</h2><p>Story: Part 2</p>"#
+            ),
+            "{}",
+            story.content
+        );
+    }
+
+    #[test]
+    fn title_prefix_splits_at_the_last_delimiter() {
+        assert_eq!(title_prefix("Story: Part 2"), Some("Story"));
+        assert_eq!(
+            title_prefix("Saga: Book 1 - Chapter 3"),
+            Some("Saga: Book 1")
+        );
+        assert_eq!(title_prefix("No delimiter"), None);
+        assert_eq!(title_prefix(": Leading"), None);
+    }
+
+    #[test]
+    fn url_entry_ids_are_canonicalized() {
+        let params = default_tracking_params();
+        let id = "https://blog.example.com/post?page=2";
+        assert_eq!(normalize_entry_id(id, &params), id);
+        assert_eq!(
+            normalize_entry_id(
+                " https://Blog.Example.com/post/?page=2&utm_source=rss#comments",
+                &params
+            ),
+            id
+        );
+        assert_eq!(
+            normalize_entry_id("https://blog.example.com/", &params),
+            "https://blog.example.com"
+        );
+        assert_eq!(
+            normalize_entry_id("tag:blog.example.com,2024:Post/1", &params),
+            "tag:blog.example.com,2024:Post/1"
+        );
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config: Config = serde_yml::from_str(EXAMPLE_CONFIG).unwrap();
+        config.validate().unwrap();
+        assert!(!config.rss.is_empty());
+    }
+
+    #[test]
+    fn example_config_settings_parse_when_uncommented() {
+        let setting = Regex::new(r"^(\s*)# ([a-z_]+:( |$))").unwrap();
+        let uncommented: String = EXAMPLE_CONFIG
+            .lines()
+            .map(|line| setting.replace(line, "$1$2").into_owned() + "\n")
+            .collect();
+
+        if let Err(e) = serde_yml::from_str::<Config>(&uncommented) {
+            panic!("{}\n{}", e, uncommented);
+        }
+    }
+
+    #[test]
+    fn transforms_rewrite_the_html_before_parsing() {
+        let entry = atom_entry(
+            r#"<content type="html">&lt;p&gt;First&lt;p/&gt;Second&lt;/p&gt;&lt;amp-img src="https://example.com/a.png"&gt;&lt;/amp-img&gt;</content>"#,
+        );
+        let transforms = "\
+transforms:
+  - find: '<p/>'
+    replace: '</p><p>'
+  - find: '<amp-img (?P<attributes>[^>]*)></amp-img>'
+    replace: '<img ${attributes}>'";
+
+        let content = parse(&config(""), transforms, entry);
+
+        assert!(content.contains("<p>First</p><p>Second</p>"), "{}", content);
+        assert!(
+            content.contains(r#"<img src="https://example.com/a.png""#),
+            "{}",
+            content
+        );
+        assert!(!content.contains("amp-img"), "{}", content);
+    }
+
+    #[test]
+    fn invalid_transform_is_refused_at_load() {
+        let error = serde_yml::from_str::<FeedConfig>(
+            "url: https://example.com/feed.xml\nrandom: false\ntransforms:\n  - find: '(unclosed'\n    replace: ''",
+        )
+        .unwrap_err()
+        .to_string();
+
+        assert!(error.contains("invalid pattern \"(unclosed\""), "{}", error);
+    }
+
+    #[test]
+    fn cached_feeds_are_kept_by_url_with_their_fetch_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().to_str().unwrap();
+        let config = config(&format!("cache_dir: {}", cache_dir));
+        let url = "https://example.com/feed.xml";
+
+        write_cached_feed(cache_dir, url, b"<rss/>").unwrap();
+
+        assert_eq!(read_cached_feed(&config, url).unwrap(), b"<rss/>");
+        let meta: CachedFeed = serde_json::from_slice(
+            &fs::read(cache_path(cache_dir, url).with_extension("json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(meta.url, url);
+        assert!(Utc::now() - meta.fetched_at < chrono::Duration::minutes(1));
+        // a stale copy is still used
+        let stale = CachedFeed {
+            url: url.to_string(),
+            fetched_at: Utc::now() - chrono::Duration::days(3),
+        };
+        fs::write(
+            cache_path(cache_dir, url).with_extension("json"),
+            serde_json::to_vec(&stale).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(read_cached_feed(&config, url).unwrap(), b"<rss/>");
+        let error = read_cached_feed(&config, "https://example.com/other.xml")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("no cached copy of feed"), "{}", error);
+    }
+}
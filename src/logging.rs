@@ -0,0 +1,131 @@
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+use simple_logger::SimpleLogger;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+
+// a log file past this size is moved aside to <file>.1 and started over
+const LOG_FILE_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+static LOG_FILE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+// level from the command line flags, overriding RUST_LOG and the config
+static CLI_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+/// Logs to stderr through SimpleLogger and, once configured, to a file
+struct TeeLogger {
+    stderr: SimpleLogger,
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.stderr.log(record);
+
+        let mut log_file = LOG_FILE.lock().unwrap();
+        let Some((path, file)) = log_file.as_mut() else {
+            return;
+        };
+        let line = format!(
+            "{} {:<5} [{}] {}\n",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        // a failing log file has nowhere to report to but stderr
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            eprintln!("Could not write to log file {:?}: {}", path, e);
+            return;
+        }
+        if file.metadata().is_ok_and(|x| x.len() > LOG_FILE_MAX_BYTES) {
+            match rotate(path) {
+                Ok(rotated) => *file = rotated,
+                Err(e) => eprintln!("Could not rotate log file {:?}: {}", path, e),
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((_, file)) = LOG_FILE.lock().unwrap().as_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+fn rotate(path: &PathBuf) -> Result<File> {
+    let mut rotated = path.clone().into_os_string();
+    rotated.push(".1");
+    fs::rename(path, rotated)?;
+    open(path)
+}
+
+fn open(path: &PathBuf) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("could not open log file {:?}: {}", path, e))
+}
+
+/// Start logging to stderr at the command line level, or the RUST_LOG
+/// level, or info until the config is loaded
+pub fn init(cli_level: Option<LevelFilter>) {
+    if let Some(level) = cli_level {
+        CLI_LEVEL.set(level).unwrap();
+    }
+    let stderr = SimpleLogger::new()
+        .with_level(LevelFilter::Trace)
+        // xml5ever warns about unimplemented internals on every parse
+        .with_module_level("xml5ever", LevelFilter::Error);
+    log::set_boxed_logger(Box::new(TeeLogger { stderr })).unwrap();
+    log::set_max_level(override_level().unwrap_or(LevelFilter::Info));
+}
+
+/// Apply the config's log level and file, the command line flags and
+/// RUST_LOG take precedence over the level
+pub fn configure(level: Option<&str>, file: Option<&str>) -> Result<()> {
+    let level = match (override_level(), level) {
+        (Some(level), _) => level,
+        (None, Some(level)) => parse_level(level)?,
+        (None, None) => LevelFilter::Info,
+    };
+    let file = match file {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            let file = open(&path)?;
+            Some((path, file))
+        }
+        None => None,
+    };
+    *LOG_FILE.lock().unwrap() = file;
+    log::set_max_level(level);
+    Ok(())
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| {
+        anyhow!(
+            "log_level {:?} is not one of off, error, warn, info, debug, or trace",
+            level
+        )
+    })
+}
+
+fn override_level() -> Option<LevelFilter> {
+    CLI_LEVEL.get().copied().or_else(|| {
+        std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|x| LevelFilter::from_str(&x).ok())
+    })
+}
@@ -1,386 +1,295 @@
-use anyhow::{Result, anyhow};
-use chrono::{DateTime, Utc};
-use clap::{ArgAction, Parser};
-use cron::Schedule;
-use epub_builder::{EpubBuilder, EpubContent, EpubVersion, TocElement, ZipLibrary};
-use feed_rs::{model::Entry, parser};
-use html5ever::tree_builder::TreeBuilderOpts;
-use html5ever::{ParseOpts, parse_document};
-use lettre::message::{SinglePart, header};
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
-use log::{error, info, warn};
-use markup5ever_rcdom::{RcDom, SerializableHandle};
-use rand::{rng, seq::IndexedRandom};
-use reqwest::blocking;
-use rusqlite::{Connection, OptionalExtension, params};
-use serde::Deserialize;
-use simple_logger::SimpleLogger;
-use std::default::Default;
-use std::{fs, str::FromStr, thread, time::Duration};
-use tendril::TendrilSink;
-use xml5ever::serialize::{SerializeOpts, serialize};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{ArgAction, Parser, Subcommand};
+use log::{LevelFilter, error};
+use saga::{ReportFormat, RunOptions, Saga, logging, subscriptions};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Optionally run in daemon mode
-    #[arg(short, long, action = ArgAction::SetTrue)]
-    daemon: Option<bool>,
+    /// Run on the schedule until stopped instead of processing once
+    #[arg(short, long, conflicts_with = "once")]
+    daemon: bool,
+
+    /// Process once and exit, which is the default, to be explicit under
+    /// cron
+    #[arg(long)]
+    once: bool,
+
+    /// Use only this config file instead of the layered config.yml files
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Path of the sqlite database, ./database.db3 by default
+    #[arg(long, global = true)]
+    database: Option<PathBuf>,
+
+    /// Generate the output without delivering it or marking entries processed
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Log more, -v for debug and -vv for trace, overriding log_level and RUST_LOG
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log warnings and errors, overriding log_level and RUST_LOG
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Only process this configured feed, repeat it for several
+    #[arg(long = "feed", value_name = "URL")]
+    feeds: Vec<String>,
+
+    /// Consider entries published after this date instead of after the last
+    /// run, e.g. 2025-01-31 or "2025-01-31 08:00"
+    #[arg(long, value_name = "DATE", value_parser = saga::parse_since, conflicts_with = "daemon")]
+    since: Option<DateTime<Utc>>,
+
+    /// Read the feeds from cache_dir, as the last run fetched them, instead
+    /// of fetching them again
+    #[arg(long, conflicts_with = "daemon")]
+    use_cache: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// To think about:
-/// - Reading pdf papers
-/// - Reading html blogs that don't support RSS
-fn main() -> Result<()> {
-    SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
-        .init()
-        .unwrap();
-
-    let cli = Cli::parse();
-
-    let config = get_config()?;
-
-    let db = get_db_conn()?;
-
-    match cli.daemon {
-        Some(true) => start_daemon(&db, &config),
-        _ => process(&db, &config),
-    }
+#[derive(Subcommand)]
+enum Command {
+    /// Fetch and parse a single feed and report which entry fields it provides
+    Inspect {
+        /// Url of the feed to inspect
+        #[arg(long)]
+        feed: String,
+        /// Number of entries to report on
+        #[arg(long, default_value_t = 5)]
+        entries: usize,
+    },
+    /// Deliver only a list of headlines and links instead of full articles
+    Toc,
+    /// Process once and exit, even when daemon mode is set, e.g. to catch
+    /// up from cron or by hand alongside a running daemon
+    Run,
+    /// Write a commented example config.yml covering every setting
+    Init {
+        /// Overwrite the config file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+    /// Add a feed to the config file
+    Add {
+        /// Url of the feed to add
+        url: String,
+        /// Pick a random old entry when the feed has nothing new
+        #[arg(long)]
+        random: bool,
+    },
+    /// Write an epub of a feed that isn't in the config to see what it
+    /// would look like, nothing is emailed or marked processed
+    Preview {
+        /// Url of the feed to preview
+        url: String,
+        /// Pick a random entry instead of the latest ones
+        #[arg(long)]
+        random: bool,
+    },
+    /// List the configured feeds and when each was last processed
+    List {
+        /// Print as text or as json for scripts
+        #[arg(long, value_enum, default_value_t)]
+        format: ReportFormat,
+    },
+    /// Show when each feed was last processed and how many of its entries
+    /// were delivered, including feeds only left in the database
+    Status {
+        /// Print as text or as json for scripts
+        #[arg(long, value_enum, default_value_t)]
+        format: ReportFormat,
+    },
+    /// Remove a feed from the config file and the database
+    Remove {
+        /// Url of the feed to remove
+        url: String,
+    },
+    /// Add the feeds from an OPML file to the config file
+    Import {
+        /// OPML file exported from another reader
+        file: PathBuf,
+    },
+    /// Write the configured feeds out as an OPML file
+    Export {
+        /// Where to write the OPML file
+        file: PathBuf,
+    },
+    /// Write an epub of the stored entries processed since a date
+    Rebuild {
+        /// Date to rebuild from, e.g. 2025-01-31
+        #[arg(long)]
+        since: NaiveDate,
+    },
+    /// Search the stored entries, e.g. rust AND "error handling"
+    Search {
+        /// Full-text query, AND, OR, NOT, "phrases", and prefix* are supported
+        query: String,
+        /// Most matches shown
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Deliver a feed's unprocessed backlog, oldest first, in one go
+    Backfill {
+        /// Url of the configured feed to catch up on
+        url: String,
+        /// Most entries delivered
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Show how a run would pick a feed's entries and which it would pick,
+    /// nothing is delivered or marked processed
+    Explain {
+        /// Url of the configured feed to explain
+        url: String,
+        /// Print as text or as json for scripts
+        #[arg(long, value_enum, default_value_t)]
+        format: ReportFormat,
+    },
+    /// Send a test email to the configured recipients to check the email
+    /// settings
+    TestEmail,
+    /// Forget processed entries older than the given age
+    Prune {
+        /// Age in days past which processed entries are forgotten
+        #[arg(long)]
+        older_than: u32,
+    },
 }
 
-fn start_daemon(db: &Connection, config: &Config) -> Result<()> {
-    info!("Using schedule: {}", config.schedule);
-
-    let schedule = Schedule::from_str(&config.schedule).unwrap();
-    info!("Daemon started, waiting for next scheduled run...");
-    loop {
-        if let Some(next) = schedule.upcoming(Utc).next() {
-            let now = Utc::now();
-            let duration_until_next = next.signed_duration_since(now);
-
-            if duration_until_next > chrono::Duration::zero() {
-                info!("Next run scheduled at: {}", next);
-                if let Ok(std_duration) = duration_until_next.to_std() {
-                    thread::sleep(std_duration);
-                } else {
-                    warn!("Calculated duration is negative, running immediately.");
-                }
-            } else {
-                info!("Scheduled time is now or in the past, running immediately.");
-            }
-
-            info!("Running scheduled process...");
-            if let Err(e) = process(db, config) {
-                error!("Error during scheduled process: {}", e);
-            }
-            info!("Scheduled process finished.");
-
-            thread::sleep(Duration::from_secs(1));
-        } else {
-            error!("Could not determine next schedule time.");
-            thread::sleep(Duration::from_secs(60));
+impl Cli {
+    fn log_level(&self) -> Option<LevelFilter> {
+        // logs go to stderr so the json stays parseable, and scripts
+        // reading it only want to hear about problems
+        let json = matches!(
+            self.command,
+            Some(Command::List {
+                format: ReportFormat::Json
+            }) | Some(Command::Status {
+                format: ReportFormat::Json
+            }) | Some(Command::Explain {
+                format: ReportFormat::Json,
+                ..
+            })
+        );
+        match (self.quiet, self.verbose) {
+            (true, _) => Some(LevelFilter::Warn),
+            (false, 0) if json => Some(LevelFilter::Warn),
+            (false, 0) => None,
+            (false, 1) => Some(LevelFilter::Debug),
+            (false, _) => Some(LevelFilter::Trace),
         }
     }
 }
 
-fn process(db: &Connection, config: &Config) -> Result<()> {
-    let mut entries = vec![];
-    let cutoff = Utc::now();
-    for feed_conf in &config.rss {
-        if let Some(entry) = get_entry(db, feed_conf, cutoff)? {
-            info!("Found entry {}", entry.title.clone());
-            entries.push(entry);
-        }
-    }
-
-    let epub_content = generate_epub(entries)?;
-
-    let epub_name = format!("saga_output_{}.epub", Utc::now().format("%Y%m%d_%H%M%S"));
-
-    fs::write(&epub_name, &epub_content)?;
-    info!("EPUB file saved as: {}", epub_name);
-
-    send_email(config, &epub_name, epub_content)?;
-
-    // update last_processed time and insert entries in transaction
-
-    Ok(())
-}
-
-// cut off time is used to guard against race condition of an entry
-// being published during processing and being considered missed
-fn get_entry(
-    db: &Connection,
-    feed_conf: &FeedConfig,
-    cutoff: DateTime<Utc>,
-) -> Result<Option<DisplayEntry>> {
-    info!("Processing rss feed: {}", feed_conf.url);
-
-    info!("Fetching entries");
-
-    let entries = get_entries(&feed_conf.url)?;
-
-    info!("Finding entry");
-
-    // find new entries that have not been processed yet
-    let new_entries: Vec<DisplayEntry> = entries
-        .into_iter()
-        .filter(|x| x.published < cutoff && !is_entry_already_processed(db, &x.id).unwrap())
-        .collect();
-
-    if new_entries.is_empty() {
-        warn!("Feed is empty");
-        return Ok(None);
-    }
-
-    let entry = pick_entry(db, feed_conf, new_entries)?;
-
-    Ok(Some(entry))
-}
-
-fn pick_entry(
-    db: &Connection,
-    feed_conf: &FeedConfig,
-    new_entries: Vec<DisplayEntry>,
-) -> Result<DisplayEntry> {
-    match get_feed_last_processed(db, &feed_conf.url)? {
-        Some(last_processed) => {
-            // find unprocessed new entries published after the last processed time
-            let mut unprocessed_entries: Vec<&DisplayEntry> = new_entries
-                .iter()
-                .filter(|x| x.published > last_processed)
-                .collect();
-
-            // if there is nothing new and random is set
-            // take a random old one that has not been processed
-            if unprocessed_entries.is_empty() && feed_conf.random {
-                info!("Picking a random entry");
-                return new_entries
-                    .choose(&mut rng())
-                    .cloned()
-                    .ok_or(anyhow!("failed to pick random entry"));
-            }
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    logging::init(cli.log_level());
 
-            info!("Picking oldest of the new entries");
-            // take the oldest after the cutoff
-            unprocessed_entries.sort_by(|a, b| a.published.cmp(&b.published));
-            unprocessed_entries
-                .first()
-                .cloned()
-                .cloned()
-                .ok_or(anyhow!("failed to pick first entry"))
+    match &cli.command {
+        Some(Command::Inspect { feed, entries }) => return saga::inspect_feed(feed, *entries),
+        Some(Command::Init { force }) => {
+            return saga::init_config(&editable_config_path(&cli)?, *force);
+        }
+        Some(Command::Add { url, random }) => {
+            return subscriptions::add_feed(&editable_config_path(&cli)?, url, *random);
+        }
+        Some(Command::Remove { url }) => {
+            subscriptions::remove_feed(&editable_config_path(&cli)?, url)?;
+            let db = saga::get_db_conn(cli.database.as_deref())?;
+            return saga::forget_feed(&db, url);
         }
-        None => {
-            // If there's no last processed time, take the newest entry
-            info!("Picking the latest entry");
-            new_entries
-                .first()
-                .cloned()
-                .ok_or(anyhow!("failed to pick first entry"))
+        Some(Command::Import { file }) => {
+            return subscriptions::import_opml(&editable_config_path(&cli)?, file).map(|_| ());
         }
+        _ => {}
     }
-}
-
-#[derive(Deserialize, Debug)]
-struct Config {
-    email: EmailConfig,
-    schedule: String,
-    rss: Vec<FeedConfig>,
-}
-
-#[derive(Deserialize, Debug)]
-struct FeedConfig {
-    url: String,
-    random: bool,
-}
 
-#[derive(Deserialize, Debug)]
-struct EmailConfig {
-    to: String,
-    from: String,
-    relay: String,
-    username: String,
-    password: String,
-}
-
-fn get_config() -> Result<Config> {
-    let mut config_path = std::env::current_dir()?;
-    config_path.push("config.yml");
-
-    let config_str = std::fs::read_to_string(&config_path)?;
-    let config: Config = serde_yml::from_str(&config_str)?;
+    let config = saga::get_config(cli.config.as_deref())?;
+    config.configure_logging()?;
 
-    info!("Using config at path {:?}", config_path);
-
-    Ok(config)
-}
-
-#[derive(Debug, Clone)]
-struct DisplayEntry {
-    id: String,
-    feed_title: String,
-    title: String,
-    authors: Vec<String>,
-    published: DateTime<Utc>,
-    content: String,
-}
-
-fn get_entries(url: &String) -> Result<Vec<DisplayEntry>> {
-    let resp = blocking::get(url)?.text()?;
-    let feed = parser::parse(resp.as_bytes())?;
-    let mut display_enrties: Vec<DisplayEntry> = vec![];
-    for entry in feed.entries {
-        let feed_title = feed
-            .title
-            .as_ref()
-            .map_or(String::from("Unknown Feed"), |x| x.content.clone());
-        let id = entry.id.clone();
-        let title = entry
-            .title
-            .as_ref()
-            .map_or(String::from("Unknown Title"), |x| x.content.clone());
-        let authors = entry.authors.iter().map(|a| a.name.clone()).collect();
-        let published = entry.published.unwrap_or(DateTime::<Utc>::MIN_UTC);
-        let content = parse_xhtml(entry)?;
-        info!("Contet: {}", content);
-        display_enrties.push(DisplayEntry {
-            id,
-            feed_title,
-            title,
-            authors,
-            published,
-            content,
-        });
+    if let Some(Command::Export { file }) = &cli.command {
+        return subscriptions::export_opml(file, &config.feed_urls());
     }
 
-    Ok(display_enrties)
-}
-
-// TODO: Maybe support content being a src link if we see it happening
-fn parse_xhtml(entry: Entry) -> Result<String> {
-    let content = entry
-        .content
-        .ok_or(anyhow!("No content found"))?
-        .body
-        .ok_or(anyhow!("No content body found"))?;
-
-    let parse_opts = ParseOpts {
-        tree_builder: TreeBuilderOpts {
-            drop_doctype: true,
-            ..Default::default()
-        },
-        ..Default::default()
-    };
-    let dom = parse_document(RcDom::default(), parse_opts)
-        .from_utf8()
-        .read_from(&mut content.as_bytes())?;
+    if let Some(Command::TestEmail) = cli.command {
+        return saga::send_test_email(&config);
+    }
 
-    // Prepare for serialization
-    let mut buffer = Vec::new();
+    if let Some(Command::Preview { url, random }) = &cli.command {
+        return saga::preview_feed(&config, url, *random);
+    }
 
-    // Serialize with XML compliant options
-    let ser_opts = SerializeOpts {
-        // traversal_scope: TraversalScope::IncludeNode,
+    let saga = Saga::new(config, saga::get_db_conn(cli.database.as_deref())?)?;
+    let run_options = RunOptions {
+        dry_run: cli.dry_run,
+        feeds: (!cli.feeds.is_empty()).then(|| cli.feeds.clone()),
+        since: cli.since,
+        use_cache: cli.use_cache,
         ..Default::default()
     };
 
-    // Convert DOM to XHTML
-    let document: SerializableHandle = dom.document.clone().into();
-    serialize(&mut buffer, &document, ser_opts)?;
+    if let Some(Command::List { format }) = cli.command {
+        return saga.list_feeds(format);
+    }
 
-    Ok(String::from_utf8(buffer)?)
-}
+    if let Some(Command::Prune { older_than }) = cli.command {
+        return saga.prune(older_than).map(|_| ());
+    }
 
-fn generate_epub(entries: Vec<DisplayEntry>) -> Result<Vec<u8>> {
-    let mut output = Vec::<u8>::new();
-    let mut builder = EpubBuilder::new(ZipLibrary::new()?)?;
-    let title = format!("Saga - 1");
-    builder
-        .epub_version(EpubVersion::V30)
-        .metadata("author", "Saga")?
-        .metadata("title", title)?;
+    if let Some(Command::Rebuild { since }) = cli.command {
+        return saga.rebuild(since);
+    }
 
-    let entry = entries.first().unwrap();
-    builder.add_content(
-        EpubContent::new("chapter_1.xhtml", entry.content.as_bytes())
-            .title("Chapter 1")
-            .child(TocElement::new("chapter_1.xhtml#1", "1.1")),
-    )?;
-    builder.inline_toc();
-    builder.generate(&mut output)?;
-    Ok(output)
-}
+    if let Some(Command::Search { query, limit }) = &cli.command {
+        return saga.search(query, *limit);
+    }
 
-fn send_email(config: &Config, epub_name: &String, epub_content: Vec<u8>) -> Result<()> {
-    info!("Sending to email: {}", config.email.to);
+    if let Some(Command::Backfill { url, limit }) = &cli.command {
+        return saga.backfill(url, *limit, &run_options).map(|_| ());
+    }
 
-    let email = Message::builder()
-        .from(config.email.from.parse()?)
-        .to(config.email.to.parse()?)
-        .singlepart(
-            SinglePart::builder()
-                .header(header::ContentType::parse("application/epub+zip").unwrap())
-                .header(header::ContentDisposition::attachment(epub_name))
-                .body(epub_content),
-        )?;
-    let creds = Credentials::new(config.email.username.clone(), config.email.password.clone());
-    let mailer = SmtpTransport::relay(&config.email.relay)?
-        .credentials(creds)
-        .build();
+    if let Some(Command::Explain { url, format }) = &cli.command {
+        return saga.explain(url, *format, &run_options);
+    }
 
-    match mailer.send(&email) {
-        Ok(_) => info!("Email sent successfully!"),
-        Err(e) => error!("Could not send email: {:?}", e),
-    };
+    if let Some(Command::Status { format }) = cli.command {
+        return saga.show_status(format);
+    }
 
-    Ok(())
-}
+    if let Some(Command::Toc) = cli.command {
+        let options = RunOptions {
+            toc_only: true,
+            ..run_options
+        };
+        return saga.run_once(&options);
+    }
 
-fn get_db_conn() -> Result<Connection> {
-    let mut db_path = std::env::current_dir()?;
-    db_path.push("database.db3");
-    let conn = Connection::open(&db_path)?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS feeds (
-            url TEXT PRIMARY KEY,
-            last_processed INTEGER
-        )",
-        [],
-    )?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS entries (
-            id TEXT PRIMARY KEY
-        )",
-        [],
-    )?;
-    info!("Openned connection at path: {:?}", db_path);
-    Ok(conn)
-}
+    // run ignores the daemon flag so it always processes once and exits
+    if let Some(Command::Run) = cli.command {
+        return saga.run_once(&run_options);
+    }
 
-fn get_feed_last_processed(conn: &Connection, url: &String) -> Result<Option<DateTime<Utc>>> {
-    let last_processed = match conn
-        .query_row(
-            "SELECT last_processed FROM feeds WHERE url = ?1",
-            params![url],
-            |row| row.get(0),
-        )
-        .optional()?
-    {
-        Some(last_processed) => Some(
-            DateTime::from_timestamp_millis(last_processed)
-                .ok_or(anyhow!("couldn't parse last_processed"))?,
-        ),
-        None => None,
-    };
-    Ok(last_processed)
+    match cli.daemon {
+        // logged as well so the reason shows up alongside the daemon's
+        // own logs under a supervisor
+        true => saga
+            .run_daemon(cli.config.as_deref(), &run_options)
+            .inspect_err(|e| error!("Daemon stopped: {:#}", e)),
+        false => saga.run_once(&run_options),
+    }
 }
 
-fn is_entry_already_processed(conn: &Connection, id: &String) -> rusqlite::Result<bool> {
-    conn.query_row(
-        "SELECT count(*) FROM entries WHERE id = ?1",
-        params![id],
-        |row| row.get(0).map(|x: i64| x > 0),
-    )
+// feeds are added to and removed from the explicit config, or the
+// ./config.yml layer since that is the one the user works with
+fn editable_config_path(cli: &Cli) -> Result<PathBuf> {
+    let path = cli.config.as_deref().unwrap_or(Path::new("config.yml"));
+    Ok(std::env::current_dir()?.join(path))
 }
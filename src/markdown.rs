@@ -0,0 +1,226 @@
+use anyhow::Result;
+use markup5ever_rcdom::{Handle, NodeData};
+
+use crate::{DisplayEntry, html};
+
+pub fn generate_markdown(entries: &[DisplayEntry]) -> Result<Vec<u8>> {
+    let mut output = String::new();
+    for entry in entries {
+        output.push_str(&format!("# {}\n\n", entry.title));
+
+        let mut byline = vec![format!("*{}*", entry.feed_title)];
+        if !entry.authors.is_empty() {
+            byline.push(format!("by {}", entry.authors.join(", ")));
+        }
+        if let Some(published) = entry.published {
+            byline.push(published.format("%B %-d, %Y").to_string());
+        }
+        output.push_str(&byline.join(" · "));
+        output.push_str("\n\n");
+
+        output.push_str(&html_to_markdown(&entry.content)?);
+        output.push_str("\n\n");
+    }
+    Ok(output.into_bytes())
+}
+
+pub fn html_to_markdown(content: &str) -> Result<String> {
+    let dom = html::parse(content)?;
+
+    let markdown = match html::elements(&dom.document, "body").first() {
+        Some(body) => render_children(body),
+        None => render_children(&dom.document),
+    };
+    Ok(tidy(&markdown))
+}
+
+// collapse the runs of blank lines left behind by nested blocks
+fn tidy(markdown: &str) -> String {
+    let mut output = String::new();
+    let mut blank = true;
+    for line in markdown.lines() {
+        if line.trim().is_empty() {
+            if !blank {
+                output.push('\n');
+            }
+            blank = true;
+        } else {
+            output.push_str(line);
+            output.push('\n');
+            blank = false;
+        }
+    }
+    output.trim().to_string()
+}
+
+fn render_children(handle: &Handle) -> String {
+    handle.children.borrow().iter().map(render_node).collect()
+}
+
+fn render_node(handle: &Handle) -> String {
+    let name = match &handle.data {
+        NodeData::Text { contents } => return collapse_whitespace(&contents.borrow()),
+        NodeData::Element { name, .. } => name.local.to_string(),
+        _ => return String::new(),
+    };
+
+    match name.as_str() {
+        "head" | "script" | "style" => String::new(),
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = name[1..].parse().unwrap_or(1);
+            format!(
+                "\n\n{} {}\n\n",
+                "#".repeat(level),
+                render_children(handle).trim()
+            )
+        }
+        "br" => "  \n".to_string(),
+        "hr" => "\n\n---\n\n".to_string(),
+        "strong" | "b" => wrap_inline(handle, "**"),
+        "em" | "i" => wrap_inline(handle, "*"),
+        "code" => format!("`{}`", html::text_content(handle)),
+        "pre" => format!(
+            "\n\n```{}\n{}\n```\n\n",
+            html::code_language(handle).unwrap_or_default(),
+            html::text_content(handle).trim_end()
+        ),
+        "a" => {
+            let text = render_children(handle);
+            match html::get_attr(handle, "href") {
+                Some(href) if !text.trim().is_empty() => format!("[{}]({})", text.trim(), href),
+                _ => text,
+            }
+        }
+        "img" => match html::get_attr(handle, "src") {
+            Some(src) => format!(
+                "![{}]({})",
+                html::get_attr(handle, "alt").unwrap_or_default(),
+                src
+            ),
+            None => String::new(),
+        },
+        "ul" | "ol" => render_list(handle, name == "ol"),
+        "blockquote" => {
+            let quoted: Vec<String> = tidy(&render_children(handle))
+                .lines()
+                .map(|x| format!("> {}", x).trim_end().to_string())
+                .collect();
+            format!("\n\n{}\n\n", quoted.join("\n"))
+        }
+        "p" | "div" | "section" | "article" | "figure" | "figcaption" | "table" | "tr" => {
+            format!("\n\n{}\n\n", render_children(handle).trim())
+        }
+        _ => render_children(handle),
+    }
+}
+
+// keeps a single space at the edges so words either side of inline
+// elements stay apart
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::new();
+    let mut in_space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_space {
+                collapsed.push(' ');
+            }
+            in_space = true;
+        } else {
+            collapsed.push(c);
+            in_space = false;
+        }
+    }
+    collapsed
+}
+
+fn wrap_inline(handle: &Handle, marker: &str) -> String {
+    let text = render_children(handle);
+    if text.trim().is_empty() {
+        return text;
+    }
+    format!("{}{}{}", marker, text.trim(), marker)
+}
+
+fn render_list(handle: &Handle, ordered: bool) -> String {
+    let items: Vec<String> = handle
+        .children
+        .borrow()
+        .iter()
+        .filter(|x| html::is_element(x, "li"))
+        .enumerate()
+        .map(|(i, item)| {
+            let marker = if ordered {
+                format!("{}. ", i + 1)
+            } else {
+                "- ".to_string()
+            };
+            let indent = " ".repeat(marker.len());
+            let body = render_children(item);
+            let mut lines = body.trim().lines().filter(|x| !x.trim().is_empty());
+            let mut rendered = format!("{}{}", marker, lines.next().unwrap_or_default().trim_end());
+            for line in lines {
+                rendered.push_str(&format!("\n{}{}", indent, line.trim_end()));
+            }
+            rendered
+        })
+        .collect();
+    format!("\n\n{}\n\n", items.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markdown(content: &str) -> String {
+        html_to_markdown(content).unwrap()
+    }
+
+    #[test]
+    fn headings_keep_their_level() {
+        assert_eq!(
+            markdown("<h1>Title</h1><p>Intro</p><h3> Sub <em>part</em> </h3>"),
+            "# Title\n\nIntro\n\n### Sub *part*"
+        );
+    }
+
+    #[test]
+    fn links_and_images_are_inlined() {
+        assert_eq!(
+            markdown(
+                r#"<p>See <a href="https://example.com/a">the post</a> and <img src="https://example.com/i.png" alt="a chart"></p>"#
+            ),
+            "See [the post](https://example.com/a) and ![a chart](https://example.com/i.png)"
+        );
+        // a link without text keeps nothing rather than an empty link
+        assert_eq!(markdown(r#"<a href="https://example.com/a"> </a>"#), "");
+    }
+
+    #[test]
+    fn lists_are_numbered_or_bulleted() {
+        assert_eq!(
+            markdown(
+                "<ul><li>One</li><li>Two <b>bold</b></li></ul><ol><li>First</li><li>Second</li></ol>"
+            ),
+            "- One\n- Two **bold**\n\n1. First\n2. Second"
+        );
+    }
+
+    #[test]
+    fn nested_lists_are_indented() {
+        assert_eq!(
+            markdown("<ol><li>Parent<ul><li>Child</li></ul></li></ol>"),
+            "1. Parent\n   - Child"
+        );
+    }
+
+    #[test]
+    fn code_blocks_are_fenced_with_their_language() {
+        assert_eq!(
+            markdown(
+                "<pre><code class=\"language-rust\">fn main() {\n    run();\n}\n</code></pre>"
+            ),
+            "```rust\nfn main() {\n    run();\n}\n```"
+        );
+        assert_eq!(markdown("<pre>plain</pre>"), "```\nplain\n```");
+    }
+}
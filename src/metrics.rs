@@ -0,0 +1,229 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::notify::RunReport;
+
+/// Counters and gauges of the daemon's runs, served in the Prometheus
+/// text format when metrics_port is set
+#[derive(Debug, Default)]
+pub struct Metrics {
+    feeds: usize,
+    runs: u64,
+    failed_runs: u64,
+    entries_delivered: u64,
+    emails_sent: u64,
+    emails_failed: u64,
+    last_run: Option<DateTime<Utc>>,
+    last_success: Option<DateTime<Utc>>,
+}
+
+impl Metrics {
+    pub fn set_feeds(&mut self, feeds: usize) {
+        self.feeds = feeds;
+    }
+
+    /// Count a finished run
+    pub fn record_run(&mut self, report: &RunReport, result: &Result<()>) {
+        let now = Utc::now();
+        self.runs += 1;
+        self.entries_delivered += report.entries_delivered as u64;
+        self.emails_sent += report.emails_sent as u64;
+        self.emails_failed += report.emails_failed as u64;
+        self.last_run = Some(now);
+        match result {
+            Ok(_) => self.last_success = Some(now),
+            Err(_) => self.failed_runs += 1,
+        }
+    }
+
+    fn render(&self) -> String {
+        let timestamp = |x: Option<DateTime<Utc>>| x.map_or(0, |x| x.timestamp());
+        let metrics: [(&str, &str, &str, i64); 8] = [
+            (
+                "saga_feeds",
+                "gauge",
+                "Configured sources",
+                self.feeds as i64,
+            ),
+            (
+                "saga_runs_total",
+                "counter",
+                "Runs finished",
+                self.runs as i64,
+            ),
+            (
+                "saga_run_failures_total",
+                "counter",
+                "Runs that ended in an error",
+                self.failed_runs as i64,
+            ),
+            (
+                "saga_entries_delivered_total",
+                "counter",
+                "Entries delivered",
+                self.entries_delivered as i64,
+            ),
+            (
+                "saga_emails_sent_total",
+                "counter",
+                "Emails sent",
+                self.emails_sent as i64,
+            ),
+            (
+                "saga_email_failures_total",
+                "counter",
+                "Emails that could not be sent",
+                self.emails_failed as i64,
+            ),
+            (
+                "saga_last_run_timestamp_seconds",
+                "gauge",
+                "When the last run finished, 0 before the first",
+                timestamp(self.last_run),
+            ),
+            (
+                "saga_last_success_timestamp_seconds",
+                "gauge",
+                "When the last successful run finished, 0 before the first",
+                timestamp(self.last_success),
+            ),
+        ];
+        let mut body = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(body, "# HELP {} {}", name, help);
+            let _ = writeln!(body, "# TYPE {} {}", name, kind);
+            let _ = writeln!(body, "{} {}", name, value);
+        }
+        body
+    }
+}
+
+/// Serve the metrics at /metrics on the address and port from a
+/// background thread. Binding happens before returning so a port in use
+/// fails the daemon's start.
+pub fn serve(addr: IpAddr, port: u16, metrics: Arc<Mutex<Metrics>>) -> Result<()> {
+    let listener = TcpListener::bind((addr, port))?;
+    info!(
+        "Serving metrics at http://{}/metrics",
+        listener.local_addr()?
+    );
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| respond(stream, &metrics));
+            if let Err(e) = result {
+                warn!("Could not serve metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// scrapes are tiny and rare so one connection is handled at a time, and
+// a client that stops reading or writing can't hold up the next one
+fn respond(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", metrics.lock().unwrap().render()),
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::io::Read;
+
+    // a port that was free a moment ago
+    fn free_port() -> u16 {
+        TcpListener::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn get(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn runs_are_counted() {
+        let mut metrics = Metrics::default();
+        metrics.set_feeds(3);
+        let report = RunReport {
+            entries_delivered: 4,
+            emails_sent: 1,
+            ..Default::default()
+        };
+
+        metrics.record_run(&report, &Ok(()));
+        metrics.record_run(&RunReport::default(), &Err(anyhow!("smtp down")));
+
+        let body = metrics.render();
+        for line in [
+            "saga_feeds 3",
+            "saga_runs_total 2",
+            "saga_run_failures_total 1",
+            "saga_entries_delivered_total 4",
+            "saga_emails_sent_total 1",
+            "saga_email_failures_total 0",
+        ] {
+            assert!(body.lines().any(|x| x == line), "{}\n{}", line, body);
+        }
+        assert!(body.contains("# TYPE saga_runs_total counter\n"));
+        assert!(!body.contains("saga_last_run_timestamp_seconds 0\n"));
+    }
+
+    #[test]
+    fn metrics_are_served_at_their_path() {
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        metrics.lock().unwrap().set_feeds(2);
+        let port = free_port();
+
+        serve(IpAddr::from([127, 0, 0, 1]), port, Arc::clone(&metrics)).unwrap();
+
+        let response = get(port, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        for name in [
+            "saga_feeds",
+            "saga_runs_total",
+            "saga_run_failures_total",
+            "saga_entries_delivered_total",
+            "saga_emails_sent_total",
+            "saga_email_failures_total",
+            "saga_last_run_timestamp_seconds",
+            "saga_last_success_timestamp_seconds",
+        ] {
+            assert!(response.contains(&format!("# HELP {} ", name)), "{}", name);
+        }
+        assert!(response.contains("\nsaga_feeds 2\n"), "{}", response);
+        assert!(get(port, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,52 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_json::json;
+use std::time::Duration;
+
+use crate::http::HttpConfig;
+
+/// What a run got done, reported to the notify_url once it ends
+#[derive(Debug, Default)]
+pub struct RunReport {
+    pub feeds_processed: usize,
+    pub entries_delivered: usize,
+    pub emails_sent: usize,
+    pub emails_failed: usize,
+    /// Feeds that failed with their errors, and feeds that had nothing
+    /// new, for the run report chapter
+    pub failed_feeds: Vec<(String, String)>,
+    pub quiet_feeds: Vec<String>,
+    /// How often fetched feeds asked to be polled, for the daemon to
+    /// schedule them no more often than that
+    pub poll_intervals: Vec<(String, chrono::Duration)>,
+}
+
+/// Post a summary of the run, a failure to notify is only logged so it
+/// never fails the run itself
+pub fn notify_run(
+    url: &str,
+    http: &HttpConfig,
+    timeout: Duration,
+    started_at: DateTime<Utc>,
+    report: &RunReport,
+    result: &Result<()>,
+) {
+    let payload = json!({
+        "started_at": started_at.to_rfc3339(),
+        "finished_at": Utc::now().to_rfc3339(),
+        "feeds_processed": report.feeds_processed,
+        "entries_delivered": report.entries_delivered,
+        "success": result.is_ok(),
+        "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+    });
+    let sent = http
+        .client()
+        .and_then(|x| Ok(x.timeout(timeout).build()?))
+        .and_then(|client| http.send(client.post(url).json(&payload)))
+        .and_then(|resp| Ok(resp.error_for_status()?));
+    match sent {
+        Ok(_) => info!("Notified {} of the run", url),
+        Err(e) => warn!("Could not notify {} of the run: {}", url, e),
+    }
+}
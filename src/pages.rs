@@ -0,0 +1,95 @@
+use anyhow::{Result, anyhow};
+use log::info;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{Config, DisplayEntry, FeedConfig, content_hash, html, parse_content};
+
+/// A page from a blog without a feed, delivered whenever its text changes
+#[derive(Deserialize, Debug)]
+pub struct PageConfig {
+    pub url: String,
+    /// Css selector of the article on the page, e.g. "article.post",
+    /// guessed from the page's article or main element when unset
+    pub selector: Option<String>,
+}
+
+/// Fetch a page and extract its article as an entry. The id includes a
+/// hash of the text so the page is only picked again once it changes.
+/// None when the extracted content is left out of the run.
+pub fn fetch_page_entry(
+    config: &Config,
+    client: &Client,
+    page: &PageConfig,
+) -> Result<Option<DisplayEntry>> {
+    info!("Fetching page: {}", page.url);
+    let resp = config
+        .http
+        .send(client.get(&page.url))?
+        .error_for_status()?;
+    let body = resp.text()?;
+    let dom = html::parse(&body)?;
+
+    let title = html::elements(&dom.document, "title")
+        .into_iter()
+        .chain(html::elements(&dom.document, "h1"))
+        .map(|x| {
+            html::text_content(&x)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .find(|x| !x.is_empty())
+        .unwrap_or_else(|| page.url.clone());
+    let site = Url::parse(&page.url)?
+        .host_str()
+        .map_or(page.url.clone(), String::from);
+
+    let main = match &page.selector {
+        Some(selector) => html::select_first(&dom.document, selector).ok_or(anyhow!(
+            "selector {:?} matched nothing on {}",
+            selector,
+            page.url
+        ))?,
+        None => {
+            html::main_content(&dom.document).ok_or(anyhow!("no content found on {}", page.url))?
+        }
+    };
+    let content_dom = html::parse("")?;
+    if let Some(body) = html::elements(&content_dom.document, "body").first() {
+        let children = std::mem::take(&mut *main.children.borrow_mut());
+        html::append_children(body, children);
+    }
+    let content = html::serialize(&content_dom.document)?;
+
+    // pages have the same settings as a feed with only its url set
+    let feed_conf = FeedConfig::from_url(&page.url, false)?;
+    let Some(parsed) = parse_content(config, &feed_conf, &title, Some(page.url.clone()), &content)?
+    else {
+        return Ok(None);
+    };
+
+    let mut entry = DisplayEntry {
+        id: String::new(),
+        feed_url: page.url.clone(),
+        feed_title: site,
+        title,
+        authors: vec![],
+        published: None,
+        updated: None,
+        position: 0,
+        link: Some(page.url.clone()),
+        content: parsed.content,
+        sections: parsed.sections,
+        images: parsed.images,
+        language: None,
+        enclosures: vec![],
+        feed_icon: None,
+        merged_ids: vec![],
+        aliases: vec![],
+    };
+    let hash = content_hash(&entry).ok_or(anyhow!("page {} has no text", page.url))?;
+    entry.id = format!("{}#{}", page.url, &hash[..16]);
+    Ok(Some(entry))
+}
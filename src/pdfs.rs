@@ -0,0 +1,136 @@
+use anyhow::{Result, anyhow};
+use log::info;
+use pdf_extract::{Document, decode_text_string};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::{Config, DisplayEntry, FeedConfig, parse_content, plain_text_to_html};
+
+/// A pdf, e.g. a paper, delivered once as a chapter of its text
+#[derive(Deserialize, Debug)]
+pub struct PdfConfig {
+    /// Local path or http(s) url of the pdf
+    pub path: String,
+}
+
+impl PdfConfig {
+    pub fn is_url(&self) -> bool {
+        self.path.starts_with("http://") || self.path.starts_with("https://")
+    }
+}
+
+/// Read a pdf and turn its text into an entry titled with the pdf's own
+/// title, falling back to the file name. None when the text is left out
+/// of the run.
+pub fn read_pdf_entry(
+    config: &Config,
+    client: &Client,
+    pdf: &PdfConfig,
+) -> Result<Option<DisplayEntry>> {
+    info!("Reading pdf: {}", pdf.path);
+    let data = match pdf.is_url() {
+        true => config
+            .http
+            .send(client.get(&pdf.path))?
+            .error_for_status()?
+            .bytes()?
+            .to_vec(),
+        false => fs::read(&pdf.path).map_err(|e| anyhow!("could not read {}: {}", pdf.path, e))?,
+    };
+
+    let pages = pdf_extract::extract_text_from_mem_by_pages(&data)
+        .map_err(|e| anyhow!("could not extract text from {}: {}", pdf.path, e))?;
+    let text = paragraphs(&pages.join("\n\n"));
+    if text.is_empty() {
+        return Err(anyhow!("pdf {} has no text, it may be scanned", pdf.path));
+    }
+
+    let name = Path::new(pdf.path.trim_end_matches('/'))
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .unwrap_or(&pdf.path)
+        .to_string();
+    let title = pdf_title(&data).unwrap_or(name);
+
+    let feed_conf = FeedConfig::from_url(&pdf.path, false)?;
+    let link = pdf.is_url().then(|| pdf.path.clone());
+    let content = plain_text_to_html(&text);
+    let Some(parsed) = parse_content(config, &feed_conf, &title, link.clone(), &content)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(DisplayEntry {
+        id: pdf.path.clone(),
+        feed_url: pdf.path.clone(),
+        feed_title: String::from("PDF"),
+        title,
+        authors: vec![],
+        published: None,
+        updated: None,
+        position: 0,
+        link,
+        content: parsed.content,
+        sections: parsed.sections,
+        images: parsed.images,
+        language: None,
+        enclosures: vec![],
+        feed_icon: None,
+        merged_ids: vec![],
+        aliases: vec![],
+    }))
+}
+
+// the Title from the document info, which many generators leave empty
+fn pdf_title(data: &[u8]) -> Option<String> {
+    let document = Document::load_mem(data).ok()?;
+    let info = document.trailer.get(b"Info").ok()?;
+    let (_, info) = document.dereference(info).ok()?;
+    let title = decode_text_string(info.as_dict().ok()?.get(b"Title").ok()?).ok()?;
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!title.is_empty()).then_some(title)
+}
+
+// Extracted text breaks lines wherever the pdf did, so lines are joined
+// back into paragraphs at blank lines, rejoining words hyphenated across
+// a line break
+fn paragraphs(text: &str) -> String {
+    let mut paragraphs = vec![];
+    let mut current = String::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+        match current.strip_suffix('-') {
+            Some(hyphenated) if line.starts_with(|c: char| c.is_lowercase()) => {
+                current = hyphenated.to_string();
+            }
+            _ if !current.is_empty() => current.push(' '),
+            _ => {}
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs.join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paragraphs_rejoin_broken_lines() {
+        assert_eq!(
+            paragraphs(
+                "A line broken\n  across the page and hyph-\nenated.\n\n\nNext Para-\nGraph"
+            ),
+            "A line broken across the page and hyphenated.\n\nNext Para- Graph"
+        );
+    }
+}
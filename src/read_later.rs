@@ -0,0 +1,159 @@
+use anyhow::{Result, anyhow};
+use log::{error, info, warn};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::DisplayEntry;
+use crate::http::HttpConfig;
+
+/// Read-later service that picked entries are pushed to
+#[derive(Deserialize, Debug)]
+#[serde(tag = "service", rename_all = "lowercase")]
+pub enum ReadLaterConfig {
+    Pocket {
+        consumer_key: String,
+        access_token: String,
+    },
+    Wallabag {
+        url: String,
+        client_id: String,
+        client_secret: String,
+        username: String,
+        password: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct WallabagToken {
+    access_token: String,
+}
+
+pub fn push_entries(config: &ReadLaterConfig, http: &HttpConfig, entries: &[DisplayEntry]) {
+    let client = match http.client().and_then(|x| Ok(x.build()?)) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Could not create read-later client: {}", e);
+            return;
+        }
+    };
+    match config {
+        ReadLaterConfig::Pocket {
+            consumer_key,
+            access_token,
+        } => save_each(entries, |link, title| {
+            add_to_pocket(&client, http, consumer_key, access_token, link, title)
+        }),
+        ReadLaterConfig::Wallabag {
+            url,
+            client_id,
+            client_secret,
+            username,
+            password,
+        } => {
+            let token = match get_wallabag_token(
+                &client,
+                http,
+                url,
+                client_id,
+                client_secret,
+                username,
+                password,
+            ) {
+                Ok(token) => token,
+                Err(e) => {
+                    error!("Could not authenticate with wallabag: {}", e);
+                    return;
+                }
+            };
+            save_each(entries, |link, title| {
+                add_to_wallabag(&client, http, url, &token, link, title)
+            })
+        }
+    }
+}
+
+// Errors are logged per entry so one rejected article doesn't fail the run
+fn save_each(entries: &[DisplayEntry], save: impl Fn(&str, &str) -> Result<()>) {
+    for entry in entries {
+        let Some(link) = &entry.link else {
+            warn!("Entry {} has no link to save for later", entry.title);
+            continue;
+        };
+        match save(link, &entry.title) {
+            Ok(_) => info!("Saved entry {} for later", entry.title),
+            Err(e) => error!("Could not save entry {} for later: {}", entry.title, e),
+        }
+    }
+}
+
+fn add_to_pocket(
+    client: &Client,
+    http: &HttpConfig,
+    consumer_key: &str,
+    access_token: &str,
+    link: &str,
+    title: &str,
+) -> Result<()> {
+    let resp = http.send(
+        client
+            .post("https://getpocket.com/v3/add")
+            .header("X-Accept", "application/json")
+            .json(&json!({
+                "url": link,
+                "title": title,
+                "consumer_key": consumer_key,
+                "access_token": access_token,
+            })),
+    )?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("pocket responded with {}", resp.status()));
+    }
+    Ok(())
+}
+
+fn get_wallabag_token(
+    client: &Client,
+    http: &HttpConfig,
+    url: &str,
+    client_id: &str,
+    client_secret: &str,
+    username: &str,
+    password: &str,
+) -> Result<String> {
+    let resp = http.send(
+        client
+            .post(format!("{}/oauth/v2/token", url.trim_end_matches('/')))
+            .form(&[
+                ("grant_type", "password"),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("username", username),
+                ("password", password),
+            ]),
+    )?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("wallabag responded with {}", resp.status()));
+    }
+    Ok(resp.json::<WallabagToken>()?.access_token)
+}
+
+fn add_to_wallabag(
+    client: &Client,
+    http: &HttpConfig,
+    url: &str,
+    token: &str,
+    link: &str,
+    title: &str,
+) -> Result<()> {
+    let resp = http.send(
+        client
+            .post(format!("{}/api/entries.json", url.trim_end_matches('/')))
+            .bearer_auth(token)
+            .json(&json!({ "url": link, "title": title })),
+    )?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("wallabag responded with {}", resp.status()));
+    }
+    Ok(())
+}
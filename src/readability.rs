@@ -0,0 +1,88 @@
+// Rough Flesch-Kincaid grade level over plain text. Syllables are counted as
+// vowel groups which is crude but good enough to tell light reads from dense ones
+
+pub fn flesch_kincaid_grade(text: &str) -> Option<f64> {
+    let words: Vec<&str> = text
+        .split_whitespace()
+        .filter(|x| x.chars().any(|c| c.is_alphabetic()))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+
+    let sentences = text
+        .split(['.', '!', '?'])
+        .filter(|x| x.chars().any(|c| c.is_alphabetic()))
+        .count()
+        .max(1);
+    let syllables: usize = words.iter().map(|x| count_syllables(x)).sum();
+
+    let words_per_sentence = words.len() as f64 / sentences as f64;
+    let syllables_per_word = syllables as f64 / words.len() as f64;
+    Some(0.39 * words_per_sentence + 11.8 * syllables_per_word - 15.59)
+}
+
+fn count_syllables(word: &str) -> usize {
+    let word: Vec<char> = word
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    let is_vowel = |c: &char| "aeiouy".contains(*c);
+
+    let mut count = 0;
+    let mut previous_vowel = false;
+    for c in &word {
+        let vowel = is_vowel(c);
+        if vowel && !previous_vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+
+    // a trailing e is usually silent as in "make"
+    if word.len() > 2 && word.ends_with(&['e']) && !is_vowel(&word[word.len() - 2]) {
+        count -= 1;
+    }
+    count.max(1)
+}
+
+// words a minute assumed for the reading time
+const READING_SPEED: usize = 200;
+
+pub fn word_count(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|x| x.chars().any(|c| c.is_alphanumeric()))
+        .count()
+}
+
+/// Estimated reading time like "~3 min read", anything under a minute is
+/// "<1 min read"
+pub fn reading_time(words: usize) -> String {
+    let minutes = (words + READING_SPEED / 2) / READING_SPEED;
+    match minutes {
+        0 => String::from("<1 min read"),
+        minutes => format!("~{} min read", minutes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_time_rounds_to_the_nearest_minute() {
+        assert_eq!(reading_time(0), "<1 min read");
+        assert_eq!(reading_time(99), "<1 min read");
+        assert_eq!(reading_time(100), "~1 min read");
+        assert_eq!(reading_time(299), "~1 min read");
+        assert_eq!(reading_time(300), "~2 min read");
+        assert_eq!(reading_time(2000), "~10 min read");
+    }
+
+    #[test]
+    fn word_count_skips_punctuation() {
+        assert_eq!(word_count(""), 0);
+        assert_eq!(word_count("  Hello,  world — again!\n"), 3);
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{Result, anyhow};
+use log::{info, warn};
+use markup5ever_rcdom::RcDom;
+use serde_yml::{Mapping, Value};
+use std::fs;
+use std::path::Path;
+use tendril::TendrilSink;
+use xml5ever::driver::{XmlParseOpts, parse_document};
+
+use crate::html;
+
+// Feeds are edited on the raw yaml rather than the Config struct so
+// fields saga doesn't know about, and defaults left unset, are kept as-is.
+// Comments in the file are lost when it is rewritten.
+
+pub fn add_feed(path: &Path, url: &str, random: bool) -> Result<()> {
+    let mut config = read_config(path)?;
+    let feeds = feeds_mut(&mut config, path)?;
+    if feeds.iter().any(|x| feed_url(x) == Some(url)) {
+        return Err(anyhow!("{} is already in {:?}", url, path));
+    }
+
+    feeds.push(new_feed(url, random));
+
+    write_config(path, &config)?;
+    info!("Added feed {} to {:?}", url, path);
+    Ok(())
+}
+
+pub fn remove_feed(path: &Path, url: &str) -> Result<()> {
+    let mut config = read_config(path)?;
+    let feeds = feeds_mut(&mut config, path)?;
+    let count = feeds.len();
+    feeds.retain(|x| feed_url(x) != Some(url));
+    if feeds.len() == count {
+        return Err(anyhow!("{} is not in {:?}", url, path));
+    }
+
+    write_config(path, &config)?;
+    info!("Removed feed {} from {:?}", url, path);
+    Ok(())
+}
+
+/// Add every feed in an OPML file that isn't already configured.
+/// Returns the number of feeds added.
+pub fn import_opml(path: &Path, opml_path: &Path) -> Result<usize> {
+    let opml = fs::read_to_string(opml_path)?;
+    let dom = parse_document(RcDom::default(), XmlParseOpts::default())
+        .from_utf8()
+        .read_from(&mut opml.as_bytes())?;
+
+    let mut config = read_config(path)?;
+    let feeds = feeds_mut(&mut config, path)?;
+    let mut added = 0;
+    for outline in html::elements(&dom.document, "outline") {
+        // folders are outlines too, only the ones with a feed url count
+        let Some(url) = html::get_attr(&outline, "xmlUrl") else {
+            continue;
+        };
+        if feeds.iter().any(|x| feed_url(x) == Some(url.as_str())) {
+            warn!("Skipping {} which is already configured", url);
+            continue;
+        }
+        feeds.push(new_feed(&url, false));
+        added += 1;
+    }
+
+    write_config(path, &config)?;
+    info!(
+        "Imported {} feeds from {:?} into {:?}",
+        added, opml_path, path
+    );
+    Ok(added)
+}
+
+pub fn export_opml(opml_path: &Path, urls: &[&str]) -> Result<()> {
+    let outlines: String = urls
+        .iter()
+        .map(|url| {
+            let url = html::escape_xml(url);
+            format!(
+                "    <outline type=\"rss\" text=\"{}\" xmlUrl=\"{}\"/>\n",
+                url, url
+            )
+        })
+        .collect();
+    let opml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <opml version=\"2.0\">\n  <head>\n    <title>Saga feeds</title>\n  </head>\n  \
+        <body>\n{}  </body>\n</opml>\n",
+        outlines
+    );
+    fs::write(opml_path, opml)?;
+    info!("Exported {} feeds to {:?}", urls.len(), opml_path);
+    Ok(())
+}
+
+fn new_feed(url: &str, random: bool) -> Value {
+    let mut feed = Mapping::new();
+    feed.insert(Value::from("url"), Value::from(url));
+    feed.insert(Value::from("random"), Value::from(random));
+    Value::Mapping(feed)
+}
+
+fn read_config(path: &Path) -> Result<Value> {
+    if !path.exists() {
+        return Ok(Value::Mapping(Mapping::new()));
+    }
+    let config_str = fs::read_to_string(path)?;
+    let config: Value = serde_yml::from_str(&config_str)
+        .map_err(|e| anyhow!("invalid config at {:?}: {}", path, e))?;
+    match config {
+        Value::Null => Ok(Value::Mapping(Mapping::new())),
+        config => Ok(config),
+    }
+}
+
+fn write_config(path: &Path, config: &Value) -> Result<()> {
+    fs::write(path, serde_yml::to_string(config)?)?;
+    Ok(())
+}
+
+fn feeds_mut<'a>(config: &'a mut Value, path: &Path) -> Result<&'a mut Vec<Value>> {
+    let mapping = config
+        .as_mapping_mut()
+        .ok_or(anyhow!("config at {:?} is not a mapping", path))?;
+    let feeds = mapping
+        .entry(Value::from("rss"))
+        .or_insert_with(|| Value::Sequence(vec![]));
+    feeds
+        .as_sequence_mut()
+        .ok_or(anyhow!("rss in {:?} is not a list", path))
+}
+
+fn feed_url(feed: &Value) -> Option<&str> {
+    feed.get("url").and_then(|x| x.as_str())
+}
@@ -0,0 +1,241 @@
+use anyhow::{Result, anyhow};
+use lettre::transport::smtp::client::{Certificate, TlsParameters};
+use native_tls::TlsStream;
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyParam;
+use openssl::x509::{X509, X509StoreContext};
+use reqwest::blocking::ClientBuilder;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{WebPkiSupportedAlgorithms, ring};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+
+/// Extra trust settings for https connections to feeds and the smtp relay,
+/// for environments with an internal CA or a TLS-intercepting proxy
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM bundle of root certificates trusted alongside the system store
+    pub ca_file: Option<String>,
+    /// SHA-256 fingerprints of the certificate each host must present,
+    /// connections to these hosts fail on any other certificate
+    pub pins: HashMap<String, String>,
+}
+
+impl TlsConfig {
+    pub fn http_client(&self) -> Result<ClientBuilder> {
+        // native tls has no hook into the handshake, so with pins the
+        // certificate is verified by rustls and checked before anything is
+        // sent over the connection, redirect hops included
+        if !self.pins.is_empty() {
+            return Ok(ClientBuilder::new().use_preconfigured_tls(self.rustls_config()?));
+        }
+        let mut builder = ClientBuilder::new();
+        for pem in self.ca_certificates()? {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(builder)
+    }
+
+    pub fn smtp_parameters(&self, relay: &str) -> Result<TlsParameters> {
+        let mut builder = TlsParameters::builder(relay.to_string());
+        for pem in self.ca_certificates()? {
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+        Ok(builder.build()?)
+    }
+
+    pub fn pins_host(&self, host: &str) -> bool {
+        self.pin_for(host).is_some()
+    }
+
+    /// Check the certificate an established connection presented against
+    /// any pin for the host
+    pub fn check_peer(&self, host: &str, der: &[u8]) -> Result<()> {
+        match self.pin_for(host) {
+            Some(pin) => check_pin(host, pin, der),
+            None => Ok(()),
+        }
+    }
+
+    /// Start tls over a connected stream, trusting ca_file alongside the
+    /// system store and checking any pin for the host
+    pub fn connect(&self, host: &str, stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+        let mut builder = native_tls::TlsConnector::builder();
+        for pem in self.ca_certificates()? {
+            builder.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+        }
+        let stream = builder
+            .build()?
+            .connect(host, stream)
+            .map_err(|e| anyhow!("tls handshake with {} failed: {}", host, e))?;
+        if let Some(pin) = self.pin_for(host) {
+            let der = stream
+                .peer_certificate()?
+                .ok_or(anyhow!("{} is pinned but presented no certificate", host))?
+                .to_der()?;
+            check_pin(host, pin, &der)?;
+        }
+        Ok(stream)
+    }
+
+    fn rustls_config(&self) -> Result<ClientConfig> {
+        let provider = ring::default_provider();
+        let verifier = PinningVerifier {
+            tls: self.clone(),
+            algorithms: provider.signature_verification_algorithms,
+        };
+        Ok(ClientConfig::builder_with_provider(Arc::new(provider))
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth())
+    }
+
+    // the chain is checked against the same system store and ca_file that
+    // native tls uses so pinning doesn't change what is trusted otherwise
+    fn verify_chain(&self, host: &str, end_entity: &[u8], intermediates: &[&[u8]]) -> Result<()> {
+        let mut store = X509StoreBuilder::new()?;
+        store.set_default_paths()?;
+        for pem in self.ca_certificates()? {
+            store.add_cert(X509::from_pem(&pem)?)?;
+        }
+        let mut param = X509VerifyParam::new()?;
+        match host.parse::<IpAddr>() {
+            Ok(ip) => param.set_ip(ip)?,
+            Err(_) => param.set_host(host)?,
+        }
+        store.set_param(&param)?;
+        let store = store.build();
+
+        let leaf = X509::from_der(end_entity)?;
+        let mut chain = Stack::new()?;
+        for der in intermediates {
+            chain.push(X509::from_der(der)?)?;
+        }
+        let mut context = X509StoreContext::new()?;
+        let result = context.init(&store, &leaf, &chain, |x| {
+            Ok(x.verify_cert()?.then_some(()).ok_or(x.error()))
+        })?;
+        result.map_err(|e| anyhow!("certificate for {} is not trusted: {}", host, e))
+    }
+
+    fn pin_for(&self, host: &str) -> Option<&String> {
+        self.pins
+            .iter()
+            .find(|(pinned, _)| pinned.eq_ignore_ascii_case(host))
+            .map(|(_, pin)| pin)
+    }
+
+    // each certificate in the bundle as its own PEM block since the native
+    // tls certificate types only read the first one
+    fn ca_certificates(&self) -> Result<Vec<Vec<u8>>> {
+        let Some(path) = &self.ca_file else {
+            return Ok(vec![]);
+        };
+        let bundle = fs::read_to_string(path)
+            .map_err(|e| anyhow!("could not read ca_file {}: {}", path, e))?;
+
+        let end_marker = "-----END CERTIFICATE-----";
+        let mut certs = vec![];
+        let mut rest = bundle.as_str();
+        while let Some(start) = rest.find("-----BEGIN CERTIFICATE-----") {
+            let end = rest[start..]
+                .find(end_marker)
+                .ok_or(anyhow!("ca_file {} has an unterminated certificate", path))?
+                + start
+                + end_marker.len();
+            certs.push(rest.as_bytes()[start..end].to_vec());
+            rest = &rest[end..];
+        }
+        if certs.is_empty() {
+            return Err(anyhow!("ca_file {} contains no PEM certificates", path));
+        }
+        Ok(certs)
+    }
+}
+
+/// Verifies the chain like native tls would and then checks the pin, so a
+/// pinned host that presents another certificate never gets the request
+#[derive(Debug)]
+struct PinningVerifier {
+    tls: TlsConfig,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => IpAddr::from(*ip).to_string(),
+            _ => {
+                return Err(rustls::Error::General(String::from(
+                    "unsupported server name",
+                )));
+            }
+        };
+        let intermediates: Vec<&[u8]> = intermediates.iter().map(|x| x.as_ref()).collect();
+        self.tls
+            .verify_chain(&host, end_entity, &intermediates)
+            .and_then(|_| self.tls.check_peer(&host, end_entity))
+            .map_err(|e| rustls::Error::General(e.to_string()))?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+// fingerprints are compared as hex ignoring case and any colons
+fn check_pin(host: &str, pin: &str, der: &[u8]) -> Result<()> {
+    let expected: String = pin
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_lowercase();
+    let actual: String = openssl::sha::sha256(der)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect();
+    if expected != actual {
+        return Err(anyhow!(
+            "certificate for {} does not match its pin, expected {} but got {}",
+            host,
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
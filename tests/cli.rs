@@ -0,0 +1,292 @@
+mod common;
+
+use common::{MockServer, TestDir, feed};
+use saga::{RunOptions, Saga};
+use std::process::Command;
+
+fn saga_command() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_saga"))
+}
+
+#[test]
+fn database_is_created_at_the_given_path() {
+    let dir = TestDir::new();
+    let path = dir.path("nested.db3");
+
+    saga::get_db_conn(Some(&path)).unwrap();
+
+    assert!(path.exists());
+}
+
+#[test]
+fn config_and_database_flags_are_used() {
+    let dir = TestDir::new();
+    let config = dir.path("custom.yml");
+    std::fs::write(
+        &config,
+        "delivery: folder\nfolder: out\nschedule: \"0 0 7 * * *\"\nrss:\n  - url: https://example.com/feed.xml\n    random: false\n",
+    )
+    .unwrap();
+    let database = dir.path("custom.db3");
+
+    let output = saga_command()
+        .current_dir(dir.dir.path())
+        .arg("--config")
+        .arg(&config)
+        .arg("--database")
+        .arg("custom.db3")
+        .arg("list")
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    assert!(database.exists());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("https://example.com/feed.xml (never processed)"));
+}
+
+#[test]
+fn list_and_status_print_json() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let url = server.url("/rss.xml");
+    let database = dir.path("saga.db3");
+    let config = dir.folder_config(&feed(&url), "");
+    Saga::new(config, saga::get_db_conn(Some(&database)).unwrap())
+        .unwrap()
+        .run_once(&RunOptions::default())
+        .unwrap();
+    // a feed added after the run has never been processed
+    dir.folder_config(
+        &format!("{}\n{}", feed(&url), feed("https://example.com/new.xml")),
+        "",
+    );
+    let report = |command: &str| {
+        let output = saga_command()
+            .arg("--config")
+            .arg(dir.path("config.yml"))
+            .arg("--database")
+            .arg(&database)
+            .args([command, "--format", "json"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    let list = report("list");
+    let status = report("status");
+
+    assert_eq!(list[0]["url"], url.as_str());
+    assert!(
+        list[0]["last_processed"]
+            .as_str()
+            .unwrap()
+            .starts_with("20")
+    );
+    assert_eq!(list[1]["url"], "https://example.com/new.xml");
+    assert!(list[1]["last_processed"].is_null());
+
+    assert_eq!(status[0]["url"], url.as_str());
+    assert_eq!(status[0]["state"], "processed");
+    assert_eq!(status[0]["entries_delivered"], 2);
+    assert!(
+        chrono::DateTime::parse_from_rfc3339(status[0]["last_processed"].as_str().unwrap()).is_ok()
+    );
+    assert_eq!(status[1]["state"], "pending");
+    assert_eq!(status[1]["entries_delivered"], 0);
+    assert!(status[1]["last_processed"].is_null());
+}
+
+#[test]
+fn status_shows_the_error_of_a_failing_feed() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let database = dir.path("saga.db3");
+    let missing = server.url("/missing.xml");
+    let config = dir.folder_config(
+        &format!("{}\n{}", feed(&missing), feed(&server.url("/rss.xml"))),
+        "max_retries: 0",
+    );
+    Saga::new(config, saga::get_db_conn(Some(&database)).unwrap())
+        .unwrap()
+        .run_once(&RunOptions::default())
+        .unwrap();
+    let status = |format: &str| {
+        let output = saga_command()
+            .arg("--config")
+            .arg(dir.path("config.yml"))
+            .arg("--database")
+            .arg(&database)
+            .args(["status", "--format", format])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let json: serde_json::Value = serde_json::from_str(&status("json")).unwrap();
+    let text = status("text");
+
+    assert_eq!(json[0]["url"], missing.as_str());
+    assert_eq!(json[0]["last_status"], "error");
+    assert!(
+        json[0]["last_error"].as_str().unwrap().contains("404"),
+        "{}",
+        json[0]
+    );
+    assert_eq!(json[0]["consecutive_failures"], 1);
+    assert_eq!(json[1]["last_status"], "ok");
+    assert!(json[1]["last_error"].is_null());
+    assert!(text.contains("  last run: failed "), "{}", text);
+    assert!(text.contains("  consecutive failures: 1"), "{}", text);
+    assert!(text.contains("  last run: ok "), "{}", text);
+}
+
+#[test]
+fn failing_run_exits_non_zero() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    dir.folder_config(&feed(&server.url("/rss.xml")), "");
+    // the delivery folder can't be created where a file is
+    std::fs::write(dir.out(), b"").unwrap();
+
+    let output = saga_command()
+        .arg("--once")
+        .arg("--config")
+        .arg(dir.path("config.yml"))
+        .arg("--database")
+        .arg(dir.path("saga.db3"))
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Error"), "{}", stderr);
+    assert_eq!(server.hits("/rss.xml"), 1);
+}
+
+#[test]
+fn once_and_daemon_are_exclusive() {
+    let dir = TestDir::new();
+    dir.folder_config(&feed("https://example.com/feed.xml"), "");
+
+    let output = saga_command()
+        .args(["--once", "--daemon", "--config"])
+        .arg(dir.path("config.yml"))
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("cannot be used with"), "{}", stderr);
+}
+
+#[test]
+fn init_writes_a_loadable_config_and_keeps_an_existing_one() {
+    let dir = TestDir::new();
+    let path = dir.path("config.yml");
+    let init = |force: bool| {
+        let mut command = saga_command();
+        command.arg("--config").arg(&path).arg("init");
+        if force {
+            command.arg("--force");
+        }
+        command.output().unwrap()
+    };
+
+    assert!(init(false).status.success());
+    saga::get_config(Some(&path)).unwrap();
+
+    std::fs::write(&path, "# mine\n").unwrap();
+    let refused = init(false);
+    assert!(!refused.status.success());
+    assert!(
+        String::from_utf8(refused.stderr)
+            .unwrap()
+            .contains("already exists, pass --force")
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "# mine\n");
+
+    assert!(init(true).status.success());
+    saga::get_config(Some(&path)).unwrap();
+}
+
+#[test]
+fn explain_matches_what_a_run_picks() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let url = server.url("/rss.xml");
+    let dir = TestDir::new();
+    let database = dir.path("saga.db3");
+    let config = |random: bool| {
+        dir.folder_config(
+            &format!(
+                "  - url: {}\n    random: {}\n    max_entries_per_run: 1",
+                url, random
+            ),
+            "random_seed: 7\nskip_email_when_empty: false",
+        )
+    };
+    let explain = || {
+        let output = saga_command()
+            .arg("--config")
+            .arg(dir.path("config.yml"))
+            .arg("--database")
+            .arg(&database)
+            .args(["explain", &url, "--format", "json"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+    let run = || {
+        let output = saga_command()
+            .arg("--config")
+            .arg(dir.path("config.yml"))
+            .arg("--database")
+            .arg(&database)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        let Some(epub) = dir.delivered().pop() else {
+            panic!("{}", String::from_utf8_lossy(&output.stderr));
+        };
+        let text = common::epub_text(&epub);
+        ["First Post", "Second Post"]
+            .into_iter()
+            .filter(|x| text.contains(x))
+            .collect::<Vec<_>>()
+    };
+
+    config(false);
+    let first = explain();
+    assert_eq!(first["fetched"], 2);
+    assert_eq!(first["candidates"], 2);
+    assert!(first["last_processed"].is_null());
+    assert_eq!(first["branch"], "latest");
+    assert_eq!(first["picked"][0]["title"], "Second Post");
+    assert_eq!(first["picked"].as_array().unwrap().len(), 1);
+    assert_eq!(run(), vec!["Second Post"]);
+
+    let nothing_new = explain();
+    assert_eq!(nothing_new["already_processed"], 1);
+    assert_eq!(nothing_new["newer_than_last_processed"], 0);
+    assert_eq!(nothing_new["branch"], "nothing_new");
+    assert!(nothing_new["picked"].as_array().unwrap().is_empty());
+
+    config(true);
+    let random = explain();
+    assert_eq!(random["branch"], "random_backlog");
+    assert_eq!(random["repeatable"], true);
+    assert_eq!(random["picked"][0]["title"], "First Post");
+    // explaining doesn't commit anything
+    assert_eq!(explain(), random);
+    for path in dir.delivered() {
+        std::fs::remove_file(path).unwrap();
+    }
+    assert_eq!(run(), vec!["First Post"]);
+}
@@ -0,0 +1,701 @@
+#![allow(dead_code)]
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::HeaderMap;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use rusqlite::Connection;
+use saga::{Config, Saga};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+/// A canned response served for a path
+#[derive(Clone)]
+pub struct Mock {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// A header the request must carry, answered with 401 otherwise
+    pub required: Option<(String, String)>,
+}
+
+impl Mock {
+    pub fn new(content_type: &str, body: impl Into<Vec<u8>>) -> Mock {
+        Mock {
+            status: 200,
+            headers: vec![(String::from("content-type"), content_type.to_string())],
+            body: body.into(),
+            required: None,
+        }
+    }
+
+    pub fn status(status: u16) -> Mock {
+        Mock {
+            status,
+            headers: vec![],
+            body: vec![],
+            required: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Mock {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn require(mut self, name: &str, value: &str) -> Mock {
+        self.required = Some((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+struct Recorded {
+    path: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    mocks: HashMap<String, Mock>,
+    requests: Vec<Recorded>,
+}
+
+/// An http server on a random local port serving canned responses, 404
+/// for paths without one, and recording every request it gets
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    pub fn start() -> MockServer {
+        let state = Arc::new(Mutex::new(State::default()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = state.clone();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        continue;
+                    };
+                    let state = served.clone();
+                    let service = service_fn(move |request| respond(state.clone(), request));
+                    tokio::task::spawn(async move {
+                        let _ = http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            });
+        });
+        MockServer { addr, state }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Serve the response at the path, which may include a query
+    pub fn mock(&self, path: &str, mock: Mock) {
+        self.state
+            .lock()
+            .unwrap()
+            .mocks
+            .insert(path.to_string(), mock);
+    }
+
+    /// Serve a feed fixture from tests/fixtures at the path
+    pub fn feed(&self, path: &str, fixture_name: &str) {
+        self.mock(path, Mock::new("application/xml", fixture(fixture_name)));
+    }
+
+    /// Headers of every request made for the path, in order
+    pub fn requests(&self, path: &str) -> Vec<HeaderMap> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|x| x.path == path)
+            .map(|x| x.headers.clone())
+            .collect()
+    }
+
+    /// Bodies of every request made for the path, in order
+    pub fn bodies(&self, path: &str) -> Vec<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .requests
+            .iter()
+            .filter(|x| x.path == path)
+            .map(|x| x.body.clone())
+            .collect()
+    }
+
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests(path).len()
+    }
+}
+
+async fn respond(
+    state: Arc<Mutex<State>>,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |x| x.as_str())
+        .to_string();
+    let headers = request.headers().clone();
+    let body = match request.into_body().collect().await {
+        Ok(body) => body.to_bytes().to_vec(),
+        Err(_) => vec![],
+    };
+    let mut state = state.lock().unwrap();
+    let mut mock = state
+        .mocks
+        .get(&path)
+        .cloned()
+        .unwrap_or_else(|| Mock::status(404));
+    if let Some((name, value)) = &mock.required
+        && headers.get(name).is_none_or(|x| x != value)
+    {
+        mock = Mock::status(401);
+    }
+    state.requests.push(Recorded {
+        path,
+        headers,
+        body,
+    });
+    let mut response = Response::builder().status(StatusCode::from_u16(mock.status).unwrap());
+    for (name, value) in &mock.headers {
+        response = response.header(name, value);
+    }
+    Ok(response.body(Full::new(Bytes::from(mock.body))).unwrap())
+}
+
+pub fn fixture(name: &str) -> String {
+    String::from_utf8(fixture_bytes(name)).unwrap()
+}
+
+pub fn fixture_bytes(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    fs::read(&path).unwrap_or_else(|e| panic!("fixture {:?}: {}", path, e))
+}
+
+/// A temporary directory holding a config, with folder delivery into its
+/// out directory unless the yaml says otherwise
+pub struct TestDir {
+    pub dir: TempDir,
+}
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        TestDir {
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    pub fn out(&self) -> PathBuf {
+        self.path("out")
+    }
+
+    /// Load a config from the yaml, written to config.yml
+    pub fn config(&self, yaml: &str) -> Config {
+        let path = self.path("config.yml");
+        fs::write(&path, yaml).unwrap();
+        saga::get_config(Some(&path)).unwrap()
+    }
+
+    /// A config delivering to the out folder with the given rss list
+    /// and any other top level settings
+    pub fn folder_config(&self, rss: &str, extra: &str) -> Config {
+        self.config(&format!(
+            "delivery: folder\nfolder: {}\noutput_dir: {}\nschedule: \"0 0 7 * * *\"\nrss:\n{}\n{}",
+            self.out().display(),
+            self.path("epubs").display(),
+            rss,
+            extra
+        ))
+    }
+
+    /// A config emailing you@example.com with the file transport, which
+    /// writes each message to the out folder. email holds any other email
+    /// settings, indented under it.
+    pub fn email_config(&self, rss: &str, email: &str, extra: &str) -> Config {
+        self.config(&format!(
+            "delivery: email\nemail:\n  to: you@example.com\n  from: saga@example.com\n  transport: file\n  file_dir: {}\n{}\noutput_dir: {}\nschedule: \"0 0 7 * * *\"\nrss:\n{}\n{}",
+            self.out().display(),
+            email,
+            self.path("epubs").display(),
+            rss,
+            extra
+        ))
+    }
+
+    /// The files delivered to the out folder
+    pub fn delivered(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(self.out()) else {
+            return vec![];
+        };
+        let mut paths: Vec<PathBuf> = entries.map(|x| x.unwrap().path()).collect();
+        paths.sort();
+        paths
+    }
+}
+
+/// One configured feed, picked from without random backfill
+pub fn feed(url: &str) -> String {
+    format!(
+        "  - url: {}\n    random: false\n    max_entries_per_run: 10",
+        url
+    )
+}
+
+/// An rss feed with a single entry whose content is the given html
+pub fn rss_with_content(html: &str) -> String {
+    rss_with_entry("https://content.example.com/entry", html)
+}
+
+/// An rss feed with a single entry linking to the url
+pub fn rss_with_entry(link: &str, html: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Content Feed</title>
+    <link>https://content.example.com/</link>
+    <item>
+      <title>Content Entry</title>
+      <link>{0}</link>
+      <guid>{0}</guid>
+      <pubDate>Mon, 01 Jan 2024 08:00:00 GMT</pubDate>
+      <description><![CDATA[{1}]]></description>
+    </item>
+  </channel>
+</rss>"#,
+        link, html
+    )
+}
+
+pub fn saga(config: Config) -> Saga {
+    Saga::new(config, Connection::open_in_memory().unwrap()).unwrap()
+}
+
+/// Names of every file in the epub
+pub fn epub_names(path: &Path) -> Vec<String> {
+    let archive = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+    archive.file_names().map(String::from).collect()
+}
+
+/// The file in the epub whose name ends with the suffix
+pub fn epub_file(path: &Path, suffix: &str) -> String {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+    let name = archive
+        .file_names()
+        .find(|x| x.ends_with(suffix))
+        .unwrap_or_else(|| panic!("no {} in {:?}", suffix, path))
+        .to_string();
+    let mut text = String::new();
+    std::io::Read::read_to_string(&mut archive.by_name(&name).unwrap(), &mut text).unwrap();
+    text
+}
+
+/// The epub's package document with its metadata
+pub fn epub_package(path: &Path) -> String {
+    epub_file(path, ".opf")
+}
+
+/// Every xhtml document in the epub, concatenated
+pub fn epub_text(path: &Path) -> String {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+    let mut text = String::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).unwrap();
+        if file.name().ends_with(".xhtml") {
+            std::io::Read::read_to_string(&mut file, &mut text).unwrap();
+        }
+    }
+    text
+}
+
+/// A message appended to the mock imap server
+pub struct Appended {
+    pub folder: String,
+    pub message: Vec<u8>,
+}
+
+/// A plain text imap server on a random local port that accepts one
+/// login, records the messages appended with it, and answers anything
+/// else with BAD
+pub struct ImapServer {
+    pub port: u16,
+    commands: Arc<Mutex<Vec<String>>>,
+    appended: Arc<Mutex<Vec<Appended>>>,
+}
+
+impl ImapServer {
+    pub fn start(username: &str, password: &str) -> ImapServer {
+        let login = vec![username.as_bytes().to_vec(), password.as_bytes().to_vec()];
+        ImapServer::serve(Some(login))
+    }
+
+    /// Greets with PREAUTH and answers a login with BAD, as the
+    /// connection is already logged in
+    pub fn preauthenticated() -> ImapServer {
+        ImapServer::serve(None)
+    }
+
+    fn serve(login: Option<Vec<Vec<u8>>>) -> ImapServer {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let commands = Arc::new(Mutex::new(vec![]));
+        let appended = Arc::new(Mutex::new(vec![]));
+        let (served_commands, served_appended) = (commands.clone(), appended.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let _ = serve_imap(stream, login.as_ref(), &served_commands, &served_appended);
+            }
+        });
+        ImapServer {
+            port,
+            commands,
+            appended,
+        }
+    }
+
+    /// Every command line received, without its tag, literals replaced
+    /// by their {size}
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    pub fn appended(&self) -> std::sync::MutexGuard<'_, Vec<Appended>> {
+        self.appended.lock().unwrap()
+    }
+}
+
+fn serve_imap(
+    stream: std::net::TcpStream,
+    login: Option<&Vec<Vec<u8>>>,
+    commands: &Mutex<Vec<String>>,
+    appended: &Mutex<Vec<Appended>>,
+) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut writer = stream.try_clone()?;
+    let mut reader = std::io::BufReader::new(stream);
+    let mut logged_in = login.is_none();
+    match logged_in {
+        true => writer.write_all(b"* PREAUTH mock imap ready\r\n")?,
+        false => writer.write_all(b"* OK mock imap ready\r\n")?,
+    }
+    loop {
+        let Some((line, literals)) = read_imap_command(&mut reader, &mut writer)? else {
+            return Ok(());
+        };
+        let (tag, command) = line.split_once(' ').unwrap_or((&line, ""));
+        commands.lock().unwrap().push(command.to_string());
+        let args = imap_args(command, literals);
+        let reply = if command.starts_with("LOGIN ")
+            && let Some(login) = login
+        {
+            logged_in = &args == login;
+            match logged_in {
+                true => "OK LOGIN completed",
+                false => "NO [AUTHENTICATIONFAILED] invalid credentials",
+            }
+        } else if command.starts_with("APPEND ") && logged_in {
+            let mut args = args.into_iter();
+            appended.lock().unwrap().push(Appended {
+                folder: String::from_utf8(args.next().unwrap()).unwrap(),
+                message: args.next().unwrap(),
+            });
+            "OK APPEND completed"
+        } else if command == "LOGOUT" {
+            writer.write_all(b"* BYE logging out\r\n")?;
+            writer.write_all(format!("{} OK LOGOUT completed\r\n", tag).as_bytes())?;
+            return Ok(());
+        } else {
+            "BAD unexpected command"
+        };
+        writer.write_all(format!("{} {}\r\n", tag, reply).as_bytes())?;
+    }
+}
+
+// A command line with its literals read in, asking for each with a
+// continuation. None once the client hangs up.
+fn read_imap_command(
+    reader: &mut impl std::io::BufRead,
+    writer: &mut impl std::io::Write,
+) -> std::io::Result<Option<(String, Vec<Vec<u8>>)>> {
+    let mut line = String::new();
+    let mut literals = vec![];
+    loop {
+        let mut part = String::new();
+        if reader.read_line(&mut part)? == 0 {
+            return Ok(None);
+        }
+        let part = part.trim_end_matches("\r\n");
+        line.push_str(part);
+        let size = part
+            .strip_suffix('}')
+            .and_then(|x| x.rsplit_once('{'))
+            .and_then(|(_, size)| size.parse::<usize>().ok());
+        let Some(size) = size else {
+            return Ok(Some((line, literals)));
+        };
+        writer.write_all(b"+ Ready for literal data\r\n")?;
+        let mut literal = vec![0; size];
+        reader.read_exact(&mut literal)?;
+        literals.push(literal);
+    }
+}
+
+// the arguments after the command name, quoted strings unescaped and
+// literals in place of their sizes
+fn imap_args(command: &str, literals: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let mut literals = literals.into_iter();
+    let mut args = vec![];
+    let mut chars = command.chars().skip_while(|x| *x != ' ').peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {}
+            '"' => {
+                let mut arg = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => arg.extend(chars.next()),
+                        '"' => break,
+                        c => arg.push(c),
+                    }
+                }
+                args.push(arg.into_bytes());
+            }
+            '{' => {
+                chars.by_ref().find(|x| *x == '}');
+                args.push(literals.next().unwrap());
+            }
+            c => {
+                let mut arg = String::from(c);
+                while let Some(c) = chars.next_if(|x| *x != ' ') {
+                    arg.push(c);
+                }
+                args.push(arg.into_bytes());
+            }
+        }
+    }
+    args
+}
+
+/// A tls server for localhost with a fresh self-signed certificate,
+/// recording what makes it past the handshake
+pub struct TlsServer {
+    pub port: u16,
+    /// The certificate, to be trusted through ca_file
+    pub cert_pem: Vec<u8>,
+    /// The sha-256 fingerprint of the certificate, to be pinned
+    pub fingerprint: String,
+    received: Arc<Mutex<Vec<String>>>,
+}
+
+type TlsStream = openssl::ssl::SslStream<std::net::TcpStream>;
+
+impl TlsServer {
+    /// Answers every http request with the same raw response
+    pub fn http(response: &str) -> TlsServer {
+        let response = response.to_string();
+        TlsServer::start(move |stream, received| serve_https(stream, &response, received))
+    }
+
+    /// Speaks enough smtp to take messages, recording each command
+    pub fn smtp() -> TlsServer {
+        TlsServer::start(serve_smtp)
+    }
+
+    fn start(
+        serve: impl Fn(&mut TlsStream, &Mutex<Vec<String>>) -> std::io::Result<()> + Send + 'static,
+    ) -> TlsServer {
+        use openssl::ssl::{SslAcceptor, SslMethod};
+        let (cert, key) = self_signed_localhost();
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(vec![]));
+        let served = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a handshake the client gives up on never gets to a request
+                let Ok(mut stream) = acceptor.accept(stream) else {
+                    continue;
+                };
+                let _ = serve(&mut stream, &served);
+            }
+        });
+        TlsServer {
+            port,
+            cert_pem: cert.to_pem().unwrap(),
+            fingerprint: openssl::sha::sha256(&cert.to_der().unwrap())
+                .iter()
+                .map(|x| format!("{:02x}", x))
+                .collect(),
+            received,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("https://localhost:{}{}", self.port, path)
+    }
+
+    /// Every http request, head and body, or smtp command received
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+fn self_signed_localhost() -> (
+    openssl::x509::X509,
+    openssl::pkey::PKey<openssl::pkey::Private>,
+) {
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509, X509NameBuilder};
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+        .unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&cert.x509v3_context(None, None))
+        .unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    (cert.build(), key)
+}
+
+fn serve_https(
+    stream: &mut TlsStream,
+    response: &str,
+    received: &Mutex<Vec<String>>,
+) -> std::io::Result<()> {
+    use std::io::{Read, Write};
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    // the head, then as much body as it announces
+    let body_start = loop {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+        if let Some(end) = request.windows(4).position(|x| x == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+    let length: usize = head
+        .lines()
+        .find_map(|x| x.strip_prefix("content-length:"))
+        .map(|x| x.trim().parse().unwrap())
+        .unwrap_or(0);
+    while request.len() < body_start + length {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    received
+        .lock()
+        .unwrap()
+        .push(String::from_utf8_lossy(&request).to_string());
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+fn serve_smtp(stream: &mut TlsStream, received: &Mutex<Vec<String>>) -> std::io::Result<()> {
+    use std::io::{BufRead, Write};
+    let mut stream = std::io::BufReader::new(stream);
+    stream.get_mut().write_all(b"220 localhost mock smtp\r\n")?;
+    let mut data = false;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim_end();
+        let reply = if data {
+            // the message itself isn't recorded, only that it ended
+            if line != "." {
+                continue;
+            }
+            data = false;
+            "250 queued"
+        } else {
+            received.lock().unwrap().push(line.to_string());
+            let command = line.split(' ').next().unwrap_or_default();
+            match command.to_uppercase().as_str() {
+                "EHLO" => "250-localhost\r\n250 AUTH PLAIN LOGIN",
+                "AUTH" => "235 authenticated",
+                "DATA" => {
+                    data = true;
+                    "354 go ahead"
+                }
+                "QUIT" => {
+                    stream.get_mut().write_all(b"221 bye\r\n")?;
+                    return Ok(());
+                }
+                _ => "250 ok",
+            }
+        };
+        stream
+            .get_mut()
+            .write_all(format!("{}\r\n", reply).as_bytes())?;
+    }
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::TestDir;
+
+#[test]
+fn invalid_log_level_is_rejected_at_load() {
+    let dir = TestDir::new();
+    let path = dir.path("config.yml");
+    std::fs::write(
+        &path,
+        "delivery: folder\nfolder: out\nschedule: \"0 0 7 * * *\"\nrss: []\nlog_level: loud\n",
+    )
+    .unwrap();
+
+    let e = saga::get_config(Some(&path)).unwrap_err();
+
+    assert!(format!("{:#}", e).contains("log_level \"loud\""), "{:#}", e);
+}
+
+#[test]
+fn log_level_and_file_are_accepted() {
+    let dir = TestDir::new();
+    let config = dir.config(&format!(
+        "delivery: folder\nfolder: out\nschedule: \"0 0 7 * * *\"\nrss: []\nlog_level: debug\nlog_file: {}\n",
+        dir.path("saga.log").display()
+    ));
+
+    config.configure_logging().unwrap();
+
+    assert!(dir.path("saga.log").exists());
+}
@@ -0,0 +1,318 @@
+mod common;
+
+use common::{
+    Mock, MockServer, TestDir, epub_file, epub_names, epub_package, epub_text, feed, fixture_bytes,
+    rss_with_content, saga,
+};
+use saga::{RunOptions, Saga};
+use std::path::PathBuf;
+
+// the epub delivered for feeds of the fixtures, in that order, with the
+// dir holding it
+fn deliver_feeds(fixtures: &[&str], extra: &str) -> (TestDir, PathBuf) {
+    let server = MockServer::start();
+    let mut rss = vec![];
+    for name in fixtures {
+        let path = format!("/{}", name);
+        server.feed(&path, name);
+        rss.push(feed(&server.url(&path)));
+    }
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&rss.join("\n"), extra));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let epub = dir.delivered().remove(0);
+    (dir, epub)
+}
+
+fn deliver(extra: &str) -> (TestDir, PathBuf) {
+    deliver_feeds(&["rss.xml"], extra)
+}
+
+#[test]
+fn language_defaults_to_english() {
+    let (_dir, epub) = deliver("");
+
+    let package = epub_package(&epub);
+    assert!(
+        package.contains("<dc:language>en</dc:language>"),
+        "{}",
+        package
+    );
+}
+
+#[test]
+fn package_has_the_configured_language() {
+    let (_dir, epub) = deliver("epub_language: de");
+
+    let package = epub_package(&epub);
+    assert!(
+        package.contains("<dc:language>de</dc:language>"),
+        "{}",
+        package
+    );
+    assert!(package.contains("<dc:date>"), "{}", package);
+}
+
+#[test]
+fn generator_names_saga() {
+    let (_dir, epub) = deliver("");
+
+    let generator = format!(
+        "<meta name=\"generator\" content=\"saga {}\"",
+        env!("CARGO_PKG_VERSION")
+    );
+    assert!(epub_text(&epub).contains(&generator));
+}
+
+#[test]
+fn chapters_link_to_the_original_article() {
+    let (_dir, epub) = deliver("");
+
+    let text = epub_text(&epub);
+    for link in [
+        "https://blog.example.com/first",
+        "https://blog.example.com/second",
+    ] {
+        let footer = format!("<a href=\"{}\">Read the original</a>", link);
+        assert!(text.contains(&footer), "{} not in {}", footer, text);
+    }
+}
+
+#[test]
+fn toc_nests_entries_under_their_feed() {
+    let (_dir, epub) = deliver_feeds(&["atom.xml", "rss.xml"], "");
+
+    let nav = epub_file(&epub, "nav.xhtml")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("> <", "><");
+    let expected = concat!(
+        r#"<li><a href="feed_1.xhtml">Example Atom Feed</a><ol>"#,
+        r#"<li><a href="chapter_1.xhtml">Atom Entry</a></li></ol></li>"#,
+        r#"<li><a href="feed_2.xhtml">Example Blog</a><ol>"#,
+        r#"<li><a href="chapter_2.xhtml">First Post</a></li>"#,
+        r#"<li><a href="chapter_3.xhtml">Second Post</a></li></ol></li>"#,
+    );
+    assert!(nav.contains(expected), "{}", nav);
+}
+
+#[test]
+fn summary_links_every_entry_to_its_chapter() {
+    let (_dir, epub) = deliver_feeds(&["atom.xml", "rss.xml"], "");
+
+    let summary = epub_file(&epub, "summary.xhtml");
+    for (i, title) in ["Atom Entry", "First Post", "Second Post"]
+        .into_iter()
+        .enumerate()
+    {
+        let chapter = format!("chapter_{}.xhtml", i + 1);
+        let link = format!("<a href=\"{}\">{}</a>", chapter, title);
+        assert!(summary.contains(&link), "{} not in {}", link, summary);
+        assert!(epub_file(&epub, &chapter).contains(title));
+    }
+    assert!(summary.contains("Example Blog · by Ada Lovelace · &lt;1 min read"));
+}
+
+#[test]
+fn single_entry_has_no_summary() {
+    let (_dir, epub) = deliver_feeds(&["atom.xml"], "");
+
+    assert!(!epub_text(&epub).contains("In This Digest"));
+}
+
+#[test]
+fn every_image_is_embedded_and_failed_ones_keep_their_url() {
+    let server = MockServer::start();
+    let mut html = String::from("<p>Photos from the trip.</p>");
+    for path in ["/a.png", "/b.png", "/c.png", "/missing.png"] {
+        if path != "/missing.png" {
+            server.mock(path, Mock::new("image/png", fixture_bytes("pixel.png")));
+        }
+        html.push_str(&format!("<p><img src=\"{}\"></p>", server.url(path)));
+    }
+    server.mock(
+        "/feed.xml",
+        Mock::new("application/rss+xml", rss_with_content(&html)),
+    );
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(
+        &feed(&server.url("/feed.xml")),
+        "max_concurrency: 4\nfetch:\n  allow_private: true",
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let epub = dir.delivered().remove(0);
+    let chapter = epub_file(&epub, "chapter_1.xhtml");
+    let names = epub_names(&epub);
+    for image in ["image_1.png", "image_2.png", "image_3.png"] {
+        assert!(
+            chapter.contains(&format!("src=\"images/{}\"", image)),
+            "{}",
+            chapter
+        );
+        assert!(names.iter().any(|x| x.ends_with(image)), "{:?}", names);
+    }
+    assert!(chapter.contains(&format!("src=\"{}\"", server.url("/missing.png"))));
+}
+
+#[test]
+fn enclosures_are_linked_from_the_chapter() {
+    let (_dir, epub) = deliver_feeds(&["podcast.xml"], "");
+
+    let text = epub_text(&epub);
+    assert!(
+        text.contains("Show notes for the first episode."),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains(
+            r#"<a href="https://media.example.com/episode-1.mp3">Listen to the audio</a>"#
+        ),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains(
+            r#"<audio controls="controls" src="https://media.example.com/episode-1.mp3">"#
+        ),
+        "{}",
+        text
+    );
+}
+
+#[test]
+fn epub2_enclosures_are_only_linked() {
+    let (_dir, epub) = deliver_feeds(&["podcast.xml"], "epub_version: \"2\"");
+
+    let text = epub_text(&epub);
+    assert!(text.contains("Listen to the audio"), "{}", text);
+    assert!(!text.contains("<audio"), "{}", text);
+}
+
+#[test]
+fn favicons_are_shown_next_to_feed_titles() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    server.feed("/updated.xml", "updated_only.xml");
+    server.mock(
+        "/",
+        Mock::new(
+            "text/html",
+            r#"<html><head><link rel="shortcut icon" href="/icon.png"></head><body></body></html>"#,
+        ),
+    );
+    server.mock(
+        "/icon.png",
+        Mock::new("image/png", fixture_bytes("pixel.png")),
+    );
+    let dir = TestDir::new();
+    let database = dir.path("saga.db3");
+    let run = |path: &str, name: &str| {
+        let config = dir.folder_config(
+            &feed(&server.url(path)),
+            &format!(
+                "show_favicons: true\nepub_name_template: {}\nfetch:\n  allow_private: true",
+                name
+            ),
+        );
+        Saga::new(config, saga::get_db_conn(Some(&database)).unwrap())
+            .unwrap()
+            .run_once(&RunOptions::default())
+            .unwrap();
+    };
+
+    run("/rss.xml", "first");
+    // another feed of the same site uses the cached icon
+    run("/updated.xml", "second");
+
+    let delivered = dir.delivered();
+    assert_eq!(delivered.len(), 2);
+    for epub in &delivered {
+        assert!(
+            epub_names(epub)
+                .iter()
+                .any(|x| x.ends_with("images/icon_1.png"))
+        );
+        let text = epub_text(epub);
+        assert!(text.contains(r#"class="saga-feed-icon""#), "{}", text);
+    }
+    assert_eq!(server.hits("/icon.png"), 1);
+}
+
+#[test]
+fn missing_favicons_are_left_out() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(
+        &feed(&server.url("/rss.xml")),
+        "show_favicons: true\nfetch:\n  allow_private: true",
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let epub = dir.delivered().remove(0);
+    assert!(!epub_names(&epub).iter().any(|x| x.contains("icon_")));
+    assert!(!epub_text(&epub).contains("saga-feed-icon"));
+    assert_eq!(server.hits("/favicon.ico"), 1);
+}
+
+#[test]
+fn mimetype_is_the_first_entry_and_uncompressed() {
+    let (_dir, epub) = deliver("");
+
+    let bytes = std::fs::read(&epub).unwrap();
+    // readers sniff the type from the first local file header
+    assert_eq!(&bytes[..4], b"PK\x03\x04");
+    assert_eq!(&bytes[8..10], &[0, 0]);
+    assert_eq!(&bytes[30..38], b"mimetype");
+    let extra = u16::from_le_bytes([bytes[28], bytes[29]]) as usize;
+    assert_eq!(&bytes[38 + extra..][..20], b"application/epub+zip");
+
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(&epub).unwrap()).unwrap();
+    for i in 1..archive.len() {
+        let file = archive.by_index(i).unwrap();
+        if file.name().ends_with(".xhtml") {
+            assert_eq!(file.compression(), zip::CompressionMethod::Deflated);
+        }
+    }
+}
+
+#[test]
+fn failed_feeds_are_listed_in_the_run_report() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let missing = server.url("/missing.xml");
+    let rss = format!("{}\n{}", feed(&missing), feed(&server.url("/rss.xml")));
+    let run = |extra: &str| {
+        let dir = TestDir::new();
+        saga(dir.folder_config(&rss, &format!("max_retries: 0\n{}", extra)))
+            .run_once(&RunOptions::default())
+            .unwrap();
+        let epub = dir.delivered().remove(0);
+        (dir, epub)
+    };
+
+    let (_dir, epub) = run("include_run_report: true");
+    let (_plain_dir, plain) = run("");
+
+    let report = epub_file(&epub, "run_report.xhtml");
+    assert!(report.contains("<h1>Saga Run Report</h1>"), "{}", report);
+    assert!(report.contains("<h2>Failed feeds</h2>"), "{}", report);
+    assert!(
+        report.contains(&format!("<li><strong>{}</strong>: ", missing)),
+        "{}",
+        report
+    );
+    assert!(report.contains("404"), "{}", report);
+    assert!(
+        !epub_names(&plain)
+            .iter()
+            .any(|x| x.ends_with("run_report.xhtml"))
+    );
+}
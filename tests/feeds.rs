@@ -0,0 +1,299 @@
+mod common;
+
+use chrono::{TimeZone, Utc};
+use common::{Mock, MockServer, TestDir, feed, fixture, rss_with_content, saga};
+use saga::RunOptions;
+
+#[test]
+fn parses_rss_entries() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), ""));
+
+    let mut entries = saga.pick_entries(&RunOptions::default()).unwrap();
+    entries.sort_by_key(|x| x.published);
+
+    assert_eq!(entries.len(), 2);
+    let first = &entries[0];
+    assert_eq!(first.id, "https://blog.example.com/first");
+    assert_eq!(first.title, "First Post");
+    assert_eq!(first.feed_title, "Example Blog");
+    assert_eq!(first.feed_url, server.url("/rss.xml"));
+    assert_eq!(first.authors, vec!["Ada Lovelace"]);
+    assert_eq!(
+        first.published,
+        Some(Utc.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap())
+    );
+    assert_eq!(
+        first.link.as_deref(),
+        Some("https://blog.example.com/first")
+    );
+    assert!(first.content.contains("The first post."));
+    assert_eq!(entries[1].authors, vec!["Grace Hopper"]);
+}
+
+#[test]
+fn parses_atom_entries() {
+    let server = MockServer::start();
+    server.feed("/atom.xml", "atom.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/atom.xml")), ""));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert_eq!(entry.id, "urn:uuid:1225c695-cfb8-4ebb-aaaa-80da344efa6a");
+    assert_eq!(entry.title, "Atom Entry");
+    assert_eq!(entry.feed_title, "Example Atom Feed");
+    assert_eq!(entry.authors, vec!["Alan Turing"]);
+    assert_eq!(
+        entry.published,
+        Some(Utc.with_ymd_and_hms(2024, 2, 1, 12, 0, 0).unwrap())
+    );
+    assert_eq!(
+        entry.updated,
+        Some(Utc.with_ymd_and_hms(2024, 2, 2, 12, 0, 0).unwrap())
+    );
+    assert!(entry.content.contains("Atom content."));
+}
+
+#[test]
+fn malformed_feed_fails_only_that_feed() {
+    let server = MockServer::start();
+    server.feed("/broken.xml", "malformed.xml");
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let rss = format!(
+        "{}\n{}",
+        feed(&server.url("/broken.xml")),
+        feed(&server.url("/rss.xml"))
+    );
+    let saga = saga(dir.folder_config(&rss, ""));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().all(|x| x.feed_url == server.url("/rss.xml")));
+}
+
+#[test]
+fn repeated_entry_ids_keep_the_first_occurrence() {
+    let server = MockServer::start();
+    server.feed("/repeat.xml", "duplicate_ids.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/repeat.xml")), ""));
+
+    let mut titles: Vec<String> = saga
+        .pick_entries(&RunOptions::default())
+        .unwrap()
+        .into_iter()
+        .map(|x| x.title)
+        .collect();
+    titles.sort();
+
+    assert_eq!(titles, vec!["Original", "Other"]);
+}
+
+#[test]
+fn entries_with_only_an_updated_date_use_it_as_published() {
+    let server = MockServer::start();
+    server.feed("/updated.xml", "updated_only.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/updated.xml")), ""));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    let revised = entries.iter().find(|x| x.title == "Revised").unwrap();
+    assert_eq!(
+        revised.published,
+        Some(Utc.with_ymd_and_hms(2024, 3, 2, 9, 0, 0).unwrap())
+    );
+    let undated = entries.iter().find(|x| x.title == "Undated").unwrap();
+    assert_eq!(undated.published, None);
+}
+
+#[test]
+fn parallel_fetch_matches_sequential() {
+    let server = MockServer::start();
+    let fixtures = [
+        "rss.xml",
+        "atom.xml",
+        "updated_only.xml",
+        "duplicate_ids.xml",
+    ];
+    let mut rss = vec![];
+    for name in fixtures {
+        let path = format!("/{}", name);
+        server.feed(&path, name);
+        rss.push(feed(&server.url(&path)));
+    }
+    let pick = |max_concurrency: usize| {
+        let dir = TestDir::new();
+        let saga = saga(dir.folder_config(
+            &rss.join("\n"),
+            &format!("max_concurrency: {}", max_concurrency),
+        ));
+        saga.pick_entries(&RunOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.feed_url, x.id))
+            .collect::<Vec<_>>()
+    };
+
+    let sequential = pick(1);
+
+    assert_eq!(sequential.len(), 7);
+    assert_eq!(pick(4), sequential);
+}
+
+#[test]
+fn parses_json_feed_items() {
+    let server = MockServer::start();
+    server.mock(
+        "/feed.json",
+        Mock::new("application/feed+json", fixture("feed.json")),
+    );
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/feed.json")), ""));
+
+    let mut entries = saga.pick_entries(&RunOptions::default()).unwrap();
+    entries.sort_by_key(|x| x.published);
+
+    assert_eq!(entries.len(), 2);
+    let html = &entries[0];
+    assert_eq!(html.id, "https://json.example.com/html");
+    assert_eq!(html.title, "Html Item");
+    assert_eq!(html.feed_title, "Example JSON Feed");
+    assert_eq!(html.authors, vec!["Margaret Hamilton"]);
+    assert_eq!(
+        html.published,
+        Some(Utc.with_ymd_and_hms(2024, 4, 1, 10, 0, 0).unwrap())
+    );
+    assert!(html.content.contains("<em>html</em>"));
+    let text = &entries[1];
+    assert!(text.content.contains("<p>First line &amp; more.</p>"));
+    assert!(text.content.contains("<p>Second paragraph.</p>"));
+}
+
+#[test]
+fn escaped_html_in_cdata_becomes_paragraphs() {
+    let server = MockServer::start();
+    server.mock(
+        "/escaped.xml",
+        Mock::new(
+            "application/rss+xml",
+            rss_with_content(
+                "&lt;p&gt;First paragraph.&lt;/p&gt;&lt;p&gt;Second paragraph.&lt;/p&gt;",
+            ),
+        ),
+    );
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/escaped.xml")), ""));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    let content = &entries[0].content;
+    assert!(content.contains("<p>First paragraph.</p>"), "{}", content);
+    assert!(content.contains("<p>Second paragraph.</p>"), "{}", content);
+    assert!(!content.contains("&lt;p&gt;"), "{}", content);
+}
+
+#[test]
+fn format_override_parses_a_feed_behind_junk() {
+    let server = MockServer::start();
+    let body = format!(
+        "<html><body>Warning: deprecated call</body></html>\n{}",
+        fixture("rss.xml")
+    );
+    server.mock("/wrong.xml", Mock::new("text/html", body));
+    let pick = |extra: &str| {
+        let dir = TestDir::new();
+        let rss = format!("{}{}", feed(&server.url("/wrong.xml")), extra);
+        saga(dir.folder_config(&rss, ""))
+            .pick_entries(&RunOptions::default())
+            .unwrap()
+            .len()
+    };
+
+    assert_eq!(pick(""), 0);
+    assert_eq!(pick("\n    format: rss"), 2);
+    assert_eq!(pick("\n    format: atom"), 0);
+}
+
+#[test]
+fn code_blocks_keep_their_whitespace_and_language() {
+    let code = "fn main() {\n    let  x = 1;\n\n    println!(\"{}\", x);\n}";
+    let server = MockServer::start();
+    server.mock(
+        "/code.xml",
+        Mock::new(
+            "application/rss+xml",
+            rss_with_content(&format!(
+                "<p>Some   code:</p><pre><code class=\"language-rust\">{}</code></pre>",
+                code
+            )),
+        ),
+    );
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/code.xml")), ""));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    let content = &entries[0].content;
+    assert!(content.contains("<p>Some code:</p>"), "{}", content);
+    assert!(
+        content.contains(&format!(
+            "<pre><code class=\"language-rust\">{}</code></pre>",
+            code
+        )),
+        "{}",
+        content
+    );
+}
+
+#[test]
+fn use_cache_reads_the_cached_feed_without_fetching() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(
+        &feed(&server.url("/rss.xml")),
+        &format!("cache_dir: {}", dir.path("cache").display()),
+    ));
+    let ids = |options: &RunOptions| {
+        saga.pick_entries(options)
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.id, x.title, x.content))
+            .collect::<Vec<_>>()
+    };
+
+    let fetched = ids(&RunOptions::default());
+    let cached = ids(&RunOptions {
+        use_cache: true,
+        ..Default::default()
+    });
+
+    assert_eq!(fetched.len(), 2);
+    assert_eq!(cached, fetched);
+    assert_eq!(server.hits("/rss.xml"), 1);
+}
+
+#[test]
+fn use_cache_needs_a_cache_dir() {
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed("https://example.com/feed.xml"), ""));
+
+    let error = saga
+        .pick_entries(&RunOptions {
+            use_cache: true,
+            ..Default::default()
+        })
+        .unwrap_err()
+        .to_string();
+
+    assert!(error.contains("--use-cache needs cache_dir"), "{}", error);
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use common::{Mock, MockServer, TestDir, fixture, saga};
+use saga::RunOptions;
+
+fn serve_page(server: &MockServer, html: &str) {
+    server.mock("/notes", Mock::new("text/html; charset=utf-8", html));
+}
+
+fn pages(server: &MockServer, selector: Option<&str>) -> String {
+    let mut pages = format!("pages:\n  - url: {}", server.url("/notes"));
+    if let Some(selector) = selector {
+        pages.push_str(&format!("\n    selector: {}", selector));
+    }
+    pages
+}
+
+#[test]
+fn page_article_is_extracted() {
+    let server = MockServer::start();
+    serve_page(&server, &fixture("page.html"));
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config("  []", &pages(&server, None)));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert_eq!(entry.title, "Notes on Parsing");
+    assert_eq!(entry.feed_url, server.url("/notes"));
+    assert_eq!(entry.link.as_deref(), Some(server.url("/notes").as_str()));
+    assert!(entry.content.contains("Parsers turn text into trees."));
+    assert!(
+        entry
+            .content
+            .contains(&format!("href=\"{}\"", server.url("/posts/lexing")))
+    );
+    for left_out in ["Home", "Copyright", "track()"] {
+        assert!(!entry.content.contains(left_out), "{}", entry.content);
+    }
+}
+
+#[test]
+fn selector_picks_the_content() {
+    let server = MockServer::start();
+    serve_page(&server, &fixture("page.html"));
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config("  []", &pages(&server, Some("aside.related"))));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    assert!(entries[0].content.contains("Related reading."));
+    assert!(!entries[0].content.contains("Parsers"));
+}
+
+#[test]
+fn page_is_delivered_again_only_once_it_changes() {
+    let server = MockServer::start();
+    serve_page(&server, &fixture("page.html"));
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config("  []", &pages(&server, None)));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+    assert!(
+        saga.pick_entries(&RunOptions::default())
+            .unwrap()
+            .is_empty()
+    );
+
+    serve_page(
+        &server,
+        &fixture("page.html").replace("into trees", "into syntax trees"),
+    );
+    assert_eq!(saga.pick_entries(&RunOptions::default()).unwrap().len(), 1);
+}
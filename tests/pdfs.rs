@@ -0,0 +1,33 @@
+mod common;
+
+use common::{TestDir, saga};
+use saga::RunOptions;
+use std::path::Path;
+
+#[test]
+fn pdf_text_becomes_an_entry() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/paper.pdf");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config("  []", &format!("pdfs:\n  - path: {}", path.display())));
+
+    let entries = saga.pick_entries(&RunOptions::default()).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    let entry = &entries[0];
+    assert_eq!(entry.title, "Trees All the Way Down");
+    assert_eq!(entry.feed_title, "PDF");
+    assert_eq!(entry.link, None);
+    // both pages, each paragraph kept apart
+    assert!(
+        entry
+            .content
+            .contains("<p>Abstract. Parsers turn text into trees.</p>"),
+        "{}",
+        entry.content
+    );
+    assert!(
+        entry
+            .content
+            .contains("<p>Conclusion. Trees are useful.</p>")
+    );
+}
@@ -0,0 +1,968 @@
+mod common;
+
+use common::{ImapServer, Mock, MockServer, TestDir, TlsServer, epub_text, feed, fixture, saga};
+use saga::RunOptions;
+
+#[test]
+fn run_once_delivers_an_epub_of_the_feed() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), ""));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let delivered = dir.delivered();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0].extension().unwrap(), "epub");
+    let text = epub_text(&delivered[0]);
+    assert!(text.contains("First Post"));
+    assert!(text.contains("Second Post"));
+}
+
+#[test]
+fn second_run_delivers_nothing() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), ""));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+    assert_eq!(dir.delivered().len(), 1);
+
+    assert!(
+        saga.pick_entries(&RunOptions::default())
+            .unwrap()
+            .is_empty()
+    );
+    saga.run_once(&RunOptions::default()).unwrap();
+    assert_eq!(dir.delivered().len(), 1);
+}
+
+#[test]
+fn dry_run_leaves_the_backlog_alone() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let url = server.url("/rss.xml");
+    let saga = saga(dir.folder_config(&feed(&url), "skip_email_when_empty: false"));
+    let dry_run = RunOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+
+    saga.run_once(&dry_run).unwrap();
+    assert!(dir.delivered().is_empty());
+
+    // with the feed down, backfill only has the stored backlog to go on
+    server.mock("/rss.xml", Mock::status(404));
+    assert_eq!(saga.backfill(&url, 10, &RunOptions::default()).unwrap(), 0);
+}
+
+#[test]
+fn toc_run_leaves_entries_for_a_full_run() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), ""));
+    let toc = RunOptions {
+        toc_only: true,
+        ..Default::default()
+    };
+
+    saga.run_once(&toc).unwrap();
+    assert_eq!(dir.delivered().len(), 1);
+
+    assert_eq!(saga.pick_entries(&RunOptions::default()).unwrap().len(), 2);
+}
+
+#[test]
+fn toc_run_marks_entries_processed_when_set() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), "toc_marks_processed: true"));
+    let toc = RunOptions {
+        toc_only: true,
+        ..Default::default()
+    };
+
+    saga.run_once(&toc).unwrap();
+    assert_eq!(dir.delivered().len(), 1);
+    assert!(epub_text(&dir.delivered()[0]).contains("First Post"));
+
+    assert!(
+        saga.pick_entries(&RunOptions::default())
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn failed_delivery_leaves_entries_unprocessed() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), ""));
+    // the delivery folder can't be created over a file
+    std::fs::write(dir.out(), "").unwrap();
+
+    assert!(saga.run_once(&RunOptions::default()).is_err());
+    assert_eq!(saga.pick_entries(&RunOptions::default()).unwrap().len(), 2);
+}
+
+#[test]
+fn failing_feed_doesnt_hold_back_the_others() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let rss = format!(
+        "{}\n{}",
+        feed(&server.url("/missing.xml")),
+        feed(&server.url("/rss.xml"))
+    );
+    let saga = saga(dir.folder_config(&rss, "max_retries: 0"));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let delivered = dir.delivered();
+    assert_eq!(delivered.len(), 1);
+    assert!(epub_text(&delivered[0]).contains("First Post"));
+}
+
+#[test]
+fn not_modified_feed_is_not_processed() {
+    let server = MockServer::start();
+    server.mock(
+        "/rss.xml",
+        Mock::new("application/xml", fixture("rss.xml"))
+            .header("etag", "\"v1\"")
+            .header("last-modified", "Mon, 01 Jan 2024 08:00:00 GMT"),
+    );
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), ""));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+    server.mock("/rss.xml", Mock::status(304));
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    assert_eq!(dir.delivered().len(), 1);
+    let requests = server.requests("/rss.xml");
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].get("if-none-match").is_none());
+    assert_eq!(requests[1]["if-none-match"], "\"v1\"");
+    assert_eq!(
+        requests[1]["if-modified-since"],
+        "Mon, 01 Jan 2024 08:00:00 GMT"
+    );
+}
+
+#[test]
+fn failed_email_leaves_entries_unprocessed() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.email_config(&feed(&server.url("/rss.xml")), "", ""));
+    // the file transport can't write into a file
+    std::fs::write(dir.out(), "").unwrap();
+
+    assert!(saga.run_once(&RunOptions::default()).is_err());
+    assert_eq!(saga.pick_entries(&RunOptions::default()).unwrap().len(), 2);
+
+    std::fs::remove_file(dir.out()).unwrap();
+    saga.run_once(&RunOptions::default()).unwrap();
+    assert_eq!(dir.delivered().len(), 1);
+    assert!(
+        saga.pick_entries(&RunOptions::default())
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn feeds_with_their_own_recipient_are_sent_separately() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    server.feed("/atom.xml", "atom.xml");
+    let dir = TestDir::new();
+    let rss = format!(
+        "{}\n{}\n    to: kindle@example.com",
+        feed(&server.url("/rss.xml")),
+        feed(&server.url("/atom.xml"))
+    );
+    let saga = saga(dir.email_config(&rss, "", ""));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let mut recipients: Vec<String> = dir
+        .delivered()
+        .iter()
+        .map(|x| {
+            let message = std::fs::read_to_string(x).unwrap();
+            message
+                .lines()
+                .find_map(|x| x.strip_prefix("To: "))
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    recipients.sort();
+    assert_eq!(recipients, vec!["kindle@example.com", "you@example.com"]);
+}
+
+// the json posted to the hook by the only run
+fn notification(server: &MockServer) -> serde_json::Value {
+    let bodies = server.bodies("/hook");
+    assert_eq!(bodies.len(), 1);
+    serde_json::from_slice(&bodies[0]).unwrap()
+}
+
+#[test]
+fn run_is_reported_to_the_notify_url() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    server.mock("/hook", Mock::status(204));
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(
+        &feed(&server.url("/rss.xml")),
+        &format!("notify_url: {}", server.url("/hook")),
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let payload = notification(&server);
+    assert_eq!(payload["success"], true);
+    assert_eq!(payload["error"], serde_json::Value::Null);
+    assert_eq!(payload["feeds_processed"], 1);
+    assert_eq!(payload["entries_delivered"], 2);
+    for time in ["started_at", "finished_at"] {
+        let time = payload[time].as_str().unwrap();
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(time).is_ok(),
+            "{}",
+            time
+        );
+    }
+    assert_eq!(
+        server.requests("/hook")[0]["content-type"],
+        "application/json"
+    );
+}
+
+#[test]
+fn failed_run_is_reported_with_its_error() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    server.mock("/hook", Mock::status(204));
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(
+        &feed(&server.url("/rss.xml")),
+        &format!("notify_url: {}", server.url("/hook")),
+    ));
+    std::fs::write(dir.out(), "").unwrap();
+
+    assert!(saga.run_once(&RunOptions::default()).is_err());
+
+    let payload = notification(&server);
+    assert_eq!(payload["success"], false);
+    assert!(payload["error"].is_string());
+}
+
+#[test]
+fn failing_notify_url_doesnt_fail_the_run() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    server.mock("/hook", Mock::status(500));
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(
+        &feed(&server.url("/rss.xml")),
+        &format!("notify_url: {}", server.url("/hook")),
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    assert_eq!(dir.delivered().len(), 1);
+    assert_eq!(server.hits("/hook"), 1);
+}
+
+// titles delivered by a run capped to two entries, then the titles the
+// next run would pick
+fn capped_run(keep: &str) -> (Vec<String>, Vec<String>) {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    server.feed("/atom.xml", "atom.xml");
+    let dir = TestDir::new();
+    let rss = format!(
+        "{}\n{}",
+        feed(&server.url("/rss.xml")),
+        feed(&server.url("/atom.xml"))
+    );
+    let saga = saga(dir.folder_config(
+        &rss,
+        &format!("max_total_entries: 2\nmax_total_keep: {}", keep),
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let text = epub_text(&dir.delivered()[0]);
+    let delivered = ["First Post", "Second Post", "Atom Entry"]
+        .into_iter()
+        .filter(|x| text.contains(x))
+        .map(String::from)
+        .collect();
+    let mut left: Vec<String> = saga
+        .pick_entries(&RunOptions::default())
+        .unwrap()
+        .into_iter()
+        .map(|x| x.title)
+        .collect();
+    left.sort();
+    (delivered, left)
+}
+
+#[test]
+fn total_cap_keeps_the_oldest_and_leaves_the_rest_unprocessed() {
+    let (delivered, left) = capped_run("oldest");
+
+    assert_eq!(delivered, vec!["First Post", "Second Post"]);
+    assert_eq!(left, vec!["Atom Entry"]);
+}
+
+#[test]
+fn total_cap_can_keep_the_newest() {
+    let (delivered, left) = capped_run("newest");
+
+    assert_eq!(delivered, vec!["Second Post", "Atom Entry"]);
+    assert_eq!(left, vec!["First Post"]);
+}
+
+#[test]
+fn kindle_mode_sends_only_the_epub_without_a_subject() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.email_config(
+        &feed(&server.url("/rss.xml")),
+        "  kindle_mode: true",
+        "output_formats: [epub, markdown]\nepub_name_template: \"Daily Digest {date}\"",
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let delivered = dir.delivered();
+    assert_eq!(delivered.len(), 1);
+    let message = std::fs::read_to_string(&delivered[0]).unwrap();
+    let header = |name: &str| {
+        message
+            .lines()
+            .find(|x| x.starts_with(name))
+            .map(|x| x[name.len()..].trim().to_string())
+    };
+    assert_eq!(header("Subject:").unwrap_or_default(), "");
+    assert_eq!(
+        message.matches("Content-Disposition: attachment").count(),
+        1
+    );
+    assert!(message.contains("Content-Type: application/epub+zip"));
+    let disposition = header("Content-Disposition:").unwrap();
+    let name = disposition
+        .split("filename=")
+        .nth(1)
+        .unwrap()
+        .trim_matches('"');
+    assert!(name.starts_with("Daily_Digest_"), "{}", name);
+    assert!(name.ends_with(".epub"), "{}", name);
+}
+
+#[test]
+fn digest_email_has_the_subject() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.email_config(
+        &feed(&server.url("/rss.xml")),
+        "",
+        "email_subject_template: \"{feeds} ({count})\"",
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let message = std::fs::read_to_string(&dir.delivered()[0]).unwrap();
+    assert!(
+        message.lines().any(|x| x == "Subject: Example Blog (2)"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn test_email_is_sent_without_an_attachment() {
+    let dir = TestDir::new();
+    let config = dir.email_config(&feed("https://example.com/feed.xml"), "", "");
+
+    saga::send_test_email(&config).unwrap();
+
+    let delivered = dir.delivered();
+    assert_eq!(delivered.len(), 1);
+    let message = std::fs::read_to_string(&delivered[0]).unwrap();
+    assert!(
+        message.lines().any(|x| x == "Subject: Saga test email"),
+        "{}",
+        message
+    );
+    assert!(
+        message.lines().any(|x| x == "To: you@example.com"),
+        "{}",
+        message
+    );
+    assert!(message.contains("Your email settings work."), "{}", message);
+    assert!(
+        !message.contains("Content-Disposition: attachment"),
+        "{}",
+        message
+    );
+}
+
+#[test]
+fn test_email_needs_an_email_config() {
+    let dir = TestDir::new();
+    let config = dir.folder_config(&feed("https://example.com/feed.xml"), "");
+
+    let error = saga::send_test_email(&config).unwrap_err().to_string();
+
+    assert!(error.contains("there is no email config"), "{}", error);
+}
+
+#[test]
+fn feed_option_processes_only_that_feed() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    server.feed("/atom.xml", "atom.xml");
+    let dir = TestDir::new();
+    let rss = format!(
+        "{}\n{}",
+        feed(&server.url("/rss.xml")),
+        feed(&server.url("/atom.xml"))
+    );
+    let saga = saga(dir.folder_config(&rss, ""));
+    let only_rss = RunOptions {
+        feeds: Some(vec![server.url("/rss.xml")]),
+        ..Default::default()
+    };
+
+    saga.run_once(&only_rss).unwrap();
+
+    assert_eq!(server.hits("/atom.xml"), 0);
+    let text = epub_text(&dir.delivered()[0]);
+    assert!(text.contains("First Post"));
+    assert!(!text.contains("Atom Entry"));
+    // the other feed was left as it was, so its entry is still new
+    let left: Vec<String> = saga
+        .pick_entries(&RunOptions::default())
+        .unwrap()
+        .into_iter()
+        .map(|x| x.title)
+        .collect();
+    assert_eq!(left, vec!["Atom Entry"]);
+}
+
+#[test]
+fn feed_option_rejects_unknown_feeds() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&server.url("/rss.xml")), ""));
+    let options = RunOptions {
+        feeds: Some(vec![String::from("https://example.com/typo.xml")]),
+        ..Default::default()
+    };
+
+    let error = saga.run_once(&options).unwrap_err().to_string();
+
+    assert!(
+        error.contains("Unknown feed https://example.com/typo.xml"),
+        "{}",
+        error
+    );
+    assert!(error.contains(&server.url("/rss.xml")), "{}", error);
+    assert_eq!(server.hits("/rss.xml"), 0);
+    assert!(dir.delivered().is_empty());
+}
+
+// an rss feed of twenty entries, one published each day of January 2024
+fn rss_of_twenty() -> String {
+    let items: String = (1..=20)
+        .map(|day| {
+            format!(
+                "<item><title>Entry {0:02}</title><link>https://archive.example.com/{0}</link><guid>https://archive.example.com/{0}</guid><pubDate>{0:02} Jan 2024 08:00:00 GMT</pubDate><description>Entry number {0}.</description></item>",
+                day
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel><title>Archive</title><link>https://archive.example.com/</link>{}</channel></rss>",
+        items
+    )
+}
+
+#[test]
+fn backfill_delivers_the_oldest_up_to_the_limit() {
+    let server = MockServer::start();
+    server.mock(
+        "/archive.xml",
+        Mock::new("application/rss+xml", rss_of_twenty()),
+    );
+    let url = server.url("/archive.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&feed(&url), ""));
+
+    let delivered = saga.backfill(&url, 5, &RunOptions::default()).unwrap();
+
+    assert_eq!(delivered, 5);
+    let text = epub_text(&dir.delivered()[0]);
+    for day in 1..=5 {
+        assert!(text.contains(&format!("Entry {:02}", day)), "{}", day);
+    }
+    assert!(!text.contains("Entry 06"));
+    // only the five delivered are processed, the rest are still backlog
+    std::fs::remove_dir_all(dir.out()).unwrap();
+    assert_eq!(
+        saga.backfill(&url, 100, &RunOptions::default()).unwrap(),
+        15
+    );
+    let text = epub_text(&dir.delivered()[0]);
+    assert!(!text.contains("Entry 05"));
+    assert!(text.contains("Entry 06"));
+    assert!(text.contains("Entry 20"));
+}
+
+#[test]
+fn backfill_respects_max_age() {
+    let server = MockServer::start();
+    server.mock(
+        "/archive.xml",
+        Mock::new("application/rss+xml", rss_of_twenty()),
+    );
+    let url = server.url("/archive.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(&format!("{}\n    max_age_days: 30", feed(&url)), ""));
+
+    assert_eq!(saga.backfill(&url, 5, &RunOptions::default()).unwrap(), 0);
+    assert!(dir.delivered().is_empty());
+}
+
+// an rss feed of entries each with about size bytes of text that
+// compresses poorly, so the epubs are about as large as the text
+fn rss_of_large_entries(count: usize, size: usize) -> String {
+    let mut seed: u64 = 1;
+    let items: String = (1..=count)
+        .map(|i| {
+            let mut text = String::new();
+            while text.len() < size {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                let word: String = (0..8)
+                    .map(|shift| (b'a' + (seed >> (shift * 5 + 20)) as u8 % 26) as char)
+                    .collect();
+                text.push_str(&word);
+                text.push(' ');
+            }
+            format!(
+                "<item><title>Large {0}</title><link>https://large.example.com/{0}</link><guid>https://large.example.com/{0}</guid><pubDate>0{0} Jan 2024 08:00:00 GMT</pubDate><description>&lt;p&gt;{1}&lt;/p&gt;</description></item>",
+                i, text
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel><title>Large</title><link>https://large.example.com/</link>{}</channel></rss>",
+        items
+    )
+}
+
+#[test]
+fn oversized_digest_is_split_across_emails() {
+    let server = MockServer::start();
+    server.mock(
+        "/large.xml",
+        Mock::new("application/rss+xml", rss_of_large_entries(3, 600_000)),
+    );
+    let dir = TestDir::new();
+    let saga = saga(dir.email_config(&feed(&server.url("/large.xml")), "", "max_attachment_mb: 1"));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let emails = dir.delivered();
+    assert_eq!(emails.len(), 3);
+    let mut subjects: Vec<String> = emails
+        .iter()
+        .map(|x| {
+            let message = std::fs::read_to_string(x).unwrap();
+            message
+                .lines()
+                .find_map(|x| x.strip_prefix("Subject: "))
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    subjects.sort();
+    for (i, subject) in subjects.iter().enumerate() {
+        assert!(
+            subject.ends_with(&format!("(part {} of 3)", i + 1)),
+            "{}",
+            subject
+        );
+    }
+    let mut epubs: Vec<_> = std::fs::read_dir(dir.path("epubs"))
+        .unwrap()
+        .map(|x| x.unwrap().path())
+        .collect();
+    epubs.sort();
+    assert_eq!(epubs.len(), 3);
+    let texts: Vec<String> = epubs.iter().map(|x| epub_text(x)).collect();
+    for (i, epub) in epubs.iter().enumerate() {
+        let name = epub.file_name().unwrap().to_string_lossy().into_owned();
+        assert!(name.ends_with(&format!("_part{}.epub", i + 1)), "{}", name);
+        assert!(std::fs::metadata(epub).unwrap().len() < 1024 * 1024);
+    }
+    // every entry is in exactly one of the parts
+    for i in 1..=3 {
+        let title = format!("Large {}", i);
+        assert_eq!(
+            texts.iter().filter(|x| x.contains(&title)).count(),
+            1,
+            "{}",
+            title
+        );
+    }
+}
+
+#[test]
+fn sent_emails_are_appended_to_the_mbox_archive() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let mbox = dir.path("archive/sent.mbox");
+    let config = || {
+        dir.email_config(
+            &feed(&server.url("/rss.xml")),
+            "",
+            &format!("archive_mbox: {}", mbox.display()),
+        )
+    };
+
+    // fresh databases so both runs send the digest
+    saga(config()).run_once(&RunOptions::default()).unwrap();
+    saga(config()).run_once(&RunOptions::default()).unwrap();
+
+    let archive = std::fs::read_to_string(&mbox).unwrap();
+    let starts: Vec<&str> = archive.lines().filter(|x| x.starts_with("From ")).collect();
+    assert_eq!(starts.len(), 2, "{}", archive);
+    assert!(
+        starts
+            .iter()
+            .all(|x| x.starts_with("From saga@example.com "))
+    );
+    assert!(archive.starts_with("From saga@example.com "));
+    assert!(archive.contains("\n\nFrom saga@example.com "));
+    assert_eq!(archive.matches("To: you@example.com\n").count(), 2);
+    assert!(!archive.contains('\r'));
+    assert!(archive.ends_with("\n\n"));
+}
+
+#[test]
+fn since_surfaces_entries_from_before_the_last_run() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let rss = format!(
+        "  - url: {}\n    random: false\n    max_entries_per_run: 1",
+        server.url("/rss.xml")
+    );
+    let saga = saga(dir.folder_config(&rss, ""));
+    // the first run only takes the newest entry, leaving the older one
+    // behind the feed's last processed time
+    saga.run_once(&RunOptions::default()).unwrap();
+    assert!(
+        saga.pick_entries(&RunOptions::default())
+            .unwrap()
+            .is_empty()
+    );
+    let since = RunOptions {
+        since: Some(saga::parse_since("2023-12-31").unwrap()),
+        ..Default::default()
+    };
+
+    let entries = saga.pick_entries(&since).unwrap();
+
+    let titles: Vec<&str> = entries.iter().map(|x| x.title.as_str()).collect();
+    assert_eq!(titles, vec!["First Post"]);
+    // only looking doesn't move the stored time
+    assert!(
+        saga.pick_entries(&RunOptions::default())
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn serialized_parts_are_delivered_as_one_chapter() {
+    let server = MockServer::start();
+    server.feed("/serial.xml", "serial.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.folder_config(
+        &format!(
+            "{}\n    merge_by_title_prefix: true",
+            feed(&server.url("/serial.xml"))
+        ),
+        "",
+    ));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    let text = epub_text(&dir.delivered()[0]);
+    let first = text.find("It was dark.").unwrap();
+    let second = text.find("Dawn came at last.").unwrap();
+    assert!(first < second);
+    assert!(
+        text.contains(r#"<h2 id="part_1">The Long Night: Part 1</h2>"#),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains(r#"<h2 id="part_2">The Long Night: Part 2</h2>"#),
+        "{}",
+        text
+    );
+    // both parts are processed with the merged chapter
+    assert!(
+        saga.pick_entries(&RunOptions {
+            since: Some(chrono::DateTime::UNIX_EPOCH),
+            ..Default::default()
+        })
+        .unwrap()
+        .is_empty()
+    );
+}
+
+// an rss feed of one entry with the guid and link
+fn rss_with_guid(guid: &str, link: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel><title>Redesigned</title><link>https://blog.example.com/</link><item><title>Stable Post</title><link>{}</link><guid isPermaLink=\"false\">{}</guid><pubDate>Mon, 01 Jan 2024 08:00:00 GMT</pubDate><description>Unchanged.</description></item></channel></rss>",
+        link, guid
+    )
+}
+
+#[test]
+fn changed_id_scheme_doesnt_send_entries_again() {
+    let server = MockServer::start();
+    let serve = |guid: &str, link: &str| {
+        server.mock(
+            "/feed.xml",
+            Mock::new("application/rss+xml", rss_with_guid(guid, link)),
+        )
+    };
+    // looking at every entry regardless of when the feed last ran, so
+    // only the ids decide what is new
+    let everything = RunOptions {
+        since: Some(chrono::DateTime::UNIX_EPOCH),
+        ..Default::default()
+    };
+    let titles = |saga: &saga::Saga| {
+        saga.pick_entries(&everything)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.title)
+            .collect::<Vec<_>>()
+    };
+    let dir = TestDir::new();
+    let by_id = saga(dir.folder_config(&feed(&server.url("/feed.xml")), ""));
+    let by_link = saga(dir.folder_config(&feed(&server.url("/feed.xml")), "dedup_by_link: true"));
+    let link = "https://blog.example.com/stable-post?utm_source=rss";
+    serve("https://blog.example.com/stable-post", link);
+    by_id.run_once(&RunOptions::default()).unwrap();
+    by_link.run_once(&RunOptions::default()).unwrap();
+
+    // a tidied up permalink is the same id
+    serve("https://Blog.Example.com/stable-post/#main", link);
+    assert!(titles(&by_id).is_empty());
+
+    // a different kind of id is only recognized by the link
+    serve("urn:uuid:8c1f0f3a-4b0e-4a52-9d0b-2f7f0d1c9e11", link);
+    assert_eq!(titles(&by_id), vec!["Stable Post"]);
+    assert!(titles(&by_link).is_empty());
+
+    // and the backlog, stored under the new id, isn't delivered again
+    let backfilled = by_link
+        .backfill(&server.url("/feed.xml"), 10, &RunOptions::default())
+        .unwrap();
+    assert_eq!(backfilled, 0);
+}
+
+#[test]
+fn imap_transport_appends_the_digest_to_the_folder() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let imap = ImapServer::start("reader", "p\"ss");
+    let dir = TestDir::new();
+    let saga = saga(dir.config(&format!(
+        "delivery: email\nemail:\n  to: you@example.com\n  from: saga@example.com\n  relay: 127.0.0.1\n  port: {}\n  encryption: none\n  transport: imap\n  imap_folder: Digests\n  username: reader\n  password: 'p\"ss'\noutput_dir: {}\nschedule: \"0 0 7 * * *\"\nrss:\n{}",
+        imap.port,
+        dir.path("epubs").display(),
+        feed(&server.url("/rss.xml"))
+    )));
+
+    saga.run_once(&RunOptions::default()).unwrap();
+
+    assert_eq!(
+        imap.commands()
+            .iter()
+            .map(|x| x.split(' ').next().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["LOGIN", "APPEND", "LOGOUT"]
+    );
+    let appended = imap.appended();
+    assert_eq!(appended.len(), 1);
+    assert_eq!(appended[0].folder, "Digests");
+    let message = String::from_utf8(appended[0].message.clone()).unwrap();
+    assert!(
+        message.contains("\r\nTo: you@example.com\r\n"),
+        "{}",
+        message
+    );
+    assert!(
+        message.contains("Content-Type: application/epub+zip"),
+        "{}",
+        message
+    );
+    assert!(
+        message.contains("Content-Disposition: attachment"),
+        "{}",
+        message
+    );
+    assert!(message.ends_with("\r\n"), "{}", message);
+    assert!(!message.contains("\n\n"), "bare newlines in {}", message);
+}
+
+#[test]
+fn imap_login_failure_fails_the_delivery() {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let imap = ImapServer::start("reader", "right");
+    let dir = TestDir::new();
+    let saga = saga(dir.config(&format!(
+        "delivery: email\nemail_max_retries: 0\nemail:\n  to: you@example.com\n  from: saga@example.com\n  relay: 127.0.0.1\n  port: {}\n  encryption: none\n  transport: imap\n  username: reader\n  password: wrong\noutput_dir: {}\nschedule: \"0 0 7 * * *\"\nrss:\n{}",
+        imap.port,
+        dir.path("epubs").display(),
+        feed(&server.url("/rss.xml"))
+    )));
+
+    let error = format!("{:#}", saga.run_once(&RunOptions::default()).unwrap_err());
+
+    assert!(error.contains("imap login as reader failed"), "{}", error);
+    assert!(imap.appended().is_empty());
+}
+
+// delivers the feed to the mock imap server's Digests folder as reader
+fn run_against_imap(imap: &ImapServer, password: &str) -> anyhow::Result<()> {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let saga = saga(dir.config(&format!(
+        "delivery: email\nemail_max_retries: 0\nemail:\n  to: you@example.com\n  from: saga@example.com\n  relay: 127.0.0.1\n  port: {}\n  encryption: none\n  transport: imap\n  imap_folder: Digests\n  username: reader\n  password: {:?}\noutput_dir: {}\nschedule: \"0 0 7 * * *\"\nrss:\n{}",
+        imap.port,
+        password,
+        dir.path("epubs").display(),
+        feed(&server.url("/rss.xml"))
+    )));
+    saga.run_once(&RunOptions::default())
+}
+
+#[test]
+fn non_ascii_credentials_are_sent_as_literals() {
+    let imap = ImapServer::start("reader", "pässwörd");
+
+    run_against_imap(&imap, "pässwörd").unwrap();
+
+    assert_eq!(imap.commands()[0], "LOGIN \"reader\" {10}");
+    assert_eq!(imap.appended().len(), 1);
+}
+
+#[test]
+fn line_breaks_in_credentials_are_refused_before_sending() {
+    let imap = ImapServer::start("reader", "right");
+
+    let error = format!(
+        "{:#}",
+        run_against_imap(&imap, "right\r\na2 DELETE INBOX").unwrap_err()
+    );
+
+    assert!(error.contains("line breaks"), "{}", error);
+    assert!(imap.commands().is_empty(), "{:?}", imap.commands());
+}
+
+#[test]
+fn preauthenticated_connection_skips_the_login() {
+    let imap = ImapServer::preauthenticated();
+
+    run_against_imap(&imap, "unused").unwrap();
+
+    assert_eq!(
+        imap.commands()
+            .iter()
+            .map(|x| x.split(' ').next().unwrap())
+            .collect::<Vec<_>>(),
+        vec!["APPEND", "LOGOUT"]
+    );
+    assert_eq!(imap.appended()[0].folder, "Digests");
+}
+
+// emails the feed to a pinned smtp relay, trusting its certificate
+fn run_against_pinned_relay(relay: &TlsServer, pin: &str) -> anyhow::Result<()> {
+    let server = MockServer::start();
+    server.feed("/rss.xml", "rss.xml");
+    let dir = TestDir::new();
+    let ca_file = dir.path("ca.pem");
+    std::fs::write(&ca_file, &relay.cert_pem).unwrap();
+    let saga = saga(dir.config(&format!(
+        "delivery: email\nemail_max_retries: 0\nemail:\n  to: you@example.com\n  from: saga@example.com\n  relay: localhost\n  port: {}\n  encryption: tls\n  username: reader\n  password: secret\ntls:\n  ca_file: {}\n  pins:\n    localhost: \"{}\"\noutput_dir: {}\nschedule: \"0 0 7 * * *\"\nrss:\n{}",
+        relay.port,
+        ca_file.display(),
+        pin,
+        dir.path("epubs").display(),
+        feed(&server.url("/rss.xml"))
+    )));
+    saga.run_once(&RunOptions::default())
+}
+
+fn smtp_commands(relay: &TlsServer) -> Vec<String> {
+    relay
+        .received()
+        .iter()
+        .map(|x| x.split([' ', ':']).next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn pinned_smtp_relay_gets_the_digest() {
+    let relay = TlsServer::smtp();
+
+    run_against_pinned_relay(&relay, &relay.fingerprint).unwrap();
+
+    assert_eq!(
+        smtp_commands(&relay),
+        vec!["EHLO", "AUTH", "MAIL", "RCPT", "DATA", "QUIT"]
+    );
+}
+
+#[test]
+fn pin_mismatch_fails_before_logging_in_to_the_relay() {
+    let relay = TlsServer::smtp();
+
+    let error = format!(
+        "{:#}",
+        run_against_pinned_relay(&relay, &"00".repeat(32)).unwrap_err()
+    );
+
+    assert!(error.contains("does not match its pin"), "{}", error);
+    // the connection is given up before credentials or the message
+    assert_eq!(smtp_commands(&relay), vec!["EHLO", "QUIT"]);
+}
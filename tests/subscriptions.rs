@@ -0,0 +1,92 @@
+mod common;
+
+use common::TestDir;
+use saga::subscriptions;
+use std::fs;
+use std::path::PathBuf;
+
+const CONFIG: &str = "\
+delivery: folder
+folder: out
+schedule: \"0 0 7 * * *\"
+max_retries: 5
+rss:
+  - url: https://one.example.com/feed.xml
+    random: false
+    max_entries_per_run: 3
+";
+
+fn config_file(dir: &TestDir) -> PathBuf {
+    let path = dir.path("config.yml");
+    fs::write(&path, CONFIG).unwrap();
+    path
+}
+
+#[test]
+fn add_and_remove_round_trip() {
+    let dir = TestDir::new();
+    let path = config_file(&dir);
+
+    subscriptions::add_feed(&path, "https://two.example.com/feed.xml", true).unwrap();
+    let config = saga::get_config(Some(&path)).unwrap();
+    assert_eq!(
+        config.feed_urls(),
+        vec![
+            "https://one.example.com/feed.xml",
+            "https://two.example.com/feed.xml"
+        ]
+    );
+    assert!(subscriptions::add_feed(&path, "https://two.example.com/feed.xml", true).is_err());
+
+    subscriptions::remove_feed(&path, "https://two.example.com/feed.xml").unwrap();
+    let config = saga::get_config(Some(&path)).unwrap();
+    assert_eq!(config.feed_urls(), vec!["https://one.example.com/feed.xml"]);
+    assert!(subscriptions::remove_feed(&path, "https://two.example.com/feed.xml").is_err());
+
+    // settings saga was given are kept through the rewrites
+    let yaml = fs::read_to_string(&path).unwrap();
+    assert!(yaml.contains("max_retries: 5"));
+    assert!(yaml.contains("max_entries_per_run: 3"));
+}
+
+#[test]
+fn import_adds_the_feeds_not_yet_configured() {
+    let dir = TestDir::new();
+    let path = config_file(&dir);
+    let opml = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/subscriptions.opml");
+
+    let added = subscriptions::import_opml(&path, &opml).unwrap();
+
+    assert_eq!(added, 2);
+    let config = saga::get_config(Some(&path)).unwrap();
+    assert_eq!(
+        config.feed_urls(),
+        vec![
+            "https://one.example.com/feed.xml",
+            "https://two.example.com/feed.xml",
+            "https://three.example.com/rss?a=1&b=2"
+        ]
+    );
+}
+
+#[test]
+fn export_writes_a_file_import_reads_back() {
+    let dir = TestDir::new();
+    let opml = dir.path("export.opml");
+    let urls = [
+        "https://one.example.com/feed.xml",
+        "https://x.example.com/?a=1&b=2",
+    ];
+
+    subscriptions::export_opml(&opml, &urls).unwrap();
+    let path = dir.path("empty.yml");
+    fs::write(
+        &path,
+        "delivery: folder\nfolder: out\nschedule: \"0 0 7 * * *\"\nrss: []\n",
+    )
+    .unwrap();
+    let added = subscriptions::import_opml(&path, &opml).unwrap();
+
+    assert_eq!(added, 2);
+    assert_eq!(saga::get_config(Some(&path)).unwrap().feed_urls(), urls);
+}